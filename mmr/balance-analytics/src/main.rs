@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+
+use clap::Parser;
+use mmr_libs::{classification::SessionRules, jsonl::{JsonlReader, LineFormat, SessionClassRecord, SessionDimensionRecord}, math::wilson_interval};
+use report::{BalanceReport, BalanceRow, Dimension};
+use session_group::{SessionGroups, ALL};
+
+mod report;
+mod session_group;
+
+/// Command-line arguments for the balance analytics report.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Session classification written by `leaderboard-8` (`session_id:..,classes:a|b` lines).
+    #[arg(long, default_value = "data/leaderboard_v1/session_classification_8")]
+    pub classification: String,
+    /// Session-level dimension as `FIELD=PATH`: the `FIELD` of every `{"session_id":..}` (or
+    /// `session_id_str`) line of the JSONL file at `PATH`. Repeat to cross several dimensions.
+    #[arg(long, value_parser = parse_dimension)]
+    pub dimension: Vec<(String, String)>,
+    /// Session rules of the `leaderboard-8` run; their `factions` section gives the default
+    /// `--factions` (built-in factions when omitted).
+    #[arg(long)]
+    pub rules: Option<String>,
+    /// Classes that name the winner of a session; every other class is a balance group.
+    /// Default: `team_<n>_win` for the n-th of `--factions`.
+    #[arg(long, value_delimiter = ',')]
+    pub outcomes: Vec<String>,
+    /// Faction that wins with each outcome class, in the same order. Default: the registered
+    /// factions of `--rules`.
+    #[arg(long, value_delimiter = ',')]
+    pub factions: Vec<String>,
+    /// Standard deviations of the Wilson confidence interval (1.96 for 95%).
+    #[arg(long, default_value_t = 1.96)]
+    pub z: f64,
+    /// Output prefix: writes `<output>.json` and `<output>.csv`.
+    #[arg(long, default_value = "data/balance_analytics_v1")]
+    pub output: String,
+}
+
+fn parse_dimension(arg: &str) -> Result<(String, String), String> {
+  match arg.split_once('=') {
+    Some((field, path)) if !field.is_empty() && !path.is_empty() => Ok((field.to_string(), path.to_string())),
+    _ => Err("expected FIELD=PATH, got \"".to_string() + arg + "\"")
+  }
+}
+
+/// Reads the `field` of every session of a dimension file; string values are taken as they
+/// are, other JSON values by their JSON text. Rows without the field are rejected.
+fn read_dimension(field: &str, path: &str) -> HashMap<u64, String> {
+  let mut values: HashMap<u64, String> = HashMap::new();
+  let mut rows = JsonlReader::<SessionDimensionRecord>::open(path).unwrap_or_else(|error| panic!("{}: {}", path, error));
+  while let Some((line, row)) = rows.next() {
+    match row.fields.get(field) {
+      Some(serde_json::Value::String(value)) => {values.insert(row.session_id, value.clone());},
+      Some(value) if !value.is_null() => {values.insert(row.session_id, value.to_string());},
+      _ => rows.reject(line, "missing field ".to_string() + field)
+    }
+  }
+  println!("{}", rows.report());
+  values
+}
+
+/// Entry point for the balance analytics report.
+///
+/// 1. Takes the factions from `--rules` (`faction_1` and `faction_2` by default) and their
+///    outcome classes `team_<n>_win` unless they are given, and reads every `--dimension`
+///    file into a session -> value map.
+/// 2. Reads the named session classes of a `leaderboard-8` run and splits them into balance
+///    groups and the outcome (see `SessionGroups`); sessions missing from a dimension are
+///    skipped.
+/// 3. Counts, per (dimension values, balance group, faction), the sessions and the wins of the
+///    faction, every session also counting in the `all` balance group. Sessions whose outcome
+///    is none of `--outcomes` are only counted as `undecided`.
+/// 4. Computes the win rates with their Wilson intervals, flags those whose interval excludes
+///    the fair share `1 / factions`, and writes the report as JSON and CSV.
+fn main() {
+  let mut args: Args = Args::parse();
+  if args.factions.is_empty() {
+    args.factions = args.rules.as_ref().map(|path| SessionRules::read(path)).unwrap_or_default().factions.factions;
+  }
+  if args.outcomes.is_empty() {
+    args.outcomes = (1..=args.factions.len()).map(|team| "team_".to_string() + team.to_string().as_str() + "_win").collect();
+  }
+  assert_eq!(args.outcomes.len(), args.factions.len(), "--outcomes and --factions must have the same length");
+
+  let dimensions: Vec<HashMap<u64, String>> = args.dimension.iter().map(|(field, path)| read_dimension(field, path)).collect();
+
+  // (dimension values, balance group, faction) -> (wins, sessions)
+  let mut counts: BTreeMap<(Vec<String>, String, String), (u64, u64)> = BTreeMap::new();
+  let mut sessions: u64 = 0;
+  let mut skipped: u64 = 0;
+  let mut undecided: u64 = 0;
+
+  let mut rows = JsonlReader::<SessionClassRecord>::open(&args.classification)
+    .unwrap_or_else(|error| panic!("{}: {}", args.classification, error))
+    .with_format(LineFormat::KeyValue);
+  for (_line, SessionClassRecord { session_id, classes }) in rows.by_ref() {
+    let values: Option<Vec<String>> = dimensions.iter().map(|dimension| dimension.get(&session_id).cloned()).collect();
+    let values = match values {
+      Some(values) => values,
+      None => {
+        skipped += 1;
+        continue;
+      }
+    };
+    let groups = SessionGroups::new(&classes, &args.outcomes);
+    if !args.outcomes.contains(&groups.win_group) {
+      undecided += 1;
+      continue;
+    }
+    for balance_group in std::iter::once(ALL.to_string()).chain(groups.balance_groups) {
+      for (faction, outcome) in args.factions.iter().zip(args.outcomes.iter()) {
+        let count = counts.entry((values.clone(), balance_group.clone(), faction.clone())).or_insert((0, 0));
+        count.0 += if *outcome == groups.win_group {1} else {0};
+        count.1 += 1;
+      }
+    }
+    sessions += 1;
+  }
+  println!("{}", rows.report());
+
+  let fair_share = 1.0 / args.factions.len() as f64;
+  let rows: Vec<BalanceRow> = counts.into_iter().map(|((dimensions, balance_group, faction), (wins, sessions))| {
+    let outcome = args.outcomes[args.factions.iter().position(|name| *name == faction).unwrap()].clone();
+    let (ci_low, ci_high) = wilson_interval(wins, sessions, args.z).unwrap();
+    BalanceRow {
+      dimensions,
+      balance_group,
+      faction,
+      outcome,
+      wins,
+      sessions,
+      win_rate: wins as f64 / sessions as f64,
+      ci_low,
+      ci_high,
+      significant: fair_share < ci_low || fair_share > ci_high
+    }
+  }).collect();
+
+  let report = BalanceReport {
+    classification: args.classification.clone(),
+    dimensions: args.dimension.iter().map(|(field, path)| Dimension { field: field.clone(), path: path.clone() }).collect(),
+    outcomes: args.outcomes.clone(),
+    factions: args.factions.clone(),
+    z: args.z,
+    fair_share,
+    sessions,
+    skipped,
+    undecided,
+    rows
+  };
+  report.write(&args.output);
+
+  for row in report.rows.iter().filter(|row| row.significant) {
+    println!("dimensions:{},balance_group:{},faction:{},win_rate:{:.4},ci_low:{:.4},ci_high:{:.4},sessions:{}", row.dimensions.join("|"), row.balance_group, row.faction, row.win_rate, row.ci_low, row.ci_high, row.sessions);
+  }
+  println!("sessions:{},skipped:{},undecided:{},rows:{},significant:{}", report.sessions, report.skipped, report.undecided, report.rows.len(), report.rows.iter().filter(|row| row.significant).count());
+}
@@ -0,0 +1,80 @@
+use serde::Serialize;
+
+/// Dimension file joined to the sessions: the field used and the file it is read from.
+#[derive(Clone, Debug, Serialize)]
+pub struct Dimension {
+    pub field: String,
+    pub path: String
+}
+
+/// Win rate of one faction in one (dimension values, balance group) cell.
+#[derive(Clone, Debug, Serialize)]
+pub struct BalanceRow {
+    // Value of every dimension, in `--dimension` order.
+    pub dimensions: Vec<String>,
+    pub balance_group: String,
+    pub faction: String,
+    // Outcome class that marks a win of the faction.
+    pub outcome: String,
+    pub wins: u64,
+    pub sessions: u64,
+    pub win_rate: f64,
+    // Wilson score interval of the win rate.
+    pub ci_low: f64,
+    pub ci_high: f64,
+    // The fair share lies outside the interval.
+    pub significant: bool
+}
+
+/// Balance analytics of one classification run, written as JSON and CSV.
+#[derive(Clone, Debug, Serialize)]
+pub struct BalanceReport {
+    pub classification: String,
+    pub dimensions: Vec<Dimension>,
+    pub outcomes: Vec<String>,
+    pub factions: Vec<String>,
+    pub z: f64,
+    // Win rate of a faction when no faction is favoured: `1 / factions`.
+    pub fair_share: f64,
+    // Classified sessions with a value in every dimension and one of the outcomes.
+    pub sessions: u64,
+    // Classified sessions missing from a dimension file.
+    pub skipped: u64,
+    // Sessions in every dimension without an outcome class (no single winner).
+    pub undecided: u64,
+    // Ordered by dimension values, balance group and faction.
+    pub rows: Vec<BalanceRow>
+}
+
+impl BalanceReport {
+    /// Rows as `;`-separated CSV with one leading column per dimension field.
+    pub fn csv(&self) -> String {
+        let mut str = String::new();
+        for dimension in self.dimensions.iter() {
+            str += (dimension.field.clone() + ";").as_str();
+        }
+        str += "balance_group;faction;outcome;wins;sessions;win_rate;ci_low;ci_high;significant\n";
+        for row in self.rows.iter() {
+            for value in row.dimensions.iter() {
+                str += (value.clone() + ";").as_str();
+            }
+            str += (row.balance_group.clone()
+                + ";" + row.faction.as_str()
+                + ";" + row.outcome.as_str()
+                + ";" + row.wins.to_string().as_str()
+                + ";" + row.sessions.to_string().as_str()
+                + ";" + format!("{:.4}", row.win_rate).as_str()
+                + ";" + format!("{:.4}", row.ci_low).as_str()
+                + ";" + format!("{:.4}", row.ci_high).as_str()
+                + ";" + row.significant.to_string().as_str()
+                + "\n").as_str();
+        }
+        str
+    }
+
+    /// Writes `<prefix>.json` (the full report) and `<prefix>.csv` ([`BalanceReport::csv`]).
+    pub fn write(&self, prefix: &str) {
+        std::fs::write(prefix.to_string() + ".json", serde_json::to_string_pretty(self).unwrap() + "\n").unwrap();
+        std::fs::write(prefix.to_string() + ".csv", self.csv()).unwrap();
+    }
+}
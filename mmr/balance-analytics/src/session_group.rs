@@ -0,0 +1,34 @@
+/// Session class used as the balance group when a session matched no class besides its outcome.
+pub const BALANCED: &str = "balanced";
+
+/// Balance group that counts every session, next to its own balance groups.
+pub const ALL: &str = "all";
+
+/// Outcome of a session that matched none of the outcome classes.
+pub const UNDECIDED: &str = "undecided";
+
+/// Balance groups and outcome of one classified session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionGroups {
+    // Classes that are not outcomes, `balanced` when there is none.
+    pub balance_groups: Vec<String>,
+    // First outcome class of the session, `undecided` when there is none.
+    pub win_group: String
+}
+
+impl SessionGroups {
+    /// Splits the `|`-separated class names written by `leaderboard-8` into balance groups and
+    /// the outcome, `outcomes` being the class names that describe the winner.
+    pub fn new(classes: &str, outcomes: &[String]) -> Self {
+        let classes: Vec<String> = classes.split('|').filter(|class| !class.is_empty()).map(|class| class.to_string()).collect();
+        let mut balance_groups: Vec<String> = classes.iter().filter(|class| !outcomes.contains(class)).cloned().collect();
+        if balance_groups.is_empty() {
+            balance_groups.push(BALANCED.to_string());
+        }
+        let win_group = match classes.iter().find(|class| outcomes.contains(class)) {
+            Some(class) => class.clone(),
+            None => UNDECIDED.to_string()
+        };
+        Self { balance_groups, win_group }
+    }
+}
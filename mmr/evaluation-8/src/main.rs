@@ -0,0 +1,214 @@
+use std::time::Instant;
+use mmr_libs::algorithm::{Algorithm, BoxedTask, RatingTask};
+use mmr_libs::classification::SessionRules;
+use mmr_libs::datasets::{Registrations, SessionMode, UserFaction, UserTeam};
+use mmr_libs::evaluation::{evaluate_session, Evaluation, EvaluationMetrics};
+use mmr_libs::memory::SessionMemory;
+use mmr_libs::params::MMRParams;
+use mmr_libs::rating::{self, RatingSystem};
+use mmr_libs::userstat::UserStatReader;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use clap::{Parser, ValueEnum};
+
+/// Command-line arguments for the evaluation harness.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Algorithms to compare (repeat the flag); all algorithms when omitted.
+    #[arg(long, value_enum)]
+    pub algorithm: Vec<Algorithm>,
+    /// Path to the file containing (user_id, session_id) -> (team, victory) mappings.
+    #[arg(long)]
+    pub user_team: String,
+    /// Path to the file mapping session_id to mode name.
+    #[arg(long)]
+    pub session_mode: String,
+    /// Path to the file mapping user_id to faction.
+    #[arg(long)]
+    pub user_faction: String,
+    /// Path to the main userstat dataset file.
+    #[arg(long)]
+    pub data: String,
+    /// RON file with v1/v2 formula constants (built-in defaults when omitted).
+    #[arg(long)]
+    pub params: Option<String>,
+    /// RON file with the session skip rules (built-in rules when omitted).
+    #[arg(long)]
+    pub rules: Option<String>,
+    /// Number of equal-width calibration bins.
+    #[arg(long, default_value_t = 10)]
+    pub bins: usize,
+    /// Report path prefix: writes `<output>`, `<output>_calibration` and `<output>_compare`.
+    #[arg(long, default_value = "data/evaluation_8")]
+    pub output: String,
+}
+
+/// Entry point for the evaluation harness.
+///
+/// 1. Loads the auxiliary datasets once.
+/// 2. Replays the userstat dataset with every selected algorithm from an empty leaderboard,
+///    recording the predicted team 1 win probability before each rated session.
+/// 3. Writes log-loss, Brier score and AUC per algorithm and `SessionMode` group, the
+///    calibration curves and a per-group comparison ranked by log-loss.
+#[tokio::main]
+async fn main() {
+  let args: Args = Args::parse();
+  let start = Instant::now();
+
+  // (user_id, session_id) -> (team_id, victory_flag)
+  let user_team = UserTeam::new(&args.user_team).0;
+  let session_mode = SessionMode::new(&args.session_mode);
+  let registrations = Registrations::new();
+  let user_faction = UserFaction::new(&args.user_faction);
+
+  println!("Load datasets: {:?}", start.elapsed());
+
+  let algorithms = if args.algorithm.is_empty() {
+    Algorithm::value_variants().to_vec()
+  } else {
+    args.algorithm.clone()
+  };
+
+  // algorithm name -> group -> metrics
+  let mut results: Vec<(&'static str, std::collections::BTreeMap<String, EvaluationMetrics>)> = Vec::new();
+  for algorithm in algorithms {
+    let replay_start = Instant::now();
+    let name = algorithm.name();
+    let evaluation = algorithm.dispatch(Replay {
+      args: &args,
+      user_team: &user_team,
+      session_mode: &session_mode,
+      registrations: &registrations,
+      user_faction: &user_faction,
+    }).await;
+    println!("Replay {}: {:?}, undecided sessions: {}", name, replay_start.elapsed(), evaluation.undecided);
+    results.push((name, evaluation.metrics(args.bins)));
+  }
+
+  write_report(&args.output, &results).await;
+}
+
+/// [`replay`] with the leaderboard of one `--algorithm`.
+struct Replay<'a> {
+  args: &'a Args,
+  user_team: &'a std::collections::HashMap<(u64,u64), (u8, bool)>,
+  session_mode: &'a SessionMode,
+  registrations: &'a Registrations,
+  user_faction: &'a UserFaction,
+}
+
+impl<'a> RatingTask for Replay<'a> {
+  type Output = BoxedTask<'a, Evaluation>;
+
+  fn call<T: RatingSystem>(self) -> Self::Output {
+    Box::pin(replay::<T>(self.args, self.user_team, self.session_mode, self.registrations, self.user_faction))
+  }
+}
+
+/// Replays the dataset with the rating algorithm `T` and returns its predictions.
+///
+/// Rows are grouped by `session_id` exactly as in the leaderboard pipeline. For every
+/// completed session the prediction is recorded with [`evaluate_session`] first and the
+/// session is then applied through `rating::proc_session` with classifier id `0`, so no
+/// statistics or change files are produced. Unlike the pipeline, the trailing session is
+/// processed too and no state is persisted.
+async fn replay<T: RatingSystem>(
+  args: &Args,
+  user_team: &std::collections::HashMap<(u64,u64), (u8, bool)>,
+  session_mode: &SessionMode,
+  registrations: &Registrations,
+  user_faction: &UserFaction
+) -> Evaluation {
+  let mut leaderboard = T::empty();
+  if let Some(path) = &args.params {
+    leaderboard.set_params(&MMRParams::read(path));
+  }
+  let rules = args.rules.as_ref().map(|path| SessionRules::read(path)).unwrap_or_default();
+  let mut evaluation = Evaluation::new();
+  let mut record_memory = SessionMemory {
+    now_session_id: 0,
+    rows: Vec::new()
+  };
+
+  // Receivers are dropped: with classifier id 0 nothing is sent on the statistic channels and
+  // change records are discarded.
+  let (sender, _) = flume::unbounded();
+  let (sender_tasks, _) = flume::unbounded::<T::Change>();
+  let (sender_check, _) = flume::unbounded();
+  let (sender_session_class, _) = flume::unbounded();
+
+  if let Ok(mut rows) = UserStatReader::open(&args.data, user_team, user_faction) {
+    for row in rows.by_ref() {
+      if row.session_id != record_memory.now_session_id {
+        // Predict first, then let the algorithm learn from the session.
+        evaluate_session(&leaderboard, &record_memory, session_mode, registrations, &rules, &mut evaluation);
+        rating::proc_session(&mut leaderboard, record_memory.clone(), 0, sender.clone(), session_mode, registrations, &rules, sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+
+        record_memory.now_session_id = row.session_id;
+        record_memory.rows = Vec::new();
+      }
+      record_memory.rows.push(row);
+    }
+    println!("{}", rows.report());
+  }
+  evaluate_session(&leaderboard, &record_memory, session_mode, registrations, &rules, &mut evaluation);
+  rating::proc_session(&mut leaderboard, record_memory, 0, sender, session_mode, registrations, &rules, sender_tasks, sender_check, sender_session_class).await;
+
+  evaluation
+}
+
+/// Writes the evaluation report files and prints the comparison table.
+///
+/// - `<output>`             — `algorithm:..,group:..,sessions:..,log_loss:..,brier:..,auc:..` lines.
+/// - `<output>_calibration` — one line per non-empty calibration bin.
+/// - `<output>_compare`     — per group, algorithms ranked by log-loss with the log-loss and
+///   Brier differences to the best one.
+async fn write_report(
+  output: &str,
+  results: &[(&'static str, std::collections::BTreeMap<String, EvaluationMetrics>)]
+) {
+  let metrics_file = tokio::fs::File::create(output).await.unwrap();
+  let mut metrics_file = BufWriter::new(metrics_file);
+  let calibration_file = tokio::fs::File::create(output.to_string() + "_calibration").await.unwrap();
+  let mut calibration_file = BufWriter::new(calibration_file);
+  for (algorithm, groups) in results.iter() {
+    for (group, metrics) in groups.iter() {
+      metrics_file.write_all(metrics.to_string(algorithm, group).as_bytes()).await.unwrap();
+      calibration_file.write_all(metrics.calibration_to_string(algorithm, group).as_bytes()).await.unwrap();
+    }
+  }
+  metrics_file.flush().await.unwrap();
+  calibration_file.flush().await.unwrap();
+
+  // group -> [(algorithm, metrics)]
+  let mut by_group: std::collections::BTreeMap<&String, Vec<(&'static str, &EvaluationMetrics)>> = std::collections::BTreeMap::new();
+  for (algorithm, groups) in results.iter() {
+    for (group, metrics) in groups.iter() {
+      by_group.entry(group).or_default().push((algorithm, metrics));
+    }
+  }
+
+  let compare_file = tokio::fs::File::create(output.to_string() + "_compare").await.unwrap();
+  let mut compare_file = BufWriter::new(compare_file);
+  for (group, mut ranked) in by_group.into_iter() {
+    ranked.sort_by(|a, b| a.1.log_loss.total_cmp(&b.1.log_loss));
+    let (best_log_loss, best_brier) = (ranked[0].1.log_loss, ranked[0].1.brier);
+    for (rank, (algorithm, metrics)) in ranked.iter().enumerate() {
+      let str = "group:".to_string() + group.as_str()
+        + ",rank:" + (rank + 1).to_string().as_str()
+        + ",algorithm:" + algorithm
+        + ",sessions:" + metrics.sessions.to_string().as_str()
+        + ",log_loss:" + metrics.log_loss.to_string().as_str()
+        + ",log_loss_diff:" + (metrics.log_loss - best_log_loss).to_string().as_str()
+        + ",brier:" + metrics.brier.to_string().as_str()
+        + ",brier_diff:" + (metrics.brier - best_brier).to_string().as_str()
+        + ",auc:" + match metrics.auc {
+          Some(auc) => auc.to_string(),
+          None => "none".to_string()
+        }.as_str();
+      println!("{}", str);
+      compare_file.write_all((str + "\n").as_bytes()).await.unwrap();
+    }
+  }
+  compare_file.flush().await.unwrap();
+}
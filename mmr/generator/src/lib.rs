@@ -1,11 +1,11 @@
-use std::collections::{BTreeMap, HashMap};
-use rand::Rng;
-use mmr_libs::{memory::SessionMemory, types::{Leaderboard, LeaderboardRow, LeaderboardV2, UserBattleRow}};
+use mmr_libs::{memory::SessionMemory, rating::RatingSystem, types::{Leaderboard, LeaderboardRow, LeaderboardV2, UserBattleRow}};
+
+pub mod world;
 
 macro_rules! leaderboard_row {
     ($uid:expr, $mmr:expr, $battle:expr) => {
         LeaderboardRow{
-            id: $uid as u64, 
+            user_id: $uid as u64, 
             mmr: $mmr,
             battle_score: $battle * 1600,
             battles: $battle,
@@ -20,7 +20,7 @@ macro_rules! leaderboard_row {
 macro_rules! user_battle_row {
     ($uid:expr, $session_id:expr, $team:expr, $battle_score:expr, $victory:expr, $top_20:expr, $early_quit:expr, $faction:expr) => {
         UserBattleRow{
-            id: $uid as u64, 
+            user_id: $uid as u64, 
             session_id: $session_id,
             commit_time: 0,
             team: $team,
@@ -34,19 +34,19 @@ macro_rules! user_battle_row {
 }
 
 pub fn generate_v1_leaderboard(players: &[(u64, u32, u32)]) -> Leaderboard {
-    let mut leaderboard = Leaderboard{users: HashMap::new(), sets: Vec::new(), battle_score_hash: BTreeMap::new(), battle_faction_hash: HashMap::new()};
+    let mut leaderboard = Leaderboard::empty();
     for (uid, mmr, battles) in players.iter() {
         leaderboard.users.insert(*uid, leaderboard_row!(*uid, *mmr, *battles));
-        leaderboard.battle_score_hash.insert((1600 as u32, *uid), *mmr);
+        leaderboard.battle_score_hash.insert((1600_u32, *uid), *mmr);
     }
     leaderboard
 }
 
 pub fn generate_v2_leaderboard(players: &[(u64, u32, u32)]) -> LeaderboardV2 {
-    let mut leaderboard = LeaderboardV2{users: HashMap::new(), sets: Vec::new(), battle_score_hash: BTreeMap::new(), battle_faction_hash: HashMap::new()};
+    let mut leaderboard = LeaderboardV2::empty();
     for (uid, mmr, battles) in players.iter() {
         leaderboard.users.insert(*uid, leaderboard_row!(*uid, *mmr, *battles));
-        leaderboard.battle_score_hash.insert((1600 as u32, *uid), *mmr);
+        leaderboard.battle_score_hash.insert((1600_u32, *uid), *mmr);
     }
     leaderboard
 }
@@ -0,0 +1,61 @@
+use std::time::Instant;
+use generator::world::{self, World, WorldParams};
+use mmr_libs::params::MMRParams;
+use mmr_libs::types::{Leaderboard, LeaderboardGlicko, LeaderboardTrueSkill, LeaderboardV2};
+use clap::Parser;
+
+/// Command-line arguments for the synthetic world generator.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// RON file with the world parameters (`world::WorldParams`); defaults when omitted.
+    #[arg(long)]
+    pub config: Option<String>,
+    /// Overrides the seed of the config.
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Output directory. `Registrations::new` reads `data/regs.json`, so the default
+    /// output can be fed to the pipeline binaries as is.
+    #[arg(long, default_value = "data")]
+    pub output: String,
+    /// RON file with v1/v2 formula constants used by `--check`.
+    #[arg(long)]
+    pub params: Option<String>,
+    /// Replays the world with every algorithm and prints how well it recovers true skill.
+    #[arg(long, default_value_t = false)]
+    pub check: bool,
+}
+
+/// Entry point for the synthetic world generator.
+///
+/// 1. Reads the world parameters and generates players and sessions (`World::generate`).
+/// 2. Writes `userstat.json`, `user_team.json`, `session_mode.json`, `user_faction.json`,
+///    `regs.json` and `true_skill.json` into `--output`.
+/// 3. With `--check`, replays the world with v1, v2, Glicko-2 and TrueSkill and prints the
+///    Spearman correlation between true skill and the final MMR of calibrated players.
+#[tokio::main]
+async fn main() {
+  let args: Args = Args::parse();
+  let start = Instant::now();
+
+  let mut params = args.config.as_ref().map(|path| WorldParams::read(path)).unwrap_or_default();
+  if let Some(seed) = args.seed {
+    params.seed = seed;
+  }
+  let world = World::generate(&params);
+  world.write(&args.output).unwrap();
+  println!("Generate: {:?}, players: {}, sessions: {}", start.elapsed(), world.players.len(), world.sessions.len());
+
+  if args.check {
+    let rating_params = args.params.as_ref().map(|path| MMRParams::read(path));
+    for (name, recovery) in [
+      ("v1", world::recovery::<Leaderboard>(&world, rating_params.as_ref()).await),
+      ("v2", world::recovery::<LeaderboardV2>(&world, rating_params.as_ref()).await),
+      ("glicko", world::recovery::<LeaderboardGlicko>(&world, rating_params.as_ref()).await),
+      ("trueskill", world::recovery::<LeaderboardTrueSkill>(&world, rating_params.as_ref()).await),
+    ] {
+      println!("algorithm:{},players:{},spearman:{}", name, recovery.players, recovery.spearman.map(|value| format!("{:.4}", value)).unwrap_or("none".to_string()));
+    }
+  }
+  println!("Total: {:?}", start.elapsed());
+}
@@ -0,0 +1,407 @@
+use std::io::Write;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use mmr_libs::classification::SessionRules;
+use mmr_libs::datasets::{Registrations, SessionMode};
+use mmr_libs::math::spearman;
+use mmr_libs::matchmaker::{make_match, MatchParams, QueueEntry};
+use mmr_libs::memory::SessionMemory;
+use mmr_libs::params::MMRParams;
+use mmr_libs::rating::{self, RatingSystem};
+use mmr_libs::types::{MMRType, UserBattleRow};
+
+// commit_time is in milliseconds.
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+// Sessions after which the matchmaker sees a calibrated MMR.
+const CALIBRATION_SESSIONS: u32 = 6;
+// Share of a team flagged `team_score_top_20_percent`.
+const TOP_SHARE: f64 = 0.2;
+
+/// Knobs of the synthetic world, stored as RON.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldParams {
+    pub seed: u64,
+    pub players: usize,
+    pub sessions: usize,
+    pub team_size: usize,
+    // Hidden skill ~ N(skill_mean, skill_sd).
+    pub skill_mean: f64,
+    pub skill_sd: f64,
+    // Session performance ~ N(skill, performance_sd); the team with the higher mean wins.
+    pub performance_sd: f64,
+    // battle_score = score_base + score_per_skill * (performance - skill_mean) + N(0, score_sd).
+    pub score_base: f64,
+    pub score_per_skill: f64,
+    pub score_sd: f64,
+    // Per-player early quit probability; a quitter adds half a performance and keeps
+    // `early_quit_score` of the battle score.
+    pub early_quit_rate: f64,
+    pub early_quit_score: f64,
+    // Share of players with a main faction, passed to the matchmaker as a preference.
+    pub faction_rate: f64,
+    // Share of players registered a week before `start_time`; the rest register during the run.
+    pub initial_players: f64,
+    pub start_time: u64,
+    // Milliseconds between two sessions.
+    pub session_interval: u64,
+    // Share of `lobby` sessions; the others are `low_teir_ranked` / `high_teir_ranked` by the
+    // mean skill of their players.
+    pub lobby_rate: f64,
+    // Noise of the MMR the matchmaker sees once a player has 6 sessions.
+    pub matchmaking_noise: f64
+}
+
+/// A synthetic player.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Player {
+    pub user_id: u64,
+    // Hidden true skill; only `true_skill.json` exposes it.
+    pub skill: f64,
+    pub registered_time: u64,
+    // `faction_1` / `faction_2`, `None` without a preference.
+    pub faction: Option<String>,
+    pub sessions: u32
+}
+
+/// Population and session history of a synthetic world.
+#[derive(Clone, Debug)]
+pub struct World {
+    pub params: WorldParams,
+    pub players: Vec<Player>,
+    // Sessions in `session_id` order; rows carry team, faction and outcome.
+    pub sessions: Vec<SessionMemory>,
+    // session_id -> raw mode name.
+    pub modes: std::collections::BTreeMap<u64, String>
+}
+
+/// How well a rating algorithm recovers the hidden skill.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recovery {
+    // Players calibrated by the algorithm.
+    pub players: u64,
+    // Spearman correlation of true skill and MMR over those players.
+    pub spearman: Option<f64>
+}
+
+impl Default for WorldParams {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            players: 1000,
+            sessions: 20000,
+            team_size: 6,
+            skill_mean: 1500.0,
+            skill_sd: 300.0,
+            performance_sd: 300.0,
+            score_base: 1600.0,
+            score_per_skill: 1.0,
+            score_sd: 300.0,
+            early_quit_rate: 0.03,
+            early_quit_score: 0.3,
+            faction_rate: 0.5,
+            initial_players: 0.5,
+            start_time: 1_700_000_000_000,
+            session_interval: 60_000,
+            lobby_rate: 0.2,
+            matchmaking_noise: 150.0
+        }
+    }
+}
+
+impl WorldParams {
+    /// Reads a RON world file; missing fields keep their defaults.
+    pub fn read(path: &str) -> Self {
+        let data = std::fs::read_to_string(path).unwrap();
+        ron::from_str(&data).unwrap()
+    }
+}
+
+impl World {
+    /// Generates a world from `params`; the same seed always gives the same world.
+    ///
+    /// 1. Draws the players: hidden skill, registration time and main faction.
+    /// 2. For every session, draws `2 * team_size` registered players and lets
+    ///    `matchmaker::make_match` split them by the MMR it can see (noisy skill once
+    ///    calibrated), newbie status and faction preference.
+    /// 3. Samples performances, early quits, the winner, battle scores and the top-20% flags,
+    ///    and picks the mode from the mean skill of the session.
+    pub fn generate(params: &WorldParams) -> Self {
+        let mut rng = StdRng::seed_from_u64(params.seed);
+        let end_time = params.start_time + params.session_interval * params.sessions as u64;
+        let mut players: Vec<Player> = (0..params.players).map(|index| {
+            let registered_time = if rng.random::<f64>() < params.initial_players {
+                params.start_time.saturating_sub(7 * DAY_MS)
+            } else {
+                rng.random_range(params.start_time..end_time.max(params.start_time + 1))
+            };
+            let faction = if rng.random::<f64>() < params.faction_rate {
+                Some(if rng.random::<bool>() {"faction_1"} else {"faction_2"}.to_string())
+            } else {
+                None
+            };
+            Player {
+                user_id: index as u64 + 1,
+                skill: normal(&mut rng, params.skill_mean, params.skill_sd),
+                registered_time,
+                faction,
+                sessions: 0
+            }
+        }).collect();
+
+        let match_params = MatchParams { team_size: params.team_size, ..MatchParams::default() };
+        let mut sessions = Vec::new();
+        let mut modes = std::collections::BTreeMap::new();
+        for index in 0..params.sessions {
+            let session_id = index as u64 + 1;
+            let commit_time = params.start_time + params.session_interval * index as u64;
+            let active: Vec<usize> = (0..players.len()).filter(|player| players[*player].registered_time <= commit_time).collect();
+            if params.team_size == 0 || active.len() < 2 * params.team_size {
+                continue;
+            }
+            let queue: Vec<QueueEntry> = rand::seq::index::sample(&mut rng, active.len(), 2 * params.team_size).into_iter().map(|pick| {
+                let player = &players[active[pick]];
+                QueueEntry {
+                    row: UserBattleRow {
+                        user_id: player.user_id,
+                        session_id,
+                        commit_time,
+                        team: 0,
+                        battle_score: 0,
+                        victories: false,
+                        early_quit: false,
+                        team_score_top_20_percent: false,
+                        faction: String::new()
+                    },
+                    mmr: if player.sessions >= CALIBRATION_SESSIONS {
+                        MMRType::MMR(normal(&mut rng, player.skill, params.matchmaking_noise).max(0.0) as u32)
+                    } else {
+                        MMRType::NotEnought(0)
+                    },
+                    newbie: commit_time - player.registered_time < DAY_MS,
+                    faction: player.faction.clone()
+                }
+            }).collect();
+            let mut session = make_match(&queue, &match_params).unwrap().to_session(session_id, &match_params.factions);
+
+            // Team performance is the mean of the player performances; a quitter counts half.
+            let mut performance = [0.0_f64; 2];
+            let mut performances = Vec::new();
+            for row in session.rows.iter_mut() {
+                let player = &mut players[row.user_id as usize - 1];
+                player.sessions += 1;
+                row.early_quit = rng.random::<f64>() < params.early_quit_rate;
+                let value = normal(&mut rng, player.skill, params.performance_sd);
+                performance[row.team as usize - 1] += if row.early_quit {0.5 * value} else {value};
+                performances.push(value);
+            }
+            let team_1_won = performance[0] > performance[1];
+
+            for (row, value) in session.rows.iter_mut().zip(performances) {
+                let score = (params.score_base + params.score_per_skill * (value - params.skill_mean) + normal(&mut rng, 0.0, params.score_sd)).max(0.0);
+                row.battle_score = (if row.early_quit {score * params.early_quit_score} else {score}) as u32;
+                row.victories = (row.team == 1) == team_1_won;
+            }
+            for team in [1, 2] {
+                let mut scores: Vec<(u32, u64)> = session.rows.iter().filter(|row| row.team == team).map(|row| (row.battle_score, row.user_id)).collect();
+                scores.sort_unstable_by(|a, b| b.cmp(a));
+                let top = (scores.len() as f64 * TOP_SHARE).ceil() as usize;
+                let top_ids: Vec<u64> = scores.into_iter().take(top).map(|score| score.1).collect();
+                for row in session.rows.iter_mut().filter(|row| row.team == team) {
+                    row.team_score_top_20_percent = top_ids.contains(&row.user_id);
+                }
+            }
+
+            let mean_skill = session.rows.iter().map(|row| players[row.user_id as usize - 1].skill).sum::<f64>() / session.rows.len() as f64;
+            let mode = if rng.random::<f64>() < params.lobby_rate {
+                "lobby"
+            } else if mean_skill >= params.skill_mean {
+                "high_teir_ranked"
+            } else {
+                "low_teir_ranked"
+            };
+            modes.insert(session_id, mode.to_string());
+            sessions.push(session);
+        }
+
+        Self {
+            params: params.clone(),
+            players,
+            sessions,
+            modes
+        }
+    }
+
+    /// Normalized session modes, as `SessionMode::new` would read them from `session_mode.json`.
+    pub fn session_mode(&self) -> SessionMode {
+        SessionMode(self.modes.iter().map(|(session_id, mode)| (*session_id, SessionMode::normalize(mode))).collect())
+    }
+
+    /// Registration times, as `Registrations::new` would read them from `regs.json`.
+    pub fn registrations(&self) -> Registrations {
+        Registrations(self.players.iter().map(|player| (player.user_id, player.registered_time)).collect())
+    }
+
+    /// Writes the world into `dir` in the formats read by the pipeline binaries:
+    ///
+    /// - `userstat.json`     — `{"user_id":1,"session_id":"1f","commit_time":..,"battle_score":..,"early_quit":0,"team_score_top_20_percent":1,"faction":"faction_1"}`
+    /// - `user_team.json`    — `{"user_id":1,"session_id":31,"team":1,"victory":true}`
+    /// - `session_mode.json` — `{"session_id":31,"mode":"high_teir_ranked"}`
+    /// - `user_faction.json` — `{"user_id":1,"session_id":"1f","mode":"faction_1"}`
+    /// - `regs.json`         — `{"user_id":1,"registered_time":..}` (`Registrations::new` reads `data/regs.json`)
+    /// - `true_skill.json`   — `{"user_id":1,"skill":1523.4}`, the hidden skill
+    ///
+    /// Session ids are hexadecimal in the userstat and faction files, decimal elsewhere.
+    pub fn write(&self, dir: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let flag = |value: bool| if value {"1"} else {"0"};
+
+        let mut userstat = create(dir, "userstat.json")?;
+        let mut user_team = create(dir, "user_team.json")?;
+        let mut user_faction = create(dir, "user_faction.json")?;
+        for session in self.sessions.iter() {
+            let hex_id = format!("{:x}", session.now_session_id);
+            for row in session.rows.iter() {
+                let str = "{\"user_id\":".to_string() + row.user_id.to_string().as_str()
+                    + ",\"session_id\":\"" + hex_id.as_str() + "\""
+                    + ",\"commit_time\":" + row.commit_time.to_string().as_str()
+                    + ",\"battle_score\":" + row.battle_score.to_string().as_str()
+                    + ",\"early_quit\":" + flag(row.early_quit)
+                    + ",\"team_score_top_20_percent\":" + flag(row.team_score_top_20_percent)
+                    + ",\"faction\":\"" + row.faction.as_str() + "\"}\n";
+                userstat.write_all(str.as_bytes())?;
+
+                let str = "{\"user_id\":".to_string() + row.user_id.to_string().as_str()
+                    + ",\"session_id\":" + session.now_session_id.to_string().as_str()
+                    + ",\"team\":" + row.team.to_string().as_str()
+                    + ",\"victory\":" + row.victories.to_string().as_str() + "}\n";
+                user_team.write_all(str.as_bytes())?;
+
+                let str = "{\"user_id\":".to_string() + row.user_id.to_string().as_str()
+                    + ",\"session_id\":\"" + hex_id.as_str() + "\""
+                    + ",\"mode\":\"" + row.faction.as_str() + "\"}\n";
+                user_faction.write_all(str.as_bytes())?;
+            }
+        }
+        userstat.flush()?;
+        user_team.flush()?;
+        user_faction.flush()?;
+
+        let mut session_mode = create(dir, "session_mode.json")?;
+        for (session_id, mode) in self.modes.iter() {
+            let str = "{\"session_id\":".to_string() + session_id.to_string().as_str()
+                + ",\"mode\":\"" + mode.as_str() + "\"}\n";
+            session_mode.write_all(str.as_bytes())?;
+        }
+        session_mode.flush()?;
+
+        let mut regs = create(dir, "regs.json")?;
+        let mut true_skill = create(dir, "true_skill.json")?;
+        for player in self.players.iter() {
+            let str = "{\"user_id\":".to_string() + player.user_id.to_string().as_str()
+                + ",\"registered_time\":" + player.registered_time.to_string().as_str() + "}\n";
+            regs.write_all(str.as_bytes())?;
+            let str = "{\"user_id\":".to_string() + player.user_id.to_string().as_str()
+                + ",\"skill\":" + format!("{:.1}", player.skill).as_str() + "}\n";
+            true_skill.write_all(str.as_bytes())?;
+        }
+        regs.flush()?;
+        true_skill.flush()
+    }
+}
+
+/// Replays the world with `T` from an empty leaderboard and rank-correlates the final MMR of
+/// calibrated players with their hidden skill.
+pub async fn recovery<T: RatingSystem>(world: &World, params: Option<&MMRParams>) -> Recovery {
+    let mut leaderboard = T::empty();
+    if let Some(params) = params {
+        leaderboard.set_params(params);
+    }
+    let session_mode = world.session_mode();
+    let registrations = world.registrations();
+    let rules = SessionRules::default();
+    let (sender, _) = flume::unbounded();
+    let (sender_tasks, _) = flume::unbounded::<T::Change>();
+    let (sender_check, _) = flume::unbounded();
+    let (sender_session_class, _) = flume::unbounded();
+    for session in world.sessions.iter() {
+        rating::proc_session(&mut leaderboard, session.clone(), 0, sender.clone(), &session_mode, &registrations, &rules, sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+    }
+
+    let (skill, mmr): (Vec<f64>, Vec<f64>) = world.players.iter().filter_map(|player| match leaderboard.get_mmr(player.user_id) {
+        MMRType::MMR(mmr) => Some((player.skill, mmr as f64)),
+        _ => None
+    }).unzip();
+    Recovery {
+        players: skill.len() as u64,
+        spearman: spearman(&skill, &mmr)
+    }
+}
+
+fn create(dir: &str, name: &str) -> std::io::Result<std::io::BufWriter<std::fs::File>> {
+    Ok(std::io::BufWriter::new(std::fs::File::create(dir.to_string() + "/" + name)?))
+}
+
+/// Box–Muller sample of N(mean, sd).
+fn normal(rng: &mut StdRng, mean: f64, sd: f64) -> f64 {
+    let u_1: f64 = rng.random::<f64>().max(f64::MIN_POSITIVE);
+    let u_2: f64 = rng.random::<f64>();
+    mean + sd * (-2.0 * u_1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u_2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mmr_libs::datasets::{SessionMode, UserFaction, UserTeam};
+    use mmr_libs::evaluation::load_sessions;
+    use mmr_libs::types::{Leaderboard, LeaderboardGlicko, LeaderboardTrueSkill, LeaderboardV2};
+
+    fn small_world() -> World {
+        World::generate(&WorldParams { players: 120, sessions: 2000, ..WorldParams::default() })
+    }
+
+    #[test]
+    fn written_files_read_back_through_the_pipeline_loaders() {
+        let world = small_world();
+        let dir = std::env::temp_dir().join("mmr_generator_test_".to_string() + std::process::id().to_string().as_str());
+        let dir = dir.to_str().unwrap();
+        world.write(dir).unwrap();
+
+        let user_team = UserTeam::new(&(dir.to_string() + "/user_team.json")).0;
+        let user_faction = UserFaction::new(&(dir.to_string() + "/user_faction.json"));
+        let sessions = load_sessions(&(dir.to_string() + "/userstat.json"), &user_team, &user_faction);
+        let session_mode = SessionMode::new(&(dir.to_string() + "/session_mode.json"));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(sessions.len(), world.sessions.len());
+        for (read, generated) in sessions.iter().zip(world.sessions.iter()) {
+            assert_eq!(read.now_session_id, generated.now_session_id);
+            assert_eq!(read.rows.len(), 12);
+            for (a, b) in read.rows.iter().zip(generated.rows.iter()) {
+                assert_eq!((a.user_id, a.team, a.victories, a.battle_score, a.early_quit, &a.faction), (b.user_id, b.team, b.victories, b.battle_score, b.early_quit, &b.faction));
+            }
+        }
+        assert_eq!(session_mode.0, world.session_mode().0);
+        // Same seed, same world.
+        assert_eq!(small_world().players, world.players);
+    }
+
+    #[tokio::test]
+    async fn every_algorithm_recovers_true_skill() {
+        let world = small_world();
+        // Floors sit below the correlations of seeds 1-3. Glicko-2 rates a player against
+        // the opposing top 3 only, so on balanced teams it recovers much less.
+        for (recovered, floor) in [
+            (recovery::<Leaderboard>(&world, None).await, 0.6),
+            (recovery::<LeaderboardV2>(&world, None).await, 0.8),
+            (recovery::<LeaderboardGlicko>(&world, None).await, 0.1),
+            (recovery::<LeaderboardTrueSkill>(&world, None).await, 0.9),
+        ] {
+            assert!(recovered.players > 100);
+            assert!(recovered.spearman.unwrap() > floor, "{:?}", recovered);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use mmr_libs::algorithm::{Algorithm, RatingTask};
+use mmr_libs::history::{HistoryEntry, HistoryStore};
+use mmr_libs::rating::RatingSystem;
+use clap::Parser;
+
+/// `HistoryStore::dir` of the dispatched leaderboard.
+struct HistoryDir;
+
+impl RatingTask for HistoryDir {
+  type Output = String;
+
+  fn call<T: RatingSystem>(self) -> String {
+    HistoryStore::dir::<T>()
+  }
+}
+
+/// Command-line arguments for the rating history query tool.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Algorithm of the store in `data/leaderboard_<algorithm>/history`.
+    #[arg(long, value_enum)]
+    pub algorithm: Algorithm,
+    /// Store directory, overrides the one of `--algorithm`.
+    #[arg(long)]
+    pub dir: Option<String>,
+    /// Player to query: prints its rating trajectory.
+    #[arg(long)]
+    pub user: Option<u64>,
+    /// With `--user`, prints only the rating after the last session at or before this commit_time (ms).
+    #[arg(long)]
+    pub at: Option<u64>,
+    /// Prints the entries whose MMR delta is below this value, e.g. `-50` (of `--user` only, when given).
+    #[arg(long, allow_hyphen_values = true)]
+    pub below: Option<i64>,
+}
+
+/// Entry point for the rating history query tool.
+///
+/// 1. Opens the `HistoryStore` written by `leaderboard-8 --history`.
+/// 2. Answers one query, one flat key:value line per entry:
+///    - `--user U --at T` — rating of U at time T (`none` before its first session);
+///    - `--user U`        — trajectory of U, optionally only entries with delta `< --below`;
+///    - `--below D`       — every entry with delta `< D` over all players;
+///    - neither           — number of players and bytes in the store.
+/// 3. Reports an unreadable or damaged store on stderr and exits with status 1.
+fn main() {
+  let args: Args = Args::parse();
+  let dir = args.dir.clone().unwrap_or_else(|| args.algorithm.dispatch(HistoryDir));
+  if let Err(error) = query(&args, &dir) {
+    eprintln!("{}: {}", dir, error);
+    std::process::exit(1);
+  }
+}
+
+/// Answers the query of `args` on the store in `dir`.
+fn query(args: &Args, dir: &str) -> std::io::Result<()> {
+  let mut store = HistoryStore::open(dir)?;
+
+  let entries: Vec<HistoryEntry> = match (args.user, args.at, args.below) {
+    (Some(user_id), Some(time), _) => {
+      let rating = store.rating_at(user_id, time)?;
+      println!("user_id:{},at:{},mmr:{}", user_id, time, rating.map(|mmr| mmr.to_string()).unwrap_or("none".to_string()));
+      return Ok(());
+    },
+    (Some(user_id), None, below) => store.trajectory(user_id)?.into_iter()
+      .filter(|entry| below.map(|below| entry.delta() < below).unwrap_or(true))
+      .collect(),
+    (None, _, Some(below)) => store.drops(below)?,
+    (None, _, None) => {
+      println!("dir:{},users:{},bytes:{}", dir, store.users(), store.size());
+      return Ok(());
+    }
+  };
+  for entry in entries.iter() {
+    println!("{}", entry);
+  }
+  Ok(())
+}
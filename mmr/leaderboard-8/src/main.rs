@@ -0,0 +1,849 @@
+use std::time::{Duration, Instant};
+use mmr_libs::abuse::AbuseDetector;
+use mmr_libs::algorithm::{Algorithm, BoxedTask, RatingTask};
+use mmr_libs::batch::{self, SessionBatch};
+use mmr_libs::checkpoint::Checkpoint;
+use mmr_libs::classification::{SessionClasses, SessionRules};
+use mmr_libs::datasets::{Registrations, SessionMode, UserFaction, UserTeam};
+use mmr_libs::history::HistoryStore;
+use mmr_libs::memory::SessionMemory;
+use mmr_libs::modes::ModeLeaderboards;
+use mmr_libs::params::MMRParams;
+use mmr_libs::rating::{leaderboard_dir, RatingSystem};
+use mmr_libs::season::Seasons;
+use mmr_libs::userstat::UserStatReader;
+use mmr_libs::statistic::{Statistic, StatisticReport};
+use tokio::io::AsyncWriteExt;
+use std::sync::{Arc};
+use tokio::sync::Mutex;
+use tokio::io::BufWriter;
+use clap::Parser;
+
+// flume = "0.11.0" — multi-producer multi-consumer channel crate
+
+use flume::{Receiver, RecvError};
+
+/// Command-line arguments for the leaderboard pipeline.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Rating algorithm used to recalculate the leaderboard.
+    #[arg(long, value_enum)]
+    pub algorithm: Algorithm,
+    /// Classifier id: selects `data/csv/<id>.csv` and enables statistics when greater than 0.
+    #[arg(long, default_value_t = 0)]
+    pub cl_id: u16,
+    /// Path to the file containing (user_id, session_id) -> (team, victory) mappings.
+    #[arg(long)]
+    pub user_team: String,
+    /// Path to the file mapping session_id to mode name.
+    #[arg(long)]
+    pub session_mode: String,
+    /// Path to the file mapping user_id to faction.
+    #[arg(long)]
+    pub user_faction: String,
+    /// Path to the main userstat dataset file.
+    #[arg(long)]
+    pub data: String,
+    /// RON file with v1/v2 formula constants (built-in defaults when omitted).
+    #[arg(long)]
+    pub params: Option<String>,
+    /// RON file with the session skip and classification rules (built-in rules when omitted).
+    #[arg(long)]
+    pub rules: Option<String>,
+    /// Path to the existing leaderboard snapshot (used as a starting state).
+    #[arg(long)]
+    pub leaderboard: String,
+    /// Decays every inactive player at the end of the slice (`decay` section of `--params`).
+    #[arg(long)]
+    pub decay_sweep: bool,
+    /// Keeps per-mode leaderboards next to the global one (`modes` section of `--params`).
+    #[arg(long)]
+    pub modes: bool,
+    /// Writes a checkpoint to `data/leaderboard_<algorithm>/checkpoint` every N sessions (0 disables).
+    #[arg(long, default_value_t = 0)]
+    pub checkpoint_every: u64,
+    /// Continues from the last checkpoint instead of starting from the persisted leaderboard.
+    #[arg(long)]
+    pub resume: bool,
+    /// Appends every rating change to the history store in `data/leaderboard_<algorithm>/history`.
+    #[arg(long)]
+    pub history: bool,
+    /// Flags win-trading, smurf accounts and early-quit streaks into
+    /// `data/leaderboard_<algorithm>/abuse_review` (`abuse` section of `--params`).
+    #[arg(long)]
+    pub abuse: bool,
+    /// With `--abuse`, keeps flagged players out of the calibration estimates of new players (v1, v2).
+    #[arg(long, requires = "abuse")]
+    pub abuse_exclude: bool,
+    /// Rates up to N consecutive sessions without a shared player together, preparing their
+    /// updates in parallel (1 rates one session at a time; the results are the same).
+    #[arg(long, default_value_t = 1)]
+    pub batch: usize,
+    /// Threads preparing a batch (0 uses every available core).
+    #[arg(long, default_value_t = 0)]
+    pub threads: usize,
+}
+
+/// Checkpoint requests to the background workers.
+///
+/// A worker drains its channel before it replies, so the reply covers every session sent
+/// before the request.
+struct Workers {
+  statistic: flume::Sender<flume::Sender<std::collections::BTreeMap<String, Statistic>>>,
+  statistic_check: flume::Sender<flume::Sender<std::collections::BTreeMap<i32, (u64, u64)>>>,
+  // Replies with the byte length of every output file, the history store last.
+  changes: flume::Sender<flume::Sender<Vec<u64>>>,
+  session_class: flume::Sender<flume::Sender<Vec<u64>>>,
+  change_paths: Vec<String>,
+  session_class_path: String
+}
+
+/// Per-stage time totals of the streaming pass.
+#[derive(Default)]
+struct StageTotals {
+  // Rated sessions.
+  count: u64,
+  session_dt: Duration,         // total time spent rating batches
+  prepear_session_dt: Duration, // stage 1: prepare session data
+  write_session_dt: Duration,   // stage 2: write session rows
+  prepear_change_dt: Duration,  // stage 3: prepare MMR changes
+  set_change_dt: Duration       // stage 4: apply MMR changes
+}
+
+/// Entry point for the leaderboard pipeline.
+///
+/// Orchestrates the full MMR recalculation pass:
+/// 1. Parses CLI arguments pointing to the required input datasets and the algorithm.
+/// 2. Loads the `user_team` mapping `(user_id, session_id) -> (team, victory)` from disk.
+/// 3. Dispatches to [`run`] with the `RatingSystem` selected by `--algorithm`.
+#[tokio::main]
+async fn main() {
+  // Parse CLI arguments and initialize run timer.
+  let args: Args = Args::parse();
+  let start = Instant::now();
+
+
+  let elapsed = start.elapsed();
+
+  println!("Start: {:?}", elapsed);
+
+  // Read data with information about team and result for each user in each session.
+  // (user_id, session_id) -> (team_id, victory_flag)
+  let user_team = UserTeam::new(&args.user_team).0;
+  println!("USER TEAM ENDE"); // signals successful load of the user-team dataset
+  
+  let elapsed = start.elapsed();
+
+  println!("Load user_time: {:?}", elapsed);
+
+  args.algorithm.dispatch(Run(&args, &user_team)).await;
+}
+
+/// [`run`] with the leaderboard of `--algorithm`.
+struct Run<'a>(&'a Args, &'a std::collections::HashMap<(u64,u64), (u8, bool)>);
+
+impl<'a> RatingTask for Run<'a> {
+  type Output = BoxedTask<'a, ()>;
+
+  fn call<T: RatingSystem>(self) -> Self::Output {
+    Box::pin(run::<T>(self.0, self.1))
+  }
+}
+
+/// Runs the full recalculation pass with the rating algorithm `T`.
+///
+/// 1. Restores the leaderboard via `RatingSystem::restore` and initialises auxiliary
+///    datasets: session modes, player registrations and faction assignments.
+/// 2. Spawns four background workers via Tokio tasks:
+///    - `statistic_aggregate` — merges per-session `Statistic` payloads into a single board map.
+///    - `statistic_check`     — accumulates win-rate sanity counters bucketed by MMR delta.
+///    - `write_change`        — streams per-user MMR change records to output files and,
+///      with `--history`, to the rating history store (`HistoryStore`).
+///    - `session_class_aggreg`— persists the session classes of `--rules` per session.
+/// 3. Drives `async_main`, which reads the userstat dataset line-by-line, groups rows by
+///    `session_id`, and rates the completed sessions with `batch::proc_batch`.
+/// 4. After the streaming pass, awaits all background workers and writes the aggregated
+///    statistics as a `StatisticReport` to `data/statistic_<NAME>_8.json`, `.csv` and
+///    `_spread.csv`.
+/// 5. With `--decay-sweep`, decays every inactive player at the last commit_time of the slice.
+/// 6. Flushes the in-memory session state and the final leaderboard snapshot to disk.
+/// 7. With `--modes`, persists the per-mode leaderboards and writes their comparison with
+///    the global one to `data/leaderboard_<NAME>/modes_compare`.
+/// 8. With `--abuse`, writes the flagged players to `data/leaderboard_<NAME>/abuse_review`
+///    (see `AbuseDetector`).
+///
+/// With season starts in the `season` section of `--params`, every season boundary crossed by
+/// the slice archives the leaderboard and its final ranks to `data/leaderboard_<NAME>/season_<n>`
+/// and soft-resets the boards (see `Seasons`).
+///
+/// With `--checkpoint-every N`, every N sessions the leaderboard, the mode boards, the
+/// statistic accumulators, the output file lengths and the position in `--data` are written to
+/// `data/leaderboard_<NAME>/checkpoint` (see `Checkpoint`). `--resume` restores all of them,
+/// truncates the outputs back and continues reading at that position, so the results equal
+/// those of an uninterrupted run. The checkpoint is removed when the run completes.
+async fn run<T: RatingSystem>(
+  args: &Args,
+  user_team: &std::collections::HashMap<(u64,u64), (u8, bool)>
+) {
+  let params = args.params.as_ref().map(|path| MMRParams::read(path));
+  let rules = args.rules.as_ref().map(|path| SessionRules::read(path)).unwrap_or_default();
+  let mode_params = if args.modes {Some(params.clone().unwrap_or_default().modes)} else {None};
+  let checkpoint_dir = Checkpoint::dir::<T>();
+  // Create memory of sessions, leaderboard and the optional per-mode leaderboards, rated alongside the global one.
+  let (mut checkpoint, mut record_memory, mut leaderboard, mut modes) = if args.resume {
+    let (checkpoint, leaderboard, modes) = match Checkpoint::restore::<T>(&checkpoint_dir, mode_params) {
+      Ok(Some(resumed)) => resumed,
+      Ok(None) => panic!("no checkpoint in {}", checkpoint_dir),
+      Err(error) => {
+        eprintln!("checkpoint in {} cannot be used: {}", checkpoint_dir, error);
+        eprintln!("remove it and run without --resume to start over");
+        std::process::exit(1);
+      }
+    };
+    assert_eq!(checkpoint.data, args.data, "checkpoint was taken on another --data file");
+    println!("Resume at session {}, line {}, {} sessions done", checkpoint.session_id, checkpoint.line, checkpoint.sessions);
+    // Rows of the next session are read again from the checkpoint offset.
+    let record_memory = SessionMemory { now_session_id: checkpoint.session_id, rows: Vec::new() };
+    (checkpoint, record_memory, leaderboard, modes)
+  } else {
+    let restored = T::restore().and_then(|leaderboard| Ok((leaderboard, mode_params.map(ModeLeaderboards::<T>::restore).transpose()?)));
+    let (leaderboard, modes) = match restored {
+      Ok(restored) => restored,
+      Err(error) => {
+        eprintln!("{}: {}", leaderboard_dir::<T>(), error);
+        std::process::exit(1);
+      }
+    };
+    (Checkpoint::default(), SessionMemory::new(), leaderboard, modes)
+  };
+  if let Some(params) = &params {
+    leaderboard.set_params(params);
+    if let Some(modes) = modes.as_mut() {
+      modes.set_params(params);
+    }
+  }
+  // A resumed detector continues from its checkpoint; exclusions are not part of the snapshot.
+  checkpoint.abuse = match checkpoint.abuse.take() {
+    Some(abuse) if args.abuse => Some(abuse),
+    _ if args.abuse => Some(AbuseDetector::new(params.clone().unwrap_or_default().abuse)),
+    _ => None
+  };
+  if args.abuse_exclude {
+    for user_id in checkpoint.abuse.as_ref().unwrap().flagged() {
+      leaderboard.exclude_from_bootstrap(user_id);
+    }
+  }
+  // Create session mode information about which session was played in which mode. Format of line: {"session_id":123,"mode":"ranked"}
+  let session_mode = Arc::new(SessionMode::new(&args.session_mode));
+  // Create registrations with information about when user was registered. Format of line: {"user_id":123,"registered_time":123}
+  let registrations = Registrations::new();
+  // Create user faction with information about which user was in which faction. Format of line: {"user_id":123,"faction":"newbie"}
+  let user_faction = UserFaction::new(&args.user_faction);
+
+  // Create channels for statistic aggregate, statistic check, changes writer and session classification
+  let (sender, receiver) = flume::unbounded();
+  let (request_statistic, requests) = flume::unbounded();
+  
+  // Background worker: aggregate statistics by board key.
+  let stat_map = tokio::task::spawn(statistic_aggregate(
+    receiver.clone(),
+    requests,
+    checkpoint.statistic.clone()
+  ));
+  
+  let (sender_check, receiver_check) = flume::unbounded();
+  let (request_check, requests) = flume::unbounded();
+  
+  // Background worker: collect expected win-rate sanity buckets.
+  let stat_check = tokio::task::spawn(statistic_check(
+    receiver_check.clone(),
+    requests,
+    checkpoint.statistic_check.clone()
+  ));
+  
+  
+  let (sender_tasks, receiver_tasks) = flume::unbounded();
+  let (request_changes, requests) = flume::unbounded();
+  // One output file per classifier id (at least 0 and 1).
+  let change_paths: Vec<String> = (0..=std::cmp::max(1, args.cl_id)).map(|cl_id| "data/changes/".to_string() + cl_id.to_string().as_str()).collect();
+  let mut change_files: Vec<BufWriter<tokio::fs::File>> = Vec::new();
+  for path in change_paths.iter() {
+    change_files.push(BufWriter::new(checkpoint.reopen_output(path).await));
+  }
+  // The history store is appended to across runs; a resumed run only drops what followed the checkpoint.
+  let history_path = HistoryStore::dir::<T>() + "/entries";
+  let history = if args.history {
+    let mut history = HistoryStore::open(&HistoryStore::dir::<T>()).unwrap();
+    if let Some(size) = checkpoint.outputs.get(&history_path) {
+      history.truncate(*size).unwrap();
+    }
+    Some(history)
+  } else {
+    None
+  };
+  let mut change_paths = change_paths;
+  if args.history {
+    change_paths.push(history_path);
+  }
+  
+  // Background worker: persist per-user MMR changes.
+  let change_writer = tokio::task::spawn(write_change::<T>(
+    receiver_tasks.clone(),
+    requests,
+    change_files,
+    history,
+    session_mode.clone()
+  ));
+  
+  
+  let (sender_session_class, receiver_session_class) = flume::unbounded();
+  let (request_session_class, requests) = flume::unbounded();
+  let session_class_path = "data/leaderboard_".to_string() + T::NAME + "/session_classification_8";
+  
+  // Background worker: persist the named classes of every rated session.
+  let session_class_join = tokio::task::spawn(session_class_aggreg(
+    receiver_session_class.clone(),
+    requests,
+    checkpoint.reopen_output(&session_class_path).await
+  ));
+
+  let workers = Workers {
+    statistic: request_statistic,
+    statistic_check: request_check,
+    changes: request_changes,
+    session_class: request_session_class,
+    change_paths,
+    session_class_path
+  };
+  
+  // Seasons continue in the season of the restored leaderboard.
+  let seasons = params.as_ref()
+    .filter(|params| !params.season.starts.is_empty())
+    .map(|params| Seasons::new(params.season.clone(), &leaderboard));
+
+  // Process sessions and calculate leaderboard
+  let abuse = async_main(args, sender, user_team, &mut record_memory, &mut leaderboard, &mut modes, seasons, &session_mode, &registrations, &rules, sender_tasks, sender_check, sender_session_class, &user_faction, workers, checkpoint).await;
+
+  // Wait for statistic aggregate, statistic check, changes writer and session classification to finish
+  let stat_map = match stat_map.await {
+    Ok(data) => data,
+    _ => std::collections::BTreeMap::new()
+  };
+  
+  // Convert (wins, games) into a ratio map for quick inspection.
+  let _stat_check: std::collections::BTreeMap<i32, f64> = match stat_check.await {
+    Ok(data) => data.into_iter().map(|i| (i.0, (i.1.0 as f64) / (i.1.1 as f64))).collect(),
+    _ => std::collections::BTreeMap::new()
+  };
+
+
+  // Write the self-describing statistics report (JSON with the run parameters, board and spread CSVs).
+  let report = StatisticReport::new(T::NAME, &params.clone().unwrap_or_default(), vec![args.data.clone()], &stat_map);
+  report.write(&("data/statistic_".to_string() + T::NAME + "_8"));
+  println!("Statistics: {} boards", report.boards.len());
+
+  // Await background workers so their output files are fully flushed before exit.
+  match change_writer.await {
+    Ok(_) => {},
+    _ => {}
+  };
+  match session_class_join.await {
+    Ok(_) => {},
+    _ => {}
+  };
+
+  if args.decay_sweep {
+    // Sweep at the latest commit_time of the slice, including the buffered trailing session.
+    let now = leaderboard.get_users().values().map(|row| row.last_session)
+      .chain(record_memory.rows.iter().map(|row| row.commit_time))
+      .max()
+      .unwrap_or(0);
+    println!("Decay sweep at {}: {} players", now, leaderboard.decay_sweep(now));
+    if let Some(modes) = modes.as_mut() {
+      println!("Mode decay sweep at {}: {} players", now, modes.decay_sweep(now));
+    }
+  }
+
+  // Persist in-memory session state and final leaderboard snapshot.
+  record_memory.write();
+  leaderboard.persist().await;
+
+  if let Some(modes) = &modes {
+    modes.persist().await;
+    modes.write_compare(&leaderboard).await;
+    for comparison in modes.compare(&leaderboard).iter() {
+      println!("{}", comparison);
+    }
+  }
+
+  if let Some(abuse) = &abuse {
+    abuse.write_review(&AbuseDetector::review_path::<T>()).await;
+    println!("Abuse review: {} flags, {} players", abuse.flags.len(), abuse.flagged().len());
+  }
+
+  // The results are complete, a later --resume must not replay the tail again.
+  if args.checkpoint_every > 0 || args.resume {
+    Checkpoint::remove(&checkpoint_dir).await;
+  }
+}
+
+/// Background task that accumulates win-rate statistics bucketed by MMR delta.
+///
+/// Each message contains the average MMR of the winning team and the losing team.
+/// Results are stored as (wins, total_games) per 200-MMR bucket so callers can
+/// compute empirical win-rates and verify they match theoretical ELO expectations.
+///
+/// Starts from `state_check_hash` (empty, or restored from a checkpoint) and answers
+/// checkpoint `requests` with a copy of the counters.
+async fn statistic_check(
+  receiver: Receiver<(u32, u32)>,
+  requests: Receiver<flume::Sender<std::collections::BTreeMap<i32, (u64, u64)>>>,
+  mut state_check_hash: std::collections::BTreeMap<i32, (u64, u64)>
+) -> std::collections::BTreeMap<i32, (u64, u64)> {
+  // key: MMR delta bucket (step 200), value: (wins, total_games)
+  loop {
+    tokio::select! {
+      message = receiver.recv_async() => match message {
+        Ok((win_team, lose_team)) => add_check(&mut state_check_hash, win_team, lose_team),
+        Err(RecvError::Disconnected) => break,
+      },
+      Ok(reply) = requests.recv_async() => {
+        // Everything sent before the request is already queued.
+        for (win_team, lose_team) in receiver.drain() {
+          add_check(&mut state_check_hash, win_team, lose_team);
+        }
+        let _ = reply.send(state_check_hash.clone());
+      }
+    }
+  }
+
+  state_check_hash
+}
+
+/// Counts one session in the win-rate buckets of [`statistic_check`].
+fn add_check(state_check_hash: &mut std::collections::BTreeMap<i32, (u64, u64)>, win_team: u32, lose_team: u32) {
+  // Record a win (+1 wins, +1 games) for the winning side's MMR-delta bucket.
+  match state_check_hash.get_mut(&((((win_team as i32) - (lose_team as i32)) / 200) * 200)) {
+    Some(stat_base) => {
+      stat_base.0 += 1;
+      stat_base.1 += 1;
+    },
+    None => {
+      state_check_hash.insert((((win_team as i32) - (lose_team as i32)) / 200) * 200, (1 , 1));
+    }
+  };
+  // Record only a game (+1 games) for the losing side's MMR-delta bucket.
+  match state_check_hash.get_mut(&((((lose_team as i32) - (win_team as i32)) / 200) * 200)) {
+    Some(stat_base) => {
+      stat_base.1 += 1;
+    },
+    None => {
+      state_check_hash.insert((((lose_team as i32) - (win_team as i32)) / 200) * 200, (0 , 1));
+    }
+  };
+}
+
+/// Background task that merges incremental `Statistic` payloads into a single map.
+///
+/// Multiple sessions send `(board_key, Statistic)` pairs concurrently; this worker
+/// folds them all into one aggregate `HashMap` keyed by the logical board name.
+///
+/// Starts from `stat_map` (empty, or restored from a checkpoint) and answers checkpoint
+/// `requests` with a copy of the map.
+async fn statistic_aggregate(
+  receiver: Receiver<(String, Statistic)>,
+  requests: Receiver<flume::Sender<std::collections::BTreeMap<String, Statistic>>>,
+  mut stat_map: std::collections::BTreeMap<String, Statistic>
+) -> std::collections::BTreeMap<String, Statistic> {
+  // Merge partial statistic payloads by logical statboard key.
+  loop {
+    tokio::select! {
+      message = receiver.recv_async() => match message {
+        Ok((statboard, statistic)) => add_statistic(&mut stat_map, statboard, statistic),
+        Err(RecvError::Disconnected) => break,
+      },
+      Ok(reply) = requests.recv_async() => {
+        // Everything sent before the request is already queued.
+        for (statboard, statistic) in receiver.drain() {
+          add_statistic(&mut stat_map, statboard, statistic);
+        }
+        let _ = reply.send(stat_map.clone());
+      }
+    }
+  }
+
+  stat_map
+}
+
+/// Folds one session payload into the map of [`statistic_aggregate`].
+fn add_statistic(stat_map: &mut std::collections::BTreeMap<String, Statistic>, statboard: String, statistic: Statistic) {
+  match stat_map.get_mut(&statboard) {
+    Some(stat_base) => {
+      // Accumulate into the existing entry.
+      stat_base.add_statistic(&statistic);
+    },
+    None => {
+      // First observation for this board key — insert directly.
+      stat_map.insert(statboard, statistic);
+    }
+  };
+}
+
+/// Background task that writes per-user MMR change records to disk.
+///
+/// Receives the algorithm's change payloads and lets `RatingSystem::write_change` route each
+/// record to the output file (one per classifier id) that matches its classifier id. With a
+/// `history` store, each change is also appended there, tagged with the raw session mode. A
+/// checkpoint request flushes the files and is answered with their lengths.
+async fn write_change<T: RatingSystem>(
+  receiver: Receiver<T::Change>,
+  requests: Receiver<flume::Sender<Vec<u64>>>,
+  mut change_files: Vec<BufWriter<tokio::fs::File>>,
+  mut history: Option<HistoryStore>,
+  session_mode: Arc<SessionMode>
+) {
+  loop {
+    tokio::select! {
+      message = receiver.recv_async() => match message {
+        Ok(change) => record_change::<T>(change, &mut change_files, &mut history, &session_mode).await,
+        Err(RecvError::Disconnected) => break,
+      },
+      Ok(reply) = requests.recv_async() => {
+        for change in receiver.drain() {
+          record_change::<T>(change, &mut change_files, &mut history, &session_mode).await;
+        }
+        let mut lengths = flushed_lengths(&mut change_files).await;
+        if let Some(history) = history.as_mut() {
+          history.flush().unwrap();
+          lengths.push(history.size());
+        }
+        let _ = reply.send(lengths);
+      }
+    }
+  }
+  for change_file in change_files.iter_mut() {
+    change_file.flush().await.unwrap();
+  }
+  if let Some(history) = history.as_mut() {
+    history.flush().unwrap();
+  }
+}
+
+/// Appends one change to the history store, when given, and writes it to its change file.
+async fn record_change<T: RatingSystem>(
+  change: T::Change,
+  change_files: &mut [BufWriter<tokio::fs::File>],
+  history: &mut Option<HistoryStore>,
+  session_mode: &SessionMode
+) {
+  if let Some(history) = history.as_mut() {
+    let mut entry = T::history_entry(&change);
+    entry.mode = session_mode.0.get(&entry.session_id).map(|mode| mode.0.clone()).unwrap_or_default();
+    history.append(&entry).unwrap();
+  }
+  // Delegate the actual serialization to the algorithm's writer helper.
+  T::write_change(change, change_files).await;
+}
+
+/// Flushes `files` and returns their lengths in bytes.
+async fn flushed_lengths(files: &mut [BufWriter<tokio::fs::File>]) -> Vec<u64> {
+  let mut lengths = Vec::new();
+  for file in files.iter_mut() {
+    file.flush().await.unwrap();
+    lengths.push(file.get_ref().metadata().await.unwrap().len());
+  }
+  lengths
+}
+
+/// Background task that records per-session team composition flags.
+///
+/// Appends to `data_file`; a checkpoint request flushes it and is answered with its length.
+/// Each record has the format:
+/// `session_id:<id>,team_1:<flag>,team_2:<flag>,team_1_v:<flag>,team_2_v:<flag>`
+/// where `team_*` flag a top-3 MMR disbalance in that team's favour and `team_*_v` its victory.
+async fn session_class_aggreg(
+  receiver: Receiver<SessionClasses>,
+  requests: Receiver<flume::Sender<Vec<u64>>>,
+  data_file: tokio::fs::File
+) {
+  // Persist the session classes, one line per rated session.
+  let mut data_files = [BufWriter::new(data_file)];
+  
+  loop {
+    tokio::select! {
+      message = receiver.recv_async() => match message {
+        Ok(record) => write_session_class(&mut data_files[0], record).await,
+        Err(RecvError::Disconnected) => break,
+      },
+      Ok(reply) = requests.recv_async() => {
+        for record in receiver.drain() {
+          write_session_class(&mut data_files[0], record).await;
+        }
+        let _ = reply.send(flushed_lengths(&mut data_files).await);
+      }
+    }
+  }
+
+  data_files[0].flush().await.unwrap();
+}
+
+/// Writes one [`session_class_aggreg`] record as a flat key:value line, the class names
+/// separated by `|`.
+async fn write_session_class(data_file: &mut BufWriter<tokio::fs::File>, (session_id, classes): SessionClasses) {
+  let str = "session_id:".to_string() + session_id.to_string().as_str()
+      + ",classes:" + classes.join("|").as_str();
+  data_file.write_all((str + "\n").as_bytes()).await.unwrap();
+}
+
+
+/// Core streaming loop for the leaderboard pipeline.
+///
+/// Reads the userstat dataset line-by-line, groups rows by `session_id`, and rates each
+/// completed session with `rating::proc_session`. With `--batch N`, up to N consecutive
+/// sessions without a shared player are collected in a `SessionBatch` and rated together by
+/// `batch::proc_batch` (see [`rate_batch`]); a session sharing a player with the batch is
+/// rated after it, so the results equal those of the sequential run. Results are
+/// broadcast to the background workers through the provided flume senders:
+///
+/// - `sender`              — forwards `(board_key, Statistic)` payloads to `statistic_aggregate`.
+/// - `sender_tasks`        — forwards `(change, mmr_diff, row, debug, cl_id)` to `write_change`.
+/// - `sender_check`        — forwards `(win_team_mmr, lose_team_mmr)` to `statistic_check`.
+/// - `sender_session_class`— forwards the session classes of `rules` to `session_class_aggreg`.
+///
+/// With `modes`, each session is also rated on the board of its mode before the global update.
+/// With `--abuse`, the detector carried by `checkpoint` observes each session after the
+/// global update and is returned at the end.
+/// With `seasons`, the first session at or after a season start ends the season first (see
+/// [`end_season`]).
+///
+/// Reading starts at the position of `checkpoint` (the file start for a fresh run). Every
+/// `--checkpoint-every` sessions, the workers are asked for their state and a new checkpoint is
+/// written at the first row of the next session.
+///
+/// Additionally writes a CSV debug dump (`data/csv/<cl_id>.csv`) of every processed row and
+/// prints aggregate timing diagnostics (total wall time, per-stage breakdowns) at the end.
+#[allow(clippy::too_many_arguments)]
+async fn async_main<T: RatingSystem>(
+  args: &Args,
+  sender: flume::Sender<(String, Statistic)>, 
+  user_team: &std::collections::HashMap<(u64,u64), (u8, bool)>,
+  record_memory: &mut SessionMemory,
+  leaderboard: &mut T,
+  modes: &mut Option<ModeLeaderboards<T>>,
+  mut seasons: Option<Seasons>,
+  session_mode: &SessionMode,
+  registrations: &Registrations,
+  rules: &SessionRules,
+  sender_tasks: flume::Sender<T::Change>, 
+  sender_check: flume::Sender<(u32, u32)>, 
+  sender_session_class: flume::Sender<SessionClasses>,
+  user_faction: &UserFaction,
+  workers: Workers,
+  mut checkpoint: Checkpoint
+) -> Option<AbuseDetector> {
+  let _start = Instant::now();
+    // CSV dump used for debugging/inspection of processed sessions.
+    let session_path = "data/csv/".to_string() + args.cl_id.to_string().as_str() + ".csv";
+    let session_file = checkpoint.reopen_output(&session_path).await;
+    let session_file = Arc::new(Mutex::new(BufWriter::new(session_file)));
+    if !args.resume {
+      // Write the CSV header row.
+      let str: String = "session_id".to_string() + ";"
+        + "user_id" + ";"
+        + "team;" + "victory" + ";"
+        + "mmr_type" + ";"
+        + "mmr" + "\n";
+    
+      session_file.lock().await.write_all(str.as_bytes()).await.unwrap();
+    }
+
+      // Timing accumulators used to profile each processing stage.
+      let session_common_dt = Instant::now(); // wall-clock anchor for the whole streaming loop
+      let mut totals = StageTotals::default();
+
+      checkpoint.data = args.data.clone();
+      // 0 threads lets rayon use every available core.
+      let pool = rayon::ThreadPoolBuilder::new().num_threads(args.threads).build().unwrap();
+      let mut batch = SessionBatch::new(args.batch.max(1));
+      // Stream input rows and group them by session_id.
+      if let Ok(mut rows) = UserStatReader::open_at(&args.data, (checkpoint.offset, checkpoint.line), user_team, user_faction) {
+        // Typed rows; rejected lines are counted in the report printed below.
+        while let Some(row) = rows.next() {
+          if row.session_id != record_memory.now_session_id {
+            // Finalize the previous session when we detect a session switch.
+            // The first session of a new season waits for the end of the previous one, which
+            // sees every earlier session rated.
+            if let Some(seasons) = seasons.as_mut() {
+              if let Some(now) = record_memory.rows.iter().map(|row| row.commit_time).min().filter(|now| seasons.ends_before(*now)) {
+                rate_batch(args, &mut batch, &pool, leaderboard, modes, &mut checkpoint, &sender, session_mode, registrations, rules, &sender_tasks, &sender_check, &sender_session_class, &mut totals).await;
+                end_season(seasons, leaderboard, modes, now, rules).await;
+              }
+            }
+            // A session sharing a player with the batch waits for the batch to be rated.
+            if !batch.accepts(record_memory) {
+              rate_batch(args, &mut batch, &pool, leaderboard, modes, &mut checkpoint, &sender, session_mode, registrations, rules, &sender_tasks, &sender_check, &sender_session_class, &mut totals).await;
+            }
+            batch.push(record_memory.clone());
+
+            // Checkpoints fall on the same session boundaries as in a sequential run.
+            let boundary = args.checkpoint_every > 0 && (checkpoint.sessions + batch.len() as u64).is_multiple_of(args.checkpoint_every);
+            if batch.is_full() || boundary {
+              rate_batch(args, &mut batch, &pool, leaderboard, modes, &mut checkpoint, &sender, session_mode, registrations, rules, &sender_tasks, &sender_check, &sender_session_class, &mut totals).await;
+            }
+            if boundary {
+              // The rows of `row.session_id` start at the current row.
+              (checkpoint.offset, checkpoint.line) = rows.row_start();
+              checkpoint.session_id = row.session_id;
+              take_checkpoint(&mut checkpoint, &workers, &session_path, &session_file, leaderboard, modes).await;
+            }
+
+            // Start accumulating rows for the new session.
+            record_memory.now_session_id = row.session_id;
+            record_memory.rows = Vec::new();
+            record_memory.rows.push(row);
+          } else {
+            // Still within the current session — append the row.
+            record_memory.rows.push(row);
+          }
+        }
+        rate_batch(args, &mut batch, &pool, leaderboard, modes, &mut checkpoint, &sender, session_mode, registrations, rules, &sender_tasks, &sender_check, &sender_session_class, &mut totals).await;
+        println!("{}", rows.report());
+      }
+  
+      let elapsed = session_common_dt.elapsed();
+    
+      // Print aggregate timing diagnostics for the whole streaming pass.
+      println!("Make cluster: {:?}", elapsed);
+
+      println!("common session dt: {:?}", totals.count);
+      println!("common session dt: {:?}", totals.session_dt);
+    
+    
+      println!("prep session dt: {:?}", totals.prepear_session_dt);
+    
+       
+      println!("write session dt: {:?}", totals.write_session_dt);
+      println!("prep change dt: {:?}", totals.prepear_change_dt);
+      println!("set change dt: {:?}", totals.set_change_dt);
+
+      session_file.lock().await.flush().await.unwrap();
+      checkpoint.abuse
+}
+
+/// Rates the sessions collected in `batch` with `batch::proc_batch`, adds their stage timings
+/// to `totals` and counts them in `checkpoint.sessions`.
+#[allow(clippy::too_many_arguments)]
+async fn rate_batch<T: RatingSystem>(
+  args: &Args,
+  batch: &mut SessionBatch,
+  pool: &rayon::ThreadPool,
+  leaderboard: &mut T,
+  modes: &mut Option<ModeLeaderboards<T>>,
+  checkpoint: &mut Checkpoint,
+  sender: &flume::Sender<(String, Statistic)>,
+  session_mode: &SessionMode,
+  registrations: &Registrations,
+  rules: &SessionRules,
+  sender_tasks: &flume::Sender<T::Change>,
+  sender_check: &flume::Sender<(u32, u32)>,
+  sender_session_class: &flume::Sender<SessionClasses>,
+  totals: &mut StageTotals
+) {
+  if batch.is_empty() {
+    return;
+  }
+  let sessions = batch.take();
+  checkpoint.sessions += sessions.len() as u64;
+  let start_batch = Instant::now();
+  let timings = batch::proc_batch(leaderboard, modes, &mut checkpoint.abuse, args.abuse_exclude, sessions, pool, args.cl_id, sender.clone(), session_mode, registrations, rules, sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+  let common_batch = start_batch.elapsed();
+  totals.session_dt += common_batch;
+
+  for (prep_sess, write_sess, prep_change, set_change) in timings.into_iter().flatten() {
+    // Defensive timing check: a sub-stage cannot exceed the total batch time.
+    if set_change.1 > common_batch {
+      println!("common {}", start_batch.duration_since(start_batch).as_nanos());
+      println!("1 {}", prep_sess.0.duration_since(start_batch).as_nanos());
+      println!("2 {}", write_sess.0.duration_since(start_batch).as_nanos());
+      println!("3 {}", prep_change.0.duration_since(start_batch).as_nanos());
+      println!("4 {}", set_change.0.duration_since(start_batch).as_nanos());
+      println!("d1 {:?}", prep_sess.1);
+      println!("d2 {:?}", write_sess.1);
+      println!("d3 {:?}", prep_change.1);
+      println!("d4 {:?}", set_change.1);
+      println!("com {:?}", common_batch);
+      panic!();
+    }
+    totals.count += 1;
+    totals.prepear_session_dt += prep_sess.1;
+    totals.write_session_dt += write_sess.1;
+    totals.prepear_change_dt += prep_change.1;
+    totals.set_change_dt += set_change.1;
+  }
+}
+
+/// Ends the season of `seasons` before a session at `now`: archives the global leaderboard
+/// with its final ranks to `data/leaderboard_<NAME>/season_<n>` and soft-resets it and the
+/// mode boards (see `Seasons::end_season`). A failed archive stops the run.
+async fn end_season<T: RatingSystem>(
+  seasons: &mut Seasons,
+  leaderboard: &mut T,
+  modes: &mut Option<ModeLeaderboards<T>>,
+  now: u64,
+  rules: &SessionRules
+) {
+  let dir = Seasons::dir::<T>(seasons.season);
+  match seasons.end_season(leaderboard, &dir, now, &rules.factions).await {
+    Ok(Some(end)) => {
+      println!("{}", end);
+      if let Some(modes) = modes.as_mut() {
+        println!("Mode season reset: {} players", modes.season_reset(&seasons.params));
+      }
+    },
+    Ok(None) => {},
+    Err(error) => {
+      eprintln!("{}: {}", dir, error);
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Collects the worker state into `checkpoint` and writes it with the leaderboards to
+/// `data/leaderboard_<NAME>/checkpoint`. A failed write is reported and the run goes on.
+async fn take_checkpoint<T: RatingSystem>(
+  checkpoint: &mut Checkpoint,
+  workers: &Workers,
+  session_path: &str,
+  session_file: &Mutex<BufWriter<tokio::fs::File>>,
+  leaderboard: &T,
+  modes: &Option<ModeLeaderboards<T>>
+) {
+  let (reply, state) = flume::bounded(1);
+  workers.statistic.send(reply).unwrap();
+  checkpoint.statistic = state.recv_async().await.unwrap();
+
+  let (reply, state) = flume::bounded(1);
+  workers.statistic_check.send(reply).unwrap();
+  checkpoint.statistic_check = state.recv_async().await.unwrap();
+
+  let (reply, lengths) = flume::bounded(1);
+  workers.changes.send(reply).unwrap();
+  let lengths = lengths.recv_async().await.unwrap();
+  for (path, len) in workers.change_paths.iter().zip(lengths) {
+    checkpoint.outputs.insert(path.clone(), len);
+  }
+
+  let (reply, lengths) = flume::bounded(1);
+  workers.session_class.send(reply).unwrap();
+  checkpoint.outputs.insert(workers.session_class_path.clone(), lengths.recv_async().await.unwrap()[0]);
+
+  let mut session_file = session_file.lock().await;
+  session_file.flush().await.unwrap();
+  checkpoint.outputs.insert(session_path.to_string(), session_file.get_ref().metadata().await.unwrap().len());
+
+  let dir = Checkpoint::dir::<T>();
+  if let Err(error) = checkpoint.write(&dir, leaderboard, modes.as_ref()).await {
+    eprintln!("checkpoint not written to {}: {}; the last complete one is kept", dir, error);
+  }
+}
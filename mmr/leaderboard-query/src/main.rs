@@ -0,0 +1,151 @@
+use mmr_libs::algorithm::{Algorithm, RatingTask};
+use mmr_libs::classification::SessionRules;
+use mmr_libs::estimate::spread_estimates;
+use mmr_libs::params::MMRParams;
+use mmr_libs::ranking::{RankFilter, Ranking};
+use mmr_libs::rating::{leaderboard_dir, RatingSystem};
+use mmr_libs::spread;
+use clap::{Parser, Subcommand};
+
+/// Query to answer.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rank and percentile of a player.
+    Rank {
+        #[arg(long)]
+        user: u64,
+    },
+    /// The first N players.
+    Top {
+        #[arg(long, default_value_t = 100)]
+        n: usize,
+    },
+    /// A player with the K players above and below it.
+    Neighbours {
+        #[arg(long)]
+        user: u64,
+        #[arg(long, default_value_t = 5)]
+        k: usize,
+    },
+    /// MMR histogram by dominant faction (`spread::mmr_spread_samples`) with the standard error
+    /// and bootstrap interval of every bucket's mean MMR, as `;`-separated CSV.
+    Histogram {
+        /// Bucket width in MMR points.
+        #[arg(long, default_value_t = 100)]
+        bucket: u32,
+        /// RON file whose `estimate` section sets the resamples, confidence and minimum bucket
+        /// size (built-in defaults when omitted).
+        #[arg(long)]
+        params: Option<String>,
+        /// CSV path; printed when omitted.
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
+
+/// Command-line arguments for the leaderboard query tool.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Algorithm of the leaderboard in `data/leaderboard_<algorithm>`.
+    #[arg(long, value_enum)]
+    pub algorithm: Algorithm,
+    /// Leaderboard directory, overrides the one of `--algorithm`.
+    #[arg(long)]
+    pub dir: Option<String>,
+    /// Only players with at least this many battles (6 = calibrated).
+    #[arg(long, default_value_t = 6)]
+    pub min_battles: u32,
+    /// Only players whose last session is at or after this commit_time (ms).
+    #[arg(long, default_value_t = 0)]
+    pub active_since: u64,
+    /// Only players of this dominant faction: `faction_1`, `faction_2` or `mixed` (65% rule)
+    /// with the built-in factions.
+    #[arg(long)]
+    pub faction: Option<String>,
+    /// RON file whose `factions` section registers the factions and the dominant-faction rule
+    /// (built-in factions when omitted).
+    #[arg(long)]
+    pub rules: Option<String>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Entry point for the leaderboard query tool.
+///
+/// 1. Restores the persisted leaderboard of `--algorithm` (`RatingSystem::restore_from`).
+/// 2. Ranks the players that pass `--min-battles`, `--active-since` and `--faction`
+///    (`ranking::Ranking`); ranks and percentiles are relative to that population.
+/// 3. Prints the requested rows as flat key:value lines, or writes the histogram.
+fn main() {
+  let args: Args = Args::parse();
+  args.algorithm.dispatch(Query(&args));
+}
+
+/// [`query`] with the leaderboard of `--algorithm`.
+struct Query<'a>(&'a Args);
+
+impl RatingTask for Query<'_> {
+  type Output = ();
+
+  fn call<T: RatingSystem>(self) {
+    query::<T>(self.0)
+  }
+}
+
+/// Answers `args.command` on the leaderboard of `T`.
+fn query<T: RatingSystem>(args: &Args) {
+  let dir = args.dir.clone().unwrap_or_else(leaderboard_dir::<T>);
+  let leaderboard = match T::restore_from(&dir) {
+    Ok(leaderboard) => leaderboard,
+    Err(error) => {
+      eprintln!("{}: {}", dir, error);
+      std::process::exit(1);
+    }
+  };
+  let filter = RankFilter {
+    min_battles: args.min_battles,
+    active_since: args.active_since,
+    faction: args.faction.clone(),
+    factions: args.rules.as_ref().map(|path| SessionRules::read(path)).unwrap_or_default().factions
+  };
+
+  match &args.command {
+    Command::Rank { user } => {
+      let ranking = Ranking::new(&leaderboard, &filter);
+      match ranking.get(*user) {
+        Some(ranked) => println!("{},of:{}", ranked, ranking.rows.len()),
+        None => println!("user_id:{},rank:none", user)
+      }
+    },
+    Command::Top { n } => {
+      for ranked in Ranking::new(&leaderboard, &filter).top(*n) {
+        println!("{}", ranked);
+      }
+    },
+    Command::Neighbours { user, k } => {
+      match Ranking::new(&leaderboard, &filter).neighbours(*user, *k) {
+        Some(rows) => for ranked in rows {
+          println!("{}", ranked);
+        },
+        None => println!("user_id:{},rank:none", user)
+      }
+    },
+    Command::Histogram { bucket, params, output } => {
+      let params = params.as_ref().map(|path| MMRParams::read(path)).unwrap_or_default();
+      let samples = spread::mmr_spread_samples(&leaderboard, leaderboard.get_users(), *bucket, args.min_battles, args.active_since, &filter.factions)
+        .into_iter()
+        .filter(|((faction, _mmr_group), _)| args.faction.as_ref().map(|only| only == faction).unwrap_or(true))
+        .collect();
+      // Buckets below `estimate.min_sample` players keep their count only.
+      let mut str = "faction;mmr;players;mmr_avg;mmr_std_error;mmr_ci_low;mmr_ci_high;suppressed\n".to_string();
+      for ((faction, mmr_group), estimate) in spread_estimates(&samples, &params.estimate) {
+        str += (faction + ";" + mmr_group.to_string().as_str() + ";" + estimate.samples.to_string().as_str() + ";" + estimate.csv_columns(1).as_str() + "\n").as_str();
+      }
+      match output {
+        Some(path) => std::fs::write(path, str).unwrap(),
+        None => print!("{}", str)
+      }
+    },
+  }
+}
@@ -0,0 +1,113 @@
+use std::time::Instant;
+use mmr_libs::algorithm::{Algorithm, BoxedTask, RatingTask};
+use mmr_libs::classification::SessionRules;
+use mmr_libs::datasets::{Registrations, SessionMode, UserFaction, UserTeam};
+use mmr_libs::evaluation::load_sessions;
+use mmr_libs::matchmaker::{self, MatchParams, MatchReport};
+use mmr_libs::params::MMRParams;
+use mmr_libs::rating::RatingSystem;
+use clap::Parser;
+
+/// Command-line arguments for the matchmaking simulator.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Rating algorithm replayed alongside the matchmaker.
+    #[arg(long, value_enum)]
+    pub algorithm: Algorithm,
+    /// Path to the file containing (user_id, session_id) -> (team, victory) mappings.
+    #[arg(long)]
+    pub user_team: String,
+    /// Path to the file mapping session_id to mode name.
+    #[arg(long)]
+    pub session_mode: String,
+    /// Path to the file mapping user_id to faction.
+    #[arg(long)]
+    pub user_faction: String,
+    /// Path to the main userstat dataset file.
+    #[arg(long)]
+    pub data: String,
+    /// RON file with v1/v2 formula constants (built-in defaults when omitted).
+    #[arg(long)]
+    pub params: Option<String>,
+    /// RON file with the session skip rules (built-in rules when omitted).
+    #[arg(long)]
+    pub rules: Option<String>,
+    /// Largest allowed gap between the top-3 calibrated MMR averages of the teams.
+    #[arg(long, default_value_t = 800)]
+    pub max_top_3_gap: u32,
+    /// Largest allowed difference between the newbie counts of the teams.
+    #[arg(long, default_value_t = 1)]
+    pub max_newbie_diff: u32,
+    /// Cost in MMR points of placing a player against their main faction (0 ignores it).
+    #[arg(long, default_value_t = 100.0)]
+    pub faction_weight: f64,
+    /// Report path.
+    #[arg(long, default_value = "data/matchmaker_8")]
+    pub output: String,
+}
+
+/// Entry point for the matchmaking simulator.
+///
+/// 1. Loads the auxiliary datasets and the userstat slice into memory.
+/// 2. Replays the slice from an empty leaderboard of the selected algorithm. Before each
+///    rated session is applied, its players are re-matched into two teams with
+///    `matchmaker::make_match`, and both versions are measured with the current ratings.
+/// 3. Writes the original and re-matched top-3 gap, disbalanced and newbie-skewed session
+///    counts, mean |p - 0.5| of the predicted win probability and faction misses per group.
+#[tokio::main]
+async fn main() {
+  let args: Args = Args::parse();
+  let start = Instant::now();
+
+  // (user_id, session_id) -> (team_id, victory_flag)
+  let user_team = UserTeam::new(&args.user_team).0;
+  let session_mode = SessionMode::new(&args.session_mode);
+  let registrations = Registrations::new();
+  let user_faction = UserFaction::new(&args.user_faction);
+  let sessions = load_sessions(&args.data, &user_team, &user_faction);
+
+  println!("Load datasets: {:?}, sessions: {}", start.elapsed(), sessions.len());
+
+  let params = MatchParams {
+    max_top_3_gap: args.max_top_3_gap,
+    max_newbie_diff: args.max_newbie_diff,
+    faction_weight: args.faction_weight,
+    ..MatchParams::default()
+  };
+
+  let replay_start = Instant::now();
+  let report = args.algorithm.dispatch(Replay(&args, &sessions, &session_mode, &registrations, &params)).await;
+  println!("Replay: {:?}, infeasible re-matches: {}", replay_start.elapsed(), report.infeasible);
+
+  let str = report.to_string();
+  print!("{}", str);
+  tokio::fs::write(&args.output, str).await.unwrap();
+}
+
+/// [`replay`] with the leaderboard of `--algorithm`.
+struct Replay<'a>(&'a Args, &'a [mmr_libs::memory::SessionMemory], &'a SessionMode, &'a Registrations, &'a MatchParams);
+
+impl<'a> RatingTask for Replay<'a> {
+  type Output = BoxedTask<'a, MatchReport>;
+
+  fn call<T: RatingSystem>(self) -> Self::Output {
+    Box::pin(replay::<T>(self.0, self.1, self.2, self.3, self.4))
+  }
+}
+
+/// Replays the sessions with the rating algorithm `T` from an empty leaderboard.
+async fn replay<T: RatingSystem>(
+  args: &Args,
+  sessions: &[mmr_libs::memory::SessionMemory],
+  session_mode: &SessionMode,
+  registrations: &Registrations,
+  params: &MatchParams
+) -> MatchReport {
+  let mut leaderboard = T::empty();
+  if let Some(path) = &args.params {
+    leaderboard.set_params(&MMRParams::read(path));
+  }
+  let rules = args.rules.as_ref().map(|path| SessionRules::read(path)).unwrap_or_default();
+  matchmaker::replay(&mut leaderboard, sessions, session_mode, registrations, &rules, params).await
+}
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+
+use crate::datasets::Registrations;
+use crate::decay::CALIBRATED_BATTLES;
+use crate::factions::FactionRegistry;
+use crate::memory::SessionMemory;
+use crate::params::AbuseParams;
+use crate::rating::{leaderboard_dir, RatingSystem, SessionTeams};
+use crate::types::MMRType;
+
+/// Sessions between two sweeps of the win-trading pairs that did not meet for a while.
+pub const PAIR_SWEEP_SESSIONS: u64 = 1000;
+
+/// Pairs that did not meet on opposite teams for this long (ms) are forgotten.
+pub const PAIR_WINDOW: u64 = 30 * 24 * 60 * 60 * 1000;
+
+/// Suspicious pattern found for a player.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AbuseKind {
+    // Repeatedly on the opposite team of `partner`, with the winner switching almost every time.
+    WinTrading { partner: u64, games: u32, alternations: u32 },
+    // New account whose MMR climbed `smurf_margin` above the bootstrap estimate.
+    Smurf { estimate: u32, mmr: u32, battles: u32 },
+    // Early quits in a row.
+    EarlyQuitStreak { streak: u32 }
+}
+
+impl AbuseKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AbuseKind::WinTrading { .. } => "win_trading",
+            AbuseKind::Smurf { .. } => "smurf",
+            AbuseKind::EarlyQuitStreak { .. } => "early_quit_streak"
+        }
+    }
+}
+
+/// First occurrence of an [`AbuseKind`] for a player.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AbuseFlag {
+    pub user_id: u64,
+    // Session where the pattern crossed the threshold.
+    pub session_id: u64,
+    pub commit_time: u64,
+    pub kind: AbuseKind
+}
+
+impl std::fmt::Display for AbuseFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "user_id:{},session_id:{},commit_time:{},kind:{},", self.user_id, self.session_id, self.commit_time, self.kind.name())?;
+        match &self.kind {
+            AbuseKind::WinTrading { partner, games, alternations } => write!(f, "partner:{},games:{},alternations:{}", partner, games, alternations),
+            AbuseKind::Smurf { estimate, mmr, battles } => write!(f, "estimate:{},mmr:{},battles:{}", estimate, mmr, battles),
+            AbuseKind::EarlyQuitStreak { streak } => write!(f, "streak:{}", streak)
+        }
+    }
+}
+
+/// Meetings of two players on opposite teams.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PairRecord {
+    pub games: u32,
+    // Meetings weighted by one over the larger team size of the session, so a teammate who
+    // only follows a trader gets a share of each meeting.
+    #[serde(default)]
+    pub weight: f64,
+    // Consecutive meetings won by a different player than the previous one.
+    pub alternations: u32,
+    pub last_winner: u64,
+    pub last_time: u64
+}
+
+/// Streaming detector of win-trading, smurf accounts and early-quit streaks.
+///
+/// Fed with every session of a pass (see [`AbuseDetector::observe`]); the state is plain data
+/// so a [`crate::checkpoint::Checkpoint`] can carry it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AbuseDetector {
+    pub params: AbuseParams,
+    // Sessions observed so far.
+    pub sessions: u64,
+    // (lower user_id, higher user_id) -> meetings on opposite teams.
+    pub pairs: std::collections::BTreeMap<(u64, u64), PairRecord>,
+    // New accounts inside the smurf window: user_id -> bootstrap estimate at calibration.
+    pub calibrations: std::collections::BTreeMap<u64, u32>,
+    // user_id -> current early-quit streak.
+    pub streaks: std::collections::BTreeMap<u64, u32>,
+    // (user_id, kind name) -> first flag.
+    pub flags: std::collections::BTreeMap<(u64, String), AbuseFlag>
+}
+
+impl AbuseDetector {
+    pub fn new(params: AbuseParams) -> Self {
+        Self { params, ..Default::default() }
+    }
+
+    /// Review file of `T`: `data/leaderboard_<NAME>/abuse_review`.
+    pub fn review_path<T: RatingSystem>() -> String {
+        leaderboard_dir::<T>() + "/abuse_review"
+    }
+
+    /// Users with at least one flag.
+    pub fn flagged(&self) -> std::collections::BTreeSet<u64> {
+        self.flags.keys().map(|(user_id, _kind)| *user_id).collect()
+    }
+
+    /// Checks one session after `rating` applied it (`rated` is what `proc_session` returned).
+    ///
+    /// 1. **Early quits** — every row counts, rated or not; a finished battle resets the streak.
+    /// 2. **Win-trading** — rated sessions with a single winning team count one meeting for
+    ///    every player of the winning team with every player of another team of `factions`,
+    ///    weighted by one over the larger of the two teams; a pair is flagged once the weighted
+    ///    meetings reach `trade_min_games` and at least `trade_alternation` of the repeat
+    ///    meetings were won by the other player than the last time.
+    /// 3. **Smurfs** — accounts registered at most `smurf_account_age` before the session
+    ///    keep the [`RatingSystem::calibration_estimate`] of their battles once calibrated,
+    ///    and are flagged when their MMR reaches the estimate plus `smurf_margin` within
+    ///    `smurf_window` battles. Algorithms without a calibration estimate skip it.
+    ///
+    /// With `exclude`, newly flagged players are removed from the bootstrap estimates
+    /// ([`RatingSystem::exclude_from_bootstrap`]).
+    pub async fn observe<T: RatingSystem>(&mut self, rating: &mut T, session_memory: &SessionMemory, rated: bool, registrations: &Registrations, factions: &FactionRegistry, exclude: bool) {
+        let mut new_flags: Vec<AbuseFlag> = Vec::new();
+        let now = session_memory.rows.iter().map(|row| row.commit_time).max().unwrap_or(0);
+        let flag = |user_id: u64, kind: AbuseKind| AbuseFlag { user_id, session_id: session_memory.now_session_id, commit_time: now, kind };
+
+        // 1) Early-quit streaks.
+        for row in session_memory.rows.iter() {
+            if row.early_quit {
+                let streak = self.streaks.entry(row.user_id).or_insert(0);
+                *streak += 1;
+                if *streak >= self.params.early_quit_streak {
+                    new_flags.push(flag(row.user_id, AbuseKind::EarlyQuitStreak { streak: *streak }));
+                }
+            } else {
+                self.streaks.remove(&row.user_id);
+            }
+        }
+
+        if rated {
+            // 2) Opposite-team meetings with alternating winners.
+            let teams = SessionTeams::new(rating, session_memory, factions);
+            if let Some(winning) = teams.winner().map(|team| team as usize - 1) {
+                for (index_1, team_1) in teams.teams.iter().enumerate() {
+                    for (index_2, team_2) in teams.teams.iter().enumerate().skip(index_1 + 1) {
+                        // Only meetings with the winning team have a winner.
+                        if index_1 != winning && index_2 != winning {
+                            continue;
+                        }
+                        let weight = 1.0 / team_1.len().max(team_2.len()) as f64;
+                        for row_1 in team_1.iter() {
+                            for row_2 in team_2.iter() {
+                                let winner = if index_1 == winning {row_1.user_id} else {row_2.user_id};
+                                let key = (row_1.user_id.min(row_2.user_id), row_1.user_id.max(row_2.user_id));
+                                let pair = self.pairs.entry(key).or_default();
+                                if pair.games > 0 && pair.last_winner != winner {
+                                    pair.alternations += 1;
+                                }
+                                pair.games += 1;
+                                pair.weight += weight;
+                                pair.last_winner = winner;
+                                pair.last_time = now;
+                                if pair.weight >= self.params.trade_min_games as f64 && pair.alternations as f64 / (pair.games - 1) as f64 >= self.params.trade_alternation {
+                                    new_flags.push(flag(key.0, AbuseKind::WinTrading { partner: key.1, games: pair.games, alternations: pair.alternations }));
+                                    new_flags.push(flag(key.1, AbuseKind::WinTrading { partner: key.0, games: pair.games, alternations: pair.alternations }));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // 3) New accounts climbing far above their bootstrap estimate.
+            for row in session_memory.rows.iter() {
+                let battles = rating.get_battles(row.user_id);
+                let mmr = match rating.get_mmr(row.user_id) {
+                    MMRType::MMR(mmr) if battles <= self.params.smurf_window => mmr,
+                    _ => {
+                        self.calibrations.remove(&row.user_id);
+                        continue;
+                    }
+                };
+                if !self.calibrations.contains_key(&row.user_id) {
+                    let new_account = match registrations.0.get(&row.user_id) {
+                        Some(time) => row.commit_time >= *time && row.commit_time - time <= self.params.smurf_account_age,
+                        None => false
+                    };
+                    // Only the calibration battle records an estimate.
+                    if battles != CALIBRATED_BATTLES || !new_account {
+                        continue;
+                    }
+                    let estimate = match rating.get_users().get(&row.user_id) {
+                        Some(user) => rating.calibration_estimate(user).await,
+                        None => None
+                    };
+                    match estimate {
+                        Some(estimate) => self.calibrations.insert(row.user_id, estimate.mmr),
+                        None => continue
+                    };
+                }
+                let estimate = self.calibrations[&row.user_id];
+                if mmr >= estimate + self.params.smurf_margin {
+                    new_flags.push(flag(row.user_id, AbuseKind::Smurf { estimate, mmr, battles }));
+                    self.calibrations.remove(&row.user_id);
+                }
+            }
+        }
+
+        self.sessions += 1;
+        if self.sessions.is_multiple_of(PAIR_SWEEP_SESSIONS) {
+            self.pairs.retain(|_key, pair| now.saturating_sub(pair.last_time) <= PAIR_WINDOW);
+        }
+
+        for new_flag in new_flags {
+            let key = (new_flag.user_id, new_flag.kind.name().to_string());
+            if self.flags.contains_key(&key) {
+                continue;
+            }
+            if exclude {
+                rating.exclude_from_bootstrap(new_flag.user_id);
+            }
+            self.flags.insert(key, new_flag);
+        }
+    }
+
+    /// Writes every flag as a flat key:value line, ordered by user and kind.
+    pub async fn write_review(&self, path: &str) {
+        let mut str = String::new();
+        for flag in self.flags.values() {
+            str += (flag.to_string() + "\n").as_str();
+        }
+        tokio::fs::write(path, str).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Leaderboard, UserBattleRow};
+
+    fn row(user_id: u64, session_id: u64, faction: &str, victories: bool, early_quit: bool) -> UserBattleRow {
+        UserBattleRow { user_id, session_id, commit_time: session_id * 1000, team: 1, battle_score: 1000, victories, early_quit, team_score_top_20_percent: false, faction: faction.to_string() }
+    }
+
+    #[tokio::test]
+    async fn detector_flags_trading_pairs_and_quit_streaks() {
+        let mut leaderboard = Leaderboard::empty();
+        let mut detector = AbuseDetector::new(AbuseParams { trade_min_games: 4, trade_alternation: 0.8, early_quit_streak: 3, ..Default::default() });
+        let registrations = Registrations(std::collections::HashMap::new());
+
+        for session_id in 1..=6 {
+            // Players 1 and 2 swap sides and trade wins; from session 3 on 3 follows 1 and only
+            // ever quits early.
+            let follower: Vec<u64> = if session_id >= 3 {vec![3]} else {Vec::new()};
+            let (team_1, team_2) = if session_id % 2 == 0 {([vec![1], follower].concat(), vec![2])} else {(vec![2], [vec![1], follower].concat())};
+            let mut rows: Vec<UserBattleRow> = Vec::new();
+            rows.extend(team_1.iter().map(|user_id| row(*user_id, session_id, "faction_1", true, *user_id == 3)));
+            rows.extend(team_2.iter().map(|user_id| row(*user_id, session_id, "faction_2", false, *user_id == 3)));
+            detector.observe(&mut leaderboard, &SessionMemory { now_session_id: session_id, rows }, true, &registrations, &FactionRegistry::default(), true).await;
+        }
+
+        // Two 1v1 meetings and four 2v1 meetings at half weight.
+        assert_eq!(detector.pairs[&(1, 2)], PairRecord { games: 6, weight: 4.0, alternations: 5, last_winner: 1, last_time: 6000 });
+        // 1 and 3 are never opponents; 3 alternates against 2 too, but only alongside 1.
+        assert!(!detector.pairs.contains_key(&(1, 3)));
+        assert_eq!(detector.pairs[&(2, 3)], PairRecord { games: 4, weight: 2.0, alternations: 3, last_winner: 3, last_time: 6000 });
+        let kinds: Vec<(u64, String)> = detector.flags.keys().cloned().collect();
+        assert_eq!(kinds, vec![(1, "win_trading".to_string()), (2, "win_trading".to_string()), (3, "early_quit_streak".to_string())]);
+        assert_eq!(detector.flags[&(1, "win_trading".to_string())].session_id, 6);
+        assert_eq!(detector.flags[&(3, "early_quit_streak".to_string())].session_id, 5);
+        assert_eq!(detector.flagged().into_iter().collect::<Vec<u64>>(), vec![1, 2, 3]);
+        assert!(leaderboard.bootstrap_excluded.contains(&2));
+    }
+}
@@ -0,0 +1,104 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use clap::ValueEnum;
+
+use crate::rating::RatingSystem;
+use crate::types::{Leaderboard, LeaderboardGlicko, LeaderboardTrueSkill, LeaderboardV2};
+
+/// Rating algorithm selected with `--algorithm` by the binaries.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// ELO-based recalculation (`Leaderboard`).
+    V1,
+    /// Pool redistribution recalculation (`LeaderboardV2`).
+    V2,
+    /// Glicko-2 rating, deviation and volatility (`LeaderboardGlicko`).
+    Glicko,
+    /// TrueSkill-style team model with (mu, sigma) per player (`LeaderboardTrueSkill`).
+    Trueskill,
+}
+
+/// Code generic over the leaderboard type, run for an [`Algorithm`] by [`Algorithm::dispatch`].
+///
+/// Async code returns its future boxed ([`BoxedTask`]), e.g. `Box::pin(run::<T>(self.0))`,
+/// and the caller awaits the result of `dispatch`.
+pub trait RatingTask {
+    type Output;
+
+    /// Runs the task with the leaderboard type `T`.
+    fn call<T: RatingSystem>(self) -> Self::Output;
+}
+
+/// Output of an async [`RatingTask`] borrowing its arguments for `'a`.
+pub type BoxedTask<'a, O> = Pin<Box<dyn Future<Output = O> + 'a>>;
+
+impl Algorithm {
+    /// Runs `task` with the leaderboard type of the algorithm.
+    pub fn dispatch<R: RatingTask>(self, task: R) -> R::Output {
+        match self {
+            Algorithm::V1 => task.call::<Leaderboard>(),
+            Algorithm::V2 => task.call::<LeaderboardV2>(),
+            Algorithm::Glicko => task.call::<LeaderboardGlicko>(),
+            Algorithm::Trueskill => task.call::<LeaderboardTrueSkill>(),
+        }
+    }
+
+    /// `RatingSystem::NAME` of the algorithm, e.g. `v1`.
+    pub fn name(self) -> &'static str {
+        self.dispatch(Name)
+    }
+
+    /// Returns `true` for the algorithms with tunable formula constants (`MMRParams`).
+    pub fn tunable(self) -> bool {
+        matches!(self, Algorithm::V1 | Algorithm::V2)
+    }
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// `RatingSystem::NAME` of the dispatched type.
+struct Name;
+
+impl RatingTask for Name {
+    type Output = &'static str;
+
+    fn call<T: RatingSystem>(self) -> &'static str {
+        T::NAME
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Appends the name of the dispatched type after an await point.
+    struct Push<'a>(&'a mut Vec<&'static str>);
+
+    impl<'a> RatingTask for Push<'a> {
+        type Output = BoxedTask<'a, ()>;
+
+        fn call<T: RatingSystem>(self) -> Self::Output {
+            Box::pin(async move {
+                tokio::task::yield_now().await;
+                self.0.push(T::NAME);
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_runs_the_task_with_the_algorithm_type() {
+        let mut names = Vec::new();
+        for algorithm in Algorithm::value_variants() {
+            algorithm.dispatch(Push(&mut names)).await;
+        }
+        assert_eq!(names, vec!["v1", "v2", "glicko", "trueskill"]);
+        assert_eq!(Algorithm::from_str("trueskill", false).unwrap(), Algorithm::Trueskill);
+        assert_eq!(Algorithm::Glicko.to_string(), "glicko");
+        assert!(Algorithm::V2.tunable() && !Algorithm::Trueskill.tunable());
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::Instant;
+
+use rayon::prelude::*;
+
+use crate::abuse::AbuseDetector;
+use crate::classification::{SessionClasses, SessionRules};
+use crate::datasets::{Registrations, SessionMode};
+use crate::memory::SessionMemory;
+use crate::modes::ModeLeaderboards;
+use crate::rating::{self, RatingSystem, SessionTeams, StageTiming};
+use crate::statistic::Statistic;
+
+// Stage timings of one session of a batch, as returned by `rating::proc_session`.
+pub type SessionTiming = Option<(StageTiming, StageTiming, StageTiming, StageTiming)>;
+
+/// Consecutive sessions without a shared player, rated together by [`proc_batch`].
+#[derive(Clone, Debug, Default)]
+pub struct SessionBatch {
+    pub sessions: Vec<SessionMemory>,
+    // Players of every session in the batch.
+    pub players: std::collections::HashSet<u64>,
+    pub max_sessions: usize
+}
+
+impl SessionBatch {
+    pub fn new(max_sessions: usize) -> Self {
+        Self { max_sessions, ..Default::default() }
+    }
+
+    /// Returns `true` when `session` shares no player with the sessions already in the batch.
+    pub fn accepts(&self, session: &SessionMemory) -> bool {
+        session.rows.iter().all(|row| !self.players.contains(&row.user_id))
+    }
+
+    pub fn push(&mut self, session: SessionMemory) {
+        self.players.extend(session.rows.iter().map(|row| row.user_id));
+        self.sessions.push(session);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.sessions.len() >= self.max_sessions
+    }
+
+    /// Empties the batch and returns its sessions in input order.
+    pub fn take(&mut self) -> Vec<SessionMemory> {
+        self.players.clear();
+        std::mem::take(&mut self.sessions)
+    }
+}
+
+/// Rates a [`SessionBatch`] with the results of rating its sessions one by one.
+///
+/// Every session goes through the mode boards, [`rating::proc_session`] and the abuse
+/// detector as in a sequential pass, but the per-player updates are computed for the whole
+/// batch at once:
+/// 1. **Split** — in input order, the mode boards rate the session and
+///    [`rating::prepare_teams`] splits and filters it.
+/// 2. **Prepare changes** — [`RatingSystem::prepare_session`] of all rated sessions runs as
+///    a `par_iter` on `pool` against the ratings before the batch. No session of the batch
+///    shares a player with another one, so these are the ratings each session would see.
+/// 3. **Apply** — in input order, [`rating::write_session`] emits the statistics,
+///    [`RatingSystem::apply_session`] applies the updates and `abuse` observes the session,
+///    so every output is written in the sequential order.
+///
+/// Algorithms that are not [`RatingSystem::batch_safe`] and single-session batches are rated
+/// one session at a time. Returns the stage timings of every session in input order.
+#[allow(clippy::too_many_arguments)]
+pub async fn proc_batch<T: RatingSystem>(
+    rating: &mut T,
+    modes: &mut Option<ModeLeaderboards<T>>,
+    abuse: &mut Option<AbuseDetector>,
+    abuse_exclude: bool,
+    sessions: Vec<SessionMemory>,
+    pool: &rayon::ThreadPool,
+    cl_id: u16,
+    sender: flume::Sender<(String, Statistic)>,
+    session_mode: &SessionMode,
+    registrations: &Registrations,
+    rules: &SessionRules,
+    sender_tasks: flume::Sender<T::Change>,
+    sender_check: flume::Sender<(u32, u32)>,
+    sender_session_class: flume::Sender<SessionClasses>,
+) -> Vec<SessionTiming> {
+    let mut timings: Vec<SessionTiming> = Vec::with_capacity(sessions.len());
+    if !rating.batch_safe() || sessions.len() == 1 {
+        for session in sessions {
+            // Mode boards are seeded from the global ratings as they were before the session.
+            if let Some(modes) = modes.as_mut() {
+                modes.proc_session(rating, &session, session_mode, registrations, rules).await;
+            }
+            let timing = rating::proc_session(rating, session.clone(), cl_id, sender.clone(), session_mode, registrations, rules, sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+            if let Some(abuse) = abuse.as_mut() {
+                abuse.observe(rating, &session, timing.is_some(), registrations, &rules.factions, abuse_exclude).await;
+            }
+            timings.push(timing);
+        }
+        return timings;
+    }
+
+    // 1) Mode boards and team splits, in input order.
+    let mut staged: Vec<(SessionMemory, Option<SessionTeams>, StageTiming)> = Vec::with_capacity(sessions.len());
+    for session in sessions {
+        if let Some(modes) = modes.as_mut() {
+            modes.proc_session(rating, &session, session_mode, registrations, rules).await;
+        }
+        let prepear_session = Instant::now();
+        let teams = rating::prepare_teams(rating, &session, session_mode, registrations, rules);
+        staged.push((session, teams, (prepear_session, prepear_session.elapsed())));
+    }
+
+    // 2) Updates of every rated session from the ratings before the batch.
+    let rating_ref: &T = rating;
+    let prepared: Vec<Option<(T::Prepared, StageTiming)>> = pool.install(|| {
+        staged.par_iter().map(|(_session, teams, _timing)| teams.as_ref().map(|teams| {
+            let prepear_change = Instant::now();
+            let prepared = rating_ref.prepare_session(teams);
+            (prepared, (prepear_change, prepear_change.elapsed()))
+        })).collect()
+    });
+
+    // 3) Statistics, updates and abuse checks, in input order.
+    for ((session, teams, prepear_session), prepared) in staged.into_iter().zip(prepared) {
+        let timing = match (teams, prepared) {
+            (Some(teams), Some((prepared, prepear_change))) => {
+                let write_session = Instant::now();
+                rating::write_session(&teams, cl_id, sender.clone(), session_mode, registrations, rules, sender_check.clone(), sender_session_class.clone()).await;
+                let write_session_time = write_session.elapsed();
+
+                let set_change = Instant::now();
+                rating.apply_session(prepared, cl_id, sender_tasks.clone()).await;
+                Some((prepear_session, (write_session, write_session_time), prepear_change, (set_change, set_change.elapsed())))
+            },
+            _ => None
+        };
+        if let Some(abuse) = abuse.as_mut() {
+            abuse.observe(rating, &session, timing.is_some(), registrations, &rules.factions, abuse_exclude).await;
+        }
+        timings.push(timing);
+    }
+    timings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HistoryEntry;
+    use crate::types::{Leaderboard, UserBattleRow};
+
+    // Ten-player sessions drawn from 40 players, so that neighbouring sessions sometimes
+    // share players and sometimes do not.
+    fn sessions() -> Vec<SessionMemory> {
+        (1..=60_u64).map(|session_id| {
+            let first = (session_id * 7) % 40;
+            let rows = (0..10_u64).map(|slot| UserBattleRow {
+                user_id: (first + slot * 3) % 40,
+                session_id,
+                commit_time: session_id * 1000,
+                team: 1,
+                battle_score: 800 + ((session_id * 31 + slot * 17) % 900) as u32,
+                victories: (slot % 2 == 0) == (session_id % 3 == 0),
+                early_quit: false,
+                team_score_top_20_percent: slot < 2,
+                faction: if slot % 2 == 0 {"faction_1".to_string()} else {"faction_2".to_string()}
+            }).collect();
+            SessionMemory { now_session_id: session_id, rows }
+        }).collect()
+    }
+
+    async fn run(max_sessions: usize) -> (Leaderboard, Vec<HistoryEntry>) {
+        let session_mode = SessionMode((1..=60_u64).map(|session_id| (session_id, ("ranked".to_string(), "common".to_string(), "ranked_1".to_string()))).collect());
+        let registrations = Registrations(std::collections::HashMap::new());
+        let (sender, _receiver) = flume::unbounded();
+        let (sender_tasks, receiver_tasks) = flume::unbounded();
+        let (sender_check, _receiver_check) = flume::unbounded();
+        let (sender_session_class, _receiver_session_class) = flume::unbounded();
+        let mut leaderboard = Leaderboard::empty();
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut batch = SessionBatch::new(max_sessions);
+        let mut sessions = sessions().into_iter().peekable();
+        while let Some(session) = sessions.next() {
+            batch.push(session);
+            let flush = match sessions.peek() {
+                Some(next) => batch.is_full() || !batch.accepts(next),
+                None => true
+            };
+            if flush {
+                proc_batch(&mut leaderboard, &mut None, &mut None, false, batch.take(), &pool, 1, sender.clone(), &session_mode, &registrations, &SessionRules::default(), sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+            }
+        }
+        (leaderboard, receiver_tasks.drain().map(|change| Leaderboard::history_entry(&change)).collect())
+    }
+
+    #[tokio::test]
+    async fn batches_match_sequential_run() {
+        let (sequential, sequential_changes) = run(1).await;
+        let (batched, batched_changes) = run(8).await;
+        assert_eq!(sequential_changes.len(), 600);
+        assert_eq!(batched_changes, sequential_changes);
+        assert_eq!(batched.get_users(), sequential.get_users());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::params::CalibrationParams;
+use crate::types::LeaderboardRow;
+
+/// Per-battle averages of a player that the calibration compares.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationFeatures {
+    pub score: f64,
+    pub win_rate: f64,
+    pub top_20_rate: f64,
+    pub early_quit_rate: f64
+}
+
+impl CalibrationFeatures {
+    /// Averages of `row`; `None` before the first battle.
+    pub fn from_row(row: &LeaderboardRow) -> Option<Self> {
+        if row.battles == 0 {
+            return None;
+        }
+        let battles = row.battles as f64;
+        Some(Self {
+            score: row.battle_score as f64 / battles,
+            win_rate: row.victories as f64 / battles,
+            top_20_rate: row.top_20 as f64 / battles,
+            early_quit_rate: row.early_quites as f64 / battles
+        })
+    }
+
+    /// Euclidean distance with the score in units of `score_scale` and the rates multiplied by
+    /// their weights.
+    pub fn distance(&self, other: &Self, params: &CalibrationParams) -> f64 {
+        let score = (self.score - other.score) / params.score_scale;
+        let win = (self.win_rate - other.win_rate) * params.win_weight;
+        let top_20 = (self.top_20_rate - other.top_20_rate) * params.top_20_weight;
+        let early_quit = (self.early_quit_rate - other.early_quit_rate) * params.early_quit_weight;
+        (score * score + win * win + top_20 * top_20 + early_quit * early_quit).sqrt()
+    }
+}
+
+/// Estimated first calibrated MMR with its interval.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationEstimate {
+    pub mmr: u32,
+    // Weighted quantiles of the neighbour MMRs around the `interval` share; both equal `mmr`
+    // for the point estimate of `get_mmr_for_new`.
+    pub low: u32,
+    pub high: u32,
+    // 0 for the point estimate.
+    pub neighbours: usize
+}
+
+impl CalibrationEstimate {
+    /// Confidence in the estimate in `[0, 1]`: `1` while the interval is at most
+    /// `confident_width` wide, `confident_width / width` beyond that.
+    pub fn confidence(&self, params: &CalibrationParams) -> f64 {
+        let width = (self.high - self.low) as f64;
+        if width <= params.confident_width {
+            1.0
+        } else {
+            params.confident_width / width
+        }
+    }
+}
+
+impl std::fmt::Display for CalibrationEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "mmr:{},low:{},high:{},neighbours:{}", self.mmr, self.low, self.high, self.neighbours)
+    }
+}
+
+/// Weighted k-NN estimate of the first calibrated MMR of a player with `features`.
+///
+/// 1. Walks `battle_score_hash` (calibrated players by average battle score) outward from
+///    `features.score`, nearer score first, and keeps the `neighbours` players of `users`
+///    nearest by [`CalibrationFeatures::distance`]. The walk stops once the score gap alone is
+///    larger than the farthest kept neighbour; `excluded` players are skipped.
+/// 2. Weights each neighbour by `1 / (1 + (distance / bandwidth)^2)`; the estimate is the
+///    weighted mean MMR and the interval spans the weighted `(1 ∓ interval) / 2` quantiles.
+///
+/// Returns `None` when disabled (`neighbours == 0`), with fewer than `min_users` calibrated
+/// players, or when fewer than `neighbours` players were found.
+pub fn estimate(
+    users: &std::collections::HashMap<u64, LeaderboardRow>,
+    battle_score_hash: &std::collections::BTreeMap<(u32, u64), u32>,
+    excluded: &std::collections::HashSet<u64>,
+    features: &CalibrationFeatures,
+    params: &CalibrationParams
+) -> Option<CalibrationEstimate> {
+    if params.neighbours == 0 || battle_score_hash.len().saturating_sub(excluded.len()) < params.min_users {
+        return None;
+    }
+    let center = features.score.round().max(0.0) as u32;
+    let score_gap = |score: u32| (score as f64 - features.score).abs() / params.score_scale;
+    let mut below = battle_score_hash.range(..(center, 0)).rev().peekable();
+    let mut above = battle_score_hash.range((center, 0)..).peekable();
+
+    // (distance, mmr), nearest first.
+    let mut nearest: Vec<(f64, u32)> = Vec::with_capacity(params.neighbours + 1);
+    loop {
+        let next = match (below.peek(), above.peek()) {
+            (Some(((score_below, _), _)), Some(((score_above, _), _))) => if score_gap(*score_below) <= score_gap(*score_above) {below.next()} else {above.next()},
+            (Some(_), None) => below.next(),
+            (None, Some(_)) => above.next(),
+            (None, None) => None
+        };
+        let ((score, user_id), mmr) = match next {
+            Some(entry) => entry,
+            None => break
+        };
+        if nearest.len() == params.neighbours && score_gap(*score) >= nearest[params.neighbours - 1].0 {
+            break;
+        }
+        if excluded.contains(user_id) {
+            continue;
+        }
+        let distance = match users.get(user_id).and_then(CalibrationFeatures::from_row) {
+            Some(other) => features.distance(&other, params),
+            None => continue
+        };
+        let position = nearest.partition_point(|(other, _mmr)| *other <= distance);
+        nearest.insert(position, (distance, *mmr));
+        nearest.truncate(params.neighbours);
+    }
+    if nearest.len() < params.neighbours {
+        return None;
+    }
+
+    let mut weighted: Vec<(u32, f64)> = nearest.iter().map(|(distance, mmr)| (*mmr, 1.0 / (1.0 + (distance / params.bandwidth).powi(2)))).collect();
+    let weight_sum: f64 = weighted.iter().map(|(_mmr, weight)| weight).sum();
+    let mmr = weighted.iter().map(|(mmr, weight)| *mmr as f64 * weight).sum::<f64>() / weight_sum;
+
+    weighted.sort_by_key(|(mmr, _weight)| *mmr);
+    let quantile = |share: f64| {
+        let mut cumulative = 0.0;
+        for (mmr, weight) in weighted.iter() {
+            cumulative += weight;
+            if cumulative >= share * weight_sum {
+                return *mmr;
+            }
+        }
+        weighted.last().unwrap().0
+    };
+    Some(CalibrationEstimate {
+        mmr: mmr.round() as u32,
+        low: quantile((1.0 - params.interval) / 2.0),
+        high: quantile((1.0 + params.interval) / 2.0),
+        neighbours: nearest.len()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_weights_nearest_players() {
+        let mut users: std::collections::HashMap<u64, LeaderboardRow> = std::collections::HashMap::new();
+        let mut battle_score_hash: std::collections::BTreeMap<(u32, u64), u32> = std::collections::BTreeMap::new();
+        // Ten players per score level 1000, 1500 and 2000, MMR rising with the score.
+        for user_id in 0..30_u64 {
+            let score = 1000 + 500 * (user_id / 10) as u32;
+            let mmr = score + (user_id % 10) as u32 * 10;
+            users.insert(user_id, LeaderboardRow { user_id, mmr, battles: 10, victories: 5, early_quites: 0, top_20: 2, battle_score: score * 10, last_session: 0 });
+            battle_score_hash.insert((score, user_id), mmr);
+        }
+        let params = CalibrationParams { neighbours: 10, min_users: 20, interval: 0.7, ..Default::default() };
+        let features = CalibrationFeatures { score: 1510.0, win_rate: 0.5, top_20_rate: 0.2, early_quit_rate: 0.0 };
+
+        let estimate = estimate(&users, &battle_score_hash, &std::collections::HashSet::new(), &features, &params).unwrap();
+        assert_eq!(estimate, CalibrationEstimate { mmr: 1545, low: 1510, high: 1580, neighbours: 10 });
+        assert_eq!(estimate.confidence(&params), 1.0);
+        assert_eq!(estimate.confidence(&CalibrationParams { confident_width: 35.0, ..params.clone() }), 0.5);
+
+        // Excluded players leave the 1500 level short, so the 1000 and 2000 levels fill in.
+        let excluded: std::collections::HashSet<u64> = (10..15).collect();
+        let wide = super::estimate(&users, &battle_score_hash, &excluded, &features, &params).unwrap();
+        assert_eq!((wide.low, wide.neighbours), (1550, 10));
+        assert!(super::estimate(&users, &battle_score_hash, &excluded, &features, &CalibrationParams { min_users: 30, ..params }).is_none());
+    }
+}
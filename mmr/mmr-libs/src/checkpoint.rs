@@ -0,0 +1,283 @@
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncSeekExt;
+
+use crate::abuse::AbuseDetector;
+use crate::modes::{ModeLeaderboards, GLOBAL_BOARD};
+use crate::params::ModeParams;
+use crate::rating::{leaderboard_dir, RatingSystem};
+use crate::snapshot::SnapshotError;
+use crate::statistic::Statistic;
+
+/// Resume point of a streaming pass over a userstat file.
+///
+/// Taken at a session boundary: every session before `session_id` is applied to the
+/// leaderboard and folded into the accumulators, and the rows of `session_id` start at byte
+/// `offset` of `data`. The leaderboard and the mode boards are stored next to it (see
+/// [`Checkpoint::write`]).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    // Userstat file the offset refers to.
+    pub data: String,
+    // Byte offset and line number just before the first row of `session_id`.
+    pub offset: u64,
+    pub line: u64,
+    pub session_id: u64,
+    // Sessions processed so far.
+    pub sessions: u64,
+    // Output path -> bytes written so far; resumed runs truncate the files back to it.
+    pub outputs: std::collections::BTreeMap<String, u64>,
+    // Statistic accumulators by board key.
+    pub statistic: std::collections::BTreeMap<String, Statistic>,
+    // Win-rate buckets: MMR delta (step 200) -> (wins, games).
+    pub statistic_check: std::collections::BTreeMap<i32, (u64, u64)>,
+    // Anti-abuse detector state, when the pass runs one.
+    #[serde(default)]
+    pub abuse: Option<AbuseDetector>,
+    // Board key -> cached decay mean and its commit_time (`RatingSystem::decay_mean`); filled
+    // by `write`.
+    #[serde(default)]
+    pub decay_means: std::collections::BTreeMap<String, (f64, u64)>
+}
+
+/// Why a checkpoint cannot be written or restored.
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(std::io::Error),
+    // `state` cannot be serialized.
+    Serialize(ron::Error),
+    // `state` is not a valid checkpoint.
+    State(ron::error::SpannedError),
+    // A leaderboard snapshot of the checkpoint (path, or the modes root) is missing or fails
+    // verification.
+    Snapshot(String, SnapshotError)
+}
+
+impl std::fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckpointError::Io(error) => write!(f, "io error: {}", error),
+            CheckpointError::Serialize(error) => write!(f, "cannot serialize the state: {}", error),
+            CheckpointError::State(error) => write!(f, "damaged state: {}", error),
+            CheckpointError::Snapshot(path, error) => write!(f, "{}: {}", path, error)
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<std::io::Error> for CheckpointError {
+    fn from(error: std::io::Error) -> Self {
+        CheckpointError::Io(error)
+    }
+}
+
+/// Checkpoint restored with its leaderboard and its mode boards.
+pub type Restored<T> = (Checkpoint, T, Option<ModeLeaderboards<T>>);
+
+impl Checkpoint {
+    /// Checkpoint directory of `T`: `data/leaderboard_<NAME>/checkpoint`.
+    pub fn dir<T: RatingSystem>() -> String {
+        leaderboard_dir::<T>() + "/checkpoint"
+    }
+
+    /// Writes the checkpoint into `dir`:
+    ///
+    /// - `leaderboard/` — `RatingSystem::persist_to` of the global leaderboard.
+    /// - `modes/<mode>/` — the mode boards, when given.
+    /// - `state`        — this struct as RON, with the decay means of the boards.
+    ///
+    /// Everything goes to `<dir>.tmp` first, with `state` last, and replaces `dir` at the end,
+    /// so a crash or an error while writing leaves the previous checkpoint readable.
+    pub async fn write<T: RatingSystem>(&mut self, dir: &str, leaderboard: &T, modes: Option<&ModeLeaderboards<T>>) -> Result<(), CheckpointError> {
+        self.decay_means.clear();
+        let boards: Vec<(&str, &T)> = modes.map(|modes| modes.boards.iter().map(|(mode, board)| (mode.as_str(), board)).collect()).unwrap_or_default();
+        for (key, board) in std::iter::once((GLOBAL_BOARD, leaderboard)).chain(boards) {
+            if let Some(mean) = board.decay_mean() {
+                self.decay_means.insert(key.to_string(), mean);
+            }
+        }
+
+        let tmp = dir.to_string() + ".tmp";
+        if tokio::fs::metadata(&tmp).await.is_ok() {
+            tokio::fs::remove_dir_all(&tmp).await?;
+        }
+        tokio::fs::create_dir_all(tmp.clone() + "/leaderboard").await?;
+        leaderboard.persist_to(&(tmp.clone() + "/leaderboard")).await;
+        if let Some(modes) = modes {
+            modes.persist_to(&(tmp.clone() + "/modes")).await;
+        }
+        let data = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).map_err(CheckpointError::Serialize)?;
+        tokio::fs::write(tmp.clone() + "/state", data).await?;
+
+        if tokio::fs::metadata(dir).await.is_ok() {
+            tokio::fs::remove_dir_all(dir).await?;
+        }
+        tokio::fs::rename(&tmp, dir).await?;
+        Ok(())
+    }
+
+    /// Reads the checkpoint in `dir` with its leaderboard, and its mode boards when
+    /// `mode_params` is given.
+    ///
+    /// Falls back to a complete `<dir>.tmp` when a crash interrupted the swap in
+    /// [`Checkpoint::write`]. The boards get back their decay means. Returns `Ok(None)` without a checkpoint, and an error when the
+    /// `state` or one of the snapshots is damaged, so the run is not resumed from a partial or
+    /// empty leaderboard.
+    pub fn restore<T: RatingSystem>(dir: &str, mode_params: Option<ModeParams>) -> Result<Option<Restored<T>>, CheckpointError> {
+        let tmp = dir.to_string() + ".tmp";
+        let Some(dir) = [dir.to_string(), tmp].into_iter().find(|dir| std::path::Path::new(&(dir.clone() + "/state")).exists()) else {
+            return Ok(None);
+        };
+        let data = std::fs::read_to_string(dir.clone() + "/state")?;
+        let checkpoint: Self = ron::from_str(&data).map_err(CheckpointError::State)?;
+
+        // `restore_from` returns an empty board without a snapshot; a checkpoint always has one.
+        let path = dir.clone() + "/leaderboard/snapshot";
+        if !std::path::Path::new(&path).exists() {
+            return Err(CheckpointError::Snapshot(path, SnapshotError::Io(std::io::ErrorKind::NotFound.into())));
+        }
+        let mut leaderboard = T::restore_from(&(dir.clone() + "/leaderboard")).map_err(|error| CheckpointError::Snapshot(path, error))?;
+        let mut modes = match mode_params {
+            Some(params) => Some(ModeLeaderboards::<T>::restore_from(&(dir.clone() + "/modes"), params).map_err(|error| CheckpointError::Snapshot(dir.clone() + "/modes", error))?),
+            None => None
+        };
+        if let Some(mean) = checkpoint.decay_means.get(GLOBAL_BOARD) {
+            leaderboard.set_decay_mean(*mean);
+        }
+        for (mode, board) in modes.iter_mut().flat_map(|modes| modes.boards.iter_mut()) {
+            if let Some(mean) = checkpoint.decay_means.get(mode) {
+                board.set_decay_mean(*mean);
+            }
+        }
+        Ok(Some((checkpoint, leaderboard, modes)))
+    }
+
+    /// Removes the checkpoint in `dir`, e.g. after the run finished.
+    pub async fn remove(dir: &str) {
+        for dir in [dir.to_string(), dir.to_string() + ".tmp"] {
+            if tokio::fs::metadata(&dir).await.is_ok() {
+                tokio::fs::remove_dir_all(&dir).await.unwrap();
+            }
+        }
+    }
+
+    /// Opens `path` for appending after the bytes recorded for it, dropping anything written
+    /// after the checkpoint; creates it when it has no record.
+    pub async fn reopen_output(&self, path: &str) -> tokio::fs::File {
+        let len = self.outputs.get(path).copied().unwrap_or(0);
+        let mut file = tokio::fs::OpenOptions::new().write(true).create(true).truncate(false).open(path).await.unwrap();
+        file.set_len(len).await.unwrap();
+        file.seek(std::io::SeekFrom::Start(len)).await.unwrap();
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonl::{JsonlReader, UserStatRecord};
+    use crate::params::{DecayParams, MMRParams};
+    use crate::types::{Leaderboard, LeaderboardRow, MMRType};
+
+    #[tokio::test]
+    async fn checkpoint_round_trips_and_reader_resumes_at_the_row() {
+        let dir = std::env::temp_dir().join("mmr_checkpoint_test_".to_string() + std::process::id().to_string().as_str());
+        let dir = dir.to_str().unwrap().to_string();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let data = dir.clone() + "/userstat.json";
+        std::fs::write(&data, concat!(
+            "{\"user_id\":1,\"session_id\":\"1\",\"commit_time\":1}\n",
+            "\n",
+            "{\"user_id\":2,\"session_id\":\"2\",\"commit_time\":2}\n",
+            "{\"user_id\":3,\"session_id\":\"2\",\"commit_time\":2}\n"
+        )).unwrap();
+        let mut rows = JsonlReader::<UserStatRecord>::open(&data).unwrap();
+        rows.next();
+        let (line, row) = rows.next().unwrap();
+        assert_eq!((line, row.user_id), (3, 2));
+        let (offset, line) = rows.row_start();
+        assert_eq!((offset, line), (data_offset(1), 2));
+        let resumed: Vec<(u64, u64)> = JsonlReader::<UserStatRecord>::open_at(&data, offset, line).unwrap().map(|(line, row)| (line, row.user_id)).collect();
+        assert_eq!(resumed, vec![(3, 2), (4, 3)]);
+
+        let mut leaderboard = Leaderboard::empty();
+        leaderboard.users.insert(7, LeaderboardRow { user_id: 7, mmr: 1700, battles: 9, victories: 4, early_quites: 0, top_20: 1, battle_score: 9000, last_session: 5 });
+        let mut checkpoint = Checkpoint { data: data.clone(), offset, line, session_id: 2, sessions: 1, ..Checkpoint::default() };
+        checkpoint.outputs.insert(dir.clone() + "/out", 3);
+        checkpoint.statistic_check.insert(-200, (1, 2));
+        let checkpoint_dir = dir.clone() + "/checkpoint";
+        checkpoint.write(&checkpoint_dir, &leaderboard, None).await.unwrap();
+
+        let (restored, board, modes) = Checkpoint::restore::<Leaderboard>(&checkpoint_dir, None).unwrap().unwrap();
+        assert_eq!(restored, checkpoint);
+        assert!(matches!(board.get_mmr(7), MMRType::MMR(1700)));
+        assert!(modes.is_none());
+
+        // Output written after the checkpoint is dropped on resume.
+        std::fs::write(dir.clone() + "/out", "abcdef").unwrap();
+        drop(restored.reopen_output(&(dir.clone() + "/out")).await);
+        assert_eq!(std::fs::read_to_string(dir.clone() + "/out").unwrap(), "abc");
+
+        Checkpoint::remove(&checkpoint_dir).await;
+        assert!(Checkpoint::restore::<Leaderboard>(&checkpoint_dir, None).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumed_decay_matches_an_uninterrupted_run() {
+        let dir = std::env::temp_dir().join("mmr_checkpoint_decay_".to_string() + std::process::id().to_string().as_str());
+        let dir = dir.to_str().unwrap().to_string();
+        let params = MMRParams { decay: DecayParams { period_days: 1.0, mean_pull: 0.5, recalibration: 0, confidence_battles: 0.0 }, ..MMRParams::default() };
+        let mut straight = Leaderboard::empty();
+        straight.set_params(&params);
+        for (user_id, mmr) in [(1, 2000), (2, 1000), (3, 1500)] {
+            straight.users.insert(user_id, LeaderboardRow { user_id, mmr, battles: 10, victories: 5, early_quites: 0, top_20: 0, battle_score: 10000, last_session: 0 });
+        }
+
+        // The mean of 1500 is taken when player 1 returns and cached for the period.
+        let day = 86_400_000;
+        assert!(straight.apply_decay(1, 2 * day));
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.write(&dir, &straight, None).await.unwrap();
+        let (_, mut resumed, _) = Checkpoint::restore::<Leaderboard>(&dir, None).unwrap().unwrap();
+        resumed.set_params(&params);
+
+        // Player 2 returns within the same period: both boards still pull toward 1500.
+        assert!(straight.apply_decay(2, 2 * day + 1));
+        assert!(resumed.apply_decay(2, 2 * day + 1));
+        assert_eq!(straight.users[&2].mmr, 1375);
+        assert_eq!(resumed.users, straight.users);
+
+        Checkpoint::remove(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn restore_reports_a_damaged_checkpoint() {
+        let dir = std::env::temp_dir().join("mmr_checkpoint_damaged_".to_string() + std::process::id().to_string().as_str());
+        let dir = dir.to_str().unwrap().to_string();
+        let mut leaderboard = Leaderboard::empty();
+        leaderboard.users.insert(7, LeaderboardRow { user_id: 7, mmr: 1700, battles: 9, victories: 4, early_quites: 0, top_20: 1, battle_score: 9000, last_session: 5 });
+        Checkpoint::default().write(&dir, &leaderboard, None).await.unwrap();
+
+        // A truncated leaderboard snapshot.
+        let snapshot = dir.clone() + "/leaderboard/snapshot";
+        let bytes = std::fs::read(&snapshot).unwrap();
+        std::fs::write(&snapshot, &bytes[..bytes.len() - 1]).unwrap();
+        let error = Checkpoint::restore::<Leaderboard>(&dir, None).err().unwrap();
+        assert!(matches!(&error, CheckpointError::Snapshot(path, _) if *path == snapshot), "{}", error);
+
+        // A damaged state.
+        std::fs::write(&snapshot, &bytes).unwrap();
+        std::fs::write(dir.clone() + "/state", "(data: \"").unwrap();
+        let error = Checkpoint::restore::<Leaderboard>(&dir, None).err().unwrap();
+        assert!(matches!(error, CheckpointError::State(_)), "{}", error);
+
+        Checkpoint::remove(&dir).await;
+    }
+
+    // Byte length of the first `lines` lines of the test file plus the blank line.
+    fn data_offset(lines: usize) -> u64 {
+        ("{\"user_id\":1,\"session_id\":\"1\",\"commit_time\":1}\n".len() * lines + 1) as u64
+    }
+}
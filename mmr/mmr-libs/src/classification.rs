@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+
+use crate::datasets::{Registrations, SessionMode};
+use crate::factions::FactionRegistry;
+use crate::rating::{top_k_avg, SessionTeams};
+use crate::types::{MMRType, TeamMMR, UserBattleRow};
+
+/// Players whose session is at most this long (ms) after their registration count as newbies.
+pub const NEWBIE_WINDOW: u64 = 24 * 60 * 60 * 1000;
+
+/// Top-3 average MMR gap above which a team is disbalanced: the built-in `disbalance_team_<n>`
+/// classes and the default matchmaker limit.
+pub const DISBALANCE_GAP: u32 = 800;
+
+/// Returns `true` when the session of `row` is within [`NEWBIE_WINDOW`] of the player's
+/// registration.
+pub fn is_newbie(registrations: &Registrations, row: &UserBattleRow) -> bool {
+    match registrations.0.get(&row.user_id) {
+        Some(time) => row.commit_time > *time && row.commit_time - time < NEWBIE_WINDOW,
+        None => false
+    }
+}
+
+/// Session id and the names of the classes it matched, in rule order.
+pub type SessionClasses = (u64, Vec<String>);
+
+/// Value computed over the ranked players of one team (`SessionTeams::teams_mmr`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Aggregate {
+    // Team size.
+    Players,
+    // Average calibrated MMR of the first k players (`rating::top_k_avg`).
+    TopAvg(usize),
+    // Share of players within `NEWBIE_WINDOW` of their registration.
+    NewbieShare,
+    // Share of calibrated players.
+    CalibratedShare,
+    // Variance of the calibrated MMRs.
+    MmrVariance
+}
+
+impl Aggregate {
+    /// Value of the aggregate for `team_mmr`; `None` when it is undefined (no calibrated
+    /// player for `TopAvg` / `MmrVariance`, an empty team for the shares).
+    pub fn value(&self, team_mmr: &TeamMMR, registrations: &Registrations) -> Option<f64> {
+        let players = team_mmr.0.len();
+        let calibrated: Vec<f64> = team_mmr.0.iter().filter_map(|pair| match pair.1 {
+            MMRType::MMR(mmr) => Some(mmr as f64),
+            _ => None
+        }).collect();
+        match self {
+            Aggregate::Players => Some(players as f64),
+            Aggregate::TopAvg(k) => top_k_avg(team_mmr, *k).map(|avg| avg as f64),
+            Aggregate::NewbieShare if players > 0 => {
+                let newbies = team_mmr.0.iter().filter(|pair| is_newbie(registrations, &pair.2)).count();
+                Some(newbies as f64 / players as f64)
+            },
+            Aggregate::CalibratedShare if players > 0 => Some(calibrated.len() as f64 / players as f64),
+            Aggregate::MmrVariance if !calibrated.is_empty() => {
+                let mean = calibrated.iter().sum::<f64>() / calibrated.len() as f64;
+                Some(calibrated.iter().map(|mmr| (mmr - mean).powi(2)).sum::<f64>() / calibrated.len() as f64)
+            },
+            _ => None
+        }
+    }
+}
+
+/// Strict bound on an aggregate value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Bound {
+    Above(f64),
+    Below(f64)
+}
+
+impl Bound {
+    pub fn contains(&self, value: f64) -> bool {
+        match self {
+            Bound::Above(min) => value > *min,
+            Bound::Below(max) => value < *max
+        }
+    }
+}
+
+/// Condition on a session, its mode and its teams. Teams are addressed by their team number,
+/// `1` for the first registered faction (see [`FactionRegistry::split`]); a team the session
+/// does not have never satisfies a condition.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Predicate {
+    // Raw, common or specific mode name of the session; sessions without a mode are `newbie_common`.
+    Mode(String),
+    // Aggregate of the team within the bound.
+    Team(u8, Aggregate, Bound),
+    // Aggregate of any team of the session within the bound.
+    AnyTeam(Aggregate, Bound),
+    // Aggregate of the team minus the highest one of the other teams within the bound.
+    Gap(u8, Aggregate, Bound),
+    // The team won.
+    Won(u8),
+    // Fewer than two teams, or a team under 5 players unless free-for-all
+    // (`SessionTeams::is_incomplete`).
+    Incomplete,
+    All(Vec<Predicate>),
+    Any(Vec<Predicate>),
+    Not(Box<Predicate>)
+}
+
+impl Predicate {
+    /// Evaluates the predicate; an undefined aggregate never satisfies a bound.
+    pub fn matches(&self, teams: &SessionTeams, session_mode: &SessionMode, registrations: &Registrations) -> bool {
+        let value = |team: usize, aggregate: &Aggregate| aggregate.value(teams.teams_mmr.get(team.checked_sub(1)?)?, registrations);
+        match self {
+            Predicate::Mode(mode) => match session_mode.0.get(&teams.session_id) {
+                Some((mode_0, mode_1, mode_2)) => mode_0 == mode || mode_1 == mode || mode_2 == mode,
+                None => mode == "newbie_common"
+            },
+            Predicate::Team(team, aggregate, bound) => match value(*team as usize, aggregate) {
+                Some(value) => bound.contains(value),
+                None => false
+            },
+            Predicate::AnyTeam(aggregate, bound) => teams.teams_mmr.iter()
+                .filter_map(|team_mmr| aggregate.value(team_mmr, registrations))
+                .any(|value| bound.contains(value)),
+            Predicate::Gap(team, aggregate, bound) => {
+                let other = (1..=teams.teams_mmr.len())
+                    .filter(|other| *other != *team as usize)
+                    .filter_map(|other| value(other, aggregate))
+                    .max_by(|a, b| a.total_cmp(b));
+                match (value(*team as usize, aggregate), other) {
+                    (Some(value), Some(other_value)) => bound.contains(value - other_value),
+                    _ => false
+                }
+            },
+            Predicate::Won(team) => (*team as usize).checked_sub(1).and_then(|index| teams.results.get(index)).copied().unwrap_or(false),
+            Predicate::Incomplete => teams.is_incomplete(),
+            Predicate::All(predicates) => predicates.iter().all(|predicate| predicate.matches(teams, session_mode, registrations)),
+            Predicate::Any(predicates) => predicates.iter().any(|predicate| predicate.matches(teams, session_mode, registrations)),
+            Predicate::Not(predicate) => !predicate.matches(teams, session_mode, registrations)
+        }
+    }
+}
+
+/// Named session class.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionClassRule {
+    pub name: String,
+    pub when: Predicate
+}
+
+/// Declarative session rules, stored as RON: which sessions are skipped by the rating
+/// pipeline and which classes a rated session is written with.
+///
+/// The defaults are the built-in rules: sessions of the `newbie_common` mode or incomplete for
+/// the team mode of `factions` are skipped; a team is disbalanced when its top-3 average MMR
+/// is more than 800 above the other team's. The classes address the two teams of the
+/// built-in `factions`; rules for more teams list their own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionRules {
+    // A session matching any of these is neither rated nor classified.
+    pub skip: Vec<Predicate>,
+    pub classes: Vec<SessionClassRule>,
+    // Factions of the dataset and how they form the teams of a session.
+    pub factions: FactionRegistry
+}
+
+impl Default for SessionRules {
+    fn default() -> Self {
+        let class = |name: &str, when: Predicate| SessionClassRule { name: name.to_string(), when };
+        Self {
+            skip: vec![
+                Predicate::Mode("newbie_common".to_string()),
+                Predicate::Incomplete
+            ],
+            classes: vec![
+                class("disbalance_team_1", Predicate::Gap(1, Aggregate::TopAvg(3), Bound::Above(DISBALANCE_GAP as f64))),
+                class("disbalance_team_2", Predicate::Gap(2, Aggregate::TopAvg(3), Bound::Above(DISBALANCE_GAP as f64))),
+                class("team_1_win", Predicate::Won(1)),
+                class("team_2_win", Predicate::Won(2))
+            ],
+            factions: FactionRegistry::default()
+        }
+    }
+}
+
+impl SessionRules {
+    /// Reads a RON rules file; missing sections keep their defaults.
+    pub fn read(path: &str) -> Self {
+        let data = std::fs::read_to_string(path).unwrap();
+        ron::from_str(&data).unwrap()
+    }
+
+    /// Writes the rules as pretty-printed RON.
+    pub fn write(&self, path: &str) {
+        let data = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).unwrap();
+        std::fs::write(path, data + "\n").unwrap();
+    }
+
+    /// Returns `true` when one of the `skip` predicates matches the session.
+    pub fn skips(&self, teams: &SessionTeams, session_mode: &SessionMode, registrations: &Registrations) -> bool {
+        self.skip.iter().any(|predicate| predicate.matches(teams, session_mode, registrations))
+    }
+
+    /// Names of the classes whose predicate matches the session, in rule order.
+    pub fn classify(&self, teams: &SessionTeams, session_mode: &SessionMode, registrations: &Registrations) -> Vec<String> {
+        self.classes.iter()
+            .filter(|class| class.when.matches(teams, session_mode, registrations))
+            .map(|class| class.name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factions::FactionRegistry;
+    use crate::memory::SessionMemory;
+    use crate::factions::TeamMode;
+    use crate::rating::{self, RatingSystem};
+    use crate::types::{Leaderboard, LeaderboardRow};
+
+    fn row(user_id: u64, faction: &str) -> UserBattleRow {
+        UserBattleRow { user_id, session_id: 1, commit_time: 10_000, team: 1, battle_score: 1000, victories: faction == "faction_1", early_quit: false, team_score_top_20_percent: false, faction: faction.to_string() }
+    }
+
+    #[test]
+    fn rules_skip_and_classify_sessions() {
+        let mut leaderboard = Leaderboard::empty();
+        for user_id in 1..=10_u64 {
+            let mmr = if user_id <= 5 {2000 + user_id as u32 * 100} else {1000};
+            leaderboard.users.insert(user_id, LeaderboardRow { user_id, mmr, battles: 10, victories: 5, early_quites: 0, top_20: 0, battle_score: 10_000, last_session: 0 });
+        }
+        let rows: Vec<UserBattleRow> = (1..=10_u64).map(|user_id| row(user_id, if user_id <= 5 {"faction_1"} else {"faction_2"})).collect();
+        let teams = SessionTeams::new(&leaderboard, &SessionMemory { now_session_id: 1, rows: rows.clone() }, &FactionRegistry::default());
+        let registrations = Registrations(std::collections::HashMap::from([(6, 5_000), (7, 5_000)]));
+        let ranked = SessionMode(std::collections::HashMap::from([(1, SessionMode::normalize("ranked_high_teir_1"))]));
+        let rules = SessionRules::default();
+
+        assert!(!rules.skips(&teams, &ranked, &registrations));
+        assert!(rules.skips(&teams, &SessionMode(std::collections::HashMap::new()), &registrations));
+        assert_eq!(rules.classify(&teams, &ranked, &registrations), vec!["disbalance_team_1".to_string(), "team_1_win".to_string()]);
+
+        let rules: SessionRules = ron::from_str("(classes: [
+            (name: \"newbies\", when: Team(2, NewbieShare, Above(0.3))),
+            (name: \"calibrated\", when: All([Team(1, CalibratedShare, Above(0.99)), Not(Mode(\"high_teir_common\"))])),
+            (name: \"spread\", when: AnyTeam(MmrVariance, Above(10000.0)))
+        ])").unwrap();
+        assert_eq!(rules.skip, SessionRules::default().skip);
+        assert_eq!(rules.classify(&teams, &ranked, &registrations), vec!["newbies".to_string(), "spread".to_string()]);
+
+        let small = SessionTeams::new(&leaderboard, &SessionMemory { now_session_id: 1, rows: rows[1..].to_vec() }, &FactionRegistry::default());
+        assert!(rules.skips(&small, &ranked, &registrations));
+
+        // Three factions: team 3 plays users 9 and 10 and wins; gaps are taken to the strongest other team.
+        let factions: FactionRegistry = ron::from_str("(factions: [\"faction_1\", \"faction_2\", \"faction_3\"])").unwrap();
+        let mut rows = rows;
+        for row in rows[8..].iter_mut() {
+            row.faction = "faction_3".to_string();
+            row.victories = true;
+        }
+        rows[0].victories = false;
+        let three = SessionTeams::new(&leaderboard, &SessionMemory { now_session_id: 1, rows }, &factions);
+        assert_eq!(three.teams.iter().map(|team| team.len()).collect::<Vec<usize>>(), vec![5, 3, 2]);
+        assert_eq!((three.winner(), three.opponents(3).len()), (None, 8));
+        let rules: SessionRules = ron::from_str("(classes: [
+            (name: \"disbalance_team_1\", when: Gap(1, TopAvg(3), Above(800.0))),
+            (name: \"disbalance_team_3\", when: Gap(3, TopAvg(3), Below(-1000.0))),
+            (name: \"team_3_win\", when: Won(3)),
+            (name: \"team_4_win\", when: Won(4))
+        ])").unwrap();
+        assert_eq!(rules.classify(&three, &ranked, &registrations), vec!["disbalance_team_1".to_string(), "disbalance_team_3".to_string(), "team_3_win".to_string()]);
+    }
+
+    fn ranked_session(rows: Vec<UserBattleRow>) -> (SessionMemory, SessionMode) {
+        (SessionMemory { now_session_id: 1, rows }, SessionMode(std::collections::HashMap::from([(1, SessionMode::normalize("ranked_high_teir_1"))])))
+    }
+
+    #[test]
+    fn default_rules_skip_small_teams_of_every_faction() {
+        let mut leaderboard = Leaderboard::empty();
+        let registrations = Registrations(std::collections::HashMap::new());
+        let rules = SessionRules { factions: ron::from_str("(factions: [\"faction_1\", \"faction_2\", \"faction_3\"])").unwrap(), ..SessionRules::default() };
+        let faction = |user_id: u64| ["faction_1", "faction_2", "faction_3"][(user_id as usize - 1) / 5];
+
+        // 5 + 5 + 2 players: the third team is too small.
+        let (session, session_mode) = ranked_session((1..=12_u64).map(|user_id| row(user_id, faction(user_id))).collect());
+        assert!(rating::prepare_teams(&mut leaderboard, &session, &session_mode, &registrations, &rules).is_none());
+
+        let (session, session_mode) = ranked_session((1..=15_u64).map(|user_id| row(user_id, faction(user_id))).collect());
+        let teams = rating::prepare_teams(&mut leaderboard, &session, &session_mode, &registrations, &rules).unwrap();
+        assert_eq!(teams.teams.iter().map(|team| team.len()).collect::<Vec<usize>>(), vec![5, 5, 5]);
+    }
+
+    #[test]
+    fn default_rules_rate_free_for_all_sessions() {
+        let mut leaderboard = Leaderboard::empty();
+        let registrations = Registrations(std::collections::HashMap::new());
+        let factions = FactionRegistry { mode: TeamMode::FreeForAll, ..FactionRegistry::default() };
+        let rules = SessionRules { factions, ..SessionRules::default() };
+
+        let (session, session_mode) = ranked_session((1..=4_u64).map(|user_id| row(user_id, "faction_1")).collect());
+        let teams = rating::prepare_teams(&mut leaderboard, &session, &session_mode, &registrations, &rules).unwrap();
+        assert_eq!(teams.teams.len(), 4);
+
+        // A single player has nobody to play against.
+        let (session, session_mode) = ranked_session(vec![row(1, "faction_1")]);
+        assert!(rating::prepare_teams(&mut leaderboard, &session, &session_mode, &registrations, &rules).is_none());
+    }
+}
@@ -1,6 +1,4 @@
-use std::path::Path;
-
-use crate::memory;
+use crate::jsonl::{JsonlReader, RegistrationRecord, SessionModeRecord, UserFactionRecord, UserTeamRecord};
 
 // session_id -> (raw_mode, normalized_common_mode, normalized_specific_mode)
 #[derive(Clone)]
@@ -8,75 +6,79 @@ pub struct SessionMode(pub std::collections::HashMap<u64, (String, String, Strin
 
 impl SessionMode {
     pub fn new(path: &str) -> Self {
-        // Load session mode mapping from a JSON-like line file.
-        if Path::new(path).exists() {
-            let mut session_mode: std::collections::HashMap<u64, (String, String, String)> = std::collections::HashMap::new();
-            if let Ok(lines) = memory::read_lines(path) { 
-                
-                for line in lines.flatten() {
-                    // Lightweight key:value parser for expected input rows.
-                    let hash_line: std::collections::BTreeMap<String, String> = line.replace("\"", "").replace("{", "").replace("}", "").split(",").filter_map(|item: &str| {
-                        let splited: Vec<String> = item.split(":").map(|it| it.to_string()).collect();
-                        if splited.len() == 2 {
-                            Some((splited[0].clone(), splited[1].clone()))
-                        } else {
-                            None
-                        }
-                    }).collect();
+        // Load session mode mapping from a JSONL file: {"session_id":123,"mode":"..."}
+        let mut session_mode: std::collections::HashMap<u64, (String, String, String)> = std::collections::HashMap::new();
+        if let Ok(mut rows) = JsonlReader::<SessionModeRecord>::open(path) {
+            for (_line, row) in rows.by_ref() {
+                let mode = row.mode.unwrap_or_default();
+                session_mode.insert(row.session_id, Self::normalize(&mode));
+            }
+            println!("{}", rows.report());
+        }
+        // Empty mapping when the source file is missing.
+        Self(session_mode)
+    }
+
+    /// Normalizes a raw mode name into `(raw_mode, normalized_common_mode, normalized_specific_mode)`.
+    pub fn normalize(mode: &str) -> (String, String, String) {
+        let mode = mode.to_string();
+        let low_position = mode.find("low_teir");
+        let high_position = mode.find("high_teir");
+        let newbie_position = mode.find("newbie");
 
-                    let session_id = hash_line.get("session_id");
-                    // Skip malformed rows without session id.
-                    if session_id == None {
-                        continue;
+        // Build normalized mode names for grouped and specific reporting.
+        (mode.clone(),
+        match newbie_position {
+            Some(_pos) => "newbie_common".to_string(),
+            None => {
+                match low_position {
+                    Some(_pos) => "low_teir_common".to_string(),
+                    None => {
+                        match high_position {
+                            Some(_pos) => "high_teir_common".to_string(),
+                            None => "lobbie_common".to_string()
+                        }
                     }
-                    let mode = match hash_line.get("mode") {
-                        Some(data) => data.clone(),
-                        None => "".to_string()
-                    };
+                }
+            }
+        },
+        match newbie_position {
+            Some(_pos) => "newbie".to_string(),
+            None => {
+                match low_position {
+                    Some(pos) => mode.clone()[pos..].to_string(),
+                    None => {
+                        match high_position {
+                            Some(pos) => mode.clone()[pos..].to_string(),
+                            None => "lobbie".to_string()
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
 
-                    let low_position = mode.find("low_teir");
-                    let high_position = mode.find("high_teir");
-                    let newbie_position = mode.find("newbie");
+// (user_id, session_id) -> (team, victory)
+#[derive(Clone)]
+pub struct UserTeam(pub std::collections::HashMap<(u64, u64), (u8, bool)>);
 
-                    // Build normalized mode names for grouped and specific reporting.
-                    session_mode.insert(
-                        session_id.unwrap().parse::<u64>().unwrap(), 
-                        (mode.clone(),
-                        match newbie_position {
-                            Some(_pos) => "newbie_common".to_string(),
-                            None => {
-                                match low_position {
-                                    Some(_pos) => "low_teir_common".to_string(),
-                                    None => {
-                                        match high_position {
-                                            Some(_pos) => "high_teir_common".to_string(),
-                                            None => "lobbie_common".to_string()
-                                        }
-                                    }
-                                }
-                            }
-                        },
-                        match newbie_position {
-                            Some(_pos) => "newbie".to_string(),
-                            None => {
-                                match low_position {
-                                    Some(pos) => mode.clone()[pos..].to_string(),
-                                    None => {
-                                        match high_position {
-                                            Some(pos) => mode.clone()[pos..].to_string(),
-                                            None => "lobbie".to_string()
-                                        }
-                                    }
-                                }
-                            }
-                        })
-                    );
+impl UserTeam {
+    pub fn new(path: &str) -> Self {
+        // Load team and result for each user in each session. Format of line: {"user_id":123,"session_id":123,"team":1,"victory":true}
+        // Rows with a team outside 1..=255 are rejected.
+        let mut user_team: std::collections::HashMap<(u64, u64), (u8, bool)> = std::collections::HashMap::new();
+        if let Ok(mut rows) = JsonlReader::<UserTeamRecord>::open(path) {
+            while let Some((line, row)) = rows.next() {
+                if row.team == 0 || row.team > u8::MAX as u64 {
+                    rows.reject(line, "team must be 1..=255, got ".to_string() + row.team.to_string().as_str());
+                    continue;
                 }
+                user_team.insert((row.user_id, row.session_id), (row.team as u8, row.victory));
             }
-            return Self(session_mode);
+            println!("{}", rows.report());
         }
-        // Return empty mapping when source file is missing.
-        Self(std::collections::HashMap::new())
+        Self(user_team)
     }
 }
 
@@ -86,42 +88,16 @@ pub struct Registrations(pub std::collections::HashMap<u64, u64>);
 
 impl Registrations {
     pub fn new() -> Self {
-        // Loads user registrations from data/regs.json when available.
-        if Path::new("data/regs.json").exists() {
-            let mut regs: std::collections::HashMap<u64, u64> = std::collections::HashMap::new();
-            if let Ok(lines) = memory::read_lines("data/regs.json") { 
-                
-                for line in lines.flatten() {
-                    // Lightweight parser for JSON-like line entries.
-                    let hash_line: std::collections::BTreeMap<String, String> = line.replace("\"", "").replace("{", "").replace("}", "").split(",").filter_map(|item: &str| {
-                        let splited: Vec<String> = item.split(":").map(|it| it.to_string()).collect();
-                        if splited.len() == 2 {
-                            Some((splited[0].clone(), splited[1].clone()))
-                        } else {
-                            None
-                        }
-                    }).collect();
-
-                    let user_id = hash_line.get("user_id");
-                    // Skip malformed rows without user id.
-                    if user_id == None {
-                        continue;
-                    }
-                    let registered_time = match hash_line.get("registered_time") {
-                        Some(data) => data.clone(),
-                        None => "0".to_string()
-                    };
-
-                    regs.insert(
-                        user_id.unwrap().parse::<u64>().unwrap(), 
-                        registered_time.parse::<u64>().unwrap()
-                    );
-                }
+        // Loads user registrations from data/regs.json when available: {"user_id":123,"registered_time":1700000000}
+        let mut regs: std::collections::HashMap<u64, u64> = std::collections::HashMap::new();
+        if let Ok(mut rows) = JsonlReader::<RegistrationRecord>::open("data/regs.json") {
+            for (_line, row) in rows.by_ref() {
+                regs.insert(row.user_id, row.registered_time);
             }
-            return Self(regs);
+            println!("{}", rows.report());
         }
-        // Return empty mapping when source file is missing.
-        Self(std::collections::HashMap::new())
+        // Empty mapping when the source file is missing.
+        Self(regs)
     }
 }
 
@@ -132,43 +108,15 @@ pub struct UserFaction(pub std::collections::BTreeMap<(u64, u64), String>);
 
 impl UserFaction {
     pub fn new(path: &str) -> Self {
-        // Load user faction/mode by (user_id, session_id).
-        if Path::new(path).exists() {
-            let mut camps: std::collections::BTreeMap<(u64, u64), String> = std::collections::BTreeMap::new();
-            if let Ok(lines) = memory::read_lines(path) { 
-                
-                for line in lines.flatten() {
-                    // Lightweight parser for JSON-like line entries.
-                    let hash_line: std::collections::BTreeMap<String, String> = line.replace("\"", "").replace("{", "").replace("}", "").split(",").filter_map(|item: &str| {
-                        let splited: Vec<String> = item.split(":").map(|it| it.to_string()).collect();
-                        if splited.len() == 2 {
-                            Some((splited[0].clone(), splited[1].clone()))
-                        } else {
-                            None
-                        }
-                    }).collect();
-
-                    let user_id = hash_line.get("user_id");
-                    let session_id = hash_line.get("session_id");
-                    // Skip malformed rows missing both keys.
-                    if user_id == None && session_id == None {
-                        continue;
-                    }
-                    let mode = match hash_line.get("mode") {
-                        Some(data) => data.clone(),
-                        None => "mixed".to_string()
-                    };
-
-                    camps.insert(
-                        // sessionId is expected in hexadecimal format.
-                        (user_id.unwrap().parse::<u64>().unwrap(), u64::from_str_radix(session_id.unwrap().clone().as_str(), 16).unwrap()), 
-                        mode
-                    );
-                }
+        // Load user faction/mode by (user_id, session_id): {"user_id":123,"session_id":"1f","mode":"faction_1"}
+        let mut camps: std::collections::BTreeMap<(u64, u64), String> = std::collections::BTreeMap::new();
+        if let Ok(mut rows) = JsonlReader::<UserFactionRecord>::open(path) {
+            for (_line, row) in rows.by_ref() {
+                camps.insert((row.user_id, row.session_id), row.mode.unwrap_or("mixed".to_string()));
             }
-            return Self(camps);
+            println!("{}", rows.report());
         }
-        // Return empty mapping when source file is missing.
-        Self(std::collections::BTreeMap::new())
+        // Empty mapping when the source file is missing.
+        Self(camps)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,269 @@
+use crate::params::DecayParams;
+use crate::types::LeaderboardRow;
+
+// commit_time is in milliseconds.
+const DAY_MS: f64 = 86_400_000.0;
+// Calibration threshold shared by v1 and v2 (`MMRType::MMR` from 6 battles).
+pub(crate) const CALIBRATED_BATTLES: u32 = 6;
+
+/// Decay already applied to a player since their `last_session`.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct DecayMark {
+    // Idle periods already applied (by a sweep or an earlier return check).
+    pub periods: u32,
+    // Remaining battles subtracted from the v2 confidence exponent.
+    pub confidence: f64
+}
+
+/// Inactivity decay state of the v1/v2 leaderboards.
+#[derive(Clone, Debug, Default)]
+pub struct DecayState {
+    pub params: DecayParams,
+    // user_id -> applied decay; persisted in the leaderboard snapshot.
+    pub marks: std::collections::HashMap<u64, DecayMark>,
+    // Mean MMR of calibrated players and the commit_time it was computed at.
+    pub mean: f64,
+    pub mean_at: u64
+}
+
+/// Decay state of some players saved by [`DecayState::save`], to undo the decay of a preview.
+#[derive(Clone, Debug, Default)]
+pub struct DecayUndo {
+    // Row, decay mark and `battle_score_hash` entry of each player before the decay.
+    rows: Vec<(LeaderboardRow, Option<DecayMark>, Option<u32>)>,
+    mean: f64,
+    mean_at: u64
+}
+
+impl DecayState {
+    pub fn new(marks: std::collections::HashMap<u64, DecayMark>) -> Self {
+        Self {
+            params: DecayParams::default(),
+            marks,
+            mean: 0.0,
+            mean_at: 0
+        }
+    }
+
+    /// Returns `true` when a decay period and at least one decay effect are configured.
+    pub fn enabled(&self) -> bool {
+        self.params.period_days > 0.0
+            && (self.params.mean_pull > 0.0 || self.params.recalibration > 0 || self.params.confidence_battles > 0.0)
+    }
+
+    /// Full idle periods between `row.last_session` and `now` not yet applied.
+    fn pending_periods(&self, row: &LeaderboardRow, now: u64) -> u32 {
+        let idle = (now.saturating_sub(row.last_session) as f64 / (self.params.period_days * DAY_MS)) as u32;
+        idle.saturating_sub(self.marks.get(&row.user_id).map(|mark| mark.periods).unwrap_or(0))
+    }
+
+    /// Recomputes the mean MMR of calibrated players at most once per decay period.
+    fn refresh_mean(&mut self, users: &std::collections::HashMap<u64, LeaderboardRow>, now: u64) {
+        if self.mean_at != 0 && (now.saturating_sub(self.mean_at) as f64) < self.params.period_days * DAY_MS {
+            return;
+        }
+        self.mean = calibrated_mean(users);
+        self.mean_at = now;
+    }
+
+    /// Decays `user_id` for the idle periods elapsed at `now` and not applied yet.
+    ///
+    /// 1. Mean pull: a calibrated MMR moves toward the population mean by `mean_pull` of the
+    ///    remaining gap per period.
+    /// 2. Recalibration: a calibrated player is set back to `6 - recalibration` battles
+    ///    (battle score scaled alike) and replays the last calibration battles; the other
+    ///    counters keep their lifetime totals.
+    /// 3. Confidence: `confidence_battles` per period are added to the player's v2
+    ///    confidence penalty, capped at 6 battles.
+    ///
+    /// `battle_score_hash` is kept in sync. Returns `true` when the player was decayed.
+    pub fn decay_user(
+        &mut self,
+        user_id: u64,
+        now: u64,
+        users: &mut std::collections::HashMap<u64, LeaderboardRow>,
+        battle_score_hash: &mut std::collections::BTreeMap<(u32, u64), u32>
+    ) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+        let periods = match users.get(&user_id) {
+            Some(row) => self.pending_periods(row, now),
+            None => return false
+        };
+        if periods == 0 {
+            return false;
+        }
+        if self.params.mean_pull > 0.0 {
+            self.refresh_mean(users, now);
+        }
+        let row = users.get_mut(&user_id).unwrap();
+        if row.battles >= CALIBRATED_BATTLES {
+            let keep = if self.params.mean_pull > 0.0 {(1.0 - self.params.mean_pull.clamp(0.0, 1.0)).powi(periods as i32)} else {1.0};
+            soften(row, self.mean, keep, self.params.recalibration, battle_score_hash);
+        }
+        let mark = self.marks.entry(user_id).or_default();
+        mark.periods += periods;
+        // A returning player is at most as uncertain as a new one.
+        mark.confidence = (mark.confidence + self.params.confidence_battles * periods as f64).min(CALIBRATED_BATTLES as f64);
+        true
+    }
+
+    /// Saves what [`DecayState::decay_user`] may change for `user_ids`: their rows, marks and
+    /// index entries, and the cached mean.
+    pub fn save(
+        &self,
+        user_ids: &[u64],
+        users: &std::collections::HashMap<u64, LeaderboardRow>,
+        battle_score_hash: &std::collections::BTreeMap<(u32, u64), u32>
+    ) -> DecayUndo {
+        let rows = user_ids.iter().filter_map(|user_id| users.get(user_id)).map(|row| {
+            (row.clone(), self.marks.get(&row.user_id).copied(), index_key(row).and_then(|key| battle_score_hash.get(&key).copied()))
+        }).collect();
+        DecayUndo { rows, mean: self.mean, mean_at: self.mean_at }
+    }
+
+    /// Puts back the state saved in `undo`, dropping any decay applied since.
+    pub fn undo(
+        &mut self,
+        undo: DecayUndo,
+        users: &mut std::collections::HashMap<u64, LeaderboardRow>,
+        battle_score_hash: &mut std::collections::BTreeMap<(u32, u64), u32>
+    ) {
+        for (row, mark, indexed) in undo.rows {
+            if let Some(key) = index_key(&row) {
+                match indexed {
+                    Some(mmr) => battle_score_hash.insert(key, mmr),
+                    None => battle_score_hash.remove(&key)
+                };
+            }
+            match mark {
+                Some(mark) => self.marks.insert(row.user_id, mark),
+                None => self.marks.remove(&row.user_id)
+            };
+            users.insert(row.user_id, row);
+        }
+        self.mean = undo.mean;
+        self.mean_at = undo.mean_at;
+    }
+
+    /// Decays every player idle for at least one new period at `now`; returns their count.
+    pub fn sweep(
+        &mut self,
+        now: u64,
+        users: &mut std::collections::HashMap<u64, LeaderboardRow>,
+        battle_score_hash: &mut std::collections::BTreeMap<(u32, u64), u32>
+    ) -> u64 {
+        if !self.enabled() {
+            return 0;
+        }
+        // The mean is taken before any player of the sweep is moved.
+        self.mean_at = 0;
+        self.refresh_mean(users, now);
+        let user_ids: Vec<u64> = users.keys().copied().collect();
+        user_ids.into_iter().filter(|user_id| self.decay_user(*user_id, now, users, battle_score_hash)).count() as u64
+    }
+
+    /// Records that `user_id` played a rated session: the idle clock restarts from this
+    /// session and one battle of the confidence penalty is used up.
+    pub fn played(&mut self, user_id: u64) {
+        if let Some(mark) = self.marks.get_mut(&user_id) {
+            mark.periods = 0;
+            mark.confidence = (mark.confidence - 1.0).max(0.0);
+            if mark.confidence == 0.0 {
+                self.marks.remove(&user_id);
+            }
+        }
+    }
+
+    /// Battles subtracted from the v2 confidence exponent of `user_id`.
+    pub fn confidence_penalty(&self, user_id: u64) -> f64 {
+        self.marks.get(&user_id).map(|mark| mark.confidence).unwrap_or(0.0)
+    }
+}
+
+// `battle_score_hash` key of `row`: (average battle score, user_id).
+fn index_key(row: &LeaderboardRow) -> Option<(u32, u64)> {
+    (row.battles > 0).then(|| (row.battle_score / row.battles, row.user_id))
+}
+
+/// Mean MMR of the calibrated `users`, 0 without any.
+///
+/// The sum is taken in integers, so the mean does not depend on the iteration order of the
+/// map and a run reproduces the same rounded MMRs in [`soften`].
+pub(crate) fn calibrated_mean(users: &std::collections::HashMap<u64, LeaderboardRow>) -> f64 {
+    let (sum, count) = users.values()
+        .filter(|row| row.battles >= CALIBRATED_BATTLES)
+        .fold((0u64, 0u64), |base, row| (base.0 + row.mmr as u64, base.1 + 1));
+    if count > 0 {sum as f64 / count as f64} else {0.0}
+}
+
+/// Moves a calibrated `row` back toward `mean` and into calibration.
+///
+/// 1. The MMR keeps `keep` of its gap to `mean` (skipped for `keep >= 1` or no mean).
+/// 2. With `recalibration > 0` the player is set back to `6 - recalibration` battles, battle
+///    score scaled alike, and leaves `battle_score_hash`; otherwise the hash gets the new MMR.
+///
+/// Shared by the inactivity decay and the season soft reset (`season::soft_reset`).
+pub(crate) fn soften(
+    row: &mut LeaderboardRow,
+    mean: f64,
+    keep: f64,
+    recalibration: u32,
+    battle_score_hash: &mut std::collections::BTreeMap<(u32, u64), u32>
+) {
+    let index_key = (row.battle_score / row.battles, row.user_id);
+    if keep < 1.0 && mean > 0.0 {
+        row.mmr = (mean + (row.mmr as f64 - mean) * keep).round().max(0.0) as u32;
+    }
+    if recalibration > 0 {
+        let battles = CALIBRATED_BATTLES - recalibration.min(CALIBRATED_BATTLES - 1);
+        row.battle_score = (row.battle_score as u64 * battles as u64 / row.battles as u64) as u32;
+        row.battles = battles;
+        battle_score_hash.remove(&index_key);
+    } else {
+        battle_score_hash.insert(index_key, row.mmr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(user_id: u64, mmr: u32, battles: u32, last_session: u64) -> LeaderboardRow {
+        LeaderboardRow { user_id, mmr, battles, victories: 0, early_quites: 0, top_20: 0, battle_score: 1000 * battles, last_session }
+    }
+
+    #[test]
+    fn decay_pulls_toward_mean_once_per_period() {
+        let mut users: std::collections::HashMap<u64, LeaderboardRow> = [row(1, 2000, 10, 0), row(2, 1000, 10, 0)].into_iter().map(|row| (row.user_id, row)).collect();
+        let mut index = std::collections::BTreeMap::new();
+        let mut decay = DecayState::new(std::collections::HashMap::new());
+        decay.params = DecayParams { period_days: 1.0, mean_pull: 0.5, recalibration: 0, confidence_battles: 2.0 };
+
+        // Two idle days: the gap of 500 to the mean of 1500 shrinks to 125.
+        let now = (2.0 * DAY_MS) as u64;
+        assert_eq!(decay.sweep(now, &mut users, &mut index), 2);
+        assert_eq!((users[&1].mmr, users[&2].mmr), (1625, 1375));
+        assert_eq!(decay.confidence_penalty(1), 4.0);
+        // Already applied periods are not applied again on return.
+        assert!(!decay.decay_user(1, now + 1, &mut users, &mut index));
+
+        decay.played(1);
+        assert_eq!(decay.confidence_penalty(1), 3.0);
+
+        decay.params = DecayParams { period_days: 1.0, mean_pull: 0.0, recalibration: 2, confidence_battles: 0.0 };
+        assert!(decay.decay_user(2, 3 * DAY_MS as u64, &mut users, &mut index));
+        assert_eq!((users[&2].battles, users[&2].battle_score), (4, 4000));
+    }
+
+    #[test]
+    fn calibrated_mean_is_exact_and_skips_uncalibrated_players() {
+        let rows = [row(1, 1501, 6, 0), row(2, 1500, 9, 0), row(3, 3000, 5, 0), row(4, 1499, 7, 0), row(5, 1503, 6, 0)];
+        let users: std::collections::HashMap<u64, LeaderboardRow> = rows.iter().map(|row| (row.user_id, row.clone())).collect();
+        let reversed: std::collections::HashMap<u64, LeaderboardRow> = rows.iter().rev().map(|row| (row.user_id, row.clone())).collect();
+        assert_eq!(calibrated_mean(&users), 1500.75);
+        assert_eq!(calibrated_mean(&users).to_bits(), calibrated_mean(&reversed).to_bits());
+        assert_eq!(calibrated_mean(&std::collections::HashMap::new()), 0.0);
+    }
+}
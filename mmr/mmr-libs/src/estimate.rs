@@ -0,0 +1,127 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Binomial, Distribution};
+use serde::{Deserialize, Serialize};
+
+use crate::params::EstimateParams;
+
+/// Mean of one spread bucket with its standard error and bootstrap percentile interval.
+///
+/// A bucket with fewer than `EstimateParams::min_sample` samples is suppressed: only its
+/// `samples` count is reported.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BucketEstimate {
+    pub samples: u64,
+    pub suppressed: bool,
+    pub mean: Option<f64>,
+    pub std_error: Option<f64>,
+    pub ci_low: Option<f64>,
+    pub ci_high: Option<f64>
+}
+
+impl BucketEstimate {
+    fn suppressed(samples: u64) -> Self {
+        Self { samples, suppressed: true, mean: None, std_error: None, ci_low: None, ci_high: None }
+    }
+
+    /// Estimate of the mean of `values` (e.g. the MMRs of a bucket's players).
+    ///
+    /// 1. Standard error: sample standard deviation / √n.
+    /// 2. Interval: `resamples` means of `n` values drawn with replacement; the bounds are the
+    ///    `(1 - confidence) / 2` and `(1 + confidence) / 2` quantiles of those means.
+    pub fn of_values(values: &[f64], params: &EstimateParams) -> Self {
+        let samples = values.len() as u64;
+        if samples == 0 || samples < params.min_sample {
+            return Self::suppressed(samples);
+        }
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = if values.len() > 1 {values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (n - 1.0)} else {0.0};
+
+        let mut rng = StdRng::seed_from_u64(params.seed);
+        let means: Vec<f64> = (0..params.resamples).map(|_| {
+            (0..values.len()).map(|_| values[rng.random_range(0..values.len())]).sum::<f64>() / n
+        }).collect();
+        Self::new(samples, mean, (variance / n).sqrt(), means, params)
+    }
+
+    /// Estimate of a proportion of `successes` in `trials` (e.g. the team 1 win rate of a
+    /// `top_3_disbalance_spread` cell): standard error `√(p(1 - p) / n)` and the percentile
+    /// interval of `resamples` resampled proportions, each a `Binomial(trials, p)` draw.
+    pub fn of_proportion(successes: u64, trials: u64, params: &EstimateParams) -> Self {
+        if trials == 0 || trials < params.min_sample {
+            return Self::suppressed(trials);
+        }
+        let n = trials as f64;
+        let rate = successes as f64 / n;
+
+        let mut rng = StdRng::seed_from_u64(params.seed);
+        let binomial = Binomial::new(trials, rate).unwrap();
+        let rates: Vec<f64> = (0..params.resamples).map(|_| binomial.sample(&mut rng) as f64 / n).collect();
+        Self::new(trials, rate, (rate * (1.0 - rate) / n).sqrt(), rates, params)
+    }
+
+    fn new(samples: u64, mean: f64, std_error: f64, mut resampled: Vec<f64>, params: &EstimateParams) -> Self {
+        resampled.sort_by(|a, b| a.total_cmp(b));
+        let quantile = |q: f64| if resampled.is_empty() {mean} else {resampled[((resampled.len() - 1) as f64 * q).round() as usize]};
+        let tail = (1.0 - params.confidence) / 2.0;
+        Self {
+            samples,
+            suppressed: false,
+            mean: Some(mean),
+            std_error: Some(std_error),
+            ci_low: Some(quantile(tail)),
+            ci_high: Some(quantile(1.0 - tail))
+        }
+    }
+
+    /// `mean;std_error;ci_low;ci_high;suppressed` with `decimals` digits, the estimates left
+    /// empty when suppressed.
+    pub fn csv_columns(&self, decimals: usize) -> String {
+        let column = |value: Option<f64>| match value {
+            Some(value) => format!("{:.*}", decimals, value),
+            None => String::new()
+        };
+        column(self.mean)
+            + ";" + column(self.std_error).as_str()
+            + ";" + column(self.ci_low).as_str()
+            + ";" + column(self.ci_high).as_str()
+            + ";" + self.suppressed.to_string().as_str()
+    }
+}
+
+/// [`BucketEstimate::of_values`] of the MMRs of every bucket of a `spread::*_samples` map,
+/// ordered by bucket key.
+pub fn spread_estimates<K: Clone + Ord>(samples: &std::collections::HashMap<K, Vec<u32>>, params: &EstimateParams) -> std::collections::BTreeMap<K, BucketEstimate> {
+    samples.iter().map(|(key, mmrs)| {
+        let values: Vec<f64> = mmrs.iter().map(|mmr| *mmr as f64).collect();
+        (key.clone(), BucketEstimate::of_values(&values, params))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_cover_the_mean_and_suppress_small_buckets() {
+        let params = EstimateParams::default();
+        let values: Vec<f64> = (0..100).map(|value| 1000.0 + value as f64).collect();
+        let estimate = BucketEstimate::of_values(&values, &params);
+        assert_eq!(estimate.mean, Some(1049.5));
+        assert!((estimate.std_error.unwrap() - 2.9011).abs() < 0.0001);
+        // The percentile interval is about mean ± 1.96 standard errors.
+        assert!(estimate.ci_low.unwrap() < 1049.5 && estimate.ci_low.unwrap() > 1049.5 - 2.5 * 2.9011);
+        assert!(estimate.ci_high.unwrap() > 1049.5 && estimate.ci_high.unwrap() < 1049.5 + 2.5 * 2.9011);
+        assert_eq!(estimate, BucketEstimate::of_values(&values, &params));
+
+        let rate = BucketEstimate::of_proportion(60, 100, &params);
+        assert!((rate.std_error.unwrap() - 0.04899).abs() < 0.00001);
+        assert!(rate.ci_low.unwrap() < 0.6 && rate.ci_high.unwrap() > 0.6);
+
+        let small = BucketEstimate::of_proportion(4, 5, &params);
+        assert!(small.suppressed);
+        assert_eq!((small.samples, small.mean), (5, None));
+        assert_eq!(small.csv_columns(4), ";;;;true");
+    }
+}
@@ -0,0 +1,266 @@
+use crate::classification::SessionRules;
+use crate::datasets::{Registrations, SessionMode, UserFaction};
+use crate::memory::SessionMemory;
+use crate::rating::{self, RatingSystem, SessionTeams};
+use crate::userstat::UserStatReader;
+
+// Probabilities are clipped to [EPS, 1 - EPS] before taking logarithms.
+const LOG_LOSS_EPS: f64 = 1e-15;
+
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    // group -> (predicted team 1 win probability, team 1 won) per decided session.
+    pub predictions: std::collections::BTreeMap<String, Vec<(f64, bool)>>,
+    // Rated sessions where both or neither team won (no binary outcome to score).
+    pub undecided: u64
+}
+
+#[derive(Clone, Debug)]
+pub struct CalibrationBin {
+    // Predicted probability range [lower, upper).
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64,
+    // Mean predicted probability of the sessions in the bin.
+    pub predicted: f64,
+    // Observed team 1 win rate of the sessions in the bin.
+    pub observed: f64
+}
+
+#[derive(Clone, Debug)]
+pub struct EvaluationMetrics {
+    pub sessions: u64,
+    pub log_loss: f64,
+    pub brier: f64,
+    // `None` when the group contains only one outcome class.
+    pub auc: Option<f64>,
+    pub calibration: Vec<CalibrationBin>
+}
+
+impl Evaluation {
+    pub fn new() -> Self {
+        Self {
+            predictions: std::collections::BTreeMap::new(),
+            undecided: 0
+        }
+    }
+
+    /// Records one prediction in `group`.
+    pub fn add(&mut self, group: &str, probability: f64, team_1_won: bool) {
+        self.predictions.entry(group.to_string()).or_default().push((probability, team_1_won));
+    }
+
+    /// Computes the metrics of every group with `bins` equal-width calibration bins.
+    pub fn metrics(&self, bins: usize) -> std::collections::BTreeMap<String, EvaluationMetrics> {
+        self.predictions.iter().map(|(group, predictions)| (group.clone(), EvaluationMetrics {
+            sessions: predictions.len() as u64,
+            log_loss: log_loss(predictions),
+            brier: brier_score(predictions),
+            auc: auc(predictions),
+            calibration: calibration_curve(predictions, bins)
+        })).collect()
+    }
+}
+
+impl Default for Evaluation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EvaluationMetrics {
+    pub fn to_string(&self, algorithm: &str, group: &str) -> String {
+        // One flat key:value line per (algorithm, group).
+        "algorithm:".to_string() + algorithm
+            + ",group:" + group
+            + ",sessions:" + self.sessions.to_string().as_str()
+            + ",log_loss:" + self.log_loss.to_string().as_str()
+            + ",brier:" + self.brier.to_string().as_str()
+            + ",auc:" + match self.auc {
+                Some(auc) => auc.to_string(),
+                None => "none".to_string()
+            }.as_str()
+            + "\n"
+    }
+
+    pub fn calibration_to_string(&self, algorithm: &str, group: &str) -> String {
+        // One line per non-empty calibration bin.
+        let mut str = String::new();
+        for bin in self.calibration.iter().filter(|bin| bin.count > 0) {
+            str = str + "algorithm:" + algorithm
+                + ",group:" + group
+                + ",lower:" + bin.lower.to_string().as_str()
+                + ",upper:" + bin.upper.to_string().as_str()
+                + ",count:" + bin.count.to_string().as_str()
+                + ",predicted:" + bin.predicted.to_string().as_str()
+                + ",observed:" + bin.observed.to_string().as_str()
+                + "\n";
+        }
+        str
+    }
+}
+
+/// Records the pre-session prediction of `rating` for a completed session.
+///
+/// Uses the same filters as [`crate::rating::proc_session`]: only sessions that update the
+/// leaderboard are scored, and sessions where both or neither team won are counted as
+/// undecided. Must be called before the session is applied. The prediction is added to the
+/// `common` group and to each of the session's mode groups.
+pub fn evaluate_session<T: RatingSystem>(rating: &T, session_memory: &SessionMemory, session_mode: &SessionMode, registrations: &Registrations, rules: &SessionRules, evaluation: &mut Evaluation) {
+    let teams = SessionTeams::new(rating, session_memory, &rules.factions);
+    if !teams.is_rated(rules, session_mode, registrations) {
+        return;
+    }
+    let team_1_won = match teams.team_1_won() {
+        Some(team_1_won) => team_1_won,
+        None => {
+            evaluation.undecided += 1;
+            return;
+        }
+    };
+    let probability = rating.win_probability(&teams);
+    evaluation.add("common", probability, team_1_won);
+    if let Some((mode_0, mode_1, mode_2)) = session_mode.0.get(&teams.session_id) {
+        for group in [mode_0, mode_1, mode_2] {
+            evaluation.add(group, probability, team_1_won);
+        }
+    }
+}
+
+/// Loads a userstat dataset into memory as a list of sessions, grouped by `session_id` in
+/// file order. Used when the same slice is replayed many times.
+pub fn load_sessions(
+    path: &str,
+    user_team: &std::collections::HashMap<(u64, u64), (u8, bool)>,
+    user_faction: &UserFaction
+) -> Vec<SessionMemory> {
+    let mut sessions: Vec<SessionMemory> = Vec::new();
+    if let Ok(mut rows) = UserStatReader::open(path, user_team, user_faction) {
+        for row in rows.by_ref() {
+            match sessions.last_mut() {
+                Some(session) if session.now_session_id == row.session_id => session.rows.push(row),
+                _ => sessions.push(SessionMemory {
+                    now_session_id: row.session_id,
+                    rows: vec![row]
+                })
+            }
+        }
+        println!("{}", rows.report());
+    }
+    sessions
+}
+
+/// Replays `sessions` in order with `rating`, predicting every session before applying it.
+///
+/// Sessions are applied through [`rating::proc_session`] with classifier id `0`, so no
+/// statistics are produced and change records are discarded.
+pub async fn replay<T: RatingSystem>(
+    rating: &mut T,
+    sessions: &[SessionMemory],
+    session_mode: &SessionMode,
+    registrations: &Registrations,
+    rules: &SessionRules
+) -> Evaluation {
+    let mut evaluation = Evaluation::new();
+    let (sender, _) = flume::unbounded();
+    let (sender_tasks, _) = flume::unbounded::<T::Change>();
+    let (sender_check, _) = flume::unbounded();
+    let (sender_session_class, _) = flume::unbounded();
+    for session in sessions.iter() {
+        evaluate_session(rating, session, session_mode, registrations, rules, &mut evaluation);
+        rating::proc_session(rating, session.clone(), 0, sender.clone(), session_mode, registrations, rules, sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+    }
+    evaluation
+}
+
+/// Mean negative log-likelihood of the observed outcomes.
+pub fn log_loss(predictions: &[(f64, bool)]) -> f64 {
+    if predictions.is_empty() {
+        return 0.0;
+    }
+    predictions.iter().fold(0.0, |base, (probability, outcome)| {
+        let probability = probability.clamp(LOG_LOSS_EPS, 1.0 - LOG_LOSS_EPS);
+        base - if *outcome {probability.ln()} else {(1.0 - probability).ln()}
+    }) / predictions.len() as f64
+}
+
+/// Mean squared error between predicted probabilities and outcomes.
+pub fn brier_score(predictions: &[(f64, bool)]) -> f64 {
+    if predictions.is_empty() {
+        return 0.0;
+    }
+    predictions.iter().fold(0.0, |base, (probability, outcome)| {
+        base + (probability - if *outcome {1.0} else {0.0}).powi(2)
+    }) / predictions.len() as f64
+}
+
+/// Area under the ROC curve (Mann–Whitney statistic, ties share their average rank).
+///
+/// Returns `None` when all outcomes belong to the same class.
+pub fn auc(predictions: &[(f64, bool)]) -> Option<f64> {
+    let positives = predictions.iter().filter(|item| item.1).count() as f64;
+    let negatives = predictions.len() as f64 - positives;
+    if positives == 0.0 || negatives == 0.0 {
+        return None;
+    }
+    let mut sorted: Vec<(f64, bool)> = predictions.to_vec();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // Sum of (1-based, tie-averaged) ranks of the positive outcomes.
+    let mut rank_sum = 0.0;
+    let mut start = 0;
+    while start < sorted.len() {
+        let mut end = start;
+        while end < sorted.len() && sorted[end].0 == sorted[start].0 {
+            end += 1;
+        }
+        let avg_rank = (start + end + 1) as f64 / 2.0;
+        rank_sum += avg_rank * sorted[start..end].iter().filter(|item| item.1).count() as f64;
+        start = end;
+    }
+    Some((rank_sum - positives * (positives + 1.0) / 2.0) / (positives * negatives))
+}
+
+/// Reliability curve over `bins` equal-width predicted-probability bins.
+pub fn calibration_curve(predictions: &[(f64, bool)], bins: usize) -> Vec<CalibrationBin> {
+    let bins = std::cmp::max(bins, 1);
+    let mut curve: Vec<CalibrationBin> = (0..bins).map(|bin| CalibrationBin {
+        lower: bin as f64 / bins as f64,
+        upper: (bin + 1) as f64 / bins as f64,
+        count: 0,
+        predicted: 0.0,
+        observed: 0.0
+    }).collect();
+    for (probability, outcome) in predictions.iter() {
+        let bin = &mut curve[std::cmp::min((probability * bins as f64) as usize, bins - 1)];
+        bin.count += 1;
+        bin.predicted += probability;
+        bin.observed += if *outcome {1.0} else {0.0};
+    }
+    for bin in curve.iter_mut().filter(|bin| bin.count > 0) {
+        bin.predicted /= bin.count as f64;
+        bin.observed /= bin.count as f64;
+    }
+    curve
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_match_hand_computed_values() {
+        let predictions = [(0.8, true), (0.6, false), (0.4, true), (0.2, false)];
+        assert!((brier_score(&predictions) - 0.2).abs() < 1e-12);
+        assert!((log_loss(&predictions) - (-(0.8_f64.ln() + 0.4_f64.ln() + 0.4_f64.ln() + 0.8_f64.ln()) / 4.0)).abs() < 1e-12);
+        // Positive ranks 4 and 2 out of 2x2 pairs -> 3 concordant pairs.
+        assert_eq!(auc(&predictions), Some(0.75));
+        assert_eq!(auc(&[(0.5, true), (0.5, false)]), Some(0.5));
+        assert_eq!(auc(&[(0.5, true)]), None);
+
+        let curve = calibration_curve(&predictions, 2);
+        assert_eq!(curve[0].count, 2);
+        assert!((curve[0].predicted - 0.3).abs() < 1e-12);
+        assert!((curve[1].observed - 0.5).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+use crate::spread::DOMINANT_FACTION_SHARE;
+use crate::types::UserBattleRow;
+
+/// Typed id of a registered faction: its 1-based position in [`FactionRegistry::factions`].
+///
+/// In `TeamMode::Teams` the id is also the team number of the faction's players.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct FactionId(pub u8);
+
+/// How the rows of a session form teams.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TeamMode {
+    // One team per registered faction, in registry order.
+    Teams,
+    // Every player of a registered faction is a team of its own, in input order.
+    FreeForAll
+}
+
+/// Factions of the dataset, stored as the `factions` section of a `SessionRules` RON file.
+///
+/// The defaults are the built-in factions: `faction_1` and `faction_2` play as team 1 and
+/// team 2, and a player with 65% or more of their battles on one faction has it as dominant
+/// faction.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FactionRegistry {
+    pub factions: Vec<String>,
+    pub mode: TeamMode,
+    // Share of a player's battles on one faction that makes it their dominant faction.
+    pub dominant_share: f64,
+    // Dominant-faction bucket of players without a dominant faction.
+    pub mixed: String
+}
+
+impl Default for FactionRegistry {
+    fn default() -> Self {
+        Self {
+            factions: vec!["faction_1".to_string(), "faction_2".to_string()],
+            mode: TeamMode::Teams,
+            dominant_share: DOMINANT_FACTION_SHARE,
+            mixed: "mixed".to_string()
+        }
+    }
+}
+
+impl FactionRegistry {
+    /// Id of the faction named `faction`; `None` for unregistered factions.
+    pub fn id(&self, faction: &str) -> Option<FactionId> {
+        self.factions.iter().position(|name| name == faction).map(|index| FactionId(index as u8 + 1))
+    }
+
+    /// Name of the faction `id`.
+    pub fn name(&self, id: FactionId) -> Option<&str> {
+        self.factions.get((id.0 as usize).checked_sub(1)?).map(|name| name.as_str())
+    }
+
+    /// Splits the rows of a session into teams; team `n` is the entry `n - 1`.
+    ///
+    /// 1. `Teams`: one team per registered faction, empty when nobody played it.
+    /// 2. `FreeForAll`: one team per player of a registered faction.
+    ///
+    /// Rows of unregistered factions are left out. Every row gets its team number.
+    pub fn split(&self, rows: &[UserBattleRow]) -> Vec<Vec<UserBattleRow>> {
+        let mut teams: Vec<Vec<UserBattleRow>> = match self.mode {
+            TeamMode::Teams => vec![Vec::new(); self.factions.len()],
+            TeamMode::FreeForAll => Vec::new()
+        };
+        for row in rows.iter() {
+            if let Some(id) = self.id(&row.faction) {
+                let index = match self.mode {
+                    TeamMode::Teams => id.0 as usize - 1,
+                    TeamMode::FreeForAll => {
+                        teams.push(Vec::new());
+                        teams.len() - 1
+                    }
+                };
+                let mut row = row.clone();
+                row.team = (index + 1) as u8;
+                teams[index].push(row);
+            }
+        }
+        teams
+    }
+
+    /// Dominant faction of a player with `battles` battles in total, `faction_battles` giving
+    /// their battles per faction: the first registered faction with at least `dominant_share`
+    /// of them, otherwise `mixed`.
+    pub fn dominant(&self, battles: u32, faction_battles: impl Fn(&str) -> u64) -> String {
+        self.factions.iter()
+            .find(|faction| faction_battles(faction) as f64 / battles as f64 >= self.dominant_share)
+            .unwrap_or(&self.mixed)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(user_id: u64, faction: &str) -> UserBattleRow {
+        UserBattleRow { user_id, session_id: 1, commit_time: 0, team: 0, battle_score: 0, victories: false, early_quit: false, team_score_top_20_percent: false, faction: faction.to_string() }
+    }
+
+    #[test]
+    fn registry_splits_teams_and_buckets_dominant_factions() {
+        let registry: FactionRegistry = ron::from_str("(factions: [\"north\", \"south\", \"east\"])").unwrap();
+        assert_eq!((registry.id("east"), registry.id("west")), (Some(FactionId(3)), None));
+        assert_eq!(registry.name(FactionId(2)), Some("south"));
+        assert_eq!(registry.name(FactionId(0)), None);
+
+        let rows = vec![row(1, "east"), row(2, "north"), row(3, "west"), row(4, "east")];
+        let teams = registry.split(&rows);
+        let ids = |team: &Vec<UserBattleRow>| team.iter().map(|row| (row.user_id, row.team)).collect::<Vec<(u64, u8)>>();
+        assert_eq!(teams.iter().map(ids).collect::<Vec<_>>(), vec![vec![(2, 1)], vec![], vec![(1, 3), (4, 3)]]);
+
+        let free_for_all = FactionRegistry { mode: TeamMode::FreeForAll, ..registry.clone() };
+        let teams = free_for_all.split(&rows);
+        assert_eq!(teams.iter().map(ids).collect::<Vec<_>>(), vec![vec![(1, 1)], vec![(2, 2)], vec![(4, 3)]]);
+
+        let battles = |faction: &str| match faction {"south" => 7, "east" => 3, _ => 0};
+        assert_eq!(registry.dominant(10, battles), "south");
+        assert_eq!(registry.dominant(11, battles), "mixed");
+        assert_eq!(FactionRegistry::default().dominant(10, |faction| if faction == "faction_2" {7} else {3}), "faction_2");
+    }
+}
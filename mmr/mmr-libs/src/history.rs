@@ -0,0 +1,367 @@
+use std::io::{BufRead, Read, Seek, Write};
+
+use crate::rating::{leaderboard_dir, RatingSystem};
+use crate::snapshot::Cursor;
+
+/// First bytes of the history index file.
+pub const HISTORY_INDEX_MAGIC: &[u8; 8] = b"MMRHIDX\0";
+
+// payload length before and CRC-32 after every record
+const RECORD_OVERHEAD: u64 = 8;
+
+/// One rating update of one player, as kept in the [`HistoryStore`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub user_id: u64,
+    pub session_id: u64,
+    // Session commit time (ms).
+    pub commit_time: u64,
+    pub mmr_before: u32,
+    pub mmr_after: u32,
+    // Raw session mode (`SessionMode`), empty when the session has none.
+    pub mode: String,
+    // Debug tuple of the algorithm (`MMRChangeDebug`, `MMRChangeDebugV2`, ...) in field order.
+    pub components: Vec<f64>
+}
+
+impl HistoryEntry {
+    /// Applied rating change of the session.
+    pub fn delta(&self) -> i64 {
+        self.mmr_after as i64 - self.mmr_before as i64
+    }
+
+    /// Serializes the entry into one record of the `entries` file.
+    ///
+    /// All integers are little-endian:
+    /// 1. Payload length (u32).
+    /// 2. Payload: `user_id`, `session_id`, `commit_time` u64, `mmr_before`, `mmr_after` u32,
+    ///    mode length u16 + UTF-8 bytes, component count u16, then every component as f64.
+    /// 3. CRC-32 (u32) of the payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(36 + self.mode.len() + 2 + self.components.len() * 8);
+        for value in [self.user_id, self.session_id, self.commit_time] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload.extend_from_slice(&self.mmr_before.to_le_bytes());
+        payload.extend_from_slice(&self.mmr_after.to_le_bytes());
+        payload.extend_from_slice(&(self.mode.len() as u16).to_le_bytes());
+        payload.extend_from_slice(self.mode.as_bytes());
+        payload.extend_from_slice(&(self.components.len() as u16).to_le_bytes());
+        for component in self.components.iter() {
+            payload.extend_from_slice(&component.to_le_bytes());
+        }
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(payload.len() + RECORD_OVERHEAD as usize);
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        bytes
+    }
+
+    /// Parses the first record of `bytes`; returns the entry and the record length, or
+    /// `None` when the record is incomplete or fails its checksum.
+    pub fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let mut cursor = Cursor { bytes, position: 0 };
+        let len = cursor.u32().ok()? as usize;
+        let payload = cursor.take(len).ok()?;
+        let stored = cursor.u32().ok()?;
+        if stored != crc32fast::hash(payload) {
+            return None;
+        }
+
+        let mut cursor = Cursor { bytes: payload, position: 0 };
+        let user_id = cursor.u64().ok()?;
+        let session_id = cursor.u64().ok()?;
+        let commit_time = cursor.u64().ok()?;
+        let mmr_before = cursor.u32().ok()?;
+        let mmr_after = cursor.u32().ok()?;
+        let mode_len = cursor.u16().ok()? as usize;
+        let mode = std::str::from_utf8(cursor.take(mode_len).ok()?).ok()?.to_string();
+        let count = cursor.u16().ok()?;
+        let mut components = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            components.push(f64::from_bits(cursor.u64().ok()?));
+        }
+        Some((Self { user_id, session_id, commit_time, mmr_before, mmr_after, mode, components }, len + RECORD_OVERHEAD as usize))
+    }
+}
+
+impl std::fmt::Display for HistoryEntry {
+    /// Formats the entry as a flat key:value line.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "user_id:{},session_id:{},commit_time:{},mmr_before:{},mmr_after:{},delta:{},mode:{},components:[{}]",
+            self.user_id, self.session_id, self.commit_time, self.mmr_before, self.mmr_after, self.delta(), self.mode,
+            self.components.iter().map(|component| component.to_string()).collect::<Vec<String>>().join(","))
+    }
+}
+
+/// Append-only rating history of every player, indexed by `user_id`.
+///
+/// Lives in a directory with two files:
+/// - `entries` — [`HistoryEntry::encode`] records in append order (session order for the
+///   pipeline), never rewritten.
+/// - `index`   — `HISTORY_INDEX_MAGIC`, the covered length of `entries` (u64), the user count
+///   (u64), then per user `user_id`, offset count and the record offsets (all u64), and a
+///   CRC-32 trailer. Rewritten through a temporary file by [`HistoryStore::flush`].
+///
+/// Records appended after the last flush are indexed again on [`HistoryStore::open`]; a torn
+/// record at the end of `entries` (crash mid-write) is cut off there.
+pub struct HistoryStore {
+    dir: String,
+    entries: std::io::BufWriter<std::fs::File>,
+    // Bytes of complete records in `entries`.
+    size: u64,
+    // user_id -> record offsets in append order.
+    index: std::collections::BTreeMap<u64, Vec<u64>>
+}
+
+impl HistoryStore {
+    /// History directory of `T`: `data/leaderboard_<NAME>/history`.
+    pub fn dir<T: RatingSystem>() -> String {
+        leaderboard_dir::<T>() + "/history"
+    }
+
+    /// Opens the store in `dir`, creating an empty one when it does not exist.
+    pub fn open(dir: &str) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.to_string() + "/entries")?;
+        let file_len = file.metadata()?.len();
+
+        // A stale index (entries truncated behind its back) is rebuilt from the start.
+        let (mut size, mut index) = match std::fs::read(dir.to_string() + "/index").ok().and_then(|bytes| decode_index(&bytes)) {
+            Some((covered, index)) if covered <= file_len => (covered, index),
+            _ => (0, std::collections::BTreeMap::new())
+        };
+
+        let mut tail: Vec<u8> = Vec::with_capacity((file_len - size) as usize);
+        file.seek(std::io::SeekFrom::Start(size))?;
+        file.read_to_end(&mut tail)?;
+        let mut position = 0;
+        while let Some((entry, len)) = HistoryEntry::decode(&tail[position..]) {
+            index.entry(entry.user_id).or_default().push(size);
+            size += len as u64;
+            position += len;
+        }
+        if size < file_len {
+            file.set_len(size)?;
+        }
+        file.seek(std::io::SeekFrom::Start(size))?;
+
+        Ok(Self { dir: dir.to_string(), entries: std::io::BufWriter::new(file), size, index })
+    }
+
+    /// Bytes of complete records; the position a checkpoint records and [`Self::truncate`] restores.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of players with at least one entry.
+    pub fn users(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Appends `entry` to the end of the store.
+    pub fn append(&mut self, entry: &HistoryEntry) -> std::io::Result<()> {
+        let bytes = entry.encode();
+        self.entries.write_all(&bytes)?;
+        self.index.entry(entry.user_id).or_default().push(self.size);
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes the appended records and rewrites the index.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.entries.flush()?;
+        let path = self.dir.clone() + "/index";
+        let tmp_path = path.clone() + ".tmp";
+        std::fs::write(&tmp_path, encode_index(self.size, &self.index))?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Drops every record from byte `size` on, e.g. the entries written after a checkpoint.
+    pub fn truncate(&mut self, size: u64) -> std::io::Result<()> {
+        if size >= self.size {
+            return Ok(());
+        }
+        self.entries.flush()?;
+        let file = self.entries.get_mut();
+        file.set_len(size)?;
+        file.seek(std::io::SeekFrom::Start(size))?;
+        self.size = size;
+        self.index.retain(|_user_id, offsets| {
+            offsets.retain(|offset| *offset < size);
+            !offsets.is_empty()
+        });
+        self.flush()
+    }
+
+    /// Every entry of `user_id` in session order.
+    pub fn trajectory(&mut self, user_id: u64) -> std::io::Result<Vec<HistoryEntry>> {
+        let offsets = match self.index.get(&user_id) {
+            Some(offsets) => offsets.clone(),
+            None => return Ok(Vec::new())
+        };
+        self.entries.flush()?;
+        let mut file = std::fs::File::open(self.dir.clone() + "/entries")?;
+        let mut entries = Vec::with_capacity(offsets.len());
+        for offset in offsets {
+            file.seek(std::io::SeekFrom::Start(offset))?;
+            let mut len = [0u8; 4];
+            file.read_exact(&mut len)?;
+            let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize + RECORD_OVERHEAD as usize];
+            bytes[..4].copy_from_slice(&len);
+            file.read_exact(&mut bytes[4..])?;
+            match HistoryEntry::decode(&bytes) {
+                Some((entry, _len)) => entries.push(entry),
+                None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "history record at ".to_string() + offset.to_string().as_str() + " fails its checksum"))
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Rating of `user_id` after its last session committed at or before `time` (ms), or
+    /// `None` when it had not played by then. Inactivity decay is not recorded, so a decayed
+    /// player reports the rating of its last session.
+    pub fn rating_at(&mut self, user_id: u64, time: u64) -> std::io::Result<Option<u32>> {
+        let trajectory = self.trajectory(user_id)?;
+        let played = trajectory.partition_point(|entry| entry.commit_time <= time);
+        Ok(if played == 0 {None} else {Some(trajectory[played - 1].mmr_after)})
+    }
+
+    /// Every entry matching `predicate`, in append order (full scan).
+    pub fn find<F: Fn(&HistoryEntry) -> bool>(&mut self, predicate: F) -> std::io::Result<Vec<HistoryEntry>> {
+        self.entries.flush()?;
+        let file = std::fs::File::open(self.dir.clone() + "/entries")?;
+        let mut reader = std::io::BufReader::new(file.take(self.size));
+        let mut found = Vec::new();
+        let mut bytes: Vec<u8> = Vec::new();
+        loop {
+            bytes.clear();
+            let mut len = [0u8; 4];
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+            reader.read_exact(&mut len)?;
+            bytes.extend_from_slice(&len);
+            bytes.resize(u32::from_le_bytes(len) as usize + RECORD_OVERHEAD as usize, 0);
+            reader.read_exact(&mut bytes[4..])?;
+            match HistoryEntry::decode(&bytes) {
+                Some((entry, _len)) => if predicate(&entry) {
+                    found.push(entry);
+                },
+                None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "history record fails its checksum"))
+            }
+        }
+        Ok(found)
+    }
+
+    /// Every entry whose rating dropped by more than `-below`, i.e. `delta() < below`.
+    pub fn drops(&mut self, below: i64) -> std::io::Result<Vec<HistoryEntry>> {
+        self.find(|entry| entry.delta() < below)
+    }
+}
+
+/// Serializes the index layout described on [`HistoryStore`].
+fn encode_index(size: u64, index: &std::collections::BTreeMap<u64, Vec<u64>>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(HISTORY_INDEX_MAGIC.len() + 16 + index.len() * 24 + 4);
+    bytes.extend_from_slice(HISTORY_INDEX_MAGIC);
+    bytes.extend_from_slice(&size.to_le_bytes());
+    bytes.extend_from_slice(&(index.len() as u64).to_le_bytes());
+    for (user_id, offsets) in index.iter() {
+        bytes.extend_from_slice(&user_id.to_le_bytes());
+        bytes.extend_from_slice(&(offsets.len() as u64).to_le_bytes());
+        for offset in offsets.iter() {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+    }
+    let checksum = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+/// Parses an index written by [`encode_index`]; `None` when it is damaged.
+fn decode_index(bytes: &[u8]) -> Option<(u64, std::collections::BTreeMap<u64, Vec<u64>>)> {
+    if bytes.len() < HISTORY_INDEX_MAGIC.len() + 4 || &bytes[..HISTORY_INDEX_MAGIC.len()] != HISTORY_INDEX_MAGIC {
+        return None;
+    }
+    let (body, trailer) = bytes.split_at(bytes.len() - 4);
+    if u32::from_le_bytes(trailer.try_into().unwrap()) != crc32fast::hash(body) {
+        return None;
+    }
+    let mut cursor = Cursor { bytes: body, position: HISTORY_INDEX_MAGIC.len() };
+    let size = cursor.u64().ok()?;
+    let mut index = std::collections::BTreeMap::new();
+    for _ in 0..cursor.u64().ok()? {
+        let user_id = cursor.u64().ok()?;
+        let count = cursor.u64().ok()?;
+        let mut offsets = Vec::new();
+        for _ in 0..count {
+            offsets.push(cursor.u64().ok()?);
+        }
+        index.insert(user_id, offsets);
+    }
+    Some((size, index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(user_id: u64, session_id: u64, mmr_before: u32, mmr_after: u32) -> HistoryEntry {
+        HistoryEntry {
+            user_id,
+            session_id,
+            commit_time: session_id * 1000,
+            mmr_before,
+            mmr_after,
+            mode: "ranked_high_teir".to_string(),
+            components: vec![1.5, -2.0]
+        }
+    }
+
+    #[test]
+    fn history_queries_survive_reopen_and_torn_tail() {
+        let dir = std::env::temp_dir().join("mmr_history_test_".to_string() + std::process::id().to_string().as_str());
+        let dir = dir.to_str().unwrap().to_string();
+        if std::path::Path::new(&dir).exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let mut store = HistoryStore::open(&dir).unwrap();
+        store.append(&entry(1, 1, 1500, 1520)).unwrap();
+        store.append(&entry(2, 1, 1500, 1440)).unwrap();
+        store.flush().unwrap();
+        // Appended after the last index flush: indexed again by `open`.
+        store.append(&entry(1, 2, 1520, 1460)).unwrap();
+        let checkpoint = store.size();
+        store.append(&entry(1, 3, 1460, 1480)).unwrap();
+        drop(store);
+
+        // A torn record at the end is cut off.
+        let size = std::fs::metadata(dir.clone() + "/entries").unwrap().len();
+        let mut file = std::fs::OpenOptions::new().append(true).open(dir.clone() + "/entries").unwrap();
+        file.write_all(&entry(2, 4, 1440, 1400).encode()[..20]).unwrap();
+        drop(file);
+
+        let mut store = HistoryStore::open(&dir).unwrap();
+        assert_eq!(store.size(), size);
+        assert_eq!(store.users(), 2);
+        let trajectory: Vec<u32> = store.trajectory(1).unwrap().iter().map(|entry| entry.mmr_after).collect();
+        assert_eq!(trajectory, vec![1520, 1460, 1480]);
+        assert_eq!(store.trajectory(1).unwrap()[0], entry(1, 1, 1500, 1520));
+        assert_eq!(store.rating_at(1, 999).unwrap(), None);
+        assert_eq!(store.rating_at(1, 2500).unwrap(), Some(1460));
+        let drops: Vec<(u64, u64)> = store.drops(-50).unwrap().iter().map(|entry| (entry.user_id, entry.session_id)).collect();
+        assert_eq!(drops, vec![(2, 1), (1, 2)]);
+
+        // Resume from a checkpoint: later entries are gone, also after reopening.
+        store.truncate(checkpoint).unwrap();
+        store.append(&entry(2, 3, 1440, 1450)).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let mut store = HistoryStore::open(&dir).unwrap();
+        assert_eq!(store.rating_at(1, u64::MAX).unwrap(), Some(1460));
+        assert_eq!(store.rating_at(2, u64::MAX).unwrap(), Some(1450));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+// Rejected rows listed with their line number in a report; the rest are only counted.
+const MAX_REPORTED_ERRORS: usize = 10;
+
+/// Per-file outcome of a [`JsonlReader`] pass.
+#[derive(Clone, Debug, Default)]
+pub struct JsonlReport {
+    pub path: String,
+    // Non-empty lines read so far.
+    pub rows: u64,
+    pub rejected: u64,
+    // (line number, reason) of the first rejected rows.
+    pub errors: Vec<(u64, String)>
+}
+
+impl std::fmt::Display for JsonlReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Summary line followed by the first rejected lines.
+        write!(f, "file:{},rows:{},rejected:{}", self.path, self.rows, self.rejected)?;
+        for (line, error) in self.errors.iter() {
+            write!(f, "\n  line {}: {}", line, error)?;
+        }
+        if self.rejected > self.errors.len() as u64 {
+            f.write_str("\n  ...")?;
+        }
+        Ok(())
+    }
+}
+
+/// Line layout read by a [`JsonlReader`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LineFormat {
+    // One JSON object per line.
+    #[default]
+    Json,
+    // Legacy `key:value,key:value` lines (quotes and braces are dropped); every value is
+    // handed to `T` as a string.
+    KeyValue
+}
+
+/// Streaming reader of one JSON object per line, deserialized into `T`.
+///
+/// Yields `(line number, row)` for every valid line. Blank lines are skipped; lines that are
+/// not valid JSON or do not match `T` are counted in the [`JsonlReport`] instead of stopping
+/// the stream. Consumers reject rows that fail their own checks with [`JsonlReader::reject`].
+/// The legacy key:value files are read the same way with [`LineFormat::KeyValue`].
+pub struct JsonlReader<T, R = BufReader<File>> {
+    input: R,
+    format: LineFormat,
+    buffer: Vec<u8>,
+    line_number: u64,
+    // Bytes consumed from the start of the file.
+    offset: u64,
+    // (byte offset, line number) just before the last yielded row.
+    row_start: (u64, u64),
+    report: JsonlReport,
+    marker: std::marker::PhantomData<T>
+}
+
+impl<T: DeserializeOwned> JsonlReader<T> {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        Ok(Self::from_reader(path, BufReader::new(File::open(path)?)))
+    }
+
+    /// Opens `path` at byte `offset`, the start of the line after `line_number`, as returned
+    /// by [`JsonlReader::row_start`]. Line numbers in the report continue from there.
+    pub fn open_at(path: &str, offset: u64, line_number: u64) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = Self::from_reader(path, BufReader::new(file));
+        reader.offset = offset;
+        reader.line_number = line_number;
+        reader.row_start = (offset, line_number);
+        Ok(reader)
+    }
+}
+
+impl<T: DeserializeOwned, R: BufRead> JsonlReader<T, R> {
+    /// Reads from `input`; `path` only labels the report.
+    pub fn from_reader(path: &str, input: R) -> Self {
+        Self {
+            input,
+            format: LineFormat::Json,
+            buffer: Vec::new(),
+            line_number: 0,
+            offset: 0,
+            row_start: (0, 0),
+            report: JsonlReport { path: path.to_string(), ..JsonlReport::default() },
+            marker: std::marker::PhantomData
+        }
+    }
+
+    pub fn with_format(mut self, format: LineFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Counts the row at `line_number` as rejected.
+    pub fn reject(&mut self, line_number: u64, error: String) {
+        self.report.rejected += 1;
+        if self.report.errors.len() < MAX_REPORTED_ERRORS {
+            self.report.errors.push((line_number, error));
+        }
+    }
+
+    pub fn report(&self) -> &JsonlReport {
+        &self.report
+    }
+
+    /// (byte offset, line number) just before the last yielded row: reopening there with
+    /// [`JsonlReader::open_at`] yields that row again.
+    pub fn row_start(&self) -> (u64, u64) {
+        self.row_start
+    }
+
+    pub fn into_report(self) -> JsonlReport {
+        self.report
+    }
+}
+
+impl<T: DeserializeOwned, R: BufRead> Iterator for JsonlReader<T, R> {
+    type Item = (u64, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buffer.clear();
+            let start = (self.offset, self.line_number);
+            match self.input.read_until(b'\n', &mut self.buffer) {
+                Ok(0) => return None,
+                Ok(read) => self.offset += read as u64,
+                Err(error) => {
+                    // The position in the stream is unknown after an I/O error.
+                    self.reject(self.line_number + 1, "read error: ".to_string() + error.to_string().as_str());
+                    return None;
+                }
+            }
+            self.line_number += 1;
+            if self.buffer.iter().all(|byte| byte.is_ascii_whitespace()) {
+                continue;
+            }
+            self.report.rows += 1;
+            let row = match self.format {
+                LineFormat::Json => serde_json::from_slice::<T>(&self.buffer).map_err(|error| error.to_string()),
+                LineFormat::KeyValue => key_value_object(&self.buffer).and_then(|object| serde_json::from_value::<T>(object).map_err(|error| error.to_string()))
+            };
+            match row {
+                Ok(row) => {
+                    self.row_start = start;
+                    return Some((self.line_number, row));
+                },
+                Err(error) => self.reject(self.line_number, error)
+            }
+        }
+    }
+}
+
+/// Parses a legacy `key:value,...` line into a JSON object of string values. An item without
+/// exactly one `:` or a repeated key rejects the whole line.
+fn key_value_object(line: &[u8]) -> Result<serde_json::Value, String> {
+    let line = std::str::from_utf8(line).map_err(|_| "line is not valid utf-8".to_string())?;
+    let line = line.trim().replace(['{', '}', '"'], "");
+    let mut object = serde_json::Map::new();
+    for item in line.split(',') {
+        let (key, value) = match item.split_once(':') {
+            Some((key, value)) if !key.is_empty() && !value.contains(':') => (key, value),
+            _ => return Err(format!("expected key:value, got \"{}\"", item))
+        };
+        if object.insert(key.to_string(), serde_json::Value::String(value.to_string())).is_some() {
+            return Err(format!("duplicate key \"{}\"", key));
+        }
+    }
+    Ok(serde_json::Value::Object(object))
+}
+
+/// One row of the userstat dataset.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UserStatRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub user_id: u64,
+    #[serde(deserialize_with = "de_hex_u64")]
+    pub session_id: u64,
+    #[serde(deserialize_with = "de_u64")]
+    pub commit_time: u64,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub battle_score: u32,
+    #[serde(default, deserialize_with = "de_flag")]
+    pub early_quit: bool,
+    #[serde(default, deserialize_with = "de_flag")]
+    pub team_score_top_20_percent: bool,
+    // Used when the user faction dataset has no entry for the row.
+    #[serde(default)]
+    pub faction: Option<String>
+}
+
+/// One row of the user team dataset.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UserTeamRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub user_id: u64,
+    #[serde(deserialize_with = "de_u64")]
+    pub session_id: u64,
+    #[serde(deserialize_with = "de_u64")]
+    pub team: u64,
+    #[serde(default, deserialize_with = "de_flag")]
+    pub victory: bool
+}
+
+/// One row of the session mode dataset.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionModeRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub session_id: u64,
+    #[serde(default)]
+    pub mode: Option<String>
+}
+
+/// One row of `data/regs.json`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegistrationRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub user_id: u64,
+    #[serde(default, deserialize_with = "de_u64")]
+    pub registered_time: u64
+}
+
+/// One row of the user faction dataset.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UserFactionRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub user_id: u64,
+    #[serde(deserialize_with = "de_hex_u64")]
+    pub session_id: u64,
+    #[serde(default)]
+    pub mode: Option<String>
+}
+
+/// One row of a session-level dimension dataset (`data/session_mission.json`, a map or
+/// time-of-day export, ...): the session and any other fields.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionDimensionRecord {
+    #[serde(alias = "session_id_str", deserialize_with = "de_u64")]
+    pub session_id: u64,
+    #[serde(flatten)]
+    pub fields: std::collections::HashMap<String, serde_json::Value>
+}
+
+/// One row of `data/memory`: a row of the unfinished session, already assigned to its team.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionRowRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub user_id: u64,
+    #[serde(deserialize_with = "de_u64")]
+    pub session_id: u64,
+    #[serde(deserialize_with = "de_u64")]
+    pub commit_time: u64,
+    #[serde(default, deserialize_with = "de_u8")]
+    pub team: u8,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub battle_score: u32,
+    #[serde(default, deserialize_with = "de_flag")]
+    pub victories: bool,
+    #[serde(default, deserialize_with = "de_flag")]
+    pub early_quit: bool,
+    #[serde(default, deserialize_with = "de_flag")]
+    pub team_score_top_20_percent: bool,
+    pub faction: String
+}
+
+/// One row of a legacy leaderboard `base` file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LeaderboardRowRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub user_id: u64,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub mmr: u32,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub battles: u32,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub victories: u32,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub early_quites: u32,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub top_20: u32,
+    #[serde(default, deserialize_with = "de_u32")]
+    pub battle_score: u32,
+    #[serde(default, deserialize_with = "de_u64")]
+    pub last_session: u64,
+    // Algorithm state written after the row fields (`rating`, `mu`, ...).
+    #[serde(flatten)]
+    pub fields: std::collections::HashMap<String, serde_json::Value>
+}
+
+/// One row of a legacy leaderboard `battle_faction` file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FactionBattlesRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub user_id: u64,
+    pub faction: String,
+    #[serde(deserialize_with = "de_u64")]
+    pub battles: u64
+}
+
+/// One line of a `session_classification_8` file written by `leaderboard-8`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionClassRecord {
+    #[serde(deserialize_with = "de_u64")]
+    pub session_id: u64,
+    // Class names separated by `|`.
+    #[serde(default)]
+    pub classes: String
+}
+
+/// Unsigned integer given as a JSON number or a decimal string.
+fn de_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(number) => number.as_u64().ok_or_else(|| serde::de::Error::custom(format!("expected unsigned integer, got {}", number))),
+        serde_json::Value::String(str) => str.trim().parse::<u64>().map_err(|_| serde::de::Error::custom(format!("expected unsigned integer, got \"{}\"", str))),
+        other => Err(serde::de::Error::custom(format!("expected unsigned integer, got {}", other)))
+    }
+}
+
+fn de_u32<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let value = de_u64(deserializer)?;
+    u32::try_from(value).map_err(|_| serde::de::Error::custom(format!("{} is out of range", value)))
+}
+
+fn de_u8<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let value = de_u64(deserializer)?;
+    u8::try_from(value).map_err(|_| serde::de::Error::custom(format!("{} is out of range", value)))
+}
+
+/// Session identifier exported as hexadecimal text; a bare JSON number is read by its digits
+/// (`10` is `0x10`), as the exporters emit the same digits either way.
+fn de_hex_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let str = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(number) if number.is_u64() => number.to_string(),
+        serde_json::Value::String(str) => str,
+        other => return Err(serde::de::Error::custom(format!("expected hexadecimal id, got {}", other)))
+    };
+    u64::from_str_radix(str.trim(), 16).map_err(|_| serde::de::Error::custom(format!("expected hexadecimal id, got \"{}\"", str)))
+}
+
+/// Flag given as a bool, `0`/`1` or `"0"`/`"1"`; `null` is `false`.
+fn de_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(flag) => Ok(flag),
+        serde_json::Value::Null => Ok(false),
+        serde_json::Value::Number(number) => match number.as_u64() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(serde::de::Error::custom(format!("expected flag, got {}", number)))
+        },
+        serde_json::Value::String(str) => match str.trim() {
+            "0" | "false" => Ok(false),
+            "1" | "true" => Ok(true),
+            _ => Err(serde::de::Error::custom(format!("expected flag, got \"{}\"", str)))
+        },
+        other => Err(serde::de::Error::custom(format!("expected flag, got {}", other)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jsonl_reader_parses_and_reports_rejected_rows() {
+        let data = concat!(
+            "{\"user_id\":1,\"session_id\":\"1f\",\"commit_time\":\"1700000000000\",\"faction\":\"a, \\\"b\\\": c\",\"extra\":{\"nested\":[1,2]},\"early_quit\":1}\n",
+            "\n",
+            "{\"user_id\":\"x\",\"session_id\":\"1\",\"commit_time\":1}\n",
+            "{\"user_id\":2,\"session_id\":10,\"commit_time\":1,\"battle_score\":512,\"team_score_top_20_percent\":true}\n",
+            "user_id:3,session_id:1\n"
+        );
+        let mut reader: JsonlReader<UserStatRecord, &[u8]> = JsonlReader::from_reader("userstat", data.as_bytes());
+        let rows: Vec<(u64, UserStatRecord)> = reader.by_ref().collect();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].0, 1);
+        assert_eq!(rows[0].1.session_id, 0x1f);
+        assert_eq!(rows[0].1.faction.as_deref(), Some("a, \"b\": c"));
+        assert!(rows[0].1.early_quit);
+        assert_eq!((rows[1].0, rows[1].1.session_id, rows[1].1.battle_score), (4, 0x10, 512));
+        assert!(rows[1].1.team_score_top_20_percent);
+
+        let report = reader.into_report();
+        assert_eq!((report.rows, report.rejected), (4, 2));
+        assert_eq!(report.errors.iter().map(|error| error.0).collect::<Vec<u64>>(), vec![3, 5]);
+
+        let data = "{\"session_id_str\":\"12\",\"mission\":\"m1\"}\n{\"session_id\":13,\"map\":7}\n";
+        let rows: Vec<SessionDimensionRecord> = JsonlReader::from_reader("dimension", data.as_bytes()).map(|(_line, row)| row).collect();
+        assert_eq!((rows[0].session_id, rows[0].fields["mission"].as_str()), (12, Some("m1")));
+        assert_eq!((rows[1].session_id, rows[1].fields["map"].as_u64()), (13, Some(7)));
+
+        // Legacy key:value lines: values are strings, malformed items reject the line.
+        let data = "session_id:12,classes:team_1_win|disbalance_team_1\n\"session_id\":13\nsession_id:x,classes:a\nsession_id:14,classes:a:b\nsession_id:15,session_id:16\n";
+        let mut reader: JsonlReader<SessionClassRecord, &[u8]> = JsonlReader::from_reader("classification", data.as_bytes()).with_format(LineFormat::KeyValue);
+        let rows: Vec<(u64, SessionClassRecord)> = reader.by_ref().collect();
+        assert_eq!(rows.iter().map(|row| (row.0, row.1.session_id, row.1.classes.as_str())).collect::<Vec<(u64, u64, &str)>>(), vec![(1, 12, "team_1_win|disbalance_team_1"), (2, 13, "")]);
+        assert_eq!(reader.report().errors.iter().map(|error| error.0).collect::<Vec<u64>>(), vec![3, 4, 5]);
+    }
+}
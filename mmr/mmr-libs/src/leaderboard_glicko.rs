@@ -0,0 +1,344 @@
+use tokio::fs::File;
+use tokio::io::BufWriter;
+
+use crate::history::HistoryEntry;
+use crate::math::{glicko2_e, glicko2_update, GLICKO2_SCALE};
+use crate::params::{ModeParams, SeasonParams};
+use crate::rating::{RatingSystem, SessionTeams};
+use crate::types::{ChangeRecordGlicko, GlickoRating, GlickoUpdate, LeaderboardChangeV1, LeaderboardGlicko, LeaderboardRow, MMRChangeDebugGlicko, MMRType, UserBattleRow};
+use crate::{modes, season, snapshot, writer};
+use crate::snapshot::SnapshotError;
+
+/// Rating assigned to a player on first appearance.
+pub const GLICKO_DEFAULT_RATING: f64 = 1500.0;
+/// Deviation of an unrated player; also the upper bound of inactivity widening.
+pub const GLICKO_DEFAULT_DEVIATION: f64 = 350.0;
+/// Volatility of an unrated player.
+pub const GLICKO_DEFAULT_VOLATILITY: f64 = 0.06;
+/// System constant constraining the volatility change per rating period.
+pub const GLICKO_TAU: f64 = 0.5;
+/// Length of one idle rating period in `commit_time` units (one day in milliseconds).
+pub const GLICKO_PERIOD: u64 = 24 * 60 * 60 * 1000;
+
+impl LeaderboardGlicko {
+    /// Constructs a `LeaderboardGlicko` by restoring persisted state from disk.
+    ///
+    /// Reads the binary snapshot `data/leaderboard_glicko/snapshot` (see
+    /// [`snapshot::encode`]), the Glicko-2 state in its rating section. When it does not exist
+    /// yet, the state is migrated from the text files `base` (rows with `rating`, `deviation`
+    /// and `volatility`) and `battle_faction`; a row without them starts at its MMR with the
+    /// unrated deviation and volatility.
+    ///
+    /// Returns an empty leaderboard when no files are present and panics on a corrupted
+    /// snapshot.
+    pub fn new() -> Self {
+        Self::open("data/leaderboard_glicko").unwrap_or_else(|error| panic!("data/leaderboard_glicko: {}", error))
+    }
+
+    /// Restores a leaderboard from the snapshot (or legacy files) in `dir`, as [`Self::new`]
+    /// does for `data/leaderboard_glicko`; fails on a corrupted snapshot or a rejected legacy line.
+    pub fn open(dir: &str) -> Result<Self, SnapshotError> {
+        let snapshot = snapshot::load(dir, &["rating", "deviation", "volatility"])?;
+        let glicko = snapshot.users.values().map(|row| {
+            let rating = match snapshot.ratings.get(&row.user_id).map(|values| values.as_slice()) {
+                Some([rating, deviation, volatility]) => GlickoRating { rating: *rating, deviation: *deviation, volatility: *volatility },
+                _ => GlickoRating { rating: row.mmr as f64, deviation: GLICKO_DEFAULT_DEVIATION, volatility: GLICKO_DEFAULT_VOLATILITY }
+            };
+            (row.user_id, rating)
+        }).collect();
+
+        Ok(Self {
+            users: snapshot.users,
+            glicko,
+            battle_faction_hash: snapshot.battle_faction_hash
+        })
+    }
+
+    /// Persists the current in-memory leaderboard state to `data/leaderboard_glicko/snapshot`
+    /// (users, `battle_faction_hash` and the Glicko-2 state in one checksummed file).
+    pub async fn write(&self) {
+        self.write_to("data/leaderboard_glicko").await
+    }
+
+    /// Persists the leaderboard state to `<dir>/snapshot`.
+    pub async fn write_to(&self, dir: &str) {
+        let ratings = self.glicko.iter().map(|(user_id, rating)| (*user_id, vec![rating.rating, rating.deviation, rating.volatility])).collect();
+        let path = dir.to_string() + "/snapshot";
+        snapshot::write(&path, &self.users, &std::collections::BTreeMap::new(), &self.battle_faction_hash, &std::collections::HashMap::new(), &ratings).await.unwrap_or_else(|error| panic!("{}: {}", path, error));
+    }
+
+    /// Returns the stored Glicko-2 state for `user_id`, or the unrated defaults.
+    pub fn get_rating(&self, user_id: u64) -> GlickoRating {
+        match self.glicko.get(&user_id) {
+            Some(rating) => *rating,
+            None => GlickoRating {
+                rating: GLICKO_DEFAULT_RATING,
+                deviation: GLICKO_DEFAULT_DEVIATION,
+                volatility: GLICKO_DEFAULT_VOLATILITY
+            }
+        }
+    }
+
+    /// Returns the Glicko-2 state of `user_id` as seen at `commit_time`.
+    ///
+    /// The deviation widens by one volatility step per full [`GLICKO_PERIOD`] elapsed since
+    /// the player's `last_session`, capped at [`GLICKO_DEFAULT_DEVIATION`].
+    pub fn get_rating_at(&self, user_id: u64, commit_time: u64) -> GlickoRating {
+        let mut rating = self.get_rating(user_id);
+        if let Some(row) = self.users.get(&user_id) {
+            let idle_periods = (commit_time.saturating_sub(row.last_session) / GLICKO_PERIOD) as f64;
+            rating.deviation = (rating.deviation.powi(2) + idle_periods * rating.volatility.powi(2) * GLICKO2_SCALE.powi(2))
+                .sqrt()
+                .min(GLICKO_DEFAULT_DEVIATION);
+        }
+        rating
+    }
+
+    /// Returns the current `MMRType` for `user_id`.
+    ///
+    /// - `MMRType::MMR(rating)`        — 6+ battles.
+    /// - `MMRType::NotEnought(rating)` — provisional value (1–5 battles).
+    /// - `MMRType::None`               — user has never appeared in a session.
+    pub fn get_mmr(&self, user_id: u64) -> MMRType {
+        match self.users.get(&user_id) {
+            Some(user_row) if user_row.battles <= 5 => MMRType::NotEnought(user_row.mmr),
+            Some(user_row) => MMRType::MMR(user_row.mmr),
+            None => MMRType::None
+        }
+    }
+
+    /// Change record of one prepared update: the rating delta, the row before the session (a
+    /// fresh row at the prior rating for new players) and both states.
+    fn change_record(&self, update: &GlickoUpdate, cl_id: u16) -> ChangeRecordGlicko {
+        let (user, change, before, after) = update;
+        let user_row = self.users.get(&user.user_id).cloned().unwrap_or(LeaderboardRow {
+            user_id: user.user_id,
+            mmr: before.rating as u32,
+            battles: 0,
+            victories: 0,
+            early_quites: 0,
+            top_20: 0,
+            battle_score: 0,
+            last_session: 0
+        });
+        (change.clone(), (after.rating - before.rating) as i32, user_row, MMRChangeDebugGlicko(before.rating, before.deviation, before.volatility, after.rating, after.deviation, after.volatility), cl_id)
+    }
+
+    /// Stores the new Glicko-2 state of a player and accumulates the row counters.
+    fn set_change(&mut self, user: &UserBattleRow, rating: GlickoRating) {
+        *self.battle_faction_hash.entry((user.user_id, user.faction.clone())).or_insert(0) += 1;
+        let row = self.users.entry(user.user_id).or_insert(LeaderboardRow {
+            user_id: user.user_id,
+            mmr: 0,
+            battles: 0,
+            victories: 0,
+            early_quites: 0,
+            top_20: 0,
+            battle_score: 0,
+            last_session: 0
+        });
+        row.mmr = rating.rating.max(0.0) as u32;
+        row.battles += 1;
+        row.victories += if user.victories {1} else {0};
+        row.early_quites += if user.early_quit {1} else {0};
+        row.top_20 += if user.team_score_top_20_percent {1} else {0};
+        row.battle_score += user.battle_score;
+        row.last_session = user.commit_time;
+        self.glicko.insert(user.user_id, rating);
+    }
+}
+
+impl Default for LeaderboardGlicko {
+    fn default() -> Self {
+        <Self as RatingSystem>::empty()
+    }
+}
+
+impl RatingSystem for LeaderboardGlicko {
+    type Change = ChangeRecordGlicko;
+    type Prepared = Vec<GlickoUpdate>;
+    const NAME: &'static str = "glicko";
+
+    fn restore_from(dir: &str) -> Result<Self, SnapshotError> {
+        LeaderboardGlicko::open(dir)
+    }
+
+    fn empty() -> Self {
+        Self {
+            users: std::collections::HashMap::new(),
+            glicko: std::collections::HashMap::new(),
+            battle_faction_hash: std::collections::HashMap::new()
+        }
+    }
+
+    async fn persist_to(&self, dir: &str) {
+        self.write_to(dir).await
+    }
+
+    fn seed_from(&mut self, user_id: u64, global: &Self, params: &ModeParams) -> bool {
+        if self.users.contains_key(&user_id) {
+            return false;
+        }
+        let (global_row, row) = match global.users.get(&user_id).and_then(|global_row| modes::seed_row(global_row, params).map(|row| (global_row, row))) {
+            Some(rows) => rows,
+            None => return false
+        };
+        let rating = global.get_rating(user_id);
+        self.glicko.insert(user_id, GlickoRating {
+            rating: rating.rating,
+            deviation: modes::seed_spread(rating.deviation, GLICKO_DEFAULT_DEVIATION, global_row, &row),
+            volatility: rating.volatility
+        });
+        self.users.insert(user_id, row);
+        true
+    }
+
+    fn season_reset(&mut self, params: &SeasonParams) -> u64 {
+        season::soft_reset_spread(&mut self.users, &mut self.glicko, params, GLICKO_DEFAULT_DEVIATION, |state| (&mut state.rating, &mut state.deviation))
+    }
+
+    fn get_mmr(&self, user_id: u64) -> MMRType {
+        LeaderboardGlicko::get_mmr(self, user_id)
+    }
+
+    fn get_battles(&self, user_id: u64) -> u32 {
+        self.users.get(&user_id).map(|row| row.battles).unwrap_or(0)
+    }
+
+    fn get_users(&self) -> &std::collections::HashMap<u64, LeaderboardRow> {
+        &self.users
+    }
+
+    /// Rates every player as one Glicko-2 period of pairwise games against the top 3 of every
+    /// other team (1 against a team it beat, 0 against a team that beat it, 0.5 when both or
+    /// neither team won).
+    ///
+    /// All players are rated against pre-session opponent states, with deviations widened
+    /// for inactivity via [`LeaderboardGlicko::get_rating_at`], then applied together.
+    fn prepare_session(&self, teams: &SessionTeams) -> Vec<GlickoUpdate> {
+        let mut updates: Vec<GlickoUpdate> = Vec::new();
+        for (index, (team_rows, team_res)) in teams.teams.iter().zip(teams.results.iter()).enumerate() {
+            // Games against the top 3 of every other team, and their MMRs for the change record.
+            let mut games: Vec<(GlickoRating, f64)> = Vec::new();
+            let mut top_3: Vec<MMRType> = Vec::new();
+            for (opponents_mmr, opponents_res) in teams.teams_mmr.iter().zip(teams.results.iter()).enumerate()
+                .filter(|(other, _)| *other != index)
+                .map(|(_, opponents)| opponents) {
+                let score = if team_res == opponents_res {0.5} else if *team_res {1.0} else {0.0};
+                for opponent in opponents_mmr.0.iter().take(3) {
+                    games.push((self.get_rating_at(opponent.0, opponent.2.commit_time), score));
+                    top_3.push(opponent.1.clone());
+                }
+            }
+            for user in team_rows.iter() {
+                let before = self.get_rating_at(user.user_id, user.commit_time);
+                let after = glicko2_update(before, &games, GLICKO_TAU);
+                let change = LeaderboardChangeV1 {
+                    user_id: user.user_id,
+                    session_id: user.session_id,
+                    mmr: self.get_mmr(user.user_id),
+                    top_3: top_3.clone(),
+                    victory: *team_res,
+                    early_quite: user.early_quit,
+                    top_20: user.team_score_top_20_percent,
+                    battle_score: user.battle_score,
+                    battle_score_muld: teams.battle_score_muld(user),
+                    faction: user.faction.clone(),
+                    last_session: user.commit_time
+                };
+                updates.push((user.clone(), change, before, after));
+            }
+        }
+        updates
+    }
+
+    async fn apply_session(&mut self, prepared: Vec<GlickoUpdate>, cl_id: u16, sender_tasks: flume::Sender<ChangeRecordGlicko>) {
+        for update in prepared {
+            let record = self.change_record(&update, cl_id);
+            self.set_change(&update.0, update.3);
+            let _ = sender_tasks.send(record);
+        }
+    }
+
+    async fn preview_session(&self, prepared: Vec<GlickoUpdate>, cl_id: u16) -> Vec<ChangeRecordGlicko> {
+        prepared.iter().map(|update| self.change_record(update, cl_id)).collect()
+    }
+
+    /// Glicko-2 expected score of team 1's average rating against team 2's average rating,
+    /// with the combined average deviation of both teams.
+    fn win_probability(&self, teams: &SessionTeams) -> f64 {
+        let avg = |team: &[UserBattleRow]| {
+            let (rating, deviation) = team.iter().fold((0.0, 0.0), |base, user| {
+                let rating = self.get_rating_at(user.user_id, user.commit_time);
+                (base.0 + rating.rating, base.1 + rating.deviation.powi(2))
+            });
+            (rating / team.len() as f64, deviation / team.len() as f64)
+        };
+        let (rating_1, deviation_1) = avg(&teams.team_1);
+        let (rating_2, deviation_2) = avg(&teams.team_2);
+        glicko2_e(
+            (rating_1 - GLICKO_DEFAULT_RATING) / GLICKO2_SCALE,
+            (rating_2 - GLICKO_DEFAULT_RATING) / GLICKO2_SCALE,
+            (deviation_1 + deviation_2).sqrt() / GLICKO2_SCALE
+        )
+    }
+
+    async fn write_change(change: ChangeRecordGlicko, change_files: &mut [BufWriter<File>]) {
+        let (change, mmr_diff, user_row, debug, cl_id) = change;
+        writer::write_change_glicko(change, mmr_diff, user_row, &mut change_files[cl_id as usize], debug).await;
+    }
+
+    fn history_entry(change: &ChangeRecordGlicko) -> HistoryEntry {
+        // The stored MMR is the rating after the session, see `set_change`.
+        let (change, _mmr_diff, user_row, debug, _cl_id) = change;
+        HistoryEntry {
+            user_id: change.user_id,
+            session_id: change.session_id,
+            commit_time: change.last_session,
+            mmr_before: user_row.mmr,
+            mmr_after: debug.3.max(0.0) as u32,
+            mode: String::new(),
+            components: vec![debug.0, debug.1, debug.2, debug.3, debug.4, debug.5]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factions::FactionRegistry;
+    use crate::memory::SessionMemory;
+
+    #[tokio::test]
+    async fn glicko_rates_every_team_and_round_trips_the_snapshot() {
+        let factions: FactionRegistry = ron::from_str("(factions: [\"faction_1\", \"faction_2\", \"faction_3\"])").unwrap();
+        let rows: Vec<UserBattleRow> = (1..=6_u64).map(|user_id| {
+            let faction = "faction_".to_string() + user_id.div_ceil(2).to_string().as_str();
+            UserBattleRow { user_id, session_id: 1, commit_time: 10_000, team: 0, battle_score: 1000, victories: user_id <= 2, early_quit: false, team_score_top_20_percent: false, faction }
+        }).collect();
+        let mut leaderboard = LeaderboardGlicko::empty();
+        let teams = SessionTeams::new(&leaderboard, &SessionMemory { now_session_id: 1, rows }, &factions);
+        let updates = leaderboard.prepare_session(&teams);
+        assert_eq!(updates.len(), 6);
+        // Team 1 beat both other teams; teams 2 and 3 lost to team 1 and drew with each other.
+        let after = |user_id: u64| updates.iter().find(|update| update.0.user_id == user_id).unwrap().3;
+        assert!(after(1).rating > GLICKO_DEFAULT_RATING);
+        assert!(after(3).rating < GLICKO_DEFAULT_RATING);
+        assert_eq!(after(3).rating, after(5).rating);
+        assert_eq!(updates[0].1.top_3.len(), 4);
+
+        // A preview streams the same changes without applying them.
+        let previewed: Vec<HistoryEntry> = leaderboard.preview_session(updates.clone(), 0).await.iter().map(LeaderboardGlicko::history_entry).collect();
+        assert!(leaderboard.users.is_empty());
+        let (sender, receiver) = flume::unbounded();
+        leaderboard.apply_session(updates, 0, sender).await;
+        assert_eq!(receiver.drain().map(|change| LeaderboardGlicko::history_entry(&change)).collect::<Vec<HistoryEntry>>(), previewed);
+        let dir = std::env::temp_dir().join("mmr_glicko_test_".to_string() + std::process::id().to_string().as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        leaderboard.write_to(&dir).await;
+        let restored = LeaderboardGlicko::open(&dir).unwrap();
+        assert_eq!((restored.users, restored.glicko), (leaderboard.users, leaderboard.glicko));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
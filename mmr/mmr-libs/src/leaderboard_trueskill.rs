@@ -0,0 +1,345 @@
+use tokio::fs::File;
+use tokio::io::BufWriter;
+
+use crate::history::HistoryEntry;
+use crate::math::{norm_cdf, trueskill_update};
+use crate::params::{ModeParams, SeasonParams};
+use crate::rating::{RatingSystem, SessionTeams};
+use crate::types::{ChangeRecordV1, LeaderboardChangeV1, LeaderboardRow, LeaderboardTrueSkill, MMRChangeDebug, MMRType, TrueSkillRating, TrueSkillUpdate, UserBattleRow};
+use crate::{modes, season, snapshot, writer};
+use crate::snapshot::SnapshotError;
+
+/// Mean skill assigned to a player on first appearance.
+pub const TRUESKILL_DEFAULT_MU: f64 = 1500.0;
+/// Skill uncertainty of an unrated player.
+pub const TRUESKILL_DEFAULT_SIGMA: f64 = 500.0;
+/// Per-game performance noise (half the default uncertainty, as in the reference model).
+pub const TRUESKILL_BETA: f64 = 250.0;
+/// Dynamic uncertainty added before every session so that sigma never collapses to zero.
+pub const TRUESKILL_TAU: f64 = 5.0;
+/// Team performance gap treated as a draw (used when both or neither team won).
+pub const TRUESKILL_DRAW_MARGIN: f64 = 50.0;
+/// Partial-play weight of a player who left the session early.
+pub const TRUESKILL_EARLY_QUIT_WEIGHT: f64 = 0.5;
+
+impl LeaderboardTrueSkill {
+    /// Constructs a `LeaderboardTrueSkill` by restoring persisted state from disk.
+    ///
+    /// Reads the binary snapshot `data/leaderboard_trueskill/snapshot` (see
+    /// [`snapshot::encode`]), (mu, sigma) in its rating section. When it does not exist yet,
+    /// the state is migrated from the text files `base` (rows with `mu` and `sigma`) and
+    /// `battle_faction`; a row without them starts at its MMR with the unrated sigma.
+    ///
+    /// Returns an empty leaderboard when no files are present and panics on a corrupted
+    /// snapshot.
+    pub fn new() -> Self {
+        Self::open("data/leaderboard_trueskill").unwrap_or_else(|error| panic!("data/leaderboard_trueskill: {}", error))
+    }
+
+    /// Restores a leaderboard from the snapshot (or legacy files) in `dir`, as [`Self::new`]
+    /// does for `data/leaderboard_trueskill`; fails on a corrupted snapshot or a rejected legacy line.
+    pub fn open(dir: &str) -> Result<Self, SnapshotError> {
+        let snapshot = snapshot::load(dir, &["mu", "sigma"])?;
+        let skill = snapshot.users.values().map(|row| {
+            let skill = match snapshot.ratings.get(&row.user_id).map(|values| values.as_slice()) {
+                Some([mu, sigma]) => TrueSkillRating { mu: *mu, sigma: *sigma },
+                _ => TrueSkillRating { mu: row.mmr as f64, sigma: TRUESKILL_DEFAULT_SIGMA }
+            };
+            (row.user_id, skill)
+        }).collect();
+
+        Ok(Self {
+            users: snapshot.users,
+            skill,
+            battle_faction_hash: snapshot.battle_faction_hash
+        })
+    }
+
+    /// Persists the current in-memory leaderboard state to
+    /// `data/leaderboard_trueskill/snapshot` (users, `battle_faction_hash` and (mu, sigma) in
+    /// one checksummed file).
+    pub async fn write(&self) {
+        self.write_to("data/leaderboard_trueskill").await
+    }
+
+    /// Persists the leaderboard state to `<dir>/snapshot`.
+    pub async fn write_to(&self, dir: &str) {
+        let ratings = self.skill.iter().map(|(user_id, skill)| (*user_id, vec![skill.mu, skill.sigma])).collect();
+        let path = dir.to_string() + "/snapshot";
+        snapshot::write(&path, &self.users, &std::collections::BTreeMap::new(), &self.battle_faction_hash, &std::collections::HashMap::new(), &ratings).await.unwrap_or_else(|error| panic!("{}: {}", path, error));
+    }
+
+    /// Returns the stored (mu, sigma) of `user_id`, or the unrated defaults.
+    pub fn get_skill(&self, user_id: u64) -> TrueSkillRating {
+        match self.skill.get(&user_id) {
+            Some(skill) => *skill,
+            None => TrueSkillRating {
+                mu: TRUESKILL_DEFAULT_MU,
+                sigma: TRUESKILL_DEFAULT_SIGMA
+            }
+        }
+    }
+
+    /// Returns the current `MMRType` for `user_id`.
+    ///
+    /// - `MMRType::MMR(mu)`        — 6+ battles.
+    /// - `MMRType::NotEnought(mu)` — provisional value (1–5 battles).
+    /// - `MMRType::None`           — user has never appeared in a session.
+    pub fn get_mmr(&self, user_id: u64) -> MMRType {
+        match self.users.get(&user_id) {
+            Some(user_row) if user_row.battles <= 5 => MMRType::NotEnought(user_row.mmr),
+            Some(user_row) => MMRType::MMR(user_row.mmr),
+            None => MMRType::None
+        }
+    }
+
+    /// Change record of one prepared update: the mu delta, the row before the session (a
+    /// fresh row at the prior mu for new players) and both states.
+    fn change_record(&self, update: &TrueSkillUpdate, cl_id: u16) -> ChangeRecordV1 {
+        let (user, change, before, after, weight, credit) = update;
+        let user_row = self.users.get(&user.user_id).cloned().unwrap_or(LeaderboardRow {
+            user_id: user.user_id,
+            mmr: before.mu as u32,
+            battles: 0,
+            victories: 0,
+            early_quites: 0,
+            top_20: 0,
+            battle_score: 0,
+            last_session: 0
+        });
+        (change.clone(), (after.mu - before.mu) as i32, user_row, MMRChangeDebug(before.mu as i32, before.sigma as i32, after.mu as i32, after.sigma as i32, *weight, *credit), cl_id)
+    }
+
+    /// Stores the new (mu, sigma) of a player and accumulates the row counters.
+    fn set_change(&mut self, user: &UserBattleRow, skill: TrueSkillRating) {
+        *self.battle_faction_hash.entry((user.user_id, user.faction.clone())).or_insert(0) += 1;
+        let row = self.users.entry(user.user_id).or_insert(LeaderboardRow {
+            user_id: user.user_id,
+            mmr: 0,
+            battles: 0,
+            victories: 0,
+            early_quites: 0,
+            top_20: 0,
+            battle_score: 0,
+            last_session: 0
+        });
+        row.mmr = skill.mu.max(0.0) as u32;
+        row.battles += 1;
+        row.victories += if user.victories {1} else {0};
+        row.early_quites += if user.early_quit {1} else {0};
+        row.top_20 += if user.team_score_top_20_percent {1} else {0};
+        row.battle_score += user.battle_score;
+        row.last_session = user.commit_time;
+        self.skill.insert(user.user_id, skill);
+    }
+}
+
+impl Default for LeaderboardTrueSkill {
+    fn default() -> Self {
+        <Self as RatingSystem>::empty()
+    }
+}
+
+impl RatingSystem for LeaderboardTrueSkill {
+    type Change = ChangeRecordV1;
+    type Prepared = Vec<TrueSkillUpdate>;
+    const NAME: &'static str = "trueskill";
+
+    fn restore_from(dir: &str) -> Result<Self, SnapshotError> {
+        LeaderboardTrueSkill::open(dir)
+    }
+
+    fn empty() -> Self {
+        Self {
+            users: std::collections::HashMap::new(),
+            skill: std::collections::HashMap::new(),
+            battle_faction_hash: std::collections::HashMap::new()
+        }
+    }
+
+    async fn persist_to(&self, dir: &str) {
+        self.write_to(dir).await
+    }
+
+    fn seed_from(&mut self, user_id: u64, global: &Self, params: &ModeParams) -> bool {
+        if self.users.contains_key(&user_id) {
+            return false;
+        }
+        let (global_row, row) = match global.users.get(&user_id).and_then(|global_row| modes::seed_row(global_row, params).map(|row| (global_row, row))) {
+            Some(rows) => rows,
+            None => return false
+        };
+        let skill = global.get_skill(user_id);
+        self.skill.insert(user_id, TrueSkillRating {
+            mu: skill.mu,
+            sigma: modes::seed_spread(skill.sigma, TRUESKILL_DEFAULT_SIGMA, global_row, &row)
+        });
+        self.users.insert(user_id, row);
+        true
+    }
+
+    fn season_reset(&mut self, params: &SeasonParams) -> u64 {
+        season::soft_reset_spread(&mut self.users, &mut self.skill, params, TRUESKILL_DEFAULT_SIGMA, |state| (&mut state.mu, &mut state.sigma))
+    }
+
+    fn get_mmr(&self, user_id: u64) -> MMRType {
+        LeaderboardTrueSkill::get_mmr(self, user_id)
+    }
+
+    fn get_battles(&self, user_id: u64) -> u32 {
+        self.users.get(&user_id).map(|row| row.battles).unwrap_or(0)
+    }
+
+    fn get_users(&self) -> &std::collections::HashMap<u64, LeaderboardRow> {
+        &self.users
+    }
+
+    /// Rates the session as one two-team game of a TrueSkill factor graph for every pair of
+    /// teams.
+    ///
+    /// - With more than two teams, the mean shifts of a player's games are added up and their
+    ///   variance reductions combined (precisions add), all from the pre-session state.
+    /// - Players who quit early take part with [`TRUESKILL_EARLY_QUIT_WEIGHT`] of their
+    ///   performance (partial play).
+    /// - The mean shift is scaled by the normalized battle score (`battle_score_muld / 1600`,
+    ///   clamped to `[0.5, 1.5]`): strong performers gain more on a win and lose less on a
+    ///   defeat. Draws (every team or no team won) are not scaled.
+    ///
+    /// Change records use the v1 format with `de:[mu, sigma, new_mu, new_sigma, weight, credit]`.
+    fn prepare_session(&self, teams: &SessionTeams) -> Vec<TrueSkillUpdate> {
+        let weight = |user: &UserBattleRow| if user.early_quit {TRUESKILL_EARLY_QUIT_WEIGHT} else {1.0};
+        let before: Vec<Vec<(TrueSkillRating, f64)>> = teams.teams.iter()
+            .map(|team| team.iter().map(|user| (self.get_skill(user.user_id), weight(user))).collect())
+            .collect();
+        // team -> player -> rating after each game against another team.
+        let mut games: Vec<Vec<Vec<TrueSkillRating>>> = before.iter().map(|team| vec![Vec::new(); team.len()]).collect();
+        for first in 0..before.len() {
+            for second in first + 1..before.len() {
+                let result = if teams.results[first] == teams.results[second] {0.5} else if teams.results[first] {1.0} else {0.0};
+                let (first_after, second_after) = trueskill_update(&before[first], &before[second], result, TRUESKILL_BETA, TRUESKILL_TAU, TRUESKILL_DRAW_MARGIN);
+                for (team, after) in [(first, first_after), (second, second_after)] {
+                    for (player, after) in after.into_iter().enumerate() {
+                        games[team][player].push(after);
+                    }
+                }
+            }
+        }
+
+        let mut updates: Vec<TrueSkillUpdate> = Vec::new();
+        for (index, ((team_rows, before), games)) in teams.teams.iter().zip(before).zip(games).enumerate() {
+            let team_res = teams.results[index];
+            let decided = teams.results.iter().any(|result| *result != team_res);
+            for ((user, (before, weight)), games) in team_rows.iter().zip(before).zip(games) {
+                let prior = before.sigma.powi(2) + TRUESKILL_TAU.powi(2);
+                let after = games.into_iter().reduce(|total, game| TrueSkillRating {
+                    mu: total.mu + (game.mu - before.mu),
+                    sigma: (total.sigma.powi(-2) + game.sigma.powi(-2) - prior.recip()).recip().sqrt()
+                }).unwrap_or(before);
+                let score_k = (teams.battle_score_muld(user) as f64 / 1600.0).clamp(0.5, 1.5);
+                let credit = if !decided {1.0} else if team_res {score_k} else {2.0 - score_k};
+                let after = TrueSkillRating {
+                    mu: before.mu + credit * (after.mu - before.mu),
+                    sigma: after.sigma
+                };
+                let change = LeaderboardChangeV1 {
+                    user_id: user.user_id,
+                    session_id: user.session_id,
+                    mmr: self.get_mmr(user.user_id),
+                    top_3: teams.opponents(index as u8 + 1).into_iter().take(3).collect(),
+                    victory: user.victories,
+                    early_quite: user.early_quit,
+                    top_20: user.team_score_top_20_percent,
+                    battle_score: user.battle_score,
+                    battle_score_muld: teams.battle_score_muld(user),
+                    faction: user.faction.clone(),
+                    last_session: user.commit_time
+                };
+                updates.push((user.clone(), change, before, after, weight, credit));
+            }
+        }
+        updates
+    }
+
+    async fn apply_session(&mut self, prepared: Vec<TrueSkillUpdate>, cl_id: u16, sender_tasks: flume::Sender<ChangeRecordV1>) {
+        for update in prepared {
+            let record = self.change_record(&update, cl_id);
+            self.set_change(&update.0, update.3);
+            let _ = sender_tasks.send(record);
+        }
+    }
+
+    async fn preview_session(&self, prepared: Vec<TrueSkillUpdate>, cl_id: u16) -> Vec<ChangeRecordV1> {
+        prepared.iter().map(|update| self.change_record(update, cl_id)).collect()
+    }
+
+    /// Probability that team 1's total performance exceeds team 2's:
+    /// `Φ((Σmu_1 − Σmu_2) / sqrt(n·beta² + Σsigma²))` over all `n` players of both teams.
+    fn win_probability(&self, teams: &SessionTeams) -> f64 {
+        let sum = |team: &[UserBattleRow]| team.iter().fold((0.0, 0.0), |base, user| {
+            let skill = self.get_skill(user.user_id);
+            (base.0 + skill.mu, base.1 + skill.sigma.powi(2))
+        });
+        let (mu_1, sigma2_1) = sum(&teams.team_1);
+        let (mu_2, sigma2_2) = sum(&teams.team_2);
+        let players = (teams.team_1.len() + teams.team_2.len()) as f64;
+        norm_cdf((mu_1 - mu_2) / (players * TRUESKILL_BETA.powi(2) + sigma2_1 + sigma2_2).sqrt())
+    }
+
+    async fn write_change(change: ChangeRecordV1, change_files: &mut [BufWriter<File>]) {
+        let (change, mmr_diff, user_row, debug, cl_id) = change;
+        writer::write_change(change, mmr_diff, user_row, &mut change_files[cl_id as usize], debug).await;
+    }
+
+    fn history_entry(change: &ChangeRecordV1) -> HistoryEntry {
+        // The stored MMR is mu after the session, see `set_change`.
+        let (change, _mmr_diff, user_row, debug, _cl_id) = change;
+        HistoryEntry {
+            user_id: change.user_id,
+            session_id: change.session_id,
+            commit_time: change.last_session,
+            mmr_before: user_row.mmr,
+            mmr_after: debug.2.max(0) as u32,
+            mode: String::new(),
+            components: vec![debug.0 as f64, debug.1 as f64, debug.2 as f64, debug.3 as f64, debug.4, debug.5]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factions::FactionRegistry;
+    use crate::memory::SessionMemory;
+
+    #[tokio::test]
+    async fn trueskill_rates_every_pair_of_teams_and_round_trips_the_snapshot() {
+        let factions: FactionRegistry = ron::from_str("(factions: [\"faction_1\", \"faction_2\", \"faction_3\"])").unwrap();
+        let rows: Vec<UserBattleRow> = (1..=6_u64).map(|user_id| {
+            let faction = "faction_".to_string() + user_id.div_ceil(2).to_string().as_str();
+            UserBattleRow { user_id, session_id: 1, commit_time: 10_000, team: 0, battle_score: 1000, victories: user_id <= 2, early_quit: false, team_score_top_20_percent: false, faction }
+        }).collect();
+        let mut leaderboard = LeaderboardTrueSkill::empty();
+        let teams = SessionTeams::new(&leaderboard, &SessionMemory { now_session_id: 1, rows: rows.clone() }, &factions);
+        let updates = leaderboard.prepare_session(&teams);
+        let after = |user_id: u64| updates.iter().find(|update| update.0.user_id == user_id).unwrap().3;
+        // Team 1 won both of its games, teams 2 and 3 lost one and drew one.
+        assert!(after(1).mu > TRUESKILL_DEFAULT_MU);
+        assert!(after(3).mu < TRUESKILL_DEFAULT_MU);
+        assert_eq!(after(3).mu, after(5).mu);
+
+        // Two games shrink sigma more than the single game of a two-team session.
+        let two = SessionTeams::new(&leaderboard, &SessionMemory { now_session_id: 1, rows: rows[..4].to_vec() }, &factions);
+        let single = leaderboard.prepare_session(&two);
+        assert!(after(1).sigma < single[0].3.sigma);
+
+        let (sender, _receiver) = flume::unbounded();
+        leaderboard.apply_session(updates, 0, sender).await;
+        let dir = std::env::temp_dir().join("mmr_trueskill_test_".to_string() + std::process::id().to_string().as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        leaderboard.write_to(&dir).await;
+        let restored = LeaderboardTrueSkill::open(&dir).unwrap();
+        assert_eq!((restored.users, restored.skill), (leaderboard.users, leaderboard.skill));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,15 @@
-use std::path::Path;
-use std::time::{Duration, Instant};
-use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::fs::File;
+use tokio::io::BufWriter;
 
-use crate::memory::read_lines;
-use crate::reader::reader;
-use crate::types::{Leaderboard, LeaderboardChangeV1, LeaderboardRow, MMRAgg, MMRChangeDebug, MMRPair, MMRType, TeamMMR};
-use crate::{math, statistic::{self, Statistic}, memory,datasets};
+use crate::calibration::{self, CalibrationEstimate, CalibrationFeatures};
+use crate::decay::{DecayState, DecayUndo};
+use crate::factions::FactionRegistry;
+use crate::history::HistoryEntry;
+use crate::params::{CalibrationParams, DiffMMRParams, MMRParams, ModeParams, SeasonParams};
+use crate::rating::{RatingSystem, SessionTeams};
+use crate::types::{ChangeRecordV1, Leaderboard, LeaderboardChangeV1, LeaderboardRow, MMRType};
+use crate::{math, memory, modes, season, snapshot, writer};
+use crate::snapshot::SnapshotError;
 
 impl Leaderboard {
     /// Estimates the initial calibrated MMR for a new player who just completed their 6th battle.
@@ -24,9 +28,16 @@ impl Leaderboard {
         let mut min_mmr: Option<(u32, u32)> = None;
         let mut sum_100: u32 = 0;
         let mut count_100: u32 = 0;
-        let user_count = self.battle_score_hash.len();
+        // Players flagged for review do not count as history.
+        let user_count = self.battle_score_hash.len() - self.bootstrap_excluded.iter().filter(|user_id| match self.users.get(user_id) {
+            Some(row) if row.battles > 0 => self.battle_score_hash.contains_key(&(row.battle_score / row.battles, **user_id)),
+            _ => false
+        }).count();
 
-        for ((battle_score, _uid), mmr) in self.battle_score_hash.range((math::max((score as i32)-50, 0) as u32, 0)..=(score+50, u64::MAX)) {
+        for ((battle_score, uid), mmr) in self.battle_score_hash.range((math::max((score as i32)-50, 0) as u32, 0)..=(score+50, u64::MAX)) {
+            if self.bootstrap_excluded.contains(uid) {
+                continue;
+            }
             let dist = ((score as i32) - (*battle_score as i32)).abs() as u32;
             if min_mmr == None || dist < min_mmr.unwrap().0 {
                 min_mmr = Some((dist, *mmr));
@@ -48,6 +59,19 @@ impl Leaderboard {
         // Fallback to nearest-neighbor with conservative downscale.
         Some(((min_mmr.unwrap().1 as f64) * 0.8) as u32)
     }
+
+    /// First calibrated MMR of a player completing the 6th battle with an average battle
+    /// `score` over the six battles; `provisional` is the row of the first five.
+    ///
+    /// With `calibration.neighbours > 0` the weighted k-NN [`calibration::estimate`] of the
+    /// five provisional battles is used, otherwise [`get_mmr_for_new`] for `score`.
+    async fn calibrated_mmr(&self, provisional: &LeaderboardRow, score: u32) -> Option<u32> {
+        if self.calibration.neighbours > 0 {
+            let features = CalibrationFeatures::from_row(provisional)?;
+            return calibration::estimate(&self.users, &self.battle_score_hash, &self.bootstrap_excluded, &features, &self.calibration).map(|estimate| estimate.mmr);
+        }
+        self.get_mmr_for_new(score).await
+    }
     /// Enmodes a single `LeaderboardChangeV1` into the pending change buffer.
     ///
     /// Changes are buffered and applied in bulk by [`set_changes`] or [`set_changes_lite`].
@@ -55,24 +79,65 @@ impl Leaderboard {
         self.sets.push(change);
     }
 
+    /// Change record of one buffered change against the current state: the applied MMR delta
+    /// (not the raw formula output) and the row before the session (a zero row for new users).
+    ///
+    /// Calibration model:
+    /// - up to 5 battles: direct score-based provisional MMR
+    /// - at 6th battle: bootstrap against historical neighbors
+    /// - after 6 battles: classic diff-based MMR updates
+    async fn change_record(&self, change: &LeaderboardChangeV1, cl_id: u16) -> ChangeRecordV1 {
+        let (diff_mmr, change_debug) = math::diff_mmr(change.victory, change.battle_score_muld as i32, change.top_3.clone(), change.mmr.clone(), change.early_quite, change.top_20, &self.params);
+        match self.users.get(&change.user_id) {
+            Some(user_row) => {
+                let new_mmr = if user_row.battles + 1 == 6 {
+                    match self.calibrated_mmr(user_row, (user_row.battle_score + change.battle_score) / (user_row.battles + 1)).await {
+                        Some(mmr) => mmr,
+                        None => (user_row.battle_score + change.battle_score) / (user_row.battles + 1)
+                    }
+                } else if user_row.battles + 1 > 6 {
+                    (math::max(user_row.mmr as i32 + diff_mmr, 0)) as u32
+                } else {
+                    (user_row.battle_score + change.battle_score) / (user_row.battles + 1)
+                };
+                (change.clone(), new_mmr as i32 - user_row.mmr as i32, user_row.clone(), change_debug, cl_id)
+            },
+            None => (change.clone(), change.battle_score as i32, LeaderboardRow {
+                user_id: change.user_id,
+                mmr: 0,
+                battles: 0,
+                victories: 0,
+                early_quites: 0,
+                top_20: 0,
+                battle_score: 0,
+                last_session: 0
+            }, change_debug, cl_id)
+        }
+    }
+
     /// Applies all buffered `LeaderboardChangeV1` entries to the in-memory leaderboard.
     ///
     /// For each change the calibration phase is resolved:
     /// - battles 1–5: provisional MMR is a running average of accumulated battle score.
-    /// - battle 6: MMR is bootstrapped from historical neighbors via [`get_mmr_for_new`].
+    /// - battle 6: MMR is bootstrapped from historical neighbors via [`get_mmr_for_new`], or
+    ///   the k-NN calibration when enabled (`calibrated_mmr`).
     /// - battles 7+: classic diff-based ELO update via `math::diff_mmr`.
     ///
     /// The `battle_score_hash` index is kept consistent for fully calibrated users.
+    /// Every applied change is sent to `sender_tasks` with the applied MMR delta and the row
+    /// before the session (a zero row for new users).
     /// When `setting_change` is `true` the pending buffer is cleared after processing.
-    pub async fn set_changes(&mut self, _cl_id: u16,
-        _sender_tasks: flume::Sender<(LeaderboardChangeV1, i32, LeaderboardRow, MMRChangeDebug, u16)>,
+    pub async fn set_changes(&mut self, cl_id: u16,
+        sender_tasks: flume::Sender<ChangeRecordV1>,
         setting_change: bool
     ) {
         // Apply buffered per-user changes produced from processed sessions.
         let changes = self.sets.clone();
 
         for change in changes.iter() {
-            let (diff_mmr, _change_debug) = math::diff_mmr(change.victory, change.battle_score_muld as i32, change.top_3.clone(), change.mmr.clone(), change.early_quite, change.top_20);
+            let record = self.change_record(change, cl_id).await;
+            // A rated session restarts the player's idle clock.
+            self.decay.played(change.user_id);
             match self.battle_faction_hash.get_mut(&(change.user_id, change.faction.clone())) {
                 Some(bc) => {
                     *bc = *bc + 1;
@@ -81,35 +146,15 @@ impl Leaderboard {
             }
             match self.users.get(&change.user_id) {
                 Some(user_row) => {
-                    // Calibration model:
-                    // - up to 5 battles: direct score-based provisional MMR
-                    // - at 6th battle: bootstrap against historical neighbors
-                    // - after 6 battles: classic diff-based MMR updates
-                    let new_mmr = if user_row.battles + 1 == 6 {
-                        match self.get_mmr_for_new((user_row.battle_score + change.battle_score) / (user_row.battles + 1)).await {
-                            Some(mmr) => mmr,
-                            None => (user_row.battle_score + change.battle_score) / (user_row.battles + 1)                           
-                        }
-
-                    } else if user_row.battles + 1 > 6 {
+                    let new_mmr = (user_row.mmr as i32 + record.1) as u32;
+                    if user_row.battles + 1 > 6 {
                         self.battle_score_hash.remove(&(user_row.battle_score/user_row.battles, change.user_id));
-                        (math::max(user_row.mmr as i32 + diff_mmr, 0)) as u32
-                    } else {
-                        (user_row.battle_score + change.battle_score) / (user_row.battles + 1)
-                    };
-                    
+                    }
                     // Keep lookup index only for fully calibrated users.
                     if user_row.battles > 5 {
                         self.battle_score_hash.insert(((user_row.battle_score + change.battle_score)/(user_row.battles + 1), change.user_id), new_mmr);
                     }
-                    /* let _ = sender_tasks
-                        .send((
-                            change.clone(),
-                            diff_mmr,
-                            user_row.clone(),
-                            change_debug,
-                            cl_id
-                        )); */  
+                    let _ = sender_tasks.send(record);
 
                     self.users.insert(change.user_id, LeaderboardRow{
                         user_id: change.user_id,
@@ -124,6 +169,7 @@ impl Leaderboard {
                 },
                 None => {
                     // First appearance of a user: initialize from battle score.
+                    let _ = sender_tasks.send(record);
 
                     self.users.insert(change.user_id, LeaderboardRow{
                         user_id: change.user_id,
@@ -154,8 +200,10 @@ impl Leaderboard {
         let changes = self.sets.clone();
 
         for change in changes.iter() {
-            let (diff_mmr, change_debug) = math::diff_mmr(change.victory, change.battle_score_muld as i32, change.top_3.clone(), change.mmr.clone(), change.early_quite, change.top_20);
+            let (diff_mmr, change_debug) = math::diff_mmr(change.victory, change.battle_score_muld as i32, change.top_3.clone(), change.mmr.clone(), change.early_quite, change.top_20, &self.params);
             println!("{:?} -- {}\n{:?}", change, diff_mmr, change_debug);
+            // A rated session restarts the player's idle clock.
+            self.decay.played(change.user_id);
             match self.battle_faction_hash.get_mut(&(change.user_id, change.faction.clone())) {
                 Some(bc) => {
                     *bc = *bc + 1;
@@ -165,7 +213,7 @@ impl Leaderboard {
             match self.users.get(&change.user_id) {
                 Some(user_row) => {
                     let new_mmr = if user_row.battles + 1 == 6 {
-                        match self.get_mmr_for_new((user_row.battle_score + change.battle_score) / (user_row.battles + 1)).await {
+                        match self.calibrated_mmr(user_row, (user_row.battle_score + change.battle_score) / (user_row.battles + 1)).await {
                             Some(mmr) => mmr,
                             None => (user_row.battle_score + change.battle_score) / (user_row.battles + 1)                           
                         }
@@ -215,95 +263,42 @@ impl Leaderboard {
 
     /// Constructs a `Leaderboard` by restoring persisted state from disk.
     ///
-    /// Reads two files when they exist:
-    /// - `data/leaderboard_v1/base` — core leaderboard rows (`LeaderboardRow` per user).
-    /// - `data/leaderboard_v1/battle_faction` — per-user battle counts grouped by faction.
-    ///
-    /// Players with fewer than 6 battles are loaded but excluded from the `battle_score_hash`
-    /// lookup index used by [`get_mmr_for_new`]. Returns an empty leaderboard when the files
-    /// are absent.
+    /// Reads the binary snapshot `data/leaderboard_v1/snapshot` (see [`snapshot::encode`]).
+    /// When it does not exist yet, the state is migrated from the legacy text files
+    /// `data/leaderboard_v1/base` and `data/leaderboard_v1/battle_faction`, where players
+    /// with fewer than 6 battles are excluded from the `battle_score_hash` lookup index used by [`get_mmr_for_new`].
+    /// Returns an empty leaderboard when no files are present and panics on a corrupted
+    /// snapshot.
     pub fn new() -> Self {
-        // Restore leaderboard state from persisted files when available.
-        let mut users: std::collections::HashMap<u64, LeaderboardRow> = std::collections::HashMap::new();
-        let mut battle_scores: std::collections::BTreeMap<(u32, u64), u32> =  std::collections::BTreeMap::new();
-        if Path::new("data/leaderboard_v1/base").exists() {
-            if let Ok(lines) = read_lines("data/leaderboard_v1/base") {
-                for line in lines.flatten() {
-                    match LeaderboardRow::parse_file(line.replace("\"", "")) {
-                        Some(row) => {
-                            users.insert(row.user_id, row.clone());
-                            // Index only calibrated users for bootstrap estimates.
-                            if row.battles >= 6 {
-                                battle_scores.insert((row.battle_score / row.battles, row.user_id), row.mmr);
-                            }
-                        },
-                        None => {
-                            
-                        }
-                    };
-                }
-            }
-        }
-        let mut battle_faction: std::collections::HashMap<(u64, String), u64> = std::collections::HashMap::new();
-        // Restore per-user battles grouped by faction/mode.
-        if Path::new("data/leaderboard_v1/battle_faction").exists() {
-            if let Ok(lines) = read_lines("data/leaderboard_v1/battle_faction") {
-                for line in lines.flatten() {
-                    let hash_line = reader(&line);
-                
-                    let user_id = hash_line.get("user_id");
-                    let faction = hash_line.get("faction");
-                    let battles = hash_line.get("battles");
-                    
-                    if user_id == None || faction == None || battles == None {
-                        continue;
-                    }
-
-                    battle_faction.insert((user_id.unwrap().parse::<u64>().unwrap(), faction.unwrap().to_string()), battles.unwrap().parse::<u64>().unwrap());
-                }
-            }
-        }
+        Self::open("data/leaderboard_v1").unwrap_or_else(|error| panic!("data/leaderboard_v1: {}", error))
+    }
 
-        return Self {
-            users: users,
+    /// Restores a leaderboard from the snapshot (or legacy files) in `dir`, as [`Self::new`]
+    /// does for `data/leaderboard_v1`; fails on a corrupted snapshot or a rejected legacy line.
+    pub fn open(dir: &str) -> Result<Self, SnapshotError> {
+        let snapshot = snapshot::load(dir, &[])?;
+        Ok(Self {
+            users: snapshot.users,
             sets: Vec::new(),
-            battle_score_hash: battle_scores,
-            battle_faction_hash: battle_faction
-        };
+            battle_score_hash: snapshot.battle_score_hash,
+            battle_faction_hash: snapshot.battle_faction_hash,
+            params: DiffMMRParams::default(),
+            decay: DecayState::new(snapshot.decay_marks),
+            calibration: CalibrationParams::default(),
+            bootstrap_excluded: std::collections::HashSet::new()
+        })
     }
 
-    /// Persists the current in-memory leaderboard state to disk.
-    ///
-    /// Writes two files:
-    /// - `data/leaderboard_v1/base` — one serialized `LeaderboardRow` per line.
-    /// - `data/leaderboard_v1/battle_faction` — per-user faction battle counters in
-    ///   `user_id:<id>,faction:<name>,battles:<n>` format.
+    /// Persists the current in-memory leaderboard state to `data/leaderboard_v1/snapshot`
+    /// (users, `battle_score_hash`, `battle_faction_hash` and decay marks in one checksummed file).
     pub async fn write(&self){
+        self.write_to("data/leaderboard_v1").await
+    }
 
-        // Persist core leaderboard rows.
-
-        let data_file = tokio::fs::File::create("data/leaderboard_v1/base".to_string()).await.unwrap();
-        let mut data_file = BufWriter::new(data_file);
-        // Write contents to the file
-
-        for (_user_id, row) in self.users.clone().iter() {
-            data_file.write_all((row.to_string() + "\n").as_bytes()).await.unwrap();
-        }
-        data_file.flush().await.unwrap();
-
-        
-        // Persist per-user faction battle counters.
-        let data_file = tokio::fs::File::create("data/leaderboard_v1/battle_faction".to_string()).await.unwrap();
-        let mut data_file = BufWriter::new(data_file);
-        // Write contents to the file
-
-        for (user_id, row) in self.battle_faction_hash.iter() {
-            let str = "user_id:".to_string() + user_id.0.to_string().as_str()
-                + ",faction:" + user_id.1.as_str()
-                + ",battles:" + row.to_string().as_str();
-            data_file.write_all((str + "\n").as_bytes()).await.unwrap();
-        }
-        data_file.flush().await.unwrap();
+    /// Persists the leaderboard state to `<dir>/snapshot`.
+    pub async fn write_to(&self, dir: &str){
+        let path = dir.to_string() + "/snapshot";
+        snapshot::write(&path, &self.users, &self.battle_score_hash, &self.battle_faction_hash, &self.decay.marks, &std::collections::HashMap::new()).await.unwrap_or_else(|error| panic!("{}: {}", path, error));
     }
 
     /// Returns the current `MMRType` for `user_id`.
@@ -327,257 +322,45 @@ impl Leaderboard {
         }
     }
 
-    /// Processes a completed session and updates the leaderboard.
+    /// Builds one `LeaderboardChangeV1` per player of a split session.
     ///
-    /// Executes the full v1 session pipeline in five timed stages:
-    /// 1. **Prepare** — splits `session_memory` into team_1 / team_2 snapshots and ranks each
-    ///    team by current MMR. Sessions with fewer than 5 players per side or belonging to
-    ///    the `newbie_common` mode are skipped (`None` is returned).
-    /// 2. **Write statistics** — when `cl_id > 0`, emits `Statistic` payloads for the
-    ///    `common` board and all mode-specific boards via `sender`, and forwards
-    ///    `(win_avg_mmr, lose_avg_mmr)` to `sender_check`. Also computes top-3 MMR averages
-    ///    and sends team-disbalance flags to `sender_session_class`.
-    /// 3. **Prepare changes** — builds `LeaderboardChangeV1` records for every player using
-    ///    the opposing team's top-3 as reference context.
-    /// 4. **Apply changes** — calls [`set_changes`] to update in-memory MMR values.
-    ///
-    /// Returns four `(Instant, Duration)` tuples — one per stage — for caller-side profiling,
-    /// or `None` if the session was skipped.
-    pub async fn make_session(
-        &mut self,
-        session_memory: memory::SessionMemory,
-        cl_id: u16,
-        sender: flume::Sender<(String, Statistic)>,
-        session_mode: &datasets::SessionMode,
-        registrations: &datasets::Registrations,
-        sender_tasks: flume::Sender<(LeaderboardChangeV1, i32, LeaderboardRow, MMRChangeDebug, u16)>, 
-        sender_check: flume::Sender<(u32, u32)>, 
-        sender_session_class: flume::Sender<(u64, bool, bool, bool, bool)>,
-        setting_change: bool
-    ) -> Option<((Instant, Duration), (Instant, Duration), (Instant, Duration), (Instant, Duration))> 
-    {
-        // 1) Build per-session team snapshots and rank players by known MMR.
-        let prepear_session = Instant::now();
-    
-        let common_score = session_memory.rows.iter().fold(
-            0 as u64, 
-            |fold_obj, other| {
-                fold_obj + other.battle_score as u64
+    /// Each player is rated against the MMR list of the other teams (see
+    /// [`SessionTeams::opponents`]) with a battle score normalized to the session average.
+    fn session_changes(&self, teams: &SessionTeams) -> Vec<LeaderboardChangeV1> {
+        let mut changes = Vec::new();
+        for (index, (rows, team_res)) in teams.teams.iter().zip(teams.results.iter().copied()).enumerate() {
+            let top_3 = teams.opponents(index as u8 + 1);
+            for user in rows.iter() {
+                changes.push(LeaderboardChangeV1 {
+                    user_id: user.user_id,
+                    session_id: user.session_id,
+                    mmr: self.get_mmr(user.user_id),
+                    top_3: top_3.clone(),
+                    victory: team_res,
+                    early_quite: user.early_quit,
+                    top_20: user.team_score_top_20_percent,
+                    battle_score: user.battle_score,
+                    battle_score_muld: teams.battle_score_muld(user),
+                    faction: user.faction.clone(),
+                    last_session: user.commit_time
+                });
             }
-        );
-    
-        let team_1: memory::SessionMemory = memory::SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_1".to_string() {Some(item)} else {None}).collect() 
-        };
-        let team_2: memory::SessionMemory = memory::SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_2".to_string() {Some(item)} else {None}).collect() 
-        };
-    
-        let mut team_1_mmr: TeamMMR = TeamMMR(team_1.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_1_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_1_mmr.0.reverse();
-        let mut team_2_mmr: TeamMMR = TeamMMR(team_2.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_2_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_2_mmr.0.reverse();
-    
-        let mode_type = match session_mode.0.get(&session_memory.now_session_id) {
-            Some((_mode_0, mode_1, _mode_2)) => {
-                mode_1.clone()
-            },
-            None => "newbie_common".to_string()
-        };
-    
-        //println!("{} {}", team_1.rows.len(), team_2.rows.len());
-        // Ignore incomplete matches and newbie mode for leaderboard updates.
-        if team_1.rows.len() < 5 || team_2.rows.len() < 5 || mode_type == "newbie_common".to_string() {
-            return None;
-        }
-        
-        let prepear_session_time = prepear_session.elapsed();
-        let team_1_res = team_1.rows.iter().fold(false, |a,b| if a == true || b.victories == true {true} else {false});
-        let team_2_res = team_2.rows.iter().fold(false, |a,b| if a == true || b.victories == true {true} else {false});
-    
-        let write_session = Instant::now();
-        if cl_id > 0 { 
-            // 2) Emit statistics for common and mode-specific boards.
-            sender
-                .send(statistic::proc_statistic(
-                    "common".to_string(),
-                    &team_1_mmr,
-                    &team_2_mmr,
-                    team_1_res,
-                    team_2_res,
-                    &registrations,
-                    Some(sender_check)
-                ).await)
-                .unwrap();  
-    
-            match session_mode.0.get(&session_memory.now_session_id) {
-                Some((mode_0, mode_1, mode_2)) => {
-                    sender
-                        .send(statistic::proc_statistic(
-                            mode_0.to_string(),
-                            &team_1_mmr,
-                            &team_2_mmr,
-                            team_1_res,
-                            team_2_res,
-                            &registrations,
-                            None
-                        ).await)
-                        .unwrap();
-                    sender
-                        .send(statistic::proc_statistic(
-                            mode_1.to_string(),
-                            &team_1_mmr,
-                            &team_2_mmr,
-                            team_1_res,
-                            team_2_res,
-                            &registrations,
-                            None
-                        ).await)
-                        .unwrap();
-                    sender
-                        .send(statistic::proc_statistic(
-                            mode_2.to_string(),
-                            &team_1_mmr,
-                            &team_2_mmr,
-                            team_1_res,
-                            team_2_res,
-                            &registrations,
-                            None
-                        ).await)
-                        .unwrap();
-                },
-                None => {}
-            } 
-            let team_1_top3d: MMRAgg = team_1_mmr.0.clone().into_iter().enumerate().filter_map(|(idx, obj)| if idx < 3 {Some(obj.1)} else {None}).fold(
-                MMRAgg(0, 0),
-                |left, right| {
-                    let mut res = left.clone();
-                    match right {
-                        MMRType::MMR(mmr) => {
-                            res.0 += 1;
-                            res.1 += mmr as i64;
-                            res
-                        },
-                        _ => res
-                    }
-                }
-            );
-            let team_1_top3 = if team_1_top3d.0 == 0 {
-                None
-            } else {
-                Some((team_1_top3d.1 / team_1_top3d.0) as u32)
-            };
-            let team_2_top3d: MMRAgg = team_2_mmr.0.clone().into_iter().enumerate().filter_map(|(idx, obj)| if idx < 3 {Some(obj.1)} else {None}).fold(
-                MMRAgg(0, 0),
-                |left, right| {
-                    let mut res = left.clone();
-                    match right {
-                        MMRType::MMR(mmr) => {
-                            res.0 += 1;
-                            res.1 += mmr as i64;
-                            res
-                        },
-                        _ => res
-                    }
-                }
-            );
-            let team_2_top3 = if team_2_top3d.0 == 0 {
-                None
-            } else {
-                Some((team_2_top3d.1 / team_2_top3d.0) as u32)
-            };
-            // 3) Compute simple team disbalance flags from top-3 MMR averages.
-            let disbalance_team_1 = match team_1_top3 {
-                Some(team_1) => {
-                    match team_2_top3 {
-                        Some(team_2) => {
-                            if (team_1 as i32) - (team_2 as i32) > 800 {
-                                true
-                            } else {
-                                false
-                            }
-                        },
-                        _ => false
-                    }
-                },
-                _ => {
-                    false
-                }
-            };
-            
-            // count team 2 disbalance sessions
-            let disbalance_team_2 = match team_1_top3 {
-                Some(team_1) => {
-                    match team_2_top3 {
-                        Some(team_2) => {
-                            if (team_2 as i32) - (team_1 as i32) > 800 {
-                                true
-                            } else {
-                                false
-                            }
-                        },
-                        _ => false
-                    }
-                },
-                _ => {
-                    false
-                }
-            };
-            sender_session_class
-                .send((session_memory.now_session_id, disbalance_team_1, disbalance_team_2, team_1_res, team_2_res))
-                .unwrap();
         }
-    
-        let write_session_time = write_session.elapsed();
-    
-        let prepear_change = Instant::now();
-        // 4) Build per-user change payloads against opposite team context.
-        let mut top_3_lose = team_2_mmr.0.clone();
-        top_3_lose.sort_unstable_by_key(|o| match o.1 {
-            MMRType::MMR(data) => data,
-            _ => 0
-        });
-        top_3_lose.reverse();
-        let top_3_lose = top_3_lose.into_iter().filter_map(|o| Some(o.1)).collect::<Vec<MMRType>>();
-        for win_user in team_1.rows.iter() {
-            self.add_change(LeaderboardChangeV1 { user_id: win_user.user_id, mmr: self.get_mmr(win_user.user_id), top_3: top_3_lose.clone(), victory: team_1_res, early_quite: win_user.early_quit, top_20: win_user.team_score_top_20_percent, battle_score: win_user.battle_score, battle_score_muld: ((1600.0 * (team_2.rows.len() + team_1.rows.len()) as f64) / (common_score as f64) * (win_user.battle_score as f64)) as u32, faction: win_user.faction.clone(), last_session: win_user.commit_time })
-        }
-        
-        let mut top_3_win = team_1_mmr.0.clone();
-        top_3_win.sort_unstable_by_key(|o| match o.1 {
-            MMRType::MMR(data) => data,
-            _ => 0
-        });
-        top_3_win.reverse();
-        let top_3_win = top_3_win.into_iter().filter_map(|o| Some(o.1)).collect::<Vec<MMRType>>();
-        for lose_user in team_2.rows.iter() {
-            self.add_change(LeaderboardChangeV1 { user_id: lose_user.user_id, mmr: self.get_mmr(lose_user.user_id), top_3: top_3_win.clone(), victory: team_2_res, early_quite: lose_user.early_quit, top_20: lose_user.team_score_top_20_percent, battle_score: lose_user.battle_score, battle_score_muld: ((1600.0 * (team_2.rows.len() + team_1.rows.len()) as f64) / (common_score as f64) * (lose_user.battle_score as f64)) as u32, faction: lose_user.faction.clone(), last_session: lose_user.commit_time })
+        changes
+    }
+
+    /// Enqueues the [`session_changes`] of a split session.
+    fn prepare_changes(&mut self, teams: &SessionTeams) {
+        for change in self.session_changes(teams) {
+            self.add_change(change);
         }
-        let prepear_change_time = prepear_change.elapsed();
-    
-        let set_change = Instant::now();
-        // 5) Apply prepared changes to in-memory leaderboard state.
-        self.set_changes(cl_id, sender_tasks, setting_change).await;
-        let set_change_time = set_change.elapsed();
-        Some(((prepear_session, prepear_session_time), (write_session, write_session_time), (prepear_change, prepear_change_time), (prepear_change, set_change_time)))
-    
     }
 
     /// Processes a completed session without emitting statistics (debug/inspection variant).
     ///
-    /// Mirrors the team-split, MMR-ranking and change-building logic of [`make_session`] but
-    /// skips all statistic and session-classification side effects. Uses a hardcoded
-    /// `mode_type` of `"low_br"` so no session is filtered as `newbie_common`.
+    /// Uses the same change building as [`RatingSystem::process_session`] on the teams of
+    /// the built-in factions, but skips the mode filter and all statistic and
+    /// session-classification side effects.
     ///
     /// Calls [`set_changes_lite`] to apply and return the full list of `LeaderboardChangeV1`
     /// records, or `None` when the session has fewer than 5 players per team.
@@ -586,75 +369,162 @@ impl Leaderboard {
         session_memory: memory::SessionMemory,
     ) -> Option<Vec<LeaderboardChangeV1>> {
         // Lite variant: produces and applies change set without statistic side effects.
-        let prepear_session = Instant::now();
-    
-        let common_score = session_memory.rows.iter().fold(
-            0 as u64, 
-            |fold_obj, other| {
-                fold_obj + other.battle_score as u64
-            }
-        );
-    
-        let team_1: memory::SessionMemory = memory::SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_1".to_string() {Some(item)} else {None}).collect() 
-        };
-        let team_2: memory::SessionMemory = memory::SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_2".to_string() {Some(item)} else {None}).collect() 
-        };
-    
-        let mut team_1_mmr: TeamMMR = TeamMMR(team_1.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_1_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_1_mmr.0.reverse();
-        let mut team_2_mmr: TeamMMR = TeamMMR(team_2.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_2_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_2_mmr.0.reverse();
-    
-        let mode_type = "low_br".to_string();
-    
-        if team_1.rows.len() < 5 || team_2.rows.len() < 5 || mode_type == "newbie_common".to_string() {
+        let teams = SessionTeams::new(self, &session_memory, &FactionRegistry::default());
+        if teams.is_incomplete() {
             return None;
         }
-        
-        let _prepear_session_time = prepear_session.elapsed();
-        let team_1_res = team_1.rows[0].victories;
-        let team_2_res = team_2.rows[0].victories;
-    
-        let prepear_change = Instant::now();
-        let mut top_3_lose = team_2_mmr.0.clone();
-        top_3_lose.sort_unstable_by_key(|o| match o.1 {
-            MMRType::MMR(data) => data,
-            _ => 0
-        });
-        top_3_lose.reverse();
-        let top_3_lose = top_3_lose.into_iter().filter_map(|o| Some(o.1)).collect::<Vec<MMRType>>();
-        for win_user in team_1.rows.iter() {
-            self.add_change(LeaderboardChangeV1 { user_id: win_user.user_id, mmr: self.get_mmr(win_user.user_id), top_3: top_3_lose.clone(), victory: team_1_res, early_quite: win_user.early_quit, top_20: win_user.team_score_top_20_percent, battle_score: win_user.battle_score, battle_score_muld: ((1600.0 * (team_2.rows.len() + team_1.rows.len()) as f64) / (common_score as f64) * (win_user.battle_score as f64)) as u32, faction: win_user.faction.clone(), last_session: win_user.commit_time })
+        self.prepare_changes(&teams);
+        Some(self.set_changes_lite().await)
+    }
+}
+
+impl Default for Leaderboard {
+    fn default() -> Self {
+        <Self as RatingSystem>::empty()
+    }
+}
+
+impl RatingSystem for Leaderboard {
+    type Change = ChangeRecordV1;
+    type Prepared = Vec<LeaderboardChangeV1>;
+    const NAME: &'static str = "v1";
+
+    fn restore_from(dir: &str) -> Result<Self, SnapshotError> {
+        Leaderboard::open(dir)
+    }
+
+    fn empty() -> Self {
+        Self {
+            users: std::collections::HashMap::new(),
+            sets: Vec::new(),
+            battle_score_hash: std::collections::BTreeMap::new(),
+            battle_faction_hash: std::collections::HashMap::new(),
+            params: DiffMMRParams::default(),
+            decay: DecayState::default(),
+            calibration: CalibrationParams::default(),
+            bootstrap_excluded: std::collections::HashSet::new()
+        }
+    }
+
+    fn set_params(&mut self, params: &MMRParams) {
+        self.params = params.v1.clone();
+        self.decay.params = params.decay.clone();
+        self.calibration = params.calibration.clone();
+    }
+
+    fn apply_decay(&mut self, user_id: u64, now: u64) -> bool {
+        self.decay.decay_user(user_id, now, &mut self.users, &mut self.battle_score_hash)
+    }
+
+    fn decay_sweep(&mut self, now: u64) -> u64 {
+        self.decay.sweep(now, &mut self.users, &mut self.battle_score_hash)
+    }
+
+    fn decay_mean(&self) -> Option<(f64, u64)> {
+        Some((self.decay.mean, self.decay.mean_at))
+    }
+
+    fn set_decay_mean(&mut self, mean: (f64, u64)) {
+        (self.decay.mean, self.decay.mean_at) = mean;
+    }
+
+    fn save_decay(&self, user_ids: &[u64]) -> DecayUndo {
+        self.decay.save(user_ids, &self.users, &self.battle_score_hash)
+    }
+
+    fn undo_decay(&mut self, undo: DecayUndo) {
+        self.decay.undo(undo, &mut self.users, &mut self.battle_score_hash)
+    }
+
+    fn season_reset(&mut self, params: &SeasonParams) -> u64 {
+        season::soft_reset(&mut self.users, &mut self.battle_score_hash, params)
+    }
+
+    async fn calibration_estimate(&self, row: &LeaderboardRow) -> Option<CalibrationEstimate> {
+        let features = CalibrationFeatures::from_row(row)?;
+        if self.calibration.neighbours > 0 {
+            return calibration::estimate(&self.users, &self.battle_score_hash, &self.bootstrap_excluded, &features, &self.calibration);
+        }
+        self.get_mmr_for_new(features.score as u32).await.map(|mmr| CalibrationEstimate { mmr, low: mmr, high: mmr, neighbours: 0 })
+    }
+
+    fn exclude_from_bootstrap(&mut self, user_id: u64) {
+        self.bootstrap_excluded.insert(user_id);
+    }
+
+    async fn persist_to(&self, dir: &str) {
+        self.write_to(dir).await
+    }
+
+    fn seed_from(&mut self, user_id: u64, global: &Self, params: &ModeParams) -> bool {
+        if self.users.contains_key(&user_id) {
+            return false;
+        }
+        match global.users.get(&user_id).and_then(|row| modes::seed_row(row, params)) {
+            Some(row) => {
+                if row.battles >= 6 {
+                    self.battle_score_hash.insert((row.battle_score / row.battles, user_id), row.mmr);
+                }
+                self.users.insert(user_id, row);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn get_mmr(&self, user_id: u64) -> MMRType {
+        Leaderboard::get_mmr(self, user_id)
+    }
+
+    fn get_battles(&self, user_id: u64) -> u32 {
+        self.users.get(&user_id).map(|row| row.battles).unwrap_or(0)
+    }
+
+    fn get_users(&self) -> &std::collections::HashMap<u64, LeaderboardRow> {
+        &self.users
+    }
+
+    /// Builds `LeaderboardChangeV1` records for every player.
+    fn prepare_session(&self, teams: &SessionTeams) -> Vec<LeaderboardChangeV1> {
+        self.session_changes(teams)
+    }
+
+    /// Applies the records through [`Leaderboard::set_changes`].
+    async fn apply_session(&mut self, prepared: Vec<LeaderboardChangeV1>, cl_id: u16, sender_tasks: flume::Sender<ChangeRecordV1>) {
+        for change in prepared {
+            self.add_change(change);
+        }
+        self.set_changes(cl_id, sender_tasks, true).await;
+    }
+
+    async fn preview_session(&self, prepared: Vec<LeaderboardChangeV1>, cl_id: u16) -> Vec<ChangeRecordV1> {
+        let mut records = Vec::new();
+        for change in prepared.iter() {
+            records.push(self.change_record(change, cl_id).await);
         }
-        
-        let mut top_3_win = team_1_mmr.0.clone();
-        top_3_win.sort_unstable_by_key(|o| match o.1 {
-            MMRType::MMR(data) => data,
-            _ => 0
-        });
-        top_3_win.reverse();
-        let top_3_win = top_3_win.into_iter().filter_map(|o| Some(o.1)).collect::<Vec<MMRType>>();
-        for lose_user in team_2.rows.iter() {
-            self.add_change(LeaderboardChangeV1 { user_id: lose_user.user_id, mmr: self.get_mmr(lose_user.user_id), top_3: top_3_win.clone(), victory: team_2_res, early_quite: lose_user.early_quit, top_20: lose_user.team_score_top_20_percent, battle_score: lose_user.battle_score, battle_score_muld: ((1600.0 * (team_2.rows.len() + team_1.rows.len()) as f64) / (common_score as f64) * (lose_user.battle_score as f64)) as u32, faction: lose_user.faction.clone(), last_session: lose_user.commit_time })
+        records
+    }
+
+    /// The decay moves players and the population mean between sessions.
+    fn batch_safe(&self) -> bool {
+        !self.decay.enabled()
+    }
+
+    async fn write_change(change: ChangeRecordV1, change_files: &mut [BufWriter<File>]) {
+        let (change, mmr_diff, user_row, debug, cl_id) = change;
+        writer::write_change(change, mmr_diff, user_row, &mut change_files[cl_id as usize], debug).await;
+    }
+
+    fn history_entry(change: &ChangeRecordV1) -> HistoryEntry {
+        let (change, mmr_diff, user_row, debug, _cl_id) = change;
+        HistoryEntry {
+            user_id: change.user_id,
+            session_id: change.session_id,
+            commit_time: change.last_session,
+            mmr_before: user_row.mmr,
+            mmr_after: (user_row.mmr as i64 + *mmr_diff as i64) as u32,
+            mode: String::new(),
+            components: vec![debug.0 as f64, debug.1 as f64, debug.2 as f64, debug.3 as f64, debug.4, debug.5]
         }
-        let _prepear_change_time = prepear_change.elapsed();
-    
-        let set_change = Instant::now();
-        let sets = self.set_changes_lite().await;
-        let _set_change_time = set_change.elapsed();
-        Some(sets)
-    
-    }
-}
\ No newline at end of file
+    }
+}
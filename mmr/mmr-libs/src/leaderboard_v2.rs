@@ -1,17 +1,18 @@
-use std::path::Path;
-use tokio::io::AsyncWriteExt;
-
-use std::time::{Duration, Instant};
 
+use tokio::fs::File;
 use tokio::io::BufWriter;
 
-use crate::datasets::{Registrations, SessionMode};
 use crate::math::{divide_or_0, max, maxf, minf, sigmoid};
-use crate::memory::{read_lines, SessionMemory};
-use crate::reader::reader;
-use crate::statistic::proc_statistic;
-use crate::types::{LeaderboardChangeV2, LeaderboardRow, LeaderboardV2, MMRAgg, MMRChangeDebugV2, MMRPair, MMRType, TeamMMR, TeamMMRV2, UserBattleRow};
-use crate::{math, statistic::Statistic};
+use crate::calibration::{self, CalibrationEstimate, CalibrationFeatures};
+use crate::decay::{DecayState, DecayUndo};
+use crate::factions::FactionRegistry;
+use crate::history::HistoryEntry;
+use crate::memory::SessionMemory;
+use crate::params::{CalibrationParams, MMRParams, ModeParams, PoolParams, SeasonParams};
+use crate::rating::{RatingSystem, SessionTeams, StageTiming};
+use crate::types::{ChangeRecordV2, LeaderboardChangeV2, LeaderboardRow, LeaderboardV2, MMRChangeDebugV2, MMRType, PoolChange, TeamMMRV2};
+use crate::{math, modes, season, snapshot, writer};
+use crate::snapshot::SnapshotError;
 
 
 
@@ -20,93 +21,42 @@ impl LeaderboardV2 {
     
     /// Constructs a `LeaderboardV2` by restoring persisted state from disk.
     ///
-    /// Reads two files when they exist:
-    /// - `data/leaderboard_v2/base` — core leaderboard rows (`LeaderboardRow` per user).
-    /// - `data/leaderboard_v2/battle_faction` — per-user battle counts grouped by faction.
-    ///
-    /// Players with fewer than 6 battles are loaded but excluded from the `battle_score_hash`
-    /// lookup index. Returns an empty leaderboard when the files are absent.
+    /// Reads the binary snapshot `data/leaderboard_v2/snapshot` (see [`snapshot::encode`]).
+    /// When it does not exist yet, the state is migrated from the legacy text files
+    /// `data/leaderboard_v2/base` and `data/leaderboard_v2/battle_faction`, where players
+    /// with fewer than 6 battles are excluded from the `battle_score_hash` lookup index.
+    /// Returns an empty leaderboard when no files are present and panics on a corrupted
+    /// snapshot.
     pub fn new() -> Self {
-        // Restore persisted leaderboard state (users + faction counters).
-        let mut users: std::collections::HashMap<u64, LeaderboardRow> = std::collections::HashMap::new();
-        let mut battle_scores: std::collections::BTreeMap<(u32, u64), u32> =  std::collections::BTreeMap::new();
-        if Path::new("data/leaderboard_v2/base").exists() {
-            if let Ok(lines) = read_lines("data/leaderboard_v2/base") {
-                for line in lines.flatten() {
-                    match LeaderboardRow::parse_file(line.replace("\"", "")) {
-                        Some(row) => {
-                            users.insert(row.user_id, row.clone());
-                            // Keep index only for calibrated players.
-                            if row.battles >= 6 {
-                                battle_scores.insert((row.battle_score / row.battles, row.user_id), row.mmr);
-                            }
-                        },
-                        None => {
-                            
-                        }
-                    };
-                }
-            }
-        }
-        let mut battle_faction: std::collections::HashMap<(u64, String), u64> = std::collections::HashMap::new();
-        if Path::new("data/leaderboard_v2/battle_faction").exists() {
-            if let Ok(lines) = read_lines("data/leaderboard_v2/battle_faction") {
-                for line in lines.flatten() {
-                    let hash_line = reader(&line);
-                
-                    let user_id = hash_line.get("user_id");
-                    let faction = hash_line.get("faction");
-                    let battles = hash_line.get("battles");
-                    
-                    if user_id == None || faction == None || battles == None {
-                        continue;
-                    }
-
-                    battle_faction.insert((user_id.unwrap().parse::<u64>().unwrap(), faction.unwrap().to_string()), battles.unwrap().parse::<u64>().unwrap());
-                }
-            }
-        }
+        Self::open("data/leaderboard_v2").unwrap_or_else(|error| panic!("data/leaderboard_v2: {}", error))
+    }
 
-        return Self {
-            users: users,
+    /// Restores a leaderboard from the snapshot (or legacy files) in `dir`, as [`Self::new`]
+    /// does for `data/leaderboard_v2`; fails on a corrupted snapshot or a rejected legacy line.
+    pub fn open(dir: &str) -> Result<Self, SnapshotError> {
+        let snapshot = snapshot::load(dir, &[])?;
+        Ok(Self {
+            users: snapshot.users,
             sets: Vec::new(),
-            battle_score_hash: battle_scores,
-            battle_faction_hash: battle_faction
-        };
+            battle_score_hash: snapshot.battle_score_hash,
+            battle_faction_hash: snapshot.battle_faction_hash,
+            params: PoolParams::default(),
+            decay: DecayState::new(snapshot.decay_marks),
+            calibration: CalibrationParams::default(),
+            bootstrap_excluded: std::collections::HashSet::new()
+        })
     }
 
-    /// Persists the current in-memory leaderboard state to disk.
-    ///
-    /// Writes two files:
-    /// - `data/leaderboard_v2/base` — one serialized `LeaderboardRow` per line.
-    /// - `data/leaderboard_v2/battle_faction` — per-user faction battle counters in
-    ///   `user_id:<id>,faction:<name>,battles:<n>` format.
+    /// Persists the current in-memory leaderboard state to `data/leaderboard_v2/snapshot`
+    /// (users, `battle_score_hash`, `battle_faction_hash` and decay marks in one checksummed file).
     pub async fn write(&self){
+        self.write_to("data/leaderboard_v2").await
+    }
 
-        // Persist leaderboard rows.
-
-        let data_file = tokio::fs::File::create("data/leaderboard_v2/base".to_string()).await.unwrap();
-        let mut data_file = BufWriter::new(data_file);
-        // Write contents to the file
-
-        for (_user_id, row) in self.users.clone().iter() {
-            data_file.write_all((row.to_string() + "\n").as_bytes()).await.unwrap();
-        }
-        data_file.flush().await.unwrap();
-
-        
-        // Persist per-user battle counters split by faction/mode.
-        let data_file = tokio::fs::File::create("data/leaderboard_v2/battle_faction".to_string()).await.unwrap();
-        let mut data_file = BufWriter::new(data_file);
-        // Write contents to the file
-
-        for (user_id, row) in self.battle_faction_hash.iter() {
-            let str = "user_id:".to_string() + user_id.0.to_string().as_str()
-                + ",faction:" + user_id.1.as_str()
-                + ",battles:" + row.to_string().as_str();
-            data_file.write_all((str + "\n").as_bytes()).await.unwrap();
-        }
-        data_file.flush().await.unwrap();
+    /// Persists the leaderboard state to `<dir>/snapshot`.
+    pub async fn write_to(&self, dir: &str){
+        let path = dir.to_string() + "/snapshot";
+        snapshot::write(&path, &self.users, &self.battle_score_hash, &self.battle_faction_hash, &self.decay.marks, &std::collections::HashMap::new()).await.unwrap_or_else(|error| panic!("{}: {}", path, error));
     }
 
     /// Returns the current `MMRType` for `user_id`.
@@ -146,222 +96,42 @@ impl LeaderboardV2 {
         }
     }
 
-    /// Processes a completed session and updates the leaderboard using the v2 pool algorithm.
-    ///
-    /// Executes the full pipeline in four timed stages:
-    /// 1. **Prepare** — splits `session_memory` into team_1 / team_2 snapshots and builds
-    ///    `teams_common_mmr` with per-player confidence coefficients
-    ///    $k = (\sqrt{2})^{\min(0,\,battles-6)}$. Sessions with fewer than 5 players per
-    ///    side or in the `newbie_common` mode are skipped (`None` returned).
-    /// 2. **Write statistics** — when `cl_id > 0`, computes top-3 MMR averages, emits
-    ///    team-disbalance flags to `sender_session_class`, and sends `Statistic` payloads
-    ///    for the `common` board and all mode-specific boards via `sender`. Forwards
-    ///    `(win_avg_mmr, lose_avg_mmr)` to `sender_check`.
-    /// 3. **Prepare changes** — computes the shared MMR pool, per-player increase/decrease
-    ///    coefficients, and `bank_give`/`bank_get` sigmoid redistribution terms.
-    /// 4. **Apply changes** — calls [`set_change`] for every player to apply the computed
-    ///    delta: `inc_mmr - dec_mmr`.
+    /// Weighted k-NN estimate of a provisional player (1–5 battles) from the battles so far;
+    /// `None` for other players and with the calibration disabled.
+    fn provisional_estimate(&self, user_id: u64) -> Option<CalibrationEstimate> {
+        match self.users.get(&user_id) {
+            Some(row) if row.battles < 6 => calibration::estimate(&self.users, &self.battle_score_hash, &self.bootstrap_excluded, &CalibrationFeatures::from_row(row)?, &self.calibration),
+            _ => None
+        }
+    }
+
+    /// Computes the v2 pool redistribution deltas for every row of a split session.
     ///
-    /// Returns four `(Instant, Duration)` tuples — one per stage — for caller-side profiling,
-    /// or `None` if the session was skipped.
-    pub async fn proc_session(
-        &mut self,
-        session_memory: SessionMemory,
-        cl_id: u16,
-        sender: flume::Sender<(String, Statistic)>,
-        session_mode: &SessionMode,
-        registrations: &Registrations,
-        sender_tasks: flume::Sender<(LeaderboardChangeV2, i32, LeaderboardRow, MMRChangeDebugV2, u16)>, 
-        sender_check: flume::Sender<(u32, u32)>,
-        sender_session_class: flume::Sender<(u64, bool, bool, bool, bool)>,        
-    ) -> Option<((Instant, Duration), (Instant, Duration), (Instant, Duration), (Instant, Duration))> {
-
-        // 1) Build session teams and weighted player descriptors.
-        let prepear_session = Instant::now();
-
-        let team_1: SessionMemory = SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_1".to_string() {Some(item)} else {None}).collect() 
-        };
-        let team_2: SessionMemory = SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_2".to_string() {Some(item)} else {None}).collect() 
-        };
-        
-        // The 4th tuple item is a confidence-like coefficient based on battles count.
-        let mut teams_common_mmr: TeamMMRV2 = TeamMMRV2(session_memory.rows.clone().into_iter().map(|user| (user.user_id, self.get_mmr(user.user_id), user.clone(), (2.0 as f64).sqrt().powf(minf(0.0, self.get_battles(user.user_id) as f64 - 6.0)))).collect());
+    /// Builds `teams_common_mmr` with per-player confidence coefficients
+    /// $k = (\sqrt{2})^{\min(0,\,battles-6)}$ (provisional players with a k-NN calibration
+    /// estimate enter at the estimate with at least its confidence), the shared MMR pool, per-player
+    /// increase/decrease coefficients and `bank_give`/`bank_get` sigmoid redistribution
+    /// terms. Nothing is applied; each entry holds the delta `inc_mmr - dec_mmr` together
+    /// with its change payload and debug components.
+    fn pool_changes(&self, teams: &SessionTeams) -> Vec<PoolChange> {
+        // The 4th tuple item is a confidence-like coefficient based on battles count, lowered
+        // for players returning from inactivity (`DecayParams::confidence_battles`), but never
+        // below the coefficient of a brand-new player. A provisional player with a calibration
+        // estimate plays at the estimate, and a narrow interval raises its coefficient.
+        let mut teams_common_mmr: TeamMMRV2 = TeamMMRV2(teams.rows.clone().into_iter().map(|user| {
+            let k = (2.0 as f64).sqrt().powf(maxf(-6.0, minf(0.0, self.get_battles(user.user_id) as f64 - 6.0) - self.decay.confidence_penalty(user.user_id)));
+            match self.provisional_estimate(user.user_id) {
+                Some(estimate) => (user.user_id, MMRType::NotEnought(estimate.mmr), user.clone(), maxf(k, estimate.confidence(&self.calibration))),
+                None => (user.user_id, self.get_mmr(user.user_id), user.clone(), k)
+            }
+        }).collect());
         teams_common_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
             MMRType::MMR(mmr) => (2, mmr),
             _ => (1, 0)
         });
         teams_common_mmr.0.reverse();
 
-        
-        let mode_type = match session_mode.0.get(&session_memory.now_session_id) {
-            Some((_mode_0, mode_1, _mode_2)) => {
-                mode_1.clone()
-            },
-            None => "newbie_common".to_string()
-        };
-
-        // Skip incomplete sessions and newbie mode for MMR updates.
-        if team_1.rows.len() < 5 || team_2.rows.len() < 5 || mode_type == "newbie_common".to_string() {
-            return None;
-        }
-        let prepear_session_time = prepear_session.elapsed();
-
-        let team_1_res = team_1.rows[0].victories;
-        let team_2_res = team_2.rows[0].victories;
-        let mut team_1_mmr: TeamMMR = TeamMMR(team_1.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_1_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_1_mmr.0.reverse();
-        let mut team_2_mmr: TeamMMR = TeamMMR(team_2.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_2_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_2_mmr.0.reverse();
-        let write_session = Instant::now();
-        if cl_id > 0 {
-            // 2) Emit statistics + session disbalance classification.
-            let team_1_top3d: MMRAgg = team_1_mmr.0.clone().into_iter().enumerate().filter_map(|(idx, obj)| if idx < 3 {Some(obj.1)} else {None}).fold(
-                MMRAgg(0, 0),
-                |left, right| {
-                    let mut res = left.clone();
-                    match right {
-                        MMRType::MMR(mmr) => {
-                            res.0 += 1;
-                            res.1 += mmr as i64;
-                            res
-                        },
-                        _ => res
-                    }
-                }
-            );
-            let team_1_top3 = if team_1_top3d.0 == 0 {
-                None
-            } else {
-                Some((team_1_top3d.1 / team_1_top3d.0) as u32)
-            };
-            let team_2_top3d: MMRAgg = team_2_mmr.0.clone().into_iter().enumerate().filter_map(|(idx, obj)| if idx < 3 {Some(obj.1)} else {None}).fold(
-                MMRAgg(0, 0),
-                |left, right| {
-                    let mut res = left.clone();
-                    match right {
-                        MMRType::MMR(mmr) => {
-                            res.0 += 1;
-                            res.1 += mmr as i64;
-                            res
-                        },
-                        _ => res
-                    }
-                }
-            );
-            let team_2_top3 = if team_2_top3d.0 == 0 {
-                None
-            } else {
-                Some((team_2_top3d.1 / team_2_top3d.0) as u32)
-            };
-            // Flag sessions where team 1 top-3 average MMR greatly exceeds team 2.
-            let disbalance_team_1 = match team_1_top3 {
-                Some(team_1) => {
-                    match team_2_top3 {
-                        Some(team_2) => {
-                            if (team_1 as i32) - (team_2 as i32) > 800 {
-                                true
-                            } else {
-                                false
-                            }
-                        },
-                        _ => false
-                    }
-                },
-                _ => {
-                    false
-                }
-            };
-            
-            // Flag sessions where team 2 top-3 average MMR is much higher.
-            let disbalance_team_2 = match team_1_top3 {
-                Some(team_1) => {
-                    match team_2_top3 {
-                        Some(team_2) => {
-                            if (team_2 as i32) - (team_1 as i32) > 800 {
-                                true
-                            } else {
-                                false
-                            }
-                        },
-                        _ => false
-                    }
-                },
-                _ => {
-                    false
-                }
-            };
-            sender_session_class
-                .send((session_memory.now_session_id, disbalance_team_1, disbalance_team_2, team_1_res, team_2_res))
-                .unwrap(); 
-            sender
-                .send(proc_statistic(
-                    "common".to_string(),
-                    &team_1_mmr,
-                    &team_2_mmr,
-                    team_1_res,
-                    team_2_res,
-                    &registrations,
-                    Some(sender_check)
-                ).await)
-                .unwrap();  
-    
-            match session_mode.0.get(&session_memory.now_session_id) {
-                Some((mode_0, mode_1, mode_2)) => {
-                    sender
-                        .send(proc_statistic(
-                            mode_0.to_string(),
-                            &team_1_mmr,
-                            &team_2_mmr,
-                            team_1_res,
-                            team_2_res,
-                            &registrations,
-                            None
-                        ).await)
-                        .unwrap();
-                    sender
-                        .send(proc_statistic(
-                            mode_1.to_string(),
-                            &team_1_mmr,
-                            &team_2_mmr,
-                            team_1_res,
-                            team_2_res,
-                            &registrations,
-                            None
-                        ).await)
-                        .unwrap();
-                    sender
-                        .send(proc_statistic(
-                            mode_2.to_string(),
-                            &team_1_mmr,
-                            &team_2_mmr,
-                            team_1_res,
-                            team_2_res,
-                            &registrations,
-                            None
-                        ).await)
-                        .unwrap();
-                },
-                None => {}
-            } 
-        }
-    
-        let write_session_time = write_session.elapsed();
-
-
-        let prepear_change = Instant::now();
-        // 3) Build redistribution inputs for MMR pool calculation.
+        // Build redistribution inputs for MMR pool calculation.
         let avg_mmr = teams_common_mmr.0.iter().fold(
             (0.0 as f64, 0.0 as f64, 0.0 as f64), 
             |base, other| {
@@ -383,8 +153,8 @@ impl LeaderboardV2 {
                 MMRType::NotEnought(m) => *m,
                 MMRType::MMR(m) => *m
             };
-            let bank_give = sigmoid(mmr as f64, 0.01, 500.0, -1.0, 1.0) * 1.0 + 0.2 * (1.0 - k);
-            let bank_get = sigmoid(mmr as f64, 0.01, 9500.0, 1.0, 0.0) * 0.05;
+            let bank_give = sigmoid(mmr as f64, self.params.bank_give_slope, self.params.bank_give_center, -1.0, 1.0) * 1.0 + self.params.bank_give_confidence * (1.0 - k);
+            let bank_get = sigmoid(mmr as f64, self.params.bank_get_slope, self.params.bank_get_center, 1.0, 0.0) * self.params.bank_get_scale;
 
             (
                 *user_id, 
@@ -403,8 +173,8 @@ impl LeaderboardV2 {
                             0)
                     ) as u32, 
                     *k, 
-                    0.5 / (8.0 * divide_or_0(avg_mmr, mmr as f64).powf(0.35) + 1.0), 
-                    0.5 / (4.0 * divide_or_0(mmr as f64, avg_mmr) + 1.0), 
+                    self.params.dec_k / (self.params.dec_k_ratio * divide_or_0(avg_mmr, mmr as f64).powf(self.params.dec_k_pow) + 1.0), 
+                    self.params.inc_k / (self.params.inc_k_ratio * divide_or_0(mmr as f64, avg_mmr) + 1.0), 
                     bank_get, 
                     bank_give
                 )
@@ -416,11 +186,9 @@ impl LeaderboardV2 {
         let (mmr_pool, mmr_inc, normalization_k) = mmr_poll_diff.iter().fold((0.0, 0.0, 0.0), |base, (_user_id, (mmr, battle_score, k, dec_k, inc_k, bank_get, bank_give))| {
             (base.0 + (dec_k + bank_get) * (*mmr as f64) * k +  bank_give * maxf(avg_mmr, 500.0) - bank_get * (*mmr as f64), base.1 + inc_k / k, base.2 + (inc_k / k) * (*battle_score as f64) )
         });
-        let prepear_change_time = prepear_change.elapsed();
-    
-        let set_change = Instant::now();
-        // 4) Compute per-user delta and apply to persistent leaderboard state.
-        for (user_id, _mmr, user, _k) in teams_common_mmr.0.iter() {
+
+        // Compute per-user delta against the shared pool.
+        teams_common_mmr.0.iter().map(|(user_id, mmr_type, user, _k)| {
             let (mmr, score, k, dec_k, inc_k, bd, bi) = match mmr_poll_diff.get(user_id) {
                 Some(mdiff) => mdiff,
                 None => panic!()
@@ -429,7 +197,8 @@ impl LeaderboardV2 {
             let dec_mmr = (*mmr as f64) * (dec_k + bd) / k;
             let change = LeaderboardChangeV2{
                 user_id: *user_id,
-                mmr: self.get_mmr(*user_id),
+                session_id: user.session_id,
+                mmr: mmr_type.clone(),
                 top_3: teams_common_mmr.0.iter().map(|obj| (obj.1.clone(), obj.3)).collect::<Vec<(MMRType, f64)>>(),
                 victory: user.victories,
                 early_quite: user.early_quit,
@@ -439,159 +208,98 @@ impl LeaderboardV2 {
                 faction: user.faction.clone(),
                 last_session: user.commit_time
             };
-            self.set_change(user_id, user, ((inc_mmr - dec_mmr)) as i32, sender_tasks.clone(), cl_id, change, MMRChangeDebugV2(inc_mmr, dec_mmr, mmr_pool, mmr_inc, *inc_k, *dec_k, sum_score, *bi, *bd, *k, avg_mmr));
-        }
-        let set_change_time = set_change.elapsed();
-        Some(((prepear_session, prepear_session_time), (write_session, write_session_time), (prepear_change, prepear_change_time), (prepear_change, set_change_time)))
-
-        
+            PoolChange {
+                user_id: *user_id,
+                user: user.clone(),
+                diff_mmr: (inc_mmr - dec_mmr) as i32,
+                change,
+                debug: MMRChangeDebugV2(inc_mmr, dec_mmr, mmr_pool, mmr_inc, *inc_k, *dec_k, sum_score, *bi, *bd, *k, avg_mmr)
+            }
+        }).collect()
     }
 
     /// Processes a completed session without emitting statistics (debug/inspection variant).
     ///
-    /// Mirrors the full pool-based MMR calculation of [`proc_session`] but skips all
-    /// statistic and session-classification side effects. Uses a hardcoded `mode_type`
-    /// of `"low_br"` so no session is filtered as `newbie_common`. Calls
-    /// [`set_change_lite`] for each player, which prints detailed debug output to stdout.
+    /// Mirrors the full pool-based MMR calculation of [`RatingSystem::process_session`] on the
+    /// teams of the built-in factions, but skips the mode filter and all statistic and
+    /// session-classification side effects.
+    /// Calls [`set_change_lite`] for each player, which prints detailed debug output to stdout.
     ///
     /// Always returns `None` — timing data is tracked internally but not exposed.
     pub async fn proc_session_lite(
         &mut self,
         session_memory: SessionMemory,
         
-    ) -> Option<((Instant, Duration), (Instant, Duration), (Instant, Duration), (Instant, Duration))> {
+    ) -> Option<(StageTiming, StageTiming, StageTiming, StageTiming)> {
 
         // Lite variant: same MMR math without external statistic/classification side effects.
-        let prepear_session = Instant::now();
-
-        let team_1: SessionMemory = SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_1".to_string() {Some(item)} else {None}).collect() 
-        };
-        let team_2: SessionMemory = SessionMemory { 
-            now_session_id: session_memory.now_session_id, 
-            rows: session_memory.rows.clone().into_iter().filter_map(|item| if item.faction == "faction_2".to_string() {Some(item)} else {None}).collect() 
-        };
-        
-        let mut teams_common_mmr: TeamMMRV2 = TeamMMRV2(session_memory.rows.clone().into_iter().map(|user| (user.user_id, self.get_mmr(user.user_id), user.clone(), (2.0 as f64).sqrt().powf(minf(0.0, self.get_battles(user.user_id) as f64 - 6.0)))).collect());
-        teams_common_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        teams_common_mmr.0.reverse();
-
-        
-        // Fixed mode marker in lite mode.
-        let mode_type = "low_br".to_string();
-
-        if team_1.rows.len() < 5 || team_2.rows.len() < 5 || mode_type == "newbie_common".to_string() {
+        let teams = SessionTeams::new(self, &session_memory, &FactionRegistry::default());
+        if teams.is_incomplete() {
             return None;
         }
-        let _prepear_session_time = prepear_session.elapsed();
-
-        let _team_1_res = team_1.rows[0].victories;
-        let _team_2_res = team_2.rows[0].victories;
-        let mut team_1_mmr: TeamMMR = TeamMMR(team_1.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_1_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_1_mmr.0.reverse();
-        let mut team_2_mmr: TeamMMR = TeamMMR(team_2.rows.clone().into_iter().map(|user| MMRPair(user.user_id, self.get_mmr(user.user_id), user.clone())).collect());
-        team_2_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
-            MMRType::MMR(mmr) => (2, mmr),
-            _ => (1, 0)
-        });
-        team_2_mmr.0.reverse();
-
-
-        let prepear_change = Instant::now();
-        // Recompute pool terms and apply deltas in-place.
-        let avg_mmr = teams_common_mmr.0.iter().fold(
-            (0.0 as f64, 0.0 as f64, 0.0 as f64), 
-            |base, other| {
-                let mmr = match other.1 {
-                    MMRType::None => 0,
-                    MMRType::NotEnought(m) => m,
-                    MMRType::MMR(m) => m
-                };
-
-
-                (base.0 + (mmr as f64) * other.3, base.1 + other.3, base.2 + max(other.2.battle_score as i64 + if other.2.victories {(other.2.battle_score as i64) / 4} else {0} + if other.2.team_score_top_20_percent {(other.2.battle_score as i64) / 4} else {0} + (if other.2.early_quit {-(other.2.battle_score as i64) / 2} else {0} as i64), 0) as f64)
-            }
-        );
-        let (avg_mmr, sum_score) = (avg_mmr.0 / avg_mmr.1, avg_mmr.2);
-        // user_id -> (mmr, weighted_score, k, decrease_k, increase_k, bank_get, bank_give)
-        let mmr_poll_diff: std::collections::BTreeMap<u64, (u32, u32, f64, f64, f64, f64, f64)> = teams_common_mmr.0.iter().map(|(user_id, mmr_player, user, k)| {
-            let mmr = match mmr_player {
-                MMRType::None => 0,
-                MMRType::NotEnought(m) => *m,
-                MMRType::MMR(m) => *m
-            };
-            let bank_give = sigmoid(mmr as f64, 0.01, 500.0, -1.0, 1.0) * 1.0 + 0.2 * (1.0 - k);
-            let bank_get = sigmoid(mmr as f64, 0.01, 9500.0, 1.0, 0.0) * 0.05;
-
-            (*user_id, (mmr, (max(user.battle_score as i64 + if user.victories {(user.battle_score as i64) / 4} else {0} + if user.team_score_top_20_percent {(user.battle_score as i64) / 4} else {0} + (if user.early_quit {-(user.battle_score as i64) / 2} else {0} as i64), 0)) as u32, *k, 0.5 / (8.0 * divide_or_0(avg_mmr, mmr as f64).powf(0.35) + 1.0), 0.5 / (4.0 * divide_or_0(mmr as f64, avg_mmr) + 1.0), bank_get, bank_give))
-            
-        }).collect();
-
-        let (mmr_pool, mmr_inc, normalization_k) = mmr_poll_diff.iter().fold((0.0, 0.0, 0.0), |base, (_user_id, (mmr, battle_score, k, dec_k, inc_k, bank_get, bank_give))| {
-            (base.0 + (dec_k + bank_get) * (*mmr as f64) * k +  bank_give * maxf(avg_mmr, 500.0) - bank_get * (*mmr as f64), base.1 + inc_k / k, base.2 + (inc_k / k) * (*battle_score as f64) )
-        });
-        let _prepear_change_time = prepear_change.elapsed();
-    
-        let set_change = Instant::now();
-        // user_id, (mmr, inc_mmr, dec_mmr, common_mmr)
-        for (user_id, _mmr, user, _k) in teams_common_mmr.0.iter() {
-            let (mmr, score, k, dec_k, inc_k, bd, bi) = match mmr_poll_diff.get(user_id) {
-                Some(mdiff) => mdiff,
-                None => panic!()
-            };
-            let inc_mmr = (mmr_pool) * ((*score as f64) / sum_score) * ((inc_k) / mmr_inc) * 1.0 / (normalization_k / (sum_score * mmr_inc)) / k;
-            let dec_mmr = (*mmr as f64) * (dec_k + bd) / k;
-            let change = LeaderboardChangeV2{
-                user_id: *user_id,
-                mmr: self.get_mmr(*user_id),
-                top_3: teams_common_mmr.0.iter().map(|obj| (obj.1.clone(), obj.3)).collect::<Vec<(MMRType, f64)>>(),
-                victory: user.victories,
-                early_quite: user.early_quit,
-                top_20: user.team_score_top_20_percent,
-                battle_score: user.battle_score,
-                battle_score_muld: *score,
-                faction: user.faction.clone(),
-                last_session: user.commit_time
-            };
-            self.set_change_lite(user_id, user, ((inc_mmr - dec_mmr)) as i32, change, MMRChangeDebugV2(inc_mmr, dec_mmr, mmr_pool, mmr_inc, *inc_k, *dec_k, sum_score, *bi, *bd, *k, avg_mmr));
+        for pool_change in self.pool_changes(&teams) {
+            self.set_change_lite(pool_change);
         }
-        let _set_change_time = set_change.elapsed();
         None
-
-        
     }
+    /// Change record of one pool delta against the current state: the applied MMR delta (not
+    /// the raw formula output) and the row before the session (a zero row for new users).
+    fn change_record(&self, pool_change: &PoolChange, cl_id: u16) -> ChangeRecordV2 {
+        let PoolChange { user_id, diff_mmr, change, debug, .. } = pool_change;
+        match self.users.get(user_id) {
+            // The delta applies to the pool MMR (`change.mmr`), the calibration estimate of a
+            // provisional player.
+            Some(user) => (change.clone(), math::max(change.mmr.get() as i32 + diff_mmr, 0) - user.mmr as i32, user.clone(), debug.clone(), cl_id),
+            None => (change.clone(), math::max(*diff_mmr, 0), LeaderboardRow {
+                user_id: *user_id,
+                mmr: 0,
+                battles: 0,
+                victories: 0,
+                early_quites: 0,
+                top_20: 0,
+                battle_score: 0,
+                last_session: 0
+            }, debug.clone(), cl_id)
+        }
+    }
+
     /// Applies a pre-computed MMR delta for a single player to the in-memory leaderboard.
     ///
     /// Updates `battle_faction_hash` for the player's faction, then either:
     /// - **existing user** — applies `diff_mmr` to current MMR (clamped to 0) and
     ///   increments all counters (battles, victories, early-quits, top-20, battle score).
     /// - **new user** — initializes a fresh `LeaderboardRow` from the session contribution.
-    fn set_change(&mut self, user_id: &u64, userstat_row: &UserBattleRow, diff_mmr: i32,
-        _sender_tasks: flume::Sender<(LeaderboardChangeV2, i32, LeaderboardRow, MMRChangeDebugV2, u16)>, 
-        _cl_id: u16, change: LeaderboardChangeV2, _debug: MMRChangeDebugV2
-    ){
+    ///
+    /// The change is sent to `sender_tasks` as built by [`Self::change_record`].
+    fn set_change(&mut self, pool_change: PoolChange, sender_tasks: flume::Sender<ChangeRecordV2>, cl_id: u16) {
+        let record = self.change_record(&pool_change, cl_id);
+        let PoolChange { user_id, user: userstat_row, diff_mmr, change, .. } = pool_change;
         // Update faction battle counters for this user.
+        // A rated session restarts the player's idle clock.
+        self.decay.played(change.user_id);
         match self.battle_faction_hash.get_mut(&(change.user_id, change.faction.clone())) {
             Some(bc) => {
                 *bc = *bc + 1;
             },
             None => {self.battle_faction_hash.insert((change.user_id, change.faction.clone()), 1);}
         }
-        match self.users.get(user_id) {
+        match self.users.get(&user_id) {
             Some(user) => {
-                // Existing user: apply MMR delta and accumulate counters.
+                // Existing user: apply MMR delta and accumulate counters. The delta applies to
+                // the pool MMR (`change.mmr`), the calibration estimate of a provisional player.
+                let mmr = (math::max(change.mmr.get() as i32 + diff_mmr, 0)) as u32;
+                // Calibrated players are indexed for the calibration estimates.
+                if user.battles >= 6 {
+                    self.battle_score_hash.remove(&(user.battle_score / user.battles, user_id));
+                }
+                if user.battles + 1 >= 6 {
+                    self.battle_score_hash.insert(((user.battle_score + userstat_row.battle_score) / (user.battles + 1), user_id), mmr);
+                }
+                let _ = sender_tasks.send(record);
 
-                self.users.insert(*user_id, LeaderboardRow{
-                    user_id: *user_id,
-                    mmr: (math::max(user.mmr as i32 + diff_mmr, 0)) as u32,
+                self.users.insert(user_id, LeaderboardRow{
+                    user_id,
+                    mmr,
                     battles: user.battles + 1,
                     victories: user.victories + if userstat_row.victories {1} else {0},
                     early_quites: user.early_quites + if userstat_row.early_quit {1} else {0},
@@ -603,7 +311,7 @@ impl LeaderboardV2 {
             None => {
                 // New user: initialize row from current session contribution.
                 let row = LeaderboardRow{
-                    user_id: *user_id,
+                    user_id,
                     mmr: (math::max(diff_mmr, 0)) as u32,
                     battles: 1,
                     victories: if userstat_row.victories {1} else {0},
@@ -612,7 +320,8 @@ impl LeaderboardV2 {
                     battle_score: userstat_row.battle_score,
                     last_session: change.last_session
                 };
-                self.users.insert(*user_id, row);
+                let _ = sender_tasks.send(record);
+                self.users.insert(user_id, row);
             }
         }
     }
@@ -621,18 +330,26 @@ impl LeaderboardV2 {
     /// Identical update logic to [`set_change`] but prints `userstat_row`, `change`, `debug`
     /// and `diff_mmr` to stdout for inspection. Dead-code sender calls are retained as
     /// commented-out blocks for future use.
-    fn set_change_lite(&mut self, user_id: &u64, userstat_row: &UserBattleRow, diff_mmr: i32, change: LeaderboardChangeV2, debug: MMRChangeDebugV2
-    ){
+    fn set_change_lite(&mut self, pool_change: PoolChange) {
+        let PoolChange { user_id, user: userstat_row, diff_mmr, change, debug } = pool_change;
         // Lite mode prints detailed debug payload for inspection.
         println!("{:?}\n{:?} == {:?}\n{}", userstat_row, change, debug, diff_mmr);
+        // A rated session restarts the player's idle clock.
+        self.decay.played(change.user_id);
         match self.battle_faction_hash.get_mut(&(change.user_id, change.faction.clone())) {
             Some(bc) => {
                 *bc = *bc + 1;
             },
             None => {self.battle_faction_hash.insert((change.user_id, change.faction.clone()), 1);}
         }
-        match self.users.get(user_id) {
+        match self.users.get(&user_id) {
             Some(user) => {
+                if user.battles >= 6 {
+                    self.battle_score_hash.remove(&(user.battle_score / user.battles, user_id));
+                }
+                if user.battles + 1 >= 6 {
+                    self.battle_score_hash.insert(((user.battle_score + userstat_row.battle_score) / (user.battles + 1), user_id), (math::max(change.mmr.get() as i32 + diff_mmr, 0)) as u32);
+                }
 
                 /* let _ = sender_tasks
                 .send((
@@ -642,9 +359,9 @@ impl LeaderboardV2 {
                     debug,
                     cl_id
                 )); */
-                self.users.insert(*user_id, LeaderboardRow{
-                    user_id: *user_id,
-                    mmr: (math::max(user.mmr as i32 + diff_mmr, 0)) as u32,
+                self.users.insert(user_id, LeaderboardRow{
+                    user_id,
+                    mmr: (math::max(change.mmr.get() as i32 + diff_mmr, 0)) as u32,
                     battles: user.battles + 1,
                     victories: user.victories + if userstat_row.victories {1} else {0},
                     early_quites: user.early_quites + if userstat_row.early_quit {1} else {0},
@@ -655,7 +372,7 @@ impl LeaderboardV2 {
             },
             None => {
                 let row = LeaderboardRow{
-                    user_id: *user_id,
+                    user_id,
                     mmr: (math::max(diff_mmr, 0)) as u32,
                     battles: 1,
                     victories: if userstat_row.victories {1} else {0},
@@ -672,8 +389,150 @@ impl LeaderboardV2 {
                     debug,
                     cl_id
                 )); */
-                self.users.insert(*user_id, row);
+                self.users.insert(user_id, row);
+            }
+        }
+    }
+}
+
+impl Default for LeaderboardV2 {
+    fn default() -> Self {
+        <Self as RatingSystem>::empty()
+    }
+}
+
+impl RatingSystem for LeaderboardV2 {
+    type Change = ChangeRecordV2;
+    type Prepared = Vec<PoolChange>;
+    const NAME: &'static str = "v2";
+
+    fn restore_from(dir: &str) -> Result<Self, SnapshotError> {
+        LeaderboardV2::open(dir)
+    }
+
+    fn empty() -> Self {
+        Self {
+            users: std::collections::HashMap::new(),
+            sets: Vec::new(),
+            battle_score_hash: std::collections::BTreeMap::new(),
+            battle_faction_hash: std::collections::HashMap::new(),
+            params: PoolParams::default(),
+            decay: DecayState::default(),
+            calibration: CalibrationParams::default(),
+            bootstrap_excluded: std::collections::HashSet::new()
+        }
+    }
+
+    fn set_params(&mut self, params: &MMRParams) {
+        self.params = params.v2.clone();
+        self.decay.params = params.decay.clone();
+        self.calibration = params.calibration.clone();
+    }
+
+    fn apply_decay(&mut self, user_id: u64, now: u64) -> bool {
+        self.decay.decay_user(user_id, now, &mut self.users, &mut self.battle_score_hash)
+    }
+
+    fn decay_sweep(&mut self, now: u64) -> u64 {
+        self.decay.sweep(now, &mut self.users, &mut self.battle_score_hash)
+    }
+
+    fn decay_mean(&self) -> Option<(f64, u64)> {
+        Some((self.decay.mean, self.decay.mean_at))
+    }
+
+    fn set_decay_mean(&mut self, mean: (f64, u64)) {
+        (self.decay.mean, self.decay.mean_at) = mean;
+    }
+
+    fn save_decay(&self, user_ids: &[u64]) -> DecayUndo {
+        self.decay.save(user_ids, &self.users, &self.battle_score_hash)
+    }
+
+    fn undo_decay(&mut self, undo: DecayUndo) {
+        self.decay.undo(undo, &mut self.users, &mut self.battle_score_hash)
+    }
+
+    fn season_reset(&mut self, params: &SeasonParams) -> u64 {
+        season::soft_reset(&mut self.users, &mut self.battle_score_hash, params)
+    }
+
+    async fn calibration_estimate(&self, row: &LeaderboardRow) -> Option<CalibrationEstimate> {
+        calibration::estimate(&self.users, &self.battle_score_hash, &self.bootstrap_excluded, &CalibrationFeatures::from_row(row)?, &self.calibration)
+    }
+
+    fn exclude_from_bootstrap(&mut self, user_id: u64) {
+        self.bootstrap_excluded.insert(user_id);
+    }
+
+    async fn persist_to(&self, dir: &str) {
+        self.write_to(dir).await
+    }
+
+    fn seed_from(&mut self, user_id: u64, global: &Self, params: &ModeParams) -> bool {
+        if self.users.contains_key(&user_id) {
+            return false;
+        }
+        match global.users.get(&user_id).and_then(|row| modes::seed_row(row, params)) {
+            Some(row) => {
+                if row.battles >= 6 {
+                    self.battle_score_hash.insert((row.battle_score / row.battles, user_id), row.mmr);
+                }
+                self.users.insert(user_id, row);
+                true
             }
+            None => false
+        }
+    }
+
+    fn get_mmr(&self, user_id: u64) -> MMRType {
+        LeaderboardV2::get_mmr(self, user_id)
+    }
+
+    fn get_battles(&self, user_id: u64) -> u32 {
+        LeaderboardV2::get_battles(self, user_id)
+    }
+
+    fn get_users(&self) -> &std::collections::HashMap<u64, LeaderboardRow> {
+        &self.users
+    }
+
+    /// Computes the shared pool deltas via [`LeaderboardV2::pool_changes`].
+    fn prepare_session(&self, teams: &SessionTeams) -> Vec<PoolChange> {
+        self.pool_changes(teams)
+    }
+
+    /// Applies the pool deltas with [`LeaderboardV2::set_change`].
+    async fn apply_session(&mut self, prepared: Vec<PoolChange>, cl_id: u16, sender_tasks: flume::Sender<ChangeRecordV2>) {
+        for pool_change in prepared {
+            self.set_change(pool_change, sender_tasks.clone(), cl_id);
+        }
+    }
+
+    async fn preview_session(&self, prepared: Vec<PoolChange>, cl_id: u16) -> Vec<ChangeRecordV2> {
+        prepared.iter().map(|pool_change| self.change_record(pool_change, cl_id)).collect()
+    }
+
+    /// The decay and the k-NN calibration read the whole population.
+    fn batch_safe(&self) -> bool {
+        !self.decay.enabled() && self.calibration.neighbours == 0
+    }
+
+    async fn write_change(change: ChangeRecordV2, change_files: &mut [BufWriter<File>]) {
+        let (change, mmr_diff, user_row, debug, cl_id) = change;
+        writer::write_change_v2(change, mmr_diff, user_row, &mut change_files[cl_id as usize], debug).await;
+    }
+
+    fn history_entry(change: &ChangeRecordV2) -> HistoryEntry {
+        let (change, mmr_diff, user_row, debug, _cl_id) = change;
+        HistoryEntry {
+            user_id: change.user_id,
+            session_id: change.session_id,
+            commit_time: change.last_session,
+            mmr_before: user_row.mmr,
+            mmr_after: (user_row.mmr as i64 + *mmr_diff as i64) as u32,
+            mode: String::new(),
+            components: vec![debug.0, debug.1, debug.2, debug.3, debug.4, debug.5, debug.6, debug.7, debug.8, debug.9, debug.10]
         }
     }
-}
\ No newline at end of file
+}
@@ -8,8 +8,29 @@ pub mod memory;
 pub mod writer;
 pub mod statistic;
 pub mod leaderboard_v2;
+pub mod leaderboard_glicko;
+pub mod leaderboard_trueskill;
 pub mod spread;
-pub mod reader;
+pub mod jsonl;
+pub mod rating;
+pub mod algorithm;
+pub mod evaluation;
+pub mod params;
+pub mod decay;
+pub mod snapshot;
+pub mod modes;
+pub mod matchmaker;
+pub mod checkpoint;
+pub mod history;
+pub mod ranking;
+pub mod abuse;
+pub mod calibration;
+pub mod batch;
+pub mod classification;
+pub mod factions;
+pub mod estimate;
+pub mod win_model;
+pub mod season;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,401 @@
+use crate::classification::{is_newbie, SessionRules, DISBALANCE_GAP};
+use crate::datasets::{Registrations, SessionMode};
+use crate::factions::{FactionId, FactionRegistry};
+use crate::memory::SessionMemory;
+use crate::rating::{self, top_3_avg, RatingSystem, SessionTeams};
+use crate::spread::dominant_faction;
+use crate::types::{MMRType, UserBattleRow};
+
+// Cost per MMR point or newbie over a hard limit; dominates every soft term.
+const VIOLATION_COST: f64 = 1000.0;
+// Upper bound of improving swaps per match.
+const MAX_SWAPS: usize = 100;
+
+/// Constraints and preferences of [`make_match`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchParams {
+    // Players per team; the first `2 * team_size` queue entries are matched.
+    pub team_size: usize,
+    // Largest allowed gap between the top-3 calibrated MMR averages of the teams.
+    pub max_top_3_gap: u32,
+    // Largest allowed difference between the newbie counts of the teams.
+    pub max_newbie_diff: u32,
+    // Cost in MMR points of placing a player against their main faction; 0 ignores it.
+    pub faction_weight: f64,
+    // Team 1 and team 2 play the first two registered factions.
+    pub factions: FactionRegistry
+}
+
+/// A queued player as seen by the matchmaker.
+#[derive(Clone, Debug)]
+pub struct QueueEntry {
+    pub row: UserBattleRow,
+    pub mmr: MMRType,
+    pub newbie: bool,
+    // Main faction (a registered one), `None` for mixed or unknown players.
+    pub faction: Option<String>
+}
+
+/// Two teams formed from a queue; team 1 plays the first registered faction, team 2 the
+/// second (`faction_1` / `faction_2` by default).
+#[derive(Clone, Debug)]
+pub struct Match {
+    pub team_1: Vec<QueueEntry>,
+    pub team_2: Vec<QueueEntry>,
+    // `None` when a team has no calibrated player.
+    pub top_3_gap: Option<u32>,
+    pub newbie_diff: u32,
+    // Players placed against their main faction.
+    pub faction_misses: u32,
+    // Whether the top-3 gap and newbie spread limits hold.
+    pub feasible: bool
+}
+
+/// Balance counters of a set of sessions (original or re-matched).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MatchMetrics {
+    pub sessions: u64,
+    // Sessions where both teams have a calibrated top-3.
+    pub compared: u64,
+    pub top_3_gap_sum: f64,
+    // Sessions with a top-3 gap over 800 (the `Statistic` disbalance threshold).
+    pub disbalanced: u64,
+    // Sessions with the `Statistic` newbie skew (69%+ newbies on one side, under 31% on the other).
+    pub newbie_skew: u64,
+    // Sum of |p - 0.5| over the predicted team 1 win probabilities.
+    pub probability_gap_sum: f64,
+    pub faction_misses: u64
+}
+
+/// Historical sessions against their re-matched version, per group.
+#[derive(Clone, Debug, Default)]
+pub struct MatchReport {
+    // group -> (original, re-matched)
+    pub groups: std::collections::BTreeMap<String, (MatchMetrics, MatchMetrics)>,
+    // Re-matched sessions that violate a limit.
+    pub infeasible: u64
+}
+
+impl Default for MatchParams {
+    fn default() -> Self {
+        Self {
+            team_size: 6,
+            max_top_3_gap: DISBALANCE_GAP,
+            max_newbie_diff: 1,
+            faction_weight: 100.0,
+            factions: FactionRegistry::default()
+        }
+    }
+}
+
+/// Main faction of `user_id`: the dominant faction of `factions`, 65% or more of their
+/// battles on it by default (the `spread` rule).
+pub fn main_faction<T: RatingSystem>(rating: &T, user_id: u64, factions: &FactionRegistry) -> Option<String> {
+    let battles = rating.get_battles(user_id);
+    if battles == 0 {
+        return None;
+    }
+    Some(dominant_faction(rating, user_id, battles, factions)).filter(|faction| *faction != factions.mixed)
+}
+
+/// Queues `row` with the current rating, newbie status and main faction of the player.
+pub fn queue_entry<T: RatingSystem>(rating: &T, registrations: &Registrations, row: &UserBattleRow, factions: &FactionRegistry) -> QueueEntry {
+    QueueEntry {
+        row: row.clone(),
+        mmr: rating.get_mmr(row.user_id),
+        newbie: is_newbie(registrations, row),
+        faction: main_faction(rating, row.user_id, factions)
+    }
+}
+
+/// Forms two teams from the first `2 * team_size` entries of `queue`.
+///
+/// 1. Players are ordered by strength (calibrated first, highest MMR first) and drafted in
+///    snake order (1, 2, 2, 1, ...).
+/// 2. The best cross-team swap is applied while it lowers the cost: top-3 gap plus the gap
+///    of the calibrated averages, `faction_weight` per faction miss, and a dominating penalty
+///    for every point over the top-3 or newbie limit.
+///
+/// Returns `None` when the queue is too short. A match that cannot meet the limits is
+/// returned with `feasible: false`.
+pub fn make_match(queue: &[QueueEntry], params: &MatchParams) -> Option<Match> {
+    if params.team_size == 0 || queue.len() < 2 * params.team_size {
+        return None;
+    }
+    let mut players: Vec<QueueEntry> = queue[..2 * params.team_size].to_vec();
+    players.sort_by_key(|entry| std::cmp::Reverse(strength(&entry.mmr)));
+
+    let mut team_1 = Vec::new();
+    let mut team_2 = Vec::new();
+    for (index, entry) in players.into_iter().enumerate() {
+        if index % 4 == 0 || index % 4 == 3 {
+            team_1.push(entry);
+        } else {
+            team_2.push(entry);
+        }
+    }
+
+    let mut cost = match_cost(&team_1, &team_2, params);
+    for _ in 0..MAX_SWAPS {
+        let mut best: Option<(usize, usize, f64)> = None;
+        for i in 0..team_1.len() {
+            for j in 0..team_2.len() {
+                std::mem::swap(&mut team_1[i], &mut team_2[j]);
+                let swapped = match_cost(&team_1, &team_2, params);
+                std::mem::swap(&mut team_1[i], &mut team_2[j]);
+                if swapped < best.map(|item| item.2).unwrap_or(cost) {
+                    best = Some((i, j, swapped));
+                }
+            }
+        }
+        match best {
+            Some((i, j, swapped)) => {
+                std::mem::swap(&mut team_1[i], &mut team_2[j]);
+                cost = swapped;
+            },
+            None => break
+        }
+    }
+
+    let top_3_gap = top_3_gap(&team_1, &team_2);
+    let newbie_diff = newbie_diff(&team_1, &team_2);
+    Some(Match {
+        faction_misses: faction_misses(&team_1, &team_2, &params.factions),
+        feasible: top_3_gap.unwrap_or(0) <= params.max_top_3_gap && newbie_diff <= params.max_newbie_diff,
+        top_3_gap,
+        newbie_diff,
+        team_1,
+        team_2
+    })
+}
+
+/// Matches a queue in arrival order, `2 * team_size` players at a time; the remainder stays
+/// queued.
+pub fn drain_matches(queue: &mut Vec<QueueEntry>, params: &MatchParams) -> Vec<Match> {
+    let mut matches = Vec::new();
+    while let Some(found) = make_match(queue, params) {
+        queue.drain(..2 * params.team_size);
+        matches.push(found);
+    }
+    matches
+}
+
+impl Match {
+    /// Session rows of the match: team 1 as the first faction of `factions`, team 2 as the
+    /// second.
+    ///
+    /// The outcome of a re-matched session is unknown, so `victories` is cleared.
+    pub fn to_session(&self, session_id: u64, factions: &FactionRegistry) -> SessionMemory {
+        let side = |entry: &QueueEntry, team: u8| {
+            let mut row = entry.row.clone();
+            row.session_id = session_id;
+            row.team = team;
+            row.faction = factions.name(FactionId(team)).unwrap_or_default().to_string();
+            row.victories = false;
+            row
+        };
+        SessionMemory {
+            now_session_id: session_id,
+            rows: self.team_1.iter().map(|entry| side(entry, 1)).chain(self.team_2.iter().map(|entry| side(entry, 2))).collect()
+        }
+    }
+}
+
+impl MatchMetrics {
+    /// Adds one session with its predicted team 1 win probability.
+    pub fn add(&mut self, teams: &SessionTeams, probability: f64, registrations: &Registrations, faction_misses: u32) {
+        self.sessions += 1;
+        if let (Some(team_1), Some(team_2)) = (top_3_avg(&teams.team_1_mmr), top_3_avg(&teams.team_2_mmr)) {
+            let gap = (team_1 as i64 - team_2 as i64).unsigned_abs() as u32;
+            self.compared += 1;
+            self.top_3_gap_sum += gap as f64;
+            if gap > DISBALANCE_GAP {
+                self.disbalanced += 1;
+            }
+        }
+        let share = |team: &Vec<UserBattleRow>| {
+            team.iter().filter(|row| is_newbie(registrations, row)).count() as f64 / team.len().max(1) as f64
+        };
+        let (share_1, share_2) = (share(&teams.team_1), share(&teams.team_2));
+        if (share_1 >= 0.69 && share_2 < 0.31) || (share_2 >= 0.69 && share_1 < 0.31) {
+            self.newbie_skew += 1;
+        }
+        self.probability_gap_sum += (probability - 0.5).abs();
+        self.faction_misses += faction_misses as u64;
+    }
+
+    pub fn to_string(&self, group: &str, side: &str) -> String {
+        let mean = |sum: f64, count: u64| if count == 0 {0.0} else {sum / count as f64};
+        "group:".to_string() + group
+            + ",side:" + side
+            + ",sessions:" + self.sessions.to_string().as_str()
+            + ",top_3_gap:" + format!("{:.2}", mean(self.top_3_gap_sum, self.compared)).as_str()
+            + ",disbalanced:" + self.disbalanced.to_string().as_str()
+            + ",newbie_skew:" + self.newbie_skew.to_string().as_str()
+            + ",probability_gap:" + format!("{:.4}", mean(self.probability_gap_sum, self.sessions)).as_str()
+            + ",faction_misses:" + self.faction_misses.to_string().as_str()
+            + "\n"
+    }
+}
+
+impl std::fmt::Display for MatchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (group, (original, rematched)) in self.groups.iter() {
+            f.write_str(original.to_string(group, "original").as_str())?;
+            f.write_str(rematched.to_string(group, "rematched").as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays `sessions` in order, comparing every rated session with a re-match of its players.
+///
+/// Only sessions with two teams of `rules.factions` are re-matched. The queue of a session is
+/// its rows of both factions in input order, split into two equal teams under `params`
+/// (`team_size` comes from the session). Both versions are
+/// measured with the ratings known before the session, and the original session is then
+/// applied through [`rating::proc_session`] with classifier id `0`. Sessions are grouped
+/// under `common` and their normalized mode.
+pub async fn replay<T: RatingSystem>(
+    rating: &mut T,
+    sessions: &[SessionMemory],
+    session_mode: &SessionMode,
+    registrations: &Registrations,
+    rules: &SessionRules,
+    params: &MatchParams
+) -> MatchReport {
+    let mut report = MatchReport::default();
+    let (sender, _) = flume::unbounded();
+    let (sender_tasks, _) = flume::unbounded::<T::Change>();
+    let (sender_check, _) = flume::unbounded();
+    let (sender_session_class, _) = flume::unbounded();
+    for session in sessions.iter() {
+        let teams = SessionTeams::new(rating, session, &rules.factions);
+        if teams.teams.len() == 2 && teams.is_rated(rules, session_mode, registrations) {
+            let queue: Vec<QueueEntry> = session.rows.iter()
+                .filter(|row| rules.factions.id(&row.faction).is_some())
+                .map(|row| queue_entry(rating, registrations, row, &rules.factions))
+                .collect();
+            let session_params = MatchParams { team_size: queue.len() / 2, factions: rules.factions.clone(), ..params.clone() };
+            if let Some(rematch) = make_match(&queue, &session_params) {
+                let original_misses = faction_misses_of(&queue);
+                let rematched_teams = SessionTeams::new(rating, &rematch.to_session(session.now_session_id, &rules.factions), &rules.factions);
+                if !rematch.feasible {
+                    report.infeasible += 1;
+                }
+                let mut groups = vec!["common".to_string()];
+                if let Some((_mode_0, mode_1, _mode_2)) = session_mode.0.get(&session.now_session_id) {
+                    groups.push(mode_1.clone());
+                }
+                for group in groups {
+                    let entry = report.groups.entry(group).or_default();
+                    entry.0.add(&teams, rating.win_probability(&teams), registrations, original_misses);
+                    entry.1.add(&rematched_teams, rating.win_probability(&rematched_teams), registrations, rematch.faction_misses);
+                }
+            }
+        }
+        rating::proc_session(rating, session.clone(), 0, sender.clone(), session_mode, registrations, rules, sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+    }
+    report
+}
+
+/// Sort key of the draft: calibrated players first, then provisional, then unknown.
+fn strength(mmr: &MMRType) -> (u8, u32) {
+    match mmr {
+        MMRType::MMR(mmr) => (2, *mmr),
+        MMRType::NotEnought(mmr) => (1, *mmr),
+        MMRType::None => (0, 0)
+    }
+}
+
+/// Calibrated MMRs of a team, highest first.
+fn calibrated(team: &[QueueEntry]) -> Vec<u32> {
+    let mut mmrs: Vec<u32> = team.iter().filter_map(|entry| match entry.mmr {
+        MMRType::MMR(mmr) => Some(mmr),
+        _ => None
+    }).collect();
+    mmrs.sort_unstable_by(|a, b| b.cmp(a));
+    mmrs
+}
+
+fn average(mmrs: &[u32]) -> Option<f64> {
+    if mmrs.is_empty() {None} else {Some(mmrs.iter().map(|mmr| *mmr as f64).sum::<f64>() / mmrs.len() as f64)}
+}
+
+fn top_3_gap(team_1: &[QueueEntry], team_2: &[QueueEntry]) -> Option<u32> {
+    let top_1 = calibrated(team_1).into_iter().take(3).collect::<Vec<u32>>();
+    let top_2 = calibrated(team_2).into_iter().take(3).collect::<Vec<u32>>();
+    match (average(&top_1), average(&top_2)) {
+        (Some(a), Some(b)) => Some((a - b).abs() as u32),
+        _ => None
+    }
+}
+
+fn newbie_diff(team_1: &[QueueEntry], team_2: &[QueueEntry]) -> u32 {
+    let count = |team: &[QueueEntry]| team.iter().filter(|entry| entry.newbie).count() as i64;
+    (count(team_1) - count(team_2)).unsigned_abs() as u32
+}
+
+fn faction_misses(team_1: &[QueueEntry], team_2: &[QueueEntry], factions: &FactionRegistry) -> u32 {
+    let misses = |team: &[QueueEntry], id: FactionId| {
+        let faction = factions.name(id).unwrap_or_default();
+        team.iter().filter(|entry| matches!(&entry.faction, Some(main) if main != faction)).count() as u32
+    };
+    misses(team_1, FactionId(1)) + misses(team_2, FactionId(2))
+}
+
+/// Faction misses of a historical queue, where every row keeps its played faction.
+fn faction_misses_of(queue: &[QueueEntry]) -> u32 {
+    queue.iter().filter(|entry| matches!(&entry.faction, Some(main) if *main != entry.row.faction)).count() as u32
+}
+
+fn match_cost(team_1: &[QueueEntry], team_2: &[QueueEntry], params: &MatchParams) -> f64 {
+    let top_3_gap = top_3_gap(team_1, team_2).unwrap_or(0);
+    let avg_gap = match (average(&calibrated(team_1)), average(&calibrated(team_2))) {
+        (Some(a), Some(b)) => (a - b).abs(),
+        _ => 0.0
+    };
+    let newbie_excess = newbie_diff(team_1, team_2).saturating_sub(params.max_newbie_diff);
+    top_3_gap as f64 + avg_gap
+        + params.faction_weight * faction_misses(team_1, team_2, &params.factions) as f64
+        + VIOLATION_COST * (top_3_gap.saturating_sub(params.max_top_3_gap) as f64 + newbie_excess as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(user_id: u64, mmr: u32, newbie: bool, faction: Option<&str>) -> QueueEntry {
+        QueueEntry {
+            row: UserBattleRow { user_id, session_id: 1, commit_time: 0, team: 1, battle_score: 0, victories: false, early_quit: false, team_score_top_20_percent: false, faction: "faction_1".to_string() },
+            mmr: if mmr == 0 {MMRType::None} else {MMRType::MMR(mmr)},
+            newbie,
+            faction: faction.map(|faction| faction.to_string())
+        }
+    }
+
+    #[test]
+    fn match_balances_top_3_newbies_and_factions() {
+        let queue = vec![
+            entry(1, 3000, false, Some("faction_2")), entry(2, 2900, false, None), entry(3, 2000, false, None),
+            entry(4, 1900, false, None), entry(5, 1000, false, None), entry(6, 900, false, None),
+            entry(7, 0, true, None), entry(8, 0, true, None), entry(9, 500, false, None)
+        ];
+        let params = MatchParams { team_size: 4, max_top_3_gap: 200, max_newbie_diff: 0, faction_weight: 100.0, factions: FactionRegistry::default() };
+        let found = make_match(&queue, &params).unwrap();
+        assert!(found.feasible);
+        assert_eq!((found.team_1.len(), found.team_2.len()), (4, 4));
+        assert_eq!(found.newbie_diff, 0);
+        assert_eq!(found.faction_misses, 0);
+        assert!(found.team_2.iter().any(|entry| entry.row.user_id == 1));
+        assert!(found.top_3_gap.unwrap() <= 200);
+
+        let session = found.to_session(7, &params.factions);
+        assert!(session.rows.iter().all(|row| row.session_id == 7 && row.faction == "faction_".to_string() + row.team.to_string().as_str()));
+
+        // The ninth player stays queued.
+        let mut queue = queue;
+        assert_eq!(drain_matches(&mut queue, &params).len(), 1);
+        assert_eq!(queue.len(), 1);
+    }
+}
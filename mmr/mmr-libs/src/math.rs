@@ -1,6 +1,7 @@
 use core::f64;
 
-use crate::types::{MMRChangeDebug, MMRType};
+use crate::params::DiffMMRParams;
+use crate::types::{GlickoRating, MMRChangeDebug, MMRType, TrueSkillRating};
 
 
 /// Signed power curve: preserves the sign of `x`, scales by `a` and shifts by `b`.
@@ -174,12 +175,15 @@ pub fn avg_3(mmrs: Vec<MMRType>) -> Option<u32> {
 ///    - early quit: −20
 ///    - top-20 percent team score: +20
 ///
+/// The defeat branch shifts the score component by −66. All constants above are the
+/// defaults of `params` ([`DiffMMRParams`]).
+///
 /// Returns a tuple of `(mmr_delta, MMRChangeDebug)` where `mmr_delta` is clamped to
 /// ≥ 0 on the victory branch.
-pub fn diff_mmr(victory: bool, score: i32, top_3: Vec<MMRType>, mmr: MMRType, early_quite: bool, top_20: bool) -> (i32, MMRChangeDebug) {
+pub fn diff_mmr(victory: bool, score: i32, top_3: Vec<MMRType>, mmr: MMRType, early_quite: bool, top_20: bool, params: &DiffMMRParams) -> (i32, MMRChangeDebug) {
     if victory {
         // Victory branch: base gain from score + situational modifiers + matchup pressure.
-        let score_mmr: i32 = (50.0 / (std::f64::consts::E.powf(1000000.0 / (score as f64).powf(2.0)))) as i32;
+        let score_mmr: i32 = (params.score_max / (std::f64::consts::E.powf(params.score_scale / (score as f64).powf(2.0)))) as i32;
         let mmr_diff = match mmr {
             MMRType::MMR(data) => {
                 let avg_opp: i32 = match avg_3(top_3.clone()) {
@@ -190,18 +194,18 @@ pub fn diff_mmr(victory: bool, score: i32, top_3: Vec<MMRType>, mmr: MMRType, ea
             },
             _ => 0
         };
-        let early_quite_bonus: i32 = if early_quite {-20} else {0};
-        let top_20_bonus = if top_20 {20} else {0};
+        let early_quite_bonus: i32 = if early_quite {params.early_quit as i32} else {0};
+        let top_20_bonus = if top_20 {params.top_20 as i32} else {0};
         // Apply matchup pressure only for large MMR gaps.
-        let mul = if mmr_diff.abs() < 250 {0.0} else { 
-            2.0 / (1.0 + std::f64::consts::E.powf(-0.001 * (mmr_diff as f64))) - 1.0
+        let mul = if (mmr_diff.abs() as f64) < params.gap_threshold {0.0} else { 
+            2.0 / (1.0 + std::f64::consts::E.powf(-params.pressure_slope * (mmr_diff as f64))) - 1.0
         };
         let mmr_base = (score_mmr + early_quite_bonus + top_20_bonus) as f64;
         //dbg!((mmr_base, score_mmr, mul, mmr_diff));
-        (if mmr_base + mul * 35.0 < 0.0 {0} else {(mmr_base + mul * 35.0) as i32}, MMRChangeDebug(score_mmr, mmr_diff, early_quite_bonus, top_20_bonus, mul, mmr_base))
+        (if mmr_base + mul * params.pressure < 0.0 {0} else {(mmr_base + mul * params.pressure) as i32}, MMRChangeDebug(score_mmr, mmr_diff, early_quite_bonus, top_20_bonus, mul, mmr_base))
     } else {
         // Defeat branch: base loss = score_curve − 66 + situational modifiers + matchup pressure.
-        let score_mmr: i32 = (50.0 / (std::f64::consts::E.powf(1000000.0 / (score as f64).powf(2.0)))) as i32 - 66;
+        let score_mmr: i32 = (params.score_max / (std::f64::consts::E.powf(params.score_scale / (score as f64).powf(2.0)))) as i32 + params.defeat_offset as i32;
         let mmr_diff = match mmr {
             MMRType::MMR(data) => {
                 let avg_opp: i32 = match avg_3(top_3.clone()) {
@@ -212,14 +216,304 @@ pub fn diff_mmr(victory: bool, score: i32, top_3: Vec<MMRType>, mmr: MMRType, ea
             },
             _ => 0
         };
-        let early_quite_bonus: i32 = if early_quite {-20} else {0};
-        let top_20_bonus = if top_20 {20} else {0};
+        let early_quite_bonus: i32 = if early_quite {params.early_quit as i32} else {0};
+        let top_20_bonus = if top_20 {params.top_20 as i32} else {0};
         // Apply matchup pressure only for large MMR gaps.
-        let mul = if mmr_diff.abs() < 250 {0.0} else { 
-            2.0 / (1.0 + std::f64::consts::E.powf(-0.001 * (mmr_diff as f64))) - 1.0
+        let mul = if (mmr_diff.abs() as f64) < params.gap_threshold {0.0} else { 
+            2.0 / (1.0 + std::f64::consts::E.powf(-params.pressure_slope * (mmr_diff as f64))) - 1.0
         };
         let mmr_base = (score_mmr + early_quite_bonus + top_20_bonus) as f64;
         //dbg!((mmr_base, score_mmr, mul, mmr_diff));
-        ((mmr_base - mul * 35.0) as i32, MMRChangeDebug(score_mmr, mmr_diff, early_quite_bonus, top_20_bonus, mul, mmr_base))
+        ((mmr_base - mul * params.pressure) as i32, MMRChangeDebug(score_mmr, mmr_diff, early_quite_bonus, top_20_bonus, mul, mmr_base))
+    }
+}
+
+/// Conversion factor between the Glicko rating scale and the internal Glicko-2 scale.
+pub const GLICKO2_SCALE: f64 = 173.7178;
+
+/// Glicko-2 impact factor `g(φ) = 1 / sqrt(1 + 3φ² / π²)` of an opponent deviation.
+pub fn glicko2_g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi.powi(2) / std::f64::consts::PI.powi(2)).sqrt()
+}
+
+/// Glicko-2 expected score of a player at `mu` against an opponent at (`mu_j`, `phi_j`).
+pub fn glicko2_e(mu: f64, mu_j: f64, phi_j: f64) -> f64 {
+    1.0 / (1.0 + (-glicko2_g(phi_j) * (mu - mu_j)).exp())
+}
+
+/// Rates `player` over one rating period using the Glicko-2 system.
+///
+/// `results` holds `(opponent, score)` pairs with `score` in `[0, 1]` (1 win, 0.5 draw,
+/// 0 loss); opponents are taken at their pre-period state. `tau` constrains the volatility
+/// change. With no results only the deviation grows by the volatility (one idle period).
+///
+/// The new volatility is found with the Illinois root-finding procedure from Glickman's
+/// "Example of the Glicko-2 system".
+pub fn glicko2_update(player: GlickoRating, results: &[(GlickoRating, f64)], tau: f64) -> GlickoRating {
+    let mu = (player.rating - 1500.0) / GLICKO2_SCALE;
+    let phi = player.deviation / GLICKO2_SCALE;
+    let sigma = player.volatility;
+
+    if results.is_empty() {
+        return GlickoRating {
+            rating: player.rating,
+            deviation: (phi.powi(2) + sigma.powi(2)).sqrt() * GLICKO2_SCALE,
+            volatility: sigma
+        };
+    }
+
+    // Estimated variance `v` and improvement `delta` from the period's game outcomes.
+    let (v_inv, delta_sum) = results.iter().fold((0.0, 0.0), |base, (opponent, score)| {
+        let mu_j = (opponent.rating - 1500.0) / GLICKO2_SCALE;
+        let phi_j = opponent.deviation / GLICKO2_SCALE;
+        let g = glicko2_g(phi_j);
+        let e = glicko2_e(mu, mu_j, phi_j);
+        (base.0 + g.powi(2) * e * (1.0 - e), base.1 + g * (score - e))
+    });
+    let v = 1.0 / v_inv;
+    let delta = v * delta_sum;
+
+    // Volatility: root of f(x) with x = ln(σ'²).
+    let a = sigma.powi(2).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        ex * (delta.powi(2) - phi.powi(2) - v - ex) / (2.0 * (phi.powi(2) + v + ex).powi(2)) - (x - a) / tau.powi(2)
+    };
+    let mut big_a = a;
+    let mut big_b = if delta.powi(2) > phi.powi(2) + v {
+        (delta.powi(2) - phi.powi(2) - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * tau) < 0.0 {
+            k += 1.0;
+        }
+        a - k * tau
+    };
+    let mut f_a = f(big_a);
+    let mut f_b = f(big_b);
+    while (big_b - big_a).abs() > 0.000001 {
+        let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+        let f_c = f(big_c);
+        if f_c * f_b <= 0.0 {
+            big_a = big_b;
+            f_a = f_b;
+        } else {
+            f_a /= 2.0;
+        }
+        big_b = big_c;
+        f_b = f_c;
+    }
+    let sigma_new = (big_a / 2.0).exp();
+
+    let phi_star = (phi.powi(2) + sigma_new.powi(2)).sqrt();
+    let phi_new = 1.0 / (1.0 / phi_star.powi(2) + 1.0 / v).sqrt();
+    let mu_new = mu + phi_new.powi(2) * delta_sum;
+
+    GlickoRating {
+        rating: mu_new * GLICKO2_SCALE + 1500.0,
+        deviation: phi_new * GLICKO2_SCALE,
+        volatility: sigma_new
+    }
+}
+
+/// Complementary error function (Numerical Recipes Chebyshev fit, |error| < 1.2e-7).
+pub fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t * (-z * z - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418
+        + t * (-0.18628806 + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587
+        + t * (-0.82215223 + t * 0.17087277))))))))).exp();
+    if x >= 0.0 {r} else {2.0 - r}
+}
+
+/// Standard normal probability density.
+pub fn norm_pdf(x: f64) -> f64 {
+    (-x * x / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+/// Standard normal cumulative distribution.
+pub fn norm_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / std::f64::consts::SQRT_2)
+}
+
+/// TrueSkill mean (`v`) and variance (`w`) correction factors of a decided game.
+///
+/// `t` is the normalized performance gap in favour of the winner and `eps` the normalized
+/// draw margin.
+pub fn trueskill_win_factors(t: f64, eps: f64) -> (f64, f64) {
+    let x = t - eps;
+    let denom = norm_cdf(x);
+    if denom < 2.222758749e-162 {
+        // Far tail: v -> -x, w -> 1.
+        return (-x, 1.0);
+    }
+    let v = norm_pdf(x) / denom;
+    (v, v * (v + x))
+}
+
+/// TrueSkill mean (`v`) and variance (`w`) correction factors of a drawn game.
+///
+/// `t` is the normalized performance gap of the rated side and `eps` the normalized
+/// draw margin.
+pub fn trueskill_draw_factors(t: f64, eps: f64) -> (f64, f64) {
+    let t_abs = t.abs();
+    let denom = norm_cdf(eps - t_abs) - norm_cdf(-eps - t_abs);
+    if denom < 2.222758749e-162 {
+        return (if t < 0.0 {-t - eps} else {-t + eps}, 1.0);
     }
-}
\ No newline at end of file
+    let v = (norm_pdf(-eps - t_abs) - norm_pdf(eps - t_abs)) / denom;
+    let w = v * v + ((eps - t_abs) * norm_pdf(eps - t_abs) + (eps + t_abs) * norm_pdf(eps + t_abs)) / denom;
+    (if t < 0.0 {-v} else {v}, w)
+}
+
+/// Rates two teams after one game with a two-team TrueSkill factor graph.
+///
+/// Each team is a list of `(rating, weight)` pairs where `weight` in `[0, 1]` is the
+/// player's partial-play share of the team performance. `result` is the team-1 score
+/// (1 win, 0.5 draw, 0 loss). Every player first gains `tau` of dynamic uncertainty, then
+/// the team performance difference is truncated by the outcome and the correction is
+/// propagated back to each player in proportion to `weight * sigma²` (mean) and
+/// `weight² * sigma²` (variance).
+///
+/// Returns the updated ratings of team 1 and team 2 in input order.
+pub fn trueskill_update(team_1: &[(TrueSkillRating, f64)], team_2: &[(TrueSkillRating, f64)], result: f64, beta: f64, tau: f64, draw_margin: f64) -> (Vec<TrueSkillRating>, Vec<TrueSkillRating>) {
+    let dynamic = |team: &[(TrueSkillRating, f64)]| -> Vec<(f64, f64, f64)> {
+        team.iter().map(|(rating, weight)| (rating.mu, rating.sigma.powi(2) + tau.powi(2), *weight)).collect()
+    };
+    let team_1 = dynamic(team_1);
+    let team_2 = dynamic(team_2);
+
+    // Team performance: weighted sum of player performances N(mu, sigma² + beta²).
+    let c2 = team_1.iter().chain(team_2.iter()).fold(0.0, |base, (_, sigma2, weight)| base + weight.powi(2) * (sigma2 + beta.powi(2)));
+    let c = c2.sqrt();
+    let mu_1 = team_1.iter().fold(0.0, |base, (mu, _, weight)| base + weight * mu);
+    let mu_2 = team_2.iter().fold(0.0, |base, (mu, _, weight)| base + weight * mu);
+
+    // (v, w) from team 1's point of view; `sign` flips the mean correction for team 2.
+    let (v, w, sign) = if result > 0.5 {
+        let (v, w) = trueskill_win_factors((mu_1 - mu_2) / c, draw_margin / c);
+        (v, w, 1.0)
+    } else if result < 0.5 {
+        let (v, w) = trueskill_win_factors((mu_2 - mu_1) / c, draw_margin / c);
+        (v, w, -1.0)
+    } else {
+        let (v, w) = trueskill_draw_factors((mu_1 - mu_2) / c, draw_margin / c);
+        (v, w, 1.0)
+    };
+
+    let apply = |team: Vec<(f64, f64, f64)>, sign: f64| -> Vec<TrueSkillRating> {
+        team.into_iter().map(|(mu, sigma2, weight)| TrueSkillRating {
+            mu: mu + sign * weight * sigma2 / c * v,
+            sigma: (sigma2 * (1.0 - weight.powi(2) * sigma2 / c2 * w).max(0.0001)).sqrt()
+        }).collect()
+    };
+    (apply(team_1, sign), apply(team_2, -sign))
+}
+
+/// Ranks of `values` starting at 1; tied values share their average rank.
+pub fn average_ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        for index in order[start..end].iter() {
+            ranks[*index] = rank;
+        }
+        start = end;
+    }
+    ranks
+}
+
+/// Pearson correlation of two equally long samples; `None` for fewer than 2 points or a
+/// constant sample.
+pub fn pearson(x: &[f64], y: &[f64]) -> Option<f64> {
+    let n = x.len().min(y.len());
+    if n < 2 {
+        return None;
+    }
+    let mean_x = x[..n].iter().sum::<f64>() / n as f64;
+    let mean_y = y[..n].iter().sum::<f64>() / n as f64;
+    let (cov, var_x, var_y) = x[..n].iter().zip(y[..n].iter()).fold((0.0, 0.0, 0.0), |base, (a, b)| {
+        (base.0 + (a - mean_x) * (b - mean_y), base.1 + (a - mean_x).powi(2), base.2 + (b - mean_y).powi(2))
+    });
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+    Some(cov / (var_x * var_y).sqrt())
+}
+
+/// Spearman rank correlation: [`pearson`] over [`average_ranks`].
+pub fn spearman(x: &[f64], y: &[f64]) -> Option<f64> {
+    pearson(&average_ranks(x), &average_ranks(y))
+}
+
+/// Wilson score interval of a binomial proportion at `z` standard deviations
+/// (1.96 for 95%); `None` without trials.
+pub fn wilson_interval(successes: u64, trials: u64, z: f64) -> Option<(f64, f64)> {
+    if trials == 0 {
+        return None;
+    }
+    let n = trials as f64;
+    let p = successes as f64 / n;
+    let denom = 1.0 + z * z / n;
+    let center = (p + z * z / (2.0 * n)) / denom;
+    let half = z * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt() / denom;
+    Some(((center - half).max(0.0), (center + half).min(1.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glicko2_matches_reference_example() {
+        // Worked example from Glickman's "Example of the Glicko-2 system".
+        let player = GlickoRating { rating: 1500.0, deviation: 200.0, volatility: 0.06 };
+        let results = [
+            (GlickoRating { rating: 1400.0, deviation: 30.0, volatility: 0.06 }, 1.0),
+            (GlickoRating { rating: 1550.0, deviation: 100.0, volatility: 0.06 }, 0.0),
+            (GlickoRating { rating: 1700.0, deviation: 300.0, volatility: 0.06 }, 0.0),
+        ];
+        let updated = glicko2_update(player, &results, 0.5);
+        assert!((updated.rating - 1464.06).abs() < 0.01);
+        assert!((updated.deviation - 151.52).abs() < 0.01);
+        assert!((updated.volatility - 0.05999).abs() < 0.00001);
+    }
+
+    #[test]
+    fn trueskill_factors_match_reference_values() {
+        // Values of the standard TrueSkill correction functions at known points.
+        let (v, w) = trueskill_win_factors(0.0, 0.0);
+        assert!((v - 0.797885).abs() < 0.00001);
+        assert!((w - 0.636620).abs() < 0.00001);
+        let (v, w) = trueskill_draw_factors(0.0, 0.5);
+        assert!(v.abs() < 0.00001);
+        assert!((w - 0.919411).abs() < 0.00001);
+    }
+
+    #[test]
+    fn spearman_uses_average_ranks() {
+        assert_eq!(average_ranks(&[10.0, 30.0, 20.0, 20.0]), vec![1.0, 4.0, 2.5, 2.5]);
+        assert!((spearman(&[1.0, 2.0, 3.0], &[10.0, 40.0, 90.0]).unwrap() - 1.0).abs() < 1e-12);
+        assert!((spearman(&[1.0, 2.0, 3.0], &[3.0, 2.0, 1.0]).unwrap() + 1.0).abs() < 1e-12);
+        assert_eq!(spearman(&[1.0, 1.0], &[1.0, 2.0]), None);
+    }
+
+    #[test]
+    fn wilson_interval_matches_reference_values() {
+        // 8 successes of 10 at 95%: (0.4902, 0.9433).
+        let (low, high) = wilson_interval(8, 10, 1.96).unwrap();
+        assert!((low - 0.4902).abs() < 0.0001);
+        assert!((high - 0.9433).abs() < 0.0001);
+        let (low, high) = wilson_interval(0, 5, 1.96).unwrap();
+        assert_eq!(low, 0.0);
+        assert!(high > 0.0 && high < 0.5);
+        assert_eq!(wilson_interval(0, 0, 1.96), None);
+    }
+}
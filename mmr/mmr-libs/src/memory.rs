@@ -1,3 +1,4 @@
+use crate::jsonl::{JsonlReader, LineFormat, SessionRowRecord};
 use crate::types::UserBattleRow;
 
 use std::fs;
@@ -12,50 +13,53 @@ pub struct SessionMemory{
 }
 
 impl SessionMemory {
+    /// Restores the unfinished session of the previous run from `data/memory`.
+    ///
+    /// The file holds one `SessionRowRecord` JSON line per row. The legacy layout (the session
+    /// id alone on the first line, then key:value rows) is still read. Rejected rows are
+    /// counted in the printed report; an empty memory is returned when no row is left.
     pub fn new() -> Self {
-        if Path::new("data/memory").exists() {
-            if let Ok(lines) = read_lines("data/memory") {
-                let mut session_id: u64 = 0;
-                let mut rows: Vec<UserBattleRow> = Vec::new();
-                for (idx, line) in lines.flatten().enumerate() {
-                    if idx == 0 {
-                        session_id = line.clone().parse::<u64>().unwrap();
-                    }
-                    match UserBattleRow::parsing_row(line.replace("\"", "")) {
-                        Some(row) => {
-                            rows.push(row);
-                        },
-                        None => {
-                            
-                        }
-                    };
-                }
-                if session_id > 0 { 
-                    return Self {
-                        now_session_id: session_id,
-                        rows: rows.clone()
-                    };
-                }
-            }
+        Self::read("data/memory")
+    }
+
+    /// Restores the session memory written to `path`, as [`Self::new`] does for `data/memory`.
+    pub fn read(path: &str) -> Self {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(_) => return Self::empty()
+        };
+        // The legacy layout starts with the session id alone on the first line.
+        let (format, body) = match data.split_once('\n') {
+            Some((first, rest)) if first.trim().parse::<u64>().is_ok() => (LineFormat::KeyValue, rest),
+            _ => (LineFormat::Json, data.as_str())
+        };
+        let mut rows = JsonlReader::<SessionRowRecord, &[u8]>::from_reader(path, body.as_bytes()).with_format(format);
+        let rows_read: Vec<UserBattleRow> = rows.by_ref().map(|(_line, record)| UserBattleRow::from_memory(record)).collect();
+        if rows.report().rejected > 0 {
+            println!("{}", rows.report());
         }
+        match rows_read.first() {
+            Some(row) => Self {
+                now_session_id: row.session_id,
+                rows: rows_read
+            },
+            None => Self::empty()
+        }
+    }
 
+    fn empty() -> Self {
         Self {
             now_session_id: 0,
             rows: Vec::new()
         }
     }
 
+    /// Writes the buffered rows to `data/memory`, one `SessionRowRecord` JSON line per row.
     pub fn write(&self){
-
         let mut data_file = fs::File::create("data/memory").expect("creation failed");
-
-        // Write contents to the file
-        data_file.write((self.now_session_id.to_string() + "\n").as_bytes()).expect("write failed");
-        let mut file = fs::File::options().append(true).create(true).open("data/memory").unwrap();
-
-        for row in self.rows.clone().iter() {
-
-            file.write((row.to_string() + "\n").as_bytes()).unwrap();
+        for row in self.rows.iter() {
+            let line = serde_json::to_string(&row.to_record()).unwrap();
+            data_file.write_all((line + "\n").as_bytes()).expect("write failed");
         }
     }
 }
@@ -65,4 +69,31 @@ pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<fs::File
 where P: AsRef<Path>, {
     let file = fs::File::open(filename)?;
     Ok(io::BufReader::new(file).lines())
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_memory_reads_json_and_legacy_rows() {
+        let path = std::env::temp_dir().join("mmr_memory_test_".to_string() + std::process::id().to_string().as_str());
+        let path = path.to_str().unwrap();
+        let row = UserBattleRow { user_id: 7, session_id: 42, commit_time: 1000, team: 2, battle_score: 900, victories: true, early_quit: false, team_score_top_20_percent: true, faction: "faction_2".to_string() };
+
+        let line = serde_json::to_string(&row.to_record()).unwrap();
+        fs::write(path, line + "\n{\"user_id\":8,\"session_id\":42,\"commit_time\":1000}\n").unwrap();
+        let memory = SessionMemory::read(path);
+        assert_eq!(memory.now_session_id, 42);
+        assert_eq!(memory.rows.iter().map(|row| row.to_record().user_id).collect::<Vec<u64>>(), vec![7]);
+        assert_eq!((memory.rows[0].team, memory.rows[0].victories, memory.rows[0].faction.as_str()), (2, true, "faction_2"));
+
+        // Legacy layout; the row without a faction is rejected instead of panicking.
+        fs::write(path, "42\nuser_id:7,session_id:42,commit_time:1000,battle_score:900,team:2,faction:faction_2,victories:1\nuser_id:8,session_id:42,commit_time:1000\n").unwrap();
+        let memory = SessionMemory::read(path);
+        assert_eq!((memory.now_session_id, memory.rows.len(), memory.rows[0].battle_score), (42, 1, 900));
+
+        fs::remove_file(path).unwrap();
+        assert_eq!(SessionMemory::read(path).now_session_id, 0);
+    }
+}
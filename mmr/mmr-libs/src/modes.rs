@@ -0,0 +1,270 @@
+use crate::classification::SessionRules;
+use crate::datasets::{Registrations, SessionMode};
+use crate::math::spearman;
+use crate::memory::SessionMemory;
+use crate::params::{MMRParams, ModeKey, ModeParams, SeasonParams};
+use crate::rating::{self, leaderboard_dir, RatingSystem};
+use crate::snapshot::SnapshotError;
+use crate::types::{LeaderboardRow, MMRType};
+
+/// Board name of the global leaderboard in [`ModeComparison`] rows.
+pub const GLOBAL_BOARD: &str = "global";
+
+/// Parallel leaderboards of one algorithm keyed by normalized session mode.
+///
+/// Every board runs its own calibration. A player first seen by a board is seeded from the
+/// global leaderboard (see [`seed_row`]) when calibrated there; otherwise the board calibrates
+/// the player from scratch.
+pub struct ModeLeaderboards<T: RatingSystem> {
+    pub params: ModeParams,
+    // Formula constants applied to every board, including boards created later.
+    pub rating_params: Option<MMRParams>,
+    pub boards: std::collections::BTreeMap<String, T>
+}
+
+/// Agreement of two boards over the players calibrated in both.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeComparison {
+    pub board_a: String,
+    pub board_b: String,
+    pub players: u64,
+    // Mean of `mmr_a - mmr_b`.
+    pub mean_diff: f64,
+    // Spearman rank correlation; `None` below 2 players or for a constant board.
+    pub spearman: Option<f64>
+}
+
+impl<T: RatingSystem> ModeLeaderboards<T> {
+    pub fn new(params: ModeParams) -> Self {
+        Self {
+            params,
+            rating_params: None,
+            boards: std::collections::BTreeMap::new()
+        }
+    }
+
+    /// Restores every board persisted under `data/leaderboard_<NAME>/modes/<mode>`.
+    pub fn restore(params: ModeParams) -> Result<Self, SnapshotError> {
+        Self::restore_from(&Self::root(), params)
+    }
+
+    /// Restores every board persisted under `<root>/<mode>`; fails on the first board that
+    /// `RatingSystem::restore_from` rejects.
+    pub fn restore_from(root: &str, params: ModeParams) -> Result<Self, SnapshotError> {
+        let mut modes = Self::new(params);
+        if let Ok(entries) = std::fs::read_dir(root) {
+            for entry in entries.map_while(Result::ok) {
+                if entry.path().is_dir() {
+                    let mode = entry.file_name().to_string_lossy().to_string();
+                    modes.boards.insert(mode.clone(), T::restore_from(&(root.to_string() + "/" + mode.as_str()))?);
+                }
+            }
+        }
+        Ok(modes)
+    }
+
+    /// Directory holding all mode boards of `T`.
+    pub fn root() -> String {
+        leaderboard_dir::<T>() + "/modes"
+    }
+
+    /// Persistence directory of the `mode` board.
+    pub fn dir(mode: &str) -> String {
+        Self::root() + "/" + mode
+    }
+
+    /// Applies `params` to every current and future board.
+    pub fn set_params(&mut self, params: &MMRParams) {
+        for board in self.boards.values_mut() {
+            board.set_params(params);
+        }
+        self.rating_params = Some(params.clone());
+    }
+
+    /// Board name of `session_id`, or `None` when the session has no mode.
+    pub fn mode_key(&self, session_mode: &SessionMode, session_id: u64) -> Option<String> {
+        session_mode.0.get(&session_id).map(|(_mode, common, specific)| match self.params.key {
+            ModeKey::Common => common.clone(),
+            ModeKey::Specific => specific.clone()
+        })
+    }
+
+    /// Rates `session` on the board of its mode.
+    ///
+    /// Must run before the session is applied to `global`, so that seeding uses the ratings
+    /// the players had when the session started. Statistics and change records are not
+    /// produced for mode boards.
+    pub async fn proc_session(
+        &mut self,
+        global: &T,
+        session: &SessionMemory,
+        session_mode: &SessionMode,
+        registrations: &Registrations,
+        rules: &SessionRules
+    ) {
+        let mode = match self.mode_key(session_mode, session.now_session_id) {
+            Some(mode) => mode,
+            None => return
+        };
+        let rating_params = self.rating_params.clone();
+        let board = self.boards.entry(mode).or_insert_with(|| {
+            let mut board = T::empty();
+            if let Some(params) = rating_params.as_ref() {
+                board.set_params(params);
+            }
+            board
+        });
+        for row in session.rows.iter() {
+            board.seed_from(row.user_id, global, &self.params);
+        }
+
+        let (sender, _) = flume::unbounded();
+        let (sender_tasks, _) = flume::unbounded::<T::Change>();
+        let (sender_check, _) = flume::unbounded();
+        let (sender_session_class, _) = flume::unbounded();
+        rating::proc_session(board, session.clone(), 0, sender, session_mode, registrations, rules, sender_tasks, sender_check, sender_session_class).await;
+    }
+
+    /// Applies the inactivity decay to every board at `now`; returns the number decayed.
+    pub fn decay_sweep(&mut self, now: u64) -> u64 {
+        self.boards.values_mut().map(|board| board.decay_sweep(now)).sum()
+    }
+
+    /// Soft-resets every board at a season end; returns the number of players reset.
+    pub fn season_reset(&mut self, params: &SeasonParams) -> u64 {
+        self.boards.values_mut().map(|board| board.season_reset(params)).sum()
+    }
+
+    /// Persists every board into its own directory.
+    pub async fn persist(&self) {
+        self.persist_to(&Self::root()).await
+    }
+
+    /// Persists every board into `<root>/<mode>`.
+    pub async fn persist_to(&self, root: &str) {
+        for (mode, board) in self.boards.iter() {
+            let dir = root.to_string() + "/" + mode.as_str();
+            tokio::fs::create_dir_all(&dir).await.unwrap();
+            board.persist_to(&dir).await;
+        }
+    }
+
+    /// Compares `global` with every board and every pair of boards.
+    pub fn compare(&self, global: &T) -> Vec<ModeComparison> {
+        let mut boards: Vec<(&str, &T)> = vec![(GLOBAL_BOARD, global)];
+        boards.extend(self.boards.iter().map(|(mode, board)| (mode.as_str(), board)));
+        let mut res = Vec::new();
+        for (index, (name_a, board_a)) in boards.iter().enumerate() {
+            for (name_b, board_b) in boards[index + 1..].iter() {
+                res.push(compare_boards(name_a, *board_a, name_b, *board_b));
+            }
+        }
+        res
+    }
+
+    /// Writes [`Self::compare`] to `data/leaderboard_<NAME>/modes_compare`, one comparison per line.
+    pub async fn write_compare(&self, global: &T) {
+        let data = self.compare(global).iter().fold(String::new(), |base, comparison| base + comparison.to_string().as_str() + "\n");
+        tokio::fs::write(leaderboard_dir::<T>() + "/modes_compare", data).await.unwrap();
+    }
+}
+
+impl std::fmt::Display for ModeComparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "board_a:{},board_b:{},players:{},mean_diff:{:.2},spearman:{}", self.board_a, self.board_b, self.players, self.mean_diff,
+            self.spearman.map(|value| format!("{:.4}", value)).unwrap_or("none".to_string()))
+    }
+}
+
+/// Compares the calibrated MMRs of the players known to both boards.
+pub fn compare_boards<T: RatingSystem>(name_a: &str, board_a: &T, name_b: &str, board_b: &T) -> ModeComparison {
+    let mut user_ids: Vec<u64> = board_a.get_users().keys().copied().collect();
+    user_ids.sort();
+    let (mmr_a, mmr_b): (Vec<f64>, Vec<f64>) = user_ids.into_iter().filter_map(|user_id| {
+        match (board_a.get_mmr(user_id), board_b.get_mmr(user_id)) {
+            (MMRType::MMR(a), MMRType::MMR(b)) => Some((a as f64, b as f64)),
+            _ => None
+        }
+    }).unzip();
+    let players = mmr_a.len() as u64;
+    let mean_diff = if players == 0 {0.0} else {
+        mmr_a.iter().zip(mmr_b.iter()).fold(0.0, |base, (a, b)| base + a - b) / players as f64
+    };
+    ModeComparison {
+        board_a: name_a.to_string(),
+        board_b: name_b.to_string(),
+        players,
+        mean_diff,
+        spearman: spearman(&mmr_a, &mmr_b)
+    }
+}
+
+/// Blending rule of a mode board: the first mode row of a player is their global row with the
+/// experience capped at `seed_battles`.
+///
+/// The global MMR is carried over as is, and the battle score is scaled to the kept battles.
+/// With fewer than 6 kept battles the player replays the rest of the calibration in the
+/// mode. v2 applies its confidence exponent to the kept battles, and Glicko-2/TrueSkill widen
+/// their spread (see [`seed_spread`]). Returns `None` when the player has fewer than
+/// `seed_min_battles` global battles or seeding is disabled (`seed_battles` 0).
+pub fn seed_row(global: &LeaderboardRow, params: &ModeParams) -> Option<LeaderboardRow> {
+    if params.seed_battles == 0 || global.battles == 0 || global.battles < params.seed_min_battles {
+        return None;
+    }
+    let battles = global.battles.min(params.seed_battles);
+    Some(LeaderboardRow {
+        user_id: global.user_id,
+        mmr: global.mmr,
+        battles,
+        victories: 0,
+        early_quites: 0,
+        top_20: 0,
+        battle_score: (global.battle_score as u64 * battles as u64 / global.battles as u64) as u32,
+        last_session: global.last_session
+    })
+}
+
+/// Spread of a seeded rating: the variance is interpolated between the global one and the
+/// unrated default by the share of global battles kept in `seeded`.
+pub fn seed_spread(global_spread: f64, default_spread: f64, global: &LeaderboardRow, seeded: &LeaderboardRow) -> f64 {
+    let weight = seeded.battles as f64 / global.battles as f64;
+    (weight * global_spread.powi(2) + (1.0 - weight) * default_spread.powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Leaderboard;
+
+    fn row(user_id: u64, mmr: u32, battles: u32) -> LeaderboardRow {
+        LeaderboardRow { user_id, mmr, battles, victories: battles / 2, early_quites: 0, top_20: 0, battle_score: 1000 * battles, last_session: 0 }
+    }
+
+    #[test]
+    fn seeding_caps_experience_and_boards_compare() {
+        let params = ModeParams { key: ModeKey::Common, seed_min_battles: 6, seed_battles: 4 };
+        let seeded = seed_row(&row(1, 1800, 20), &params).unwrap();
+        assert_eq!((seeded.mmr, seeded.battles, seeded.battle_score, seeded.victories), (1800, 4, 4000, 0));
+        assert_eq!(seed_row(&row(2, 1800, 5), &params), None);
+        assert!((seed_spread(50.0, 350.0, &row(1, 1800, 20), &seeded) - (0.2 * 2500.0 + 0.8 * 122500.0_f64).sqrt()).abs() < 1e-9);
+
+        let mut global = Leaderboard::empty();
+        let mut high = Leaderboard::empty();
+        for (user_id, mmr) in [(1, 1000), (2, 1500), (3, 2000)] {
+            global.users.insert(user_id, row(user_id, mmr, 10));
+            high.users.insert(user_id, row(user_id, 3500 - mmr, 10));
+        }
+        high.users.insert(4, row(4, 1200, 10));
+        let comparison = compare_boards("global", &global, "high_teir_common", &high);
+        assert_eq!(comparison.players, 3);
+        assert_eq!(comparison.mean_diff, -500.0);
+        assert!((comparison.spearman.unwrap() + 1.0).abs() < 1e-12);
+
+        // A board seeds a player once, from the calibrated global row.
+        let mut low = Leaderboard::empty();
+        let params = ModeParams::default();
+        assert!(low.seed_from(3, &global, &params));
+        assert!(!low.seed_from(3, &global, &params));
+        assert!(matches!(low.get_mmr(3), MMRType::MMR(2000)));
+    }
+}
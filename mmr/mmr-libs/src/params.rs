@@ -0,0 +1,463 @@
+use serde::{Deserialize, Serialize};
+
+/// Constants of the v1 `math::diff_mmr` delta formula.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiffMMRParams {
+    // Score curve `score_max / e^(score_scale / score²)`.
+    pub score_max: f64,
+    pub score_scale: f64,
+    // Minimum gap to the weighted opponent average that activates matchup pressure.
+    pub gap_threshold: f64,
+    // Matchup pressure amplitude and sigmoid slope.
+    pub pressure: f64,
+    pub pressure_slope: f64,
+    // Flat offset of the defeat branch.
+    pub defeat_offset: f64,
+    // Situational modifiers.
+    pub early_quit: f64,
+    pub top_20: f64
+}
+
+/// Constants of the v2 pool redistribution (`inc_k`, `dec_k`, `bank_give`, `bank_get`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PoolParams {
+    // inc_k = inc_k / (inc_k_ratio * mmr / avg + 1)
+    pub inc_k: f64,
+    pub inc_k_ratio: f64,
+    // dec_k = dec_k / (dec_k_ratio * (avg / mmr)^dec_k_pow + 1)
+    pub dec_k: f64,
+    pub dec_k_ratio: f64,
+    pub dec_k_pow: f64,
+    // bank_give = sigmoid(mmr, bank_give_slope, bank_give_center, -1, 1) + bank_give_confidence * (1 - k)
+    pub bank_give_slope: f64,
+    pub bank_give_center: f64,
+    pub bank_give_confidence: f64,
+    // bank_get = sigmoid(mmr, bank_get_slope, bank_get_center, 1, 0) * bank_get_scale
+    pub bank_get_slope: f64,
+    pub bank_get_center: f64,
+    pub bank_get_scale: f64
+}
+
+/// Inactivity decay of returning v1/v2 players (see `decay::DecayState`); disabled by default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecayParams {
+    // Length of one idle period in days; 0 disables decay.
+    pub period_days: f64,
+    // Fraction of the gap to the mean MMR of calibrated players removed per idle period.
+    pub mean_pull: f64,
+    // Calibration battles a returning calibrated player replays (1..=5; 0 disables).
+    pub recalibration: u32,
+    // Battles subtracted from the v2 confidence exponent per idle period, used up one per session.
+    pub confidence_battles: f64
+}
+
+/// Weighted k-NN calibration of new players (see `calibration::estimate`); disabled by default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CalibrationParams {
+    // Neighbours of the estimate; 0 keeps the ±50 `get_mmr_for_new` window (v1) and the
+    // battle-count confidence (v2).
+    pub neighbours: usize,
+    // Calibrated players required before any estimate.
+    pub min_users: usize,
+    // distance = |(score - score') / score_scale, win_weight * Δwin_rate,
+    //             top_20_weight * Δtop_20_rate, early_quit_weight * Δearly_quit_rate|
+    pub score_scale: f64,
+    pub win_weight: f64,
+    pub top_20_weight: f64,
+    pub early_quit_weight: f64,
+    // Neighbour weight = 1 / (1 + (distance / bandwidth)^2)
+    pub bandwidth: f64,
+    // Central share of the weighted neighbour MMRs covered by the interval.
+    pub interval: f64,
+    // v2 confidence of a provisional player = min(1, confident_width / interval width).
+    pub confident_width: f64
+}
+
+/// Thresholds of the anti-abuse checks (see `abuse::AbuseDetector`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AbuseParams {
+    // Win-trading: meetings on opposite teams (weighted by the larger team size), and share of
+    // consecutive meetings where the winner switched.
+    pub trade_min_games: u32,
+    pub trade_alternation: f64,
+    // Smurfs: accounts registered at most this long (ms) before calibration whose MMR climbs
+    // `smurf_margin` above the bootstrap estimate within `smurf_window` battles.
+    pub smurf_account_age: u64,
+    pub smurf_window: u32,
+    pub smurf_margin: u32,
+    // Early quits in a row.
+    pub early_quit_streak: u32
+}
+
+/// Confidence intervals and minimum-sample suppression of the spread reports (see
+/// `estimate::BucketEstimate`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EstimateParams {
+    // Bootstrap resamples per bucket.
+    pub resamples: usize,
+    // Central share of the bootstrap distribution covered by the interval.
+    pub confidence: f64,
+    // Buckets with fewer players / sessions are reported with their counts only.
+    pub min_sample: u64,
+    // Seed of the resampling; every bucket restarts from it.
+    pub seed: u64
+}
+
+/// Competitive seasons of the leaderboards (see `season::Seasons`); disabled by default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeasonParams {
+    // commit_time (ms) of every season start (sorted by `Seasons::new`); empty disables seasons.
+    pub starts: Vec<u64>,
+    // Fraction of the gap to the mean MMR of calibrated players removed at a season end.
+    pub compression: f64,
+    // Calibration battles every calibrated player replays in the new season (1..=5; 0 disables).
+    pub recalibration: u32,
+    // Battles required to be ranked (and rewarded) in the final ranks of a season.
+    pub min_battles: u32,
+    // Reward tiers by percentile of the final ranks, lowest first.
+    pub tiers: Vec<RewardTier>
+}
+
+/// Season-end reward of the players at or above `min_percentile` (0..=100) of the final ranks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RewardTier {
+    pub name: String,
+    pub min_percentile: f64
+}
+
+/// `SessionMode` level that keys the per-mode leaderboards.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub enum ModeKey {
+    // Normalized mode: `low_teir_common` / `high_teir_common` / `lobbie_common`.
+    #[default]
+    Common,
+    // Specific sub-mode.
+    Specific
+}
+
+/// Per-mode leaderboards kept next to the global one (see `modes::ModeLeaderboards`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModeParams {
+    pub key: ModeKey,
+    // Global battles required before a player's first mode rating is seeded from the global one.
+    pub seed_min_battles: u32,
+    // Battles the global rating is worth in the mode leaderboard; below 6 the player finishes
+    // the remaining calibration battles in the mode.
+    pub seed_battles: u32
+}
+
+/// Tunable formula constants of the v1 and v2 algorithms, stored as RON.
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MMRParams {
+    pub v1: DiffMMRParams,
+    pub v2: PoolParams,
+    pub decay: DecayParams,
+    pub modes: ModeParams,
+    pub abuse: AbuseParams,
+    pub calibration: CalibrationParams,
+    pub estimate: EstimateParams,
+    pub season: SeasonParams
+}
+
+/// Inclusive search range of one parameter, addressed as `v1.<field>` / `v2.<field>`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SearchRange {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    // Number of grid points (grid search only).
+    pub steps: usize
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SearchSpace {
+    pub ranges: Vec<SearchRange>
+}
+
+impl Default for DiffMMRParams {
+    fn default() -> Self {
+        Self {
+            score_max: 50.0,
+            score_scale: 1000000.0,
+            gap_threshold: 250.0,
+            pressure: 35.0,
+            pressure_slope: 0.001,
+            defeat_offset: -66.0,
+            early_quit: -20.0,
+            top_20: 20.0
+        }
+    }
+}
+
+impl Default for PoolParams {
+    fn default() -> Self {
+        Self {
+            inc_k: 0.5,
+            inc_k_ratio: 4.0,
+            dec_k: 0.5,
+            dec_k_ratio: 8.0,
+            dec_k_pow: 0.35,
+            bank_give_slope: 0.01,
+            bank_give_center: 500.0,
+            bank_give_confidence: 0.2,
+            bank_get_slope: 0.01,
+            bank_get_center: 9500.0,
+            bank_get_scale: 0.05
+        }
+    }
+}
+
+impl Default for DecayParams {
+    fn default() -> Self {
+        Self {
+            period_days: 30.0,
+            mean_pull: 0.0,
+            recalibration: 0,
+            confidence_battles: 0.0
+        }
+    }
+}
+
+impl Default for ModeParams {
+    fn default() -> Self {
+        Self {
+            key: ModeKey::Common,
+            seed_min_battles: 6,
+            seed_battles: 6
+        }
+    }
+}
+
+impl Default for CalibrationParams {
+    fn default() -> Self {
+        Self {
+            neighbours: 0,
+            min_users: 1000,
+            score_scale: 50.0,
+            win_weight: 2.0,
+            top_20_weight: 1.0,
+            early_quit_weight: 2.0,
+            bandwidth: 1.0,
+            interval: 0.8,
+            confident_width: 300.0
+        }
+    }
+}
+
+impl Default for AbuseParams {
+    fn default() -> Self {
+        Self {
+            trade_min_games: 10,
+            trade_alternation: 0.9,
+            smurf_account_age: 7 * 24 * 60 * 60 * 1000,
+            smurf_window: 20,
+            smurf_margin: 500,
+            early_quit_streak: 5
+        }
+    }
+}
+
+impl Default for EstimateParams {
+    fn default() -> Self {
+        Self {
+            resamples: 1000,
+            confidence: 0.95,
+            min_sample: 30,
+            seed: 0
+        }
+    }
+}
+
+impl Default for SeasonParams {
+    fn default() -> Self {
+        let tier = |name: &str, min_percentile: f64| RewardTier { name: name.to_string(), min_percentile };
+        Self {
+            starts: Vec::new(),
+            compression: 0.5,
+            recalibration: 3,
+            min_battles: 6,
+            tiers: vec![tier("bronze", 0.0), tier("silver", 50.0), tier("gold", 75.0), tier("platinum", 90.0), tier("diamond", 98.0)]
+        }
+    }
+}
+
+impl MMRParams {
+    /// Reads a RON parameter file; missing fields keep their defaults.
+    pub fn read(path: &str) -> Self {
+        let data = std::fs::read_to_string(path).unwrap();
+        ron::from_str(&data).unwrap()
+    }
+
+    /// Writes the parameters as pretty-printed RON.
+    pub fn write(&self, path: &str) {
+        let data = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).unwrap();
+        std::fs::write(path, data + "\n").unwrap();
+    }
+
+    /// Returns the value of `v1.<field>` / `v2.<field>` / `decay.<field>`, or `None` for an unknown name.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        let value = match name {
+            "v1.score_max" => self.v1.score_max,
+            "v1.score_scale" => self.v1.score_scale,
+            "v1.gap_threshold" => self.v1.gap_threshold,
+            "v1.pressure" => self.v1.pressure,
+            "v1.pressure_slope" => self.v1.pressure_slope,
+            "v1.defeat_offset" => self.v1.defeat_offset,
+            "v1.early_quit" => self.v1.early_quit,
+            "v1.top_20" => self.v1.top_20,
+            "v2.inc_k" => self.v2.inc_k,
+            "v2.inc_k_ratio" => self.v2.inc_k_ratio,
+            "v2.dec_k" => self.v2.dec_k,
+            "v2.dec_k_ratio" => self.v2.dec_k_ratio,
+            "v2.dec_k_pow" => self.v2.dec_k_pow,
+            "v2.bank_give_slope" => self.v2.bank_give_slope,
+            "v2.bank_give_center" => self.v2.bank_give_center,
+            "v2.bank_give_confidence" => self.v2.bank_give_confidence,
+            "v2.bank_get_slope" => self.v2.bank_get_slope,
+            "v2.bank_get_center" => self.v2.bank_get_center,
+            "v2.bank_get_scale" => self.v2.bank_get_scale,
+            "decay.period_days" => self.decay.period_days,
+            "decay.mean_pull" => self.decay.mean_pull,
+            "decay.recalibration" => self.decay.recalibration as f64,
+            "decay.confidence_battles" => self.decay.confidence_battles,
+            _ => return None
+        };
+        Some(value)
+    }
+
+    /// Sets `v1.<field>` / `v2.<field>` / `decay.<field>`; returns `false` for an unknown name.
+    pub fn set(&mut self, name: &str, value: f64) -> bool {
+        if name == "decay.recalibration" {
+            self.decay.recalibration = value.round().clamp(0.0, 5.0) as u32;
+            return true;
+        }
+        let field = match name {
+            "v1.score_max" => &mut self.v1.score_max,
+            "v1.score_scale" => &mut self.v1.score_scale,
+            "v1.gap_threshold" => &mut self.v1.gap_threshold,
+            "v1.pressure" => &mut self.v1.pressure,
+            "v1.pressure_slope" => &mut self.v1.pressure_slope,
+            "v1.defeat_offset" => &mut self.v1.defeat_offset,
+            "v1.early_quit" => &mut self.v1.early_quit,
+            "v1.top_20" => &mut self.v1.top_20,
+            "v2.inc_k" => &mut self.v2.inc_k,
+            "v2.inc_k_ratio" => &mut self.v2.inc_k_ratio,
+            "v2.dec_k" => &mut self.v2.dec_k,
+            "v2.dec_k_ratio" => &mut self.v2.dec_k_ratio,
+            "v2.dec_k_pow" => &mut self.v2.dec_k_pow,
+            "v2.bank_give_slope" => &mut self.v2.bank_give_slope,
+            "v2.bank_give_center" => &mut self.v2.bank_give_center,
+            "v2.bank_give_confidence" => &mut self.v2.bank_give_confidence,
+            "v2.bank_get_slope" => &mut self.v2.bank_get_slope,
+            "v2.bank_get_center" => &mut self.v2.bank_get_center,
+            "v2.bank_get_scale" => &mut self.v2.bank_get_scale,
+            "decay.period_days" => &mut self.decay.period_days,
+            "decay.mean_pull" => &mut self.decay.mean_pull,
+            "decay.confidence_battles" => &mut self.decay.confidence_battles,
+            _ => return false
+        };
+        *field = value;
+        true
+    }
+
+    /// Flat `name:value` listing of the parameters named in `space`.
+    pub fn to_string(&self, space: &SearchSpace) -> String {
+        space.ranges.iter()
+            .map(|range| range.name.clone() + ":" + self.get(&range.name).unwrap_or(0.0).to_string().as_str())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+impl SearchSpace {
+    /// Reads a RON search space file.
+    pub fn read(path: &str) -> Self {
+        let data = std::fs::read_to_string(path).unwrap();
+        ron::from_str(&data).unwrap()
+    }
+
+    /// Default space around the hand-tuned constants of `algorithm` (`v1` or `v2`).
+    pub fn default_for(algorithm: &str) -> Self {
+        let range = |name: &str, min: f64, max: f64, steps: usize| SearchRange { name: name.to_string(), min, max, steps };
+        let ranges = match algorithm {
+            "v2" => vec![
+                range("v2.inc_k", 0.3, 0.7, 3),
+                range("v2.dec_k", 0.3, 0.7, 3),
+                range("v2.dec_k_pow", 0.2, 0.5, 3),
+                range("v2.bank_give_confidence", 0.1, 0.3, 3),
+                range("v2.bank_get_scale", 0.025, 0.075, 3)
+            ],
+            _ => vec![
+                range("v1.score_max", 30.0, 70.0, 3),
+                range("v1.gap_threshold", 150.0, 350.0, 3),
+                range("v1.pressure", 20.0, 50.0, 3),
+                range("v1.defeat_offset", -86.0, -46.0, 3),
+                range("v1.top_20", 10.0, 30.0, 3)
+            ]
+        };
+        Self { ranges }
+    }
+
+    /// Returns the names in the space that [`MMRParams::set`] does not know.
+    pub fn unknown_names(&self) -> Vec<String> {
+        let mut params = MMRParams::default();
+        self.ranges.iter().filter(|range| !params.set(&range.name, 0.0)).map(|range| range.name.clone()).collect()
+    }
+
+    /// Cartesian product of `steps` evenly spaced values per range, applied on top of `base`.
+    pub fn grid(&self, base: &MMRParams) -> Vec<MMRParams> {
+        let mut grid: Vec<MMRParams> = vec![base.clone()];
+        for range in self.ranges.iter() {
+            let steps = std::cmp::max(range.steps, 1);
+            let values: Vec<f64> = (0..steps).map(|step| if steps == 1 {
+                (range.min + range.max) / 2.0
+            } else {
+                range.min + (range.max - range.min) * step as f64 / (steps - 1) as f64
+            }).collect();
+            grid = grid.into_iter().flat_map(|params| values.iter().map(move |value| {
+                let mut params = params.clone();
+                params.set(&range.name, *value);
+                params
+            })).collect();
+        }
+        grid
+    }
+
+    /// Applies one uniform draw per range on top of `base`; `uniform` returns values in `[0, 1)`.
+    pub fn sample(&self, base: &MMRParams, mut uniform: impl FnMut() -> f64) -> MMRParams {
+        let mut params = base.clone();
+        for range in self.ranges.iter() {
+            params.set(&range.name, range.min + (range.max - range.min) * uniform());
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_round_trip_and_grid() {
+        let params = MMRParams::default();
+        let parsed: MMRParams = ron::from_str(&ron::ser::to_string(&params).unwrap()).unwrap();
+        assert_eq!(parsed, params);
+        // Missing fields fall back to the defaults.
+        let partial: MMRParams = ron::from_str("(v1: (pressure: 40.0))").unwrap();
+        assert_eq!(partial.get("v1.pressure"), Some(40.0));
+        assert_eq!(partial.get("v1.defeat_offset"), Some(-66.0));
+
+        let grid = SearchSpace::default_for("v1").grid(&params);
+        assert_eq!(grid.len(), 243);
+        assert!(SearchSpace::default_for("v2").unknown_names().is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::factions::FactionRegistry;
+use crate::rating::RatingSystem;
+use crate::spread::dominant_faction;
+use crate::types::LeaderboardRow;
+
+/// Players a [`Ranking`] is built over; the same filters as `spread::mmr_spread`.
+#[derive(Clone, Debug, Default)]
+pub struct RankFilter {
+    // Minimum total battles.
+    pub min_battles: u32,
+    // Minimum `last_session` (commit_time, ms).
+    pub active_since: u64,
+    // Dominant faction (`faction_1`, `faction_2` or `mixed` by default), any when `None`.
+    pub faction: Option<String>,
+    // Registry the dominant faction is taken from.
+    pub factions: FactionRegistry
+}
+
+impl RankFilter {
+    /// Returns `true` when `row` passes the battle and activity filters.
+    pub fn accepts(&self, row: &LeaderboardRow) -> bool {
+        row.battles >= self.min_battles && row.last_session >= self.active_since
+    }
+}
+
+/// One player of a [`Ranking`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RankedRow {
+    // 1 + number of players with a higher MMR; tied players share the rank.
+    pub rank: usize,
+    // Share of players below (ties count half), 0..=100.
+    pub percentile: f64,
+    pub faction: String,
+    pub row: LeaderboardRow
+}
+
+impl std::fmt::Display for RankedRow {
+    /// Formats the row as a flat key:value line.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "rank:{},percentile:{:.2},user_id:{},mmr:{},battles:{},victories:{},last_session:{},faction:{}",
+            self.rank, self.percentile, self.row.user_id, self.row.mmr, self.row.battles, self.row.victories, self.row.last_session, self.faction)
+    }
+}
+
+/// Players of a leaderboard that pass a [`RankFilter`], ordered by MMR (highest first, then
+/// by `user_id`).
+#[derive(Clone, Debug, Default)]
+pub struct Ranking {
+    pub rows: Vec<RankedRow>,
+    // user_id -> position in `rows`.
+    positions: std::collections::HashMap<u64, usize>
+}
+
+impl Ranking {
+    /// Ranks the players of `leaderboard` that pass `filter`.
+    pub fn new<T: RatingSystem>(leaderboard: &T, filter: &RankFilter) -> Self {
+        let mut rows: Vec<RankedRow> = leaderboard.get_users().values()
+            .filter(|row| filter.accepts(row))
+            .map(|row| RankedRow { rank: 0, percentile: 0.0, faction: dominant_faction(leaderboard, row.user_id, row.battles, &filter.factions), row: row.clone() })
+            .filter(|ranked| filter.faction.as_ref().map(|faction| *faction == ranked.faction).unwrap_or(true))
+            .collect();
+        rows.sort_unstable_by_key(|ranked| (std::cmp::Reverse(ranked.row.mmr), ranked.row.user_id));
+
+        // Rank and percentile per group of equal MMR.
+        let total = rows.len();
+        let mut start = 0;
+        while start < total {
+            let mmr = rows[start].row.mmr;
+            let end = start + rows[start..].iter().take_while(|ranked| ranked.row.mmr == mmr).count();
+            let below = total - end;
+            let percentile = 100.0 * (below as f64 + 0.5 * (end - start) as f64) / total as f64;
+            for ranked in rows[start..end].iter_mut() {
+                ranked.rank = start + 1;
+                ranked.percentile = percentile;
+            }
+            start = end;
+        }
+
+        let positions = rows.iter().enumerate().map(|(position, ranked)| (ranked.row.user_id, position)).collect();
+        Self { rows, positions }
+    }
+
+    /// Ranked row of `user_id`, or `None` when it did not pass the filter.
+    pub fn get(&self, user_id: u64) -> Option<&RankedRow> {
+        self.positions.get(&user_id).map(|position| &self.rows[*position])
+    }
+
+    /// The first `n` players.
+    pub fn top(&self, n: usize) -> &[RankedRow] {
+        &self.rows[..n.min(self.rows.len())]
+    }
+
+    /// `user_id` with the `k` players above and below it, or `None` when it did not pass the filter.
+    pub fn neighbours(&self, user_id: u64, k: usize) -> Option<&[RankedRow]> {
+        let position = *self.positions.get(&user_id)?;
+        Some(&self.rows[position.saturating_sub(k)..(position + k + 1).min(self.rows.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Leaderboard;
+
+    #[test]
+    fn ranking_filters_ties_and_neighbours() {
+        let mut leaderboard = Leaderboard::empty();
+        for (user_id, mmr, battles, last_session) in [(1, 1800, 10, 50), (2, 1600, 10, 50), (3, 1600, 10, 50), (4, 1400, 10, 50), (5, 2000, 3, 50), (6, 1500, 10, 10)] {
+            leaderboard.users.insert(user_id, LeaderboardRow { user_id, mmr, battles, victories: 0, early_quites: 0, top_20: 0, battle_score: 0, last_session });
+            leaderboard.battle_faction_hash.insert((user_id, "faction_1".to_string()), if user_id == 4 {5} else {battles as u64});
+        }
+
+        let ranking = Ranking::new(&leaderboard, &RankFilter { min_battles: 6, active_since: 20, ..RankFilter::default() });
+        let order: Vec<(u64, usize)> = ranking.rows.iter().map(|ranked| (ranked.row.user_id, ranked.rank)).collect();
+        assert_eq!(order, vec![(1, 1), (2, 2), (3, 2), (4, 4)]);
+        assert_eq!(ranking.get(1).unwrap().percentile, 87.5);
+        assert_eq!(ranking.get(3).unwrap().percentile, 50.0);
+        assert!(ranking.get(5).is_none());
+        assert_eq!(ranking.top(2).len(), 2);
+        let around: Vec<u64> = ranking.neighbours(1, 1).unwrap().iter().map(|ranked| ranked.row.user_id).collect();
+        assert_eq!(around, vec![1, 2]);
+        assert_eq!(ranking.neighbours(3, 5).unwrap().len(), 4);
+
+        let mixed = Ranking::new(&leaderboard, &RankFilter { min_battles: 6, active_since: 0, faction: Some("mixed".to_string()), ..RankFilter::default() });
+        assert_eq!(mixed.rows.iter().map(|ranked| ranked.row.user_id).collect::<Vec<u64>>(), vec![4]);
+    }
+}
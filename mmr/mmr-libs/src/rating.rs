@@ -0,0 +1,480 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use tokio::{fs::File, io::BufWriter};
+
+use crate::calibration::CalibrationEstimate;
+use crate::classification::{SessionClasses, SessionRules};
+use crate::datasets::{Registrations, SessionMode};
+use crate::decay::DecayUndo;
+use crate::factions::{FactionRegistry, TeamMode};
+use crate::history::HistoryEntry;
+use crate::memory::SessionMemory;
+use crate::params::{MMRParams, ModeParams, SeasonParams};
+use crate::snapshot::SnapshotError;
+use crate::statistic::{proc_statistic, Statistic};
+use crate::types::{LeaderboardMark, LeaderboardRow, MMRAgg, MMRPair, MMRType, TeamMMR, UserBattleRow};
+
+// Start instant and elapsed duration of one processing stage.
+pub type StageTiming = (Instant, Duration);
+
+/// Common interface of a rating algorithm that can be driven by [`proc_session`].
+///
+/// Implementors own their persisted state and the per-player update rule; team splitting,
+/// session filtering, statistics and session classification are shared and live in the
+/// driver.
+pub trait RatingSystem: LeaderboardMark + Sized + Send + Sync + 'static {
+    /// Per-user change payload streamed to the change writer.
+    type Change: Send + 'static;
+
+    /// Per-player updates of one session, computed before any of them is applied.
+    type Prepared: Send;
+
+    /// Short algorithm tag used in output paths (`data/leaderboard_<NAME>/...`).
+    const NAME: &'static str;
+
+    /// Restores the persisted leaderboard state, or returns an empty leaderboard.
+    fn restore() -> Result<Self, SnapshotError> {
+        Self::restore_from(&leaderboard_dir::<Self>())
+    }
+
+    /// Restores the leaderboard state persisted in `dir`, or returns an empty leaderboard
+    /// when nothing is persisted there. Fails on a corrupted snapshot or a rejected legacy line.
+    fn restore_from(dir: &str) -> Result<Self, SnapshotError>;
+
+    /// Returns an empty leaderboard without reading any persisted state.
+    fn empty() -> Self;
+
+    /// Replaces the tunable formula constants; algorithms without such constants ignore it.
+    fn set_params(&mut self, _params: &MMRParams) {}
+
+    /// Persists the current in-memory leaderboard state to disk.
+    fn persist(&self) -> impl Future<Output = ()> + Send {
+        async move { self.persist_to(&leaderboard_dir::<Self>()).await }
+    }
+
+    /// Persists the current in-memory leaderboard state into `dir`, which must exist.
+    fn persist_to(&self, dir: &str) -> impl Future<Output = ()> + Send;
+
+    /// Seeds `user_id` from its rating in `global` when this leaderboard does not know the
+    /// player yet; returns `true` when a row was inserted. See [`crate::modes`].
+    fn seed_from(&mut self, _user_id: u64, _global: &Self, _params: &ModeParams) -> bool {
+        false
+    }
+
+    /// Applies the inactivity decay due to `user_id` at `now` (`commit_time`, ms); returns
+    /// `true` when the rating changed. Only v1 and v2 decay; Glicko-2 widens its deviation
+    /// on its own.
+    fn apply_decay(&mut self, _user_id: u64, _now: u64) -> bool {
+        false
+    }
+
+    /// Applies the inactivity decay to every player at `now`; returns the number decayed.
+    fn decay_sweep(&mut self, _now: u64) -> u64 {
+        0
+    }
+
+    /// Cached decay mean and the `commit_time` it was taken at (v1 and v2). Not part of the
+    /// snapshot; a [`crate::checkpoint::Checkpoint`] keeps it so a resumed run refreshes the
+    /// mean at the same sessions as an uninterrupted one.
+    fn decay_mean(&self) -> Option<(f64, u64)> {
+        None
+    }
+
+    /// Restores the value returned by [`RatingSystem::decay_mean`].
+    fn set_decay_mean(&mut self, _mean: (f64, u64)) {}
+
+    /// Saves the decay state of `user_ids` before [`preview`] decays them; algorithms without
+    /// decay save nothing.
+    fn save_decay(&self, _user_ids: &[u64]) -> DecayUndo {
+        DecayUndo::default()
+    }
+
+    /// Drops the decay applied since [`RatingSystem::save_decay`].
+    fn undo_decay(&mut self, _undo: DecayUndo) {}
+
+    /// Soft-resets every calibrated player at a season end (see [`crate::season`]); returns
+    /// the number reset. A no-op by default.
+    fn season_reset(&mut self, _params: &SeasonParams) -> u64 {
+        0
+    }
+
+    /// Estimated first calibrated MMR of a player with the per-battle averages of `row` (see
+    /// [`crate::calibration`]); `None` without enough history. v1 falls back to the point
+    /// estimate of its ±50 score window, v2 estimates only with the k-NN calibration enabled.
+    fn calibration_estimate(&self, _row: &LeaderboardRow) -> impl Future<Output = Option<CalibrationEstimate>> + Send {
+        async { None }
+    }
+
+    /// Keeps `user_id` out of the bootstrap estimates of other players (see [`crate::abuse`]).
+    fn exclude_from_bootstrap(&mut self, _user_id: u64) {}
+
+    /// Returns the current `MMRType` for `user_id`.
+    fn get_mmr(&self, user_id: u64) -> MMRType;
+
+    /// Returns the total battle count for `user_id`, or `0` if unknown.
+    fn get_battles(&self, user_id: u64) -> u32;
+
+    /// Returns all known leaderboard rows keyed by `user_id`.
+    fn get_users(&self) -> &std::collections::HashMap<u64, LeaderboardRow>;
+
+    /// Computes the per-player updates of an already split and filtered session without
+    /// changing the leaderboard.
+    fn prepare_session(&self, teams: &SessionTeams) -> Self::Prepared;
+
+    /// Applies updates computed by [`RatingSystem::prepare_session`] and streams one change
+    /// payload per player to `sender_tasks`.
+    fn apply_session(
+        &mut self,
+        prepared: Self::Prepared,
+        cl_id: u16,
+        sender_tasks: flume::Sender<Self::Change>,
+    ) -> impl Future<Output = ()> + Send;
+
+    /// Change payloads [`RatingSystem::apply_session`] would stream for `prepared`, without
+    /// changing the leaderboard (previews). Every player is rated against the state before the
+    /// session, so a calibration depending on another player of the same session may differ.
+    fn preview_session(&self, prepared: Self::Prepared, cl_id: u16) -> impl Future<Output = Vec<Self::Change>> + Send;
+
+    /// Returns `true` when [`RatingSystem::prepare_session`] of a session does not depend on
+    /// the sessions applied before it with other players, so [`crate::batch`] may prepare
+    /// such sessions in parallel. Population-wide reads (decay, k-NN calibration) opt out.
+    fn batch_safe(&self) -> bool {
+        true
+    }
+
+    /// Computes and applies the per-player updates for an already split and filtered session.
+    ///
+    /// Returns the `(prepare changes, apply changes)` stage timings.
+    fn process_session(
+        &mut self,
+        teams: &SessionTeams,
+        cl_id: u16,
+        sender_tasks: flume::Sender<Self::Change>,
+    ) -> impl Future<Output = (StageTiming, StageTiming)> + Send {
+        async move {
+            let prepear_change = Instant::now();
+            let prepared = self.prepare_session(teams);
+            let prepear_change_time = prepear_change.elapsed();
+
+            let set_change = Instant::now();
+            self.apply_session(prepared, cl_id, sender_tasks).await;
+            let set_change_time = set_change.elapsed();
+            ((prepear_change, prepear_change_time), (set_change, set_change_time))
+        }
+    }
+
+    /// Serializes one change payload into the change file selected by its classifier id.
+    fn write_change(change: Self::Change, change_files: &mut [BufWriter<File>]) -> impl Future<Output = ()> + Send;
+
+    /// Rating history record of one change payload (see [`crate::history`]); `mode` is left
+    /// empty for the caller, which knows the session modes.
+    fn history_entry(change: &Self::Change) -> HistoryEntry;
+
+    /// Predicted probability that `team_1` wins the session, from the current ratings.
+    ///
+    /// The default compares the average calibrated MMR of both teams on a logistic curve with
+    /// a [`WIN_PROBABILITY_SCALE`] spread; `0.5` when either team has no calibrated player.
+    fn win_probability(&self, teams: &SessionTeams) -> f64 {
+        let avg = |team_mmr: &TeamMMR| {
+            let agg = team_mmr.0.iter().fold(MMRAgg(0, 0), |mut res, obj| {
+                if let MMRType::MMR(mmr) = obj.1 {
+                    res.0 += 1;
+                    res.1 += mmr as i64;
+                }
+                res
+            });
+            if agg.0 == 0 {None} else {Some(agg.1 as f64 / agg.0 as f64)}
+        };
+        match (avg(&teams.team_1_mmr), avg(&teams.team_2_mmr)) {
+            (Some(team_1), Some(team_2)) => 1.0 / (1.0 + 10_f64.powf((team_2 - team_1) / WIN_PROBABILITY_SCALE)),
+            _ => 0.5
+        }
+    }
+}
+
+/// Default persistence directory of `T`: `data/leaderboard_<NAME>`.
+pub fn leaderboard_dir<T: RatingSystem>() -> String {
+    "data/leaderboard_".to_string() + T::NAME
+}
+
+/// MMR gap that corresponds to 10:1 odds in the default [`RatingSystem::win_probability`].
+pub const WIN_PROBABILITY_SCALE: f64 = 400.0;
+
+/// Team snapshot of a single session, shared by every rating algorithm.
+#[derive(Clone, Debug)]
+pub struct SessionTeams {
+    pub session_id: u64,
+    // All session rows in input order, including rows of unregistered factions.
+    pub rows: Vec<UserBattleRow>,
+    // Teams in team number order (`FactionRegistry::split`), ranked by calibrated MMR
+    // (calibrated first, highest first), and whether each team won.
+    pub teams: Vec<Vec<UserBattleRow>>,
+    pub teams_mmr: Vec<TeamMMR>,
+    pub results: Vec<bool>,
+    // The first two teams, rated against each other by Glicko-2 and TrueSkill and compared
+    // by the session statistics and win probabilities; empty when the session has fewer.
+    pub team_1: Vec<UserBattleRow>,
+    pub team_2: Vec<UserBattleRow>,
+    pub team_1_mmr: TeamMMR,
+    pub team_2_mmr: TeamMMR,
+    pub team_1_res: bool,
+    pub team_2_res: bool,
+    // Sum of raw battle scores over all session rows.
+    pub common_score: u64,
+    // How the teams were formed (`FactionRegistry::mode`).
+    pub mode: TeamMode,
+}
+
+impl SessionTeams {
+    /// Splits `session_memory` into the teams of `factions` and ranks each team by the MMR
+    /// currently known to `rating`.
+    pub fn new<T: RatingSystem>(rating: &T, session_memory: &SessionMemory, factions: &FactionRegistry) -> Self {
+        let common_score = session_memory.rows.iter().fold(0_u64, |fold_obj, other| fold_obj + other.battle_score as u64);
+
+        let teams = factions.split(&session_memory.rows);
+        let teams_mmr: Vec<TeamMMR> = teams.iter().map(|team| rank_team(rating, team)).collect();
+        let results: Vec<bool> = teams.iter().map(|team| team.iter().any(|row| row.victories)).collect();
+
+        Self {
+            session_id: session_memory.now_session_id,
+            rows: session_memory.rows.clone(),
+            team_1: teams.first().cloned().unwrap_or_default(),
+            team_2: teams.get(1).cloned().unwrap_or_default(),
+            team_1_mmr: teams_mmr.first().cloned().unwrap_or_default(),
+            team_2_mmr: teams_mmr.get(1).cloned().unwrap_or_default(),
+            team_1_res: results.first().copied().unwrap_or(false),
+            team_2_res: results.get(1).copied().unwrap_or(false),
+            teams,
+            teams_mmr,
+            results,
+            common_score,
+            mode: factions.mode,
+        }
+    }
+
+    /// Returns `true` when the session has fewer than two teams or, in `TeamMode::Teams`, a
+    /// team has fewer than 5 players. Free-for-all teams are single players.
+    pub fn is_incomplete(&self) -> bool {
+        self.teams.len() < 2 || (self.mode == TeamMode::Teams && self.teams.iter().any(|team| team.len() < 5))
+    }
+
+    /// Returns `true` when the session updates the leaderboard: none of the `skip` predicates
+    /// of `rules` matches it (by default: every side is complete and the session is not
+    /// played in the `newbie_common` mode, missing modes count as newbie).
+    pub fn is_rated(&self, rules: &SessionRules, session_mode: &SessionMode, registrations: &Registrations) -> bool {
+        !rules.skips(self, session_mode, registrations)
+    }
+
+    /// Session outcome from team 1's point of view: `Some(true)` for a team 1 win,
+    /// `Some(false)` for a team 2 win, `None` when both or neither team won.
+    pub fn team_1_won(&self) -> Option<bool> {
+        match (self.team_1_res, self.team_2_res) {
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            _ => None
+        }
+    }
+
+    /// The only team that won, by team number; `None` when no team or several teams won.
+    pub fn winner(&self) -> Option<u8> {
+        match self.results.iter().filter(|result| **result).count() {
+            1 => self.results.iter().position(|result| *result).map(|index| index as u8 + 1),
+            _ => None
+        }
+    }
+
+    /// Opponent reference for a player of `team`: all MMR values of the other teams sorted
+    /// from highest to lowest (provisional and unknown players count as 0).
+    pub fn opponents(&self, team: u8) -> Vec<MMRType> {
+        let mut opponents: Vec<MMRPair> = self.teams_mmr.iter().enumerate()
+            .filter(|(index, _)| *index + 1 != team as usize)
+            .flat_map(|(_, team_mmr)| team_mmr.0.iter().cloned())
+            .collect();
+        opponents.sort_unstable_by_key(|o| match o.1 {
+            MMRType::MMR(data) => data,
+            _ => 0
+        });
+        opponents.reverse();
+        opponents.into_iter().map(|o| o.1).collect()
+    }
+
+    /// Battle score normalized to a 1600 per-player session average.
+    pub fn battle_score_muld(&self, row: &UserBattleRow) -> u32 {
+        let players = self.teams.iter().map(|team| team.len()).sum::<usize>();
+        ((1600.0 * players as f64) / (self.common_score as f64) * (row.battle_score as f64)) as u32
+    }
+}
+
+/// Ranks a team by calibrated MMR: calibrated players first (highest MMR first), then the rest.
+fn rank_team<T: RatingSystem>(rating: &T, rows: &[UserBattleRow]) -> TeamMMR {
+    let mut team_mmr = TeamMMR(rows.iter().map(|user| MMRPair(user.user_id, rating.get_mmr(user.user_id), user.clone())).collect());
+    team_mmr.0.sort_unstable_by_key(|obj| match obj.1 {
+        MMRType::MMR(mmr) => (2, mmr),
+        _ => (1, 0)
+    });
+    team_mmr.0.reverse();
+    team_mmr
+}
+
+/// Average calibrated MMR of the first three players of a ranked team.
+///
+/// Returns `None` when none of the top-3 players is calibrated.
+pub fn top_3_avg(team_mmr: &TeamMMR) -> Option<u32> {
+    top_k_avg(team_mmr, 3)
+}
+
+/// Average calibrated MMR of the first `k` players of a ranked team, rounded down.
+///
+/// Returns `None` when none of the first `k` players is calibrated.
+pub fn top_k_avg(team_mmr: &TeamMMR, k: usize) -> Option<u32> {
+    let top3d: MMRAgg = team_mmr.0.iter().take(k).fold(MMRAgg(0, 0), |mut res, obj| {
+        if let MMRType::MMR(mmr) = obj.1 {
+            res.0 += 1;
+            res.1 += mmr as i64;
+        }
+        res
+    });
+    if top3d.0 == 0 {
+        None
+    } else {
+        Some((top3d.1 / top3d.0) as u32)
+    }
+}
+
+/// Splits a completed session into teams and prepares `rating` for it: `None` for sessions
+/// skipped by `rules` (see [`SessionTeams::is_rated`]). Players returning from inactivity
+/// are decayed via [`RatingSystem::apply_decay`] and the teams re-split.
+pub fn prepare_teams<T: RatingSystem>(rating: &mut T, session_memory: &SessionMemory, session_mode: &SessionMode, registrations: &Registrations, rules: &SessionRules) -> Option<SessionTeams> {
+    let mut teams = SessionTeams::new(rating, session_memory, &rules.factions);
+
+    // Ignore skipped sessions (incomplete matches and newbie mode by default) for leaderboard updates.
+    if !teams.is_rated(rules, session_mode, registrations) {
+        return None;
+    }
+    // Players returning from inactivity are decayed before the session is rated.
+    let mut decayed = false;
+    for row in session_memory.rows.iter() {
+        decayed |= rating.apply_decay(row.user_id, row.commit_time);
+    }
+    if decayed {
+        teams = SessionTeams::new(rating, session_memory, &rules.factions);
+    }
+    Some(teams)
+}
+
+/// Emits the statistics and the session classification of a rated session when `cl_id > 0`:
+/// `Statistic` payloads for the `common` board and all mode-specific boards via `sender`,
+/// `(win_avg_mmr, lose_avg_mmr)` to `sender_check` and the names of the `rules` classes the
+/// session matches to `sender_session_class`.
+#[allow(clippy::too_many_arguments)]
+pub async fn write_session(
+    teams: &SessionTeams,
+    cl_id: u16,
+    sender: flume::Sender<(String, Statistic)>,
+    session_mode: &SessionMode,
+    registrations: &Registrations,
+    rules: &SessionRules,
+    sender_check: flume::Sender<(u32, u32)>,
+    sender_session_class: flume::Sender<SessionClasses>,
+) {
+    if cl_id == 0 {
+        return;
+    }
+    // Emit statistics for common and mode-specific boards.
+    sender
+        .send(proc_statistic(
+            "common".to_string(),
+            &teams.team_1_mmr,
+            &teams.team_2_mmr,
+            teams.team_1_res,
+            teams.team_2_res,
+            registrations,
+            Some(sender_check)
+        ).await)
+        .unwrap();
+
+    if let Some((mode_0, mode_1, mode_2)) = session_mode.0.get(&teams.session_id) {
+        for statboard in [mode_0, mode_1, mode_2] {
+            sender
+                .send(proc_statistic(
+                    statboard.to_string(),
+                    &teams.team_1_mmr,
+                    &teams.team_2_mmr,
+                    teams.team_1_res,
+                    teams.team_2_res,
+                    registrations,
+                    None
+                ).await)
+                .unwrap();
+        }
+    }
+
+    // Named classes of the session (top-3 disbalance and winner by default).
+    sender_session_class
+        .send((teams.session_id, rules.classify(teams, session_mode, registrations)))
+        .unwrap();
+}
+
+/// Change payloads of a completed session as [`proc_session`] would stream them, computed on
+/// `rating` without keeping any change ([`RatingSystem::preview_session`], classifier id 0);
+/// `None` for skipped sessions.
+///
+/// Players returning from inactivity are decayed as in [`prepare_teams`], and the decay is
+/// undone afterwards ([`RatingSystem::save_decay`]); nothing else of the leaderboard is copied.
+pub async fn preview<T: RatingSystem>(rating: &mut T, session_memory: &SessionMemory, session_mode: &SessionMode, registrations: &Registrations, rules: &SessionRules) -> Option<Vec<T::Change>> {
+    let user_ids: Vec<u64> = session_memory.rows.iter().map(|row| row.user_id).collect();
+    let undo = rating.save_decay(&user_ids);
+    let changes = match prepare_teams(rating, session_memory, session_mode, registrations, rules) {
+        Some(teams) => Some(rating.preview_session(rating.prepare_session(&teams), 0).await),
+        None => None
+    };
+    rating.undo_decay(undo);
+    changes
+}
+
+/// Processes a completed session with any [`RatingSystem`].
+///
+/// Executes the shared session pipeline in four timed stages:
+/// 1. **Prepare** — [`prepare_teams`]: splits `session_memory` into the team snapshots
+///    of `rules.factions` via [`SessionTeams::new`]. Sessions matching a `skip` predicate of `rules`
+///    (by default incomplete sessions, see [`SessionTeams::is_incomplete`], or the
+///    `newbie_common` mode) are skipped
+///    (`None` is returned). Players returning from inactivity are then decayed and the
+///    teams re-split.
+/// 2. **Write statistics** — [`write_session`]: when `cl_id > 0`, emits `Statistic`
+///    payloads, win-rate check pairs and the session classes of `rules`.
+/// 3. **Prepare changes** and 4. **Apply changes** — delegated to
+///    [`RatingSystem::process_session`].
+///
+/// Returns four `(Instant, Duration)` tuples — one per stage — for caller-side profiling,
+/// or `None` if the session was skipped. [`crate::batch::proc_batch`] runs the same stages
+/// for several sessions at once.
+#[allow(clippy::too_many_arguments)]
+pub async fn proc_session<T: RatingSystem>(
+    rating: &mut T,
+    session_memory: SessionMemory,
+    cl_id: u16,
+    sender: flume::Sender<(String, Statistic)>,
+    session_mode: &SessionMode,
+    registrations: &Registrations,
+    rules: &SessionRules,
+    sender_tasks: flume::Sender<T::Change>,
+    sender_check: flume::Sender<(u32, u32)>,
+    sender_session_class: flume::Sender<SessionClasses>,
+) -> Option<(StageTiming, StageTiming, StageTiming, StageTiming)> {
+    // 1) Build per-session team snapshots and rank players by known MMR.
+    let prepear_session = Instant::now();
+    let teams = prepare_teams(rating, &session_memory, session_mode, registrations, rules)?;
+    let prepear_session_time = prepear_session.elapsed();
+
+    // 2) Statistics and session classification.
+    let write_session_start = Instant::now();
+    write_session(&teams, cl_id, sender, session_mode, registrations, rules, sender_check, sender_session_class).await;
+    let write_session_time = write_session_start.elapsed();
+
+    // 3-4) Algorithm-specific change preparation and application.
+    let (prepear_change, set_change) = rating.process_session(&teams, cl_id, sender_tasks).await;
+
+    Some(((prepear_session, prepear_session_time), (write_session_start, write_session_time), prepear_change, set_change))
+}
@@ -0,0 +1,279 @@
+use crate::decay::{self, CALIBRATED_BATTLES};
+use crate::factions::FactionRegistry;
+use crate::params::{RewardTier, SeasonParams};
+use crate::ranking::{RankFilter, RankedRow, Ranking};
+use crate::rating::{leaderboard_dir, RatingSystem};
+use crate::types::LeaderboardRow;
+
+/// Final rank of a player in an ended season, with their reward tier.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeasonRank {
+    pub season: usize,
+    // Name of the highest tier reached, `none` below the lowest one.
+    pub tier: String,
+    pub ranked: RankedRow
+}
+
+impl std::fmt::Display for SeasonRank {
+    /// Formats the rank as a flat key:value line.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "season:{},{},tier:{}", self.season, self.ranked, self.tier)
+    }
+}
+
+/// Summary of one season end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeasonEnd {
+    pub season: usize,
+    // Season start (commit_time, ms) that ended it.
+    pub ended_at: u64,
+    pub players: u64,
+    // Players in the final ranks (at least `min_battles` battles).
+    pub ranked: u64,
+    // Calibrated players soft-reset into the new season.
+    pub reset: u64,
+    // Tier name -> players, in the order of `SeasonParams::tiers`.
+    pub tiers: Vec<(String, u64)>
+}
+
+impl std::fmt::Display for SeasonEnd {
+    /// Formats the summary as a flat key:value line, one `<tier>:<players>` pair per tier.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "season:{},ended_at:{},players:{},ranked:{},reset:{}", self.season, self.ended_at, self.players, self.ranked, self.reset)?;
+        for (tier, players) in self.tiers.iter() {
+            write!(f, ",{}:{}", tier, players)?;
+        }
+        Ok(())
+    }
+}
+
+/// Competitive seasons of a leaderboard.
+///
+/// Season `n` runs from `starts[n - 1]` up to `starts[n]`; season 0 is everything before the
+/// first start. The current season is derived from the latest `last_session` of the
+/// leaderboard, so a restored leaderboard continues in its season.
+#[derive(Clone, Debug)]
+pub struct Seasons {
+    pub params: SeasonParams,
+    pub season: usize
+}
+
+impl Seasons {
+    /// Seasons of `params` with `rating` in the season of its latest rated session; `starts`
+    /// are sorted and duplicates dropped.
+    pub fn new<T: RatingSystem>(mut params: SeasonParams, rating: &T) -> Self {
+        params.starts.sort();
+        params.starts.dedup();
+        let last_session = rating.get_users().values().map(|row| row.last_session).max().unwrap_or(0);
+        let season = season_at(&params.starts, last_session);
+        Self { params, season }
+    }
+
+    /// Archive directory of `season`: `data/leaderboard_<NAME>/season_<season>`.
+    pub fn dir<T: RatingSystem>(season: usize) -> String {
+        leaderboard_dir::<T>() + "/season_" + season.to_string().as_str()
+    }
+
+    /// Returns `true` when a session at `now` (commit_time, ms) belongs to a later season.
+    pub fn ends_before(&self, now: u64) -> bool {
+        season_at(&self.params.starts, now) > self.season
+    }
+
+    /// Final ranks of the players of `rating` with at least `min_battles` battles and their
+    /// reward tiers.
+    pub fn final_ranks<T: RatingSystem>(&self, rating: &T, factions: &FactionRegistry) -> Vec<SeasonRank> {
+        let filter = RankFilter { min_battles: self.params.min_battles, factions: factions.clone(), ..RankFilter::default() };
+        Ranking::new(rating, &filter).rows.into_iter().map(|ranked| SeasonRank {
+            season: self.season,
+            tier: tier(&self.params.tiers, ranked.percentile).map(|tier| tier.name.clone()).unwrap_or("none".to_string()),
+            ranked
+        }).collect()
+    }
+
+    /// Ends the current season before a session at `now`:
+    ///
+    /// 1. Archives the leaderboard into `dir` (`RatingSystem::persist_to`) with the final
+    ///    ranks and reward tiers in `dir/ranks`.
+    /// 2. Soft-resets the leaderboard (`RatingSystem::season_reset`).
+    /// 3. Moves on to the season of `now`; seasons without sessions are skipped.
+    ///
+    /// The last season never ends, and an empty leaderboard is neither archived nor reset
+    /// (`None`). Errors of the archive leave the leaderboard untouched.
+    pub async fn end_season<T: RatingSystem>(&mut self, rating: &mut T, dir: &str, now: u64, factions: &FactionRegistry) -> std::io::Result<Option<SeasonEnd>> {
+        let ended_at = match self.params.starts.get(self.season) {
+            Some(start) => *start,
+            None => return Ok(None)
+        };
+        let season = self.season;
+        if rating.get_users().is_empty() {
+            self.season = season_at(&self.params.starts, now);
+            return Ok(None);
+        }
+        let ranks = self.final_ranks(rating, factions);
+        tokio::fs::create_dir_all(dir).await?;
+        rating.persist_to(dir).await;
+        let lines: Vec<String> = ranks.iter().map(|rank| rank.to_string() + "\n").collect();
+        tokio::fs::write(dir.to_string() + "/ranks", lines.concat()).await?;
+        self.season = season_at(&self.params.starts, now);
+
+        let tiers = self.params.tiers.iter()
+            .map(|tier| (tier.name.clone(), ranks.iter().filter(|rank| rank.tier == tier.name).count() as u64))
+            .collect();
+        Ok(Some(SeasonEnd {
+            season,
+            ended_at,
+            players: rating.get_users().len() as u64,
+            ranked: ranks.len() as u64,
+            reset: rating.season_reset(&self.params),
+            tiers
+        }))
+    }
+}
+
+/// Season of a session at `time`: the number of season starts at or before it.
+pub fn season_at(starts: &[u64], time: u64) -> usize {
+    starts.iter().filter(|start| **start <= time).count()
+}
+
+/// Highest tier whose `min_percentile` the `percentile` reaches, `None` below every tier.
+pub fn tier(tiers: &[RewardTier], percentile: f64) -> Option<&RewardTier> {
+    tiers.iter()
+        .filter(|tier| tier.min_percentile <= percentile)
+        .max_by(|a, b| a.min_percentile.total_cmp(&b.min_percentile))
+}
+
+/// Soft reset of the v1/v2 `users` at a season end; returns the number of players reset.
+///
+/// 1. Compression: a calibrated MMR keeps `1 - compression` of its gap to the mean MMR of
+///    calibrated players, taken before anyone moves.
+/// 2. Recalibration: a calibrated player is set back to `6 - recalibration` battles (battle
+///    score scaled alike) and replays the last calibration battles.
+///
+/// Provisional players keep their rows; `battle_score_hash` is kept in sync.
+pub fn soft_reset(
+    users: &mut std::collections::HashMap<u64, LeaderboardRow>,
+    battle_score_hash: &mut std::collections::BTreeMap<(u32, u64), u32>,
+    params: &SeasonParams
+) -> u64 {
+    let mean = decay::calibrated_mean(users);
+    let keep = 1.0 - params.compression.clamp(0.0, 1.0);
+    let mut count = 0;
+    for row in users.values_mut().filter(|row| row.battles >= CALIBRATED_BATTLES) {
+        decay::soften(row, mean, keep, params.recalibration, battle_score_hash);
+        count += 1;
+    }
+    count
+}
+
+/// Soft reset of the Glicko-2/TrueSkill `users` at a season end, where `field` picks the
+/// (rating, spread) of a player state in `states`; returns the number of players reset.
+///
+/// 1. Compression: a calibrated rating keeps `1 - compression` of its gap to the mean rating
+///    of calibrated players, taken before anyone moves; the row MMR follows it.
+/// 2. Recalibration: the spread moves `recalibration / 6` of the way back up to `initial`
+///    (the unrated deviation or sigma), and the row is set back to `6 - recalibration`
+///    battles as in [`soft_reset`].
+///
+/// Provisional players keep their rows and states.
+pub fn soft_reset_spread<S>(
+    users: &mut std::collections::HashMap<u64, LeaderboardRow>,
+    states: &mut std::collections::HashMap<u64, S>,
+    params: &SeasonParams,
+    initial: f64,
+    field: impl Fn(&mut S) -> (&mut f64, &mut f64)
+) -> u64 {
+    let mut calibrated: Vec<u64> = users.values()
+        .filter(|row| row.battles >= CALIBRATED_BATTLES && states.contains_key(&row.user_id))
+        .map(|row| row.user_id)
+        .collect();
+    calibrated.sort();
+    let mean = calibrated.iter().map(|user_id| *field(states.get_mut(user_id).unwrap()).0).sum::<f64>() / calibrated.len().max(1) as f64;
+    let keep = 1.0 - params.compression.clamp(0.0, 1.0);
+    let widen = params.recalibration.min(CALIBRATED_BATTLES - 1) as f64 / CALIBRATED_BATTLES as f64;
+    for user_id in calibrated.iter() {
+        let (rating, spread) = field(states.get_mut(user_id).unwrap());
+        *rating = mean + (*rating - mean) * keep;
+        *spread += (initial - *spread).max(0.0) * widen;
+        let row = users.get_mut(user_id).unwrap();
+        row.mmr = rating.max(0.0) as u32;
+        decay::soften(row, 0.0, 1.0, params.recalibration, &mut std::collections::BTreeMap::new());
+    }
+    calibrated.len() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{GlickoRating, Leaderboard, LeaderboardGlicko, LeaderboardTrueSkill, TrueSkillRating};
+
+    #[tokio::test]
+    async fn season_end_archives_ranks_and_compresses_toward_the_mean() {
+        let dir = std::env::temp_dir().join("mmr_season_test_".to_string() + std::process::id().to_string().as_str());
+        let dir = dir.to_str().unwrap().to_string();
+
+        // Starts are sorted and deduplicated.
+        let params = SeasonParams { starts: vec![300, 100, 200, 100], ..SeasonParams::default() };
+        let mut leaderboard = Leaderboard::empty();
+        for (user_id, mmr, battles) in [(1, 2000, 10), (2, 1000, 10), (3, 1500, 10), (4, 1200, 3)] {
+            leaderboard.users.insert(user_id, LeaderboardRow { user_id, mmr, battles, victories: 0, early_quites: 0, top_20: 0, battle_score: 1000 * battles, last_session: 150 });
+            if battles >= CALIBRATED_BATTLES {
+                leaderboard.battle_score_hash.insert((1000, user_id), mmr);
+            }
+        }
+        let mut seasons = Seasons::new(params, &leaderboard);
+        assert_eq!((seasons.params.starts.clone(), seasons.season), (vec![100, 200, 300], 1));
+        assert!(!seasons.ends_before(199));
+        assert!(seasons.ends_before(350));
+
+        // The session at 350 skips season 2, which had no sessions.
+        let end = seasons.end_season(&mut leaderboard, &dir, 350, &FactionRegistry::default()).await.unwrap().unwrap();
+        assert_eq!((end.season, end.ended_at, seasons.season), (1, 200, 3));
+        assert_eq!((end.players, end.ranked, end.reset), (4, 3, 3));
+        assert_eq!(end.tiers.iter().map(|tier| tier.1).collect::<Vec<u64>>(), vec![1, 1, 1, 0, 0]);
+
+        let ranks = std::fs::read_to_string(dir.clone() + "/ranks").unwrap();
+        assert!(ranks.lines().next().unwrap().starts_with("season:1,rank:1,percentile:83.33,user_id:1,mmr:2000"));
+        assert!(ranks.lines().next().unwrap().ends_with(",tier:gold"));
+        let archived = Leaderboard::restore_from(&dir).unwrap();
+        assert_eq!(archived.users[&1].mmr, 2000);
+
+        // Half of the gap to the mean of 1500 is removed and 3 calibration battles replayed.
+        assert_eq!((leaderboard.users[&1].mmr, leaderboard.users[&2].mmr, leaderboard.users[&3].mmr), (1750, 1250, 1500));
+        assert_eq!((leaderboard.users[&1].battles, leaderboard.users[&1].battle_score), (3, 3000));
+        assert_eq!(leaderboard.users[&4], LeaderboardRow { user_id: 4, mmr: 1200, battles: 3, victories: 0, early_quites: 0, top_20: 0, battle_score: 3000, last_session: 150 });
+        assert!(leaderboard.battle_score_hash.is_empty());
+
+        // Season 3 is the last one and never ends.
+        assert_eq!(seasons.end_season(&mut leaderboard, &dir, 1000, &FactionRegistry::default()).await.unwrap(), None);
+        assert_eq!(leaderboard.users[&1].mmr, 1750);
+
+        assert_eq!(tier(&SeasonParams::default().tiers, 97.9).unwrap().name, "platinum");
+        assert!(tier(&[RewardTier { name: "top".to_string(), min_percentile: 50.0 }], 10.0).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn glicko_and_trueskill_compress_toward_the_mean_and_widen_the_spread() {
+        let params = SeasonParams { starts: vec![100], ..SeasonParams::default() };
+        let mut glicko = LeaderboardGlicko::empty();
+        let mut trueskill = LeaderboardTrueSkill::empty();
+        for (user_id, rating, battles) in [(1, 2000.0, 10), (2, 1000.0, 10), (3, 1200.0, 3)] {
+            let row = LeaderboardRow { user_id, mmr: rating as u32, battles, victories: 0, early_quites: 0, top_20: 0, battle_score: 1000 * battles, last_session: 50 };
+            glicko.users.insert(user_id, row.clone());
+            glicko.glicko.insert(user_id, GlickoRating { rating, deviation: 50.0, volatility: 0.06 });
+            trueskill.users.insert(user_id, row);
+            trueskill.skill.insert(user_id, TrueSkillRating { mu: rating, sigma: 100.0 });
+        }
+        assert_eq!(glicko.season_reset(&params), 2);
+        assert_eq!(trueskill.season_reset(&params), 2);
+
+        // Half of the gap to the mean of 1500 is removed and half of the way back to the
+        // unrated spread (350 / 500) regained with 3 calibration battles to replay.
+        assert_eq!(glicko.glicko[&1], GlickoRating { rating: 1750.0, deviation: 200.0, volatility: 0.06 });
+        assert_eq!(trueskill.skill[&2], TrueSkillRating { mu: 1250.0, sigma: 300.0 });
+        assert_eq!((glicko.users[&1].mmr, glicko.users[&1].battles, glicko.users[&1].battle_score), (1750, 3, 3000));
+        assert_eq!((trueskill.users[&2].mmr, trueskill.users[&2].battles), (1250, 3));
+        assert_eq!(glicko.glicko[&3].rating, 1200.0);
+        assert_eq!((trueskill.users[&3].mmr, trueskill.skill[&3].sigma), (1200, 100.0));
+    }
+}
@@ -0,0 +1,450 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+use crate::decay::DecayMark;
+use crate::jsonl::{FactionBattlesRecord, JsonlReader, JsonlReport, LeaderboardRowRecord, LineFormat};
+use crate::types::LeaderboardRow;
+
+/// First bytes of every snapshot file.
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"MMRSNAP\0";
+/// Layout version written by [`encode`]; [`decode`] also reads version 1 (no decay section)
+/// and version 2 (no rating section).
+pub const SNAPSHOT_VERSION: u16 = 3;
+
+// magic + version + flags
+const HEADER_LEN: usize = 12;
+// CRC-32 trailer
+const CHECKSUM_LEN: usize = 4;
+
+/// Leaderboard state shared by every rating algorithm.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Snapshot {
+    pub users: std::collections::HashMap<u64, LeaderboardRow>,
+    pub battle_score_hash: std::collections::BTreeMap<(u32, u64), u32>,
+    pub battle_faction_hash: std::collections::HashMap<(u64, String), u64>,
+    pub decay_marks: std::collections::HashMap<u64, DecayMark>,
+    // user_id -> algorithm state beyond the row: Glicko-2 (rating, deviation, volatility),
+    // TrueSkill (mu, sigma); empty for v1/v2.
+    pub ratings: std::collections::HashMap<u64, Vec<f64>>
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    // The file does not start with `SNAPSHOT_MAGIC`.
+    BadMagic,
+    UnsupportedVersion(u16),
+    // CRC-32 stored in the trailer vs. CRC-32 of the file contents.
+    ChecksumMismatch { stored: u32, computed: u32 },
+    // A section ends before its declared number of entries.
+    Truncated,
+    // Bytes left between the last section and the checksum.
+    TrailingBytes(usize),
+    // A faction name is not valid UTF-8.
+    InvalidFaction,
+    // A faction name longer than the u16 length prefix allows (length in bytes).
+    FactionTooLong(usize),
+    // Lines of a legacy text file that failed to parse; the migration is not done.
+    LegacyRejected(JsonlReport)
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "io error: {}", error),
+            SnapshotError::BadMagic => write!(f, "not a leaderboard snapshot (bad magic)"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {} (supported 1..={})", version, SNAPSHOT_VERSION),
+            SnapshotError::ChecksumMismatch { stored, computed } => write!(f, "checksum mismatch: stored {:08x}, computed {:08x}", stored, computed),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::TrailingBytes(count) => write!(f, "{} unexpected bytes before the checksum", count),
+            SnapshotError::InvalidFaction => write!(f, "faction name is not valid utf-8"),
+            SnapshotError::FactionTooLong(len) => write!(f, "faction name of {} bytes exceeds {} bytes", len, u16::MAX),
+            SnapshotError::LegacyRejected(report) => write!(f, "legacy migration rejected lines: {}", report)
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(error: std::io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+/// Serializes leaderboard state into the versioned binary layout.
+///
+/// All integers are little-endian:
+/// 1. Header: `SNAPSHOT_MAGIC`, `SNAPSHOT_VERSION` (u16), flags (u16, always 0).
+/// 2. Users: count (u64), then per row `user_id` u64, `mmr`, `battles`, `victories`,
+///    `early_quites`, `top_20`, `battle_score` u32 and `last_session` u64.
+/// 3. `battle_score_hash`: count (u64), then `avg_score` u32, `user_id` u64, `mmr` u32.
+/// 4. `battle_faction_hash`: count (u64), then `user_id` u64, faction length u16 + UTF-8
+///    bytes, `battles` u64.
+/// 5. Decay marks (version 2): count (u64), then `user_id` u64, `periods` u32,
+///    `confidence` f64.
+/// 6. Ratings (version 3): count (u64), then `user_id` u64, number of values (u64) and the
+///    values as f64.
+/// 7. CRC-32 (u32) of everything above.
+///
+/// Users, faction counters, decay marks and ratings are sorted by key, so equal states
+/// produce identical files. Fails with [`SnapshotError::FactionTooLong`] when a faction name
+/// does not fit its length prefix.
+pub fn encode(
+    users: &std::collections::HashMap<u64, LeaderboardRow>,
+    battle_score_hash: &std::collections::BTreeMap<(u32, u64), u32>,
+    battle_faction_hash: &std::collections::HashMap<(u64, String), u64>,
+    decay_marks: &std::collections::HashMap<u64, DecayMark>,
+    ratings: &std::collections::HashMap<u64, Vec<f64>>
+) -> Result<Vec<u8>, SnapshotError> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(HEADER_LEN + 24 + users.len() * 40 + battle_score_hash.len() * 16 + battle_faction_hash.len() * 32 + CHECKSUM_LEN);
+    bytes.extend_from_slice(SNAPSHOT_MAGIC);
+    bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+
+    let mut rows: Vec<&LeaderboardRow> = users.values().collect();
+    rows.sort_by_key(|row| row.user_id);
+    bytes.extend_from_slice(&(rows.len() as u64).to_le_bytes());
+    for row in rows {
+        bytes.extend_from_slice(&row.user_id.to_le_bytes());
+        for value in [row.mmr, row.battles, row.victories, row.early_quites, row.top_20, row.battle_score] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&row.last_session.to_le_bytes());
+    }
+
+    bytes.extend_from_slice(&(battle_score_hash.len() as u64).to_le_bytes());
+    for ((score, user_id), mmr) in battle_score_hash.iter() {
+        bytes.extend_from_slice(&score.to_le_bytes());
+        bytes.extend_from_slice(&user_id.to_le_bytes());
+        bytes.extend_from_slice(&mmr.to_le_bytes());
+    }
+
+    let mut factions: Vec<(&(u64, String), &u64)> = battle_faction_hash.iter().collect();
+    factions.sort();
+    bytes.extend_from_slice(&(factions.len() as u64).to_le_bytes());
+    for ((user_id, faction), battles) in factions {
+        bytes.extend_from_slice(&user_id.to_le_bytes());
+        let len = u16::try_from(faction.len()).map_err(|_| SnapshotError::FactionTooLong(faction.len()))?;
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(faction.as_bytes());
+        bytes.extend_from_slice(&battles.to_le_bytes());
+    }
+
+    let mut marks: Vec<(&u64, &DecayMark)> = decay_marks.iter().collect();
+    marks.sort_by_key(|mark| *mark.0);
+    bytes.extend_from_slice(&(marks.len() as u64).to_le_bytes());
+    for (user_id, mark) in marks {
+        bytes.extend_from_slice(&user_id.to_le_bytes());
+        bytes.extend_from_slice(&mark.periods.to_le_bytes());
+        bytes.extend_from_slice(&mark.confidence.to_le_bytes());
+    }
+
+    let mut ratings: Vec<(&u64, &Vec<f64>)> = ratings.iter().collect();
+    ratings.sort_by_key(|rating| *rating.0);
+    bytes.extend_from_slice(&(ratings.len() as u64).to_le_bytes());
+    for (user_id, values) in ratings {
+        bytes.extend_from_slice(&user_id.to_le_bytes());
+        bytes.extend_from_slice(&(values.len() as u64).to_le_bytes());
+        for value in values {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let checksum = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    Ok(bytes)
+}
+
+/// Parses a snapshot produced by [`encode`].
+///
+/// The header and the checksum are verified before any section is read, so a damaged file
+/// is rejected as a whole instead of loading a partial leaderboard.
+pub fn decode(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+    if bytes.len() < SNAPSHOT_MAGIC.len() || &bytes[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    if bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(SnapshotError::Truncated);
+    }
+    let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let (body, trailer) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+    let stored = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let computed = crc32fast::hash(body);
+    if stored != computed {
+        return Err(SnapshotError::ChecksumMismatch { stored, computed });
+    }
+
+    let mut cursor = Cursor { bytes: body, position: HEADER_LEN };
+    let mut snapshot = Snapshot::default();
+
+    let count = cursor.u64()?;
+    for _ in 0..count {
+        let row = LeaderboardRow {
+            user_id: cursor.u64()?,
+            mmr: cursor.u32()?,
+            battles: cursor.u32()?,
+            victories: cursor.u32()?,
+            early_quites: cursor.u32()?,
+            top_20: cursor.u32()?,
+            battle_score: cursor.u32()?,
+            last_session: cursor.u64()?
+        };
+        snapshot.users.insert(row.user_id, row);
+    }
+
+    let count = cursor.u64()?;
+    for _ in 0..count {
+        let score = cursor.u32()?;
+        let user_id = cursor.u64()?;
+        snapshot.battle_score_hash.insert((score, user_id), cursor.u32()?);
+    }
+
+    let count = cursor.u64()?;
+    for _ in 0..count {
+        let user_id = cursor.u64()?;
+        let len = cursor.u16()? as usize;
+        let faction = std::str::from_utf8(cursor.take(len)?).map_err(|_| SnapshotError::InvalidFaction)?.to_string();
+        snapshot.battle_faction_hash.insert((user_id, faction), cursor.u64()?);
+    }
+
+    if version >= 2 {
+        let count = cursor.u64()?;
+        for _ in 0..count {
+            let user_id = cursor.u64()?;
+            let periods = cursor.u32()?;
+            let confidence = f64::from_bits(cursor.u64()?);
+            snapshot.decay_marks.insert(user_id, DecayMark { periods, confidence });
+        }
+    }
+
+    if version >= 3 {
+        let count = cursor.u64()?;
+        for _ in 0..count {
+            let user_id = cursor.u64()?;
+            let len = cursor.u64()?;
+            let values = (0..len).map(|_| cursor.u64().map(f64::from_bits)).collect::<Result<Vec<f64>, SnapshotError>>()?;
+            snapshot.ratings.insert(user_id, values);
+        }
+    }
+
+    if cursor.position != body.len() {
+        return Err(SnapshotError::TrailingBytes(body.len() - cursor.position));
+    }
+    Ok(snapshot)
+}
+
+/// Reads and verifies the snapshot at `path`.
+pub fn read(path: &str) -> Result<Snapshot, SnapshotError> {
+    decode(&std::fs::read(path)?)
+}
+
+/// CRC-32 trailer of the snapshot at `path`, which identifies the state it holds; `None`
+/// when there is no snapshot. The file is not verified.
+pub fn checksum(path: &str) -> std::io::Result<Option<u32>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error)
+    };
+    Ok(bytes.len().checked_sub(CHECKSUM_LEN).map(|start| u32::from_le_bytes([bytes[start], bytes[start + 1], bytes[start + 2], bytes[start + 3]])))
+}
+
+/// Writes a snapshot to `path` through a temporary file and a rename, so an interrupted
+/// write never replaces the previous snapshot with a partial one.
+pub async fn write(
+    path: &str,
+    users: &std::collections::HashMap<u64, LeaderboardRow>,
+    battle_score_hash: &std::collections::BTreeMap<(u32, u64), u32>,
+    battle_faction_hash: &std::collections::HashMap<(u64, String), u64>,
+    decay_marks: &std::collections::HashMap<u64, DecayMark>,
+    ratings: &std::collections::HashMap<u64, Vec<f64>>
+) -> Result<(), SnapshotError> {
+    let bytes = encode(users, battle_score_hash, battle_faction_hash, decay_marks, ratings)?;
+    let tmp_path = path.to_string() + ".tmp";
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Loads the legacy text files `<dir>/base` and `<dir>/battle_faction` with
+/// [`LineFormat::KeyValue`].
+///
+/// Players with fewer than 6 battles are loaded but excluded from the `battle_score_hash`
+/// lookup index. The `rating_keys` of a `base` row (e.g. `rating,deviation,volatility` of
+/// the Glicko-2 text files) become its `ratings` entry when the row has all of them.
+/// Missing files yield an empty state. Any rejected line fails the migration with
+/// [`SnapshotError::LegacyRejected`], listing the rejected lines, so a partly readable
+/// leaderboard is never migrated.
+pub fn read_legacy(dir: &str, rating_keys: &[&str]) -> Result<Snapshot, SnapshotError> {
+    let mut snapshot = Snapshot::default();
+    if let Some(mut rows) = open_legacy::<LeaderboardRowRecord>(&(dir.to_string() + "/base"))? {
+        while let Some((line, record)) = rows.next() {
+            let values: Option<Vec<Option<f64>>> = rating_keys.iter()
+                .map(|key| record.fields.get(*key).map(|value| value.as_str().and_then(|value| value.parse::<f64>().ok())))
+                .collect();
+            let values = match values.filter(|values| !values.is_empty()).map(|values| values.into_iter().collect::<Option<Vec<f64>>>()) {
+                Some(Some(values)) => Some(values),
+                Some(None) => {
+                    rows.reject(line, "expected numbers for ".to_string() + rating_keys.join(",").as_str());
+                    continue;
+                },
+                None => None
+            };
+            let row = LeaderboardRow::from_record(record);
+            // Index only calibrated users for bootstrap estimates.
+            if row.battles >= 6 {
+                snapshot.battle_score_hash.insert((row.battle_score / row.battles, row.user_id), row.mmr);
+            }
+            if let Some(values) = values {
+                snapshot.ratings.insert(row.user_id, values);
+            }
+            snapshot.users.insert(row.user_id, row);
+        }
+        if rows.report().rejected > 0 {
+            return Err(SnapshotError::LegacyRejected(rows.into_report()));
+        }
+    }
+    // Restore per-user battles grouped by faction/mode.
+    if let Some(mut rows) = open_legacy::<FactionBattlesRecord>(&(dir.to_string() + "/battle_faction"))? {
+        for (_line, record) in rows.by_ref() {
+            snapshot.battle_faction_hash.insert((record.user_id, record.faction), record.battles);
+        }
+        if rows.report().rejected > 0 {
+            return Err(SnapshotError::LegacyRejected(rows.into_report()));
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Opens a legacy key:value file; `None` when it does not exist.
+fn open_legacy<T: DeserializeOwned>(path: &str) -> Result<Option<JsonlReader<T>>, SnapshotError> {
+    match JsonlReader::open(path) {
+        Ok(rows) => Ok(Some(rows.with_format(LineFormat::KeyValue))),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(SnapshotError::Io(error))
+    }
+}
+
+/// Restores leaderboard state from `<dir>/snapshot`, migrating from the legacy text files
+/// (see [`read_legacy`]) when no snapshot exists yet (the next `write` then produces the
+/// snapshot).
+///
+/// Fails when the snapshot exists but fails verification, or when the migration rejects a
+/// line: a corrupted leaderboard must not be silently replaced with a partial or empty one.
+pub fn load(dir: &str, rating_keys: &[&str]) -> Result<Snapshot, SnapshotError> {
+    let path = dir.to_string() + "/snapshot";
+    if Path::new(&path).exists() {
+        read(&path)
+    } else {
+        read_legacy(dir, rating_keys)
+    }
+}
+
+/// Bounds-checked little-endian reader over the snapshot body; also used by
+/// [`crate::history`] for its records.
+pub(crate) struct Cursor<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) position: usize
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.position.checked_add(len).ok_or(SnapshotError::Truncated)?;
+        if end > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_round_trip_and_corruption() {
+        let mut state = Snapshot::default();
+        for user_id in 1..=3u64 {
+            state.users.insert(user_id, LeaderboardRow {
+                user_id,
+                mmr: 1000 + user_id as u32,
+                battles: 6 + user_id as u32,
+                victories: 3,
+                early_quites: 1,
+                top_20: 2,
+                battle_score: 9000,
+                last_session: 1700000000000 + user_id
+            });
+            state.battle_score_hash.insert((1000, user_id), 1000 + user_id as u32);
+            state.battle_faction_hash.insert((user_id, "faction_1".to_string()), user_id * 2);
+        }
+        state.decay_marks.insert(2, DecayMark { periods: 3, confidence: 1.5 });
+        state.ratings.insert(3, vec![1612.5, 87.25, 0.06]);
+        let bytes = encode(&state.users, &state.battle_score_hash, &state.battle_faction_hash, &state.decay_marks, &state.ratings).unwrap();
+        assert_eq!(decode(&bytes).unwrap(), state);
+        assert_eq!(bytes, encode(&state.users.clone(), &state.battle_score_hash, &state.battle_faction_hash.clone(), &state.decay_marks, &state.ratings.clone()).unwrap());
+
+        let mut flipped = bytes.clone();
+        flipped[HEADER_LEN + 10] ^= 0x01;
+        assert!(matches!(decode(&flipped), Err(SnapshotError::ChecksumMismatch { .. })));
+        assert!(matches!(decode(&bytes[..bytes.len() - 1]), Err(SnapshotError::ChecksumMismatch { .. })));
+        assert!(matches!(decode(b"user_id:1,mmr:1000"), Err(SnapshotError::BadMagic)));
+
+        // Version 1 files have no decay and no rating section.
+        let mut legacy = encode(&state.users, &state.battle_score_hash, &state.battle_faction_hash, &std::collections::HashMap::new(), &std::collections::HashMap::new()).unwrap();
+        legacy.truncate(legacy.len() - CHECKSUM_LEN - 16);
+        legacy[8] = 1;
+        let checksum = crc32fast::hash(&legacy);
+        legacy.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(decode(&legacy).unwrap().users, state.users);
+
+        let mut version = bytes.clone();
+        version[8] = 4;
+        assert!(matches!(decode(&version), Err(SnapshotError::UnsupportedVersion(4))));
+
+        let long = std::collections::HashMap::from([((1, "f".repeat(70_000)), 1)]);
+        assert!(matches!(encode(&state.users, &state.battle_score_hash, &long, &std::collections::HashMap::new(), &std::collections::HashMap::new()), Err(SnapshotError::FactionTooLong(70_000))));
+    }
+
+    #[test]
+    fn legacy_migration_reads_rating_keys_and_fails_on_rejected_lines() {
+        let dir = std::env::temp_dir().join("mmr_snapshot_legacy_".to_string() + std::process::id().to_string().as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        std::fs::write(dir.clone() + "/base", "user_id:1,battles:8,battle_score:8000,mmr:1600,mu:1610.5,sigma:120\n\"user_id\":2,\"mmr\":900\n").unwrap();
+        std::fs::write(dir.clone() + "/battle_faction", "user_id:1,faction:faction_1,battles:8\n").unwrap();
+        let snapshot = read_legacy(&dir, &["mu", "sigma"]).unwrap();
+        assert_eq!((snapshot.users[&1].mmr, snapshot.users[&2].mmr), (1600, 900));
+        assert_eq!(snapshot.battle_score_hash.get(&(1000, 1)), Some(&1600));
+        assert_eq!(snapshot.ratings, std::collections::HashMap::from([(1, vec![1610.5, 120.0])]));
+        assert_eq!(snapshot.battle_faction_hash[&(1, "faction_1".to_string())], 8);
+
+        std::fs::write(dir.clone() + "/battle_faction", "user_id:1,faction:faction_1,battles:8\nuser_id:2,faction:faction_1,battles:x\n").unwrap();
+        match read_legacy(&dir, &[]) {
+            Err(SnapshotError::LegacyRejected(report)) => assert_eq!((report.rejected, report.errors[0].0), (1, 2)),
+            other => panic!("expected a rejected line, got {:?}", other)
+        }
+        std::fs::write(dir.clone() + "/base", "user_id:1,mmr:1600,mu:high,sigma:120\n").unwrap();
+        assert!(matches!(read_legacy(&dir, &["mu", "sigma"]), Err(SnapshotError::LegacyRejected(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,27 @@
+use crate::factions::FactionRegistry;
 use crate::types::{LeaderboardMark, LeaderboardRow};
 
-/// Builds an MMR distribution bucketed by dominant faction and MMR range.
-///
-/// Iterates over `users_rows`, filters by `filter_battle` (minimum total battles) and
-/// `filter_time` (minimum `last_session` timestamp), then classifies each user into one of
-/// three faction categories:
+/// Default share of a player's battles on one faction that makes it their dominant faction
+/// (`FactionRegistry::dominant_share`).
+pub const DOMINANT_FACTION_SHARE: f64 = 0.65;
+
+/// Dominant faction of `user_id` with `battles` battles in total under `factions`
+/// ([`FactionRegistry::dominant`]). With the default registry:
 /// - `"faction_1"` — played ≥ 65 % of battles as faction 1
 /// - `"faction_2"` — played ≥ 65 % of battles as faction 2
 /// - `"mixed"`     — no dominant faction
+pub fn dominant_faction<T: LeaderboardMark>(leaderboard: &T, user_id: u64, battles: u32, factions: &FactionRegistry) -> String {
+    factions.dominant(battles, |faction| match leaderboard.get_battle_faction_hash().get(&(user_id, faction.to_string())) {
+        Some(&battles) => battles,
+        None => 0
+    })
+}
+
+/// Builds an MMR distribution bucketed by dominant faction and MMR range.
+///
+/// Iterates over `users_rows`, filters by `filter_battle` (minimum total battles) and
+/// `filter_time` (minimum `last_session` timestamp), then classifies each user by its
+/// [`dominant_faction`] under `factions` (`faction_1`, `faction_2` or `mixed` by default).
 ///
 /// Players are grouped into fixed-width MMR buckets of size `mmr_dist`
 /// (bucket key = `(mmr / mmr_dist) * mmr_dist`).
@@ -18,43 +32,36 @@ pub fn mmr_spread<T>(
     users_rows: &std::collections::HashMap<u64, LeaderboardRow>,
     mmr_dist: u32,
     filter_battle: u32,
-    filter_time: u64
+    filter_time: u64,
+    factions: &FactionRegistry
 ) -> std::collections::HashMap<(String, u32), (u64, u64)>
 where 
     T: LeaderboardMark
 {
-    let mut spread: std::collections::HashMap<(String, u32), (u64, u64)> = std::collections::HashMap::new();
+    spread_counts(mmr_spread_samples(leaderboard, users_rows, mmr_dist, filter_battle, filter_time, factions))
+}
 
-    // Filter users by minimum battles and recent activity.
-    for (&user_id, user_row) in users_rows.iter().filter(|obj| if obj.1.battles >= filter_battle && obj.1.last_session >= filter_time {true} else {false}) {
-        let faction_1_battles = match leaderboard.get_battle_faction_hash().get(&(user_id, "faction_1".to_string())) {
-            Some(&battles) => battles,
-            None => 0
-        };
-        let faction_2_battles = match leaderboard.get_battle_faction_hash().get(&(user_id, "faction_2".to_string())) {
-            Some(&battles) => battles,
-            None => 0
-        };
+/// Buckets of [`mmr_spread`] with the MMR of every player instead of the count and sum,
+/// for `estimate::spread_estimates`.
+pub fn mmr_spread_samples<T>(
+    leaderboard: &T,
+    users_rows: &std::collections::HashMap<u64, LeaderboardRow>,
+    mmr_dist: u32,
+    filter_battle: u32,
+    filter_time: u64,
+    factions: &FactionRegistry
+) -> std::collections::HashMap<(String, u32), Vec<u32>>
+where 
+    T: LeaderboardMark
+{
+    let mut spread: std::collections::HashMap<(String, u32), Vec<u32>> = std::collections::HashMap::new();
 
-        // Determine dominant faction for the user (>=65% of battles), otherwise mark as mixed.
-        let campain_main = if ((faction_1_battles) as f64) / (user_row.battles as f64) >= 0.65 {
-            "faction_1".to_string()
-        } else if ((faction_2_battles) as f64) / (user_row.battles as f64) >= 0.65 {
-            "faction_2".to_string()
-        } else {
-            "mixed".to_string()
-        };
+    // Filter users by minimum battles and recent activity.
+    for (&user_id, user_row) in users_rows.iter().filter(|obj| obj.1.battles >= filter_battle && obj.1.last_session >= filter_time) {
+        let campain_main = dominant_faction(leaderboard, user_id, user_row.battles, factions);
         // Snap to the lower bound of the fixed-width MMR bucket.
         let mmr_group = (user_row.mmr / mmr_dist) * mmr_dist;
-        let mut spread_row = match spread.get(&(campain_main.clone(), mmr_group)) {
-            Some(dd) => *dd,
-            None => (0, 0 as u64)
-        };
-        // Accumulate player count and total MMR for this bucket.
-        spread_row.0 += 1;
-        spread_row.1 += user_row.mmr as u64;
-
-        spread.insert((campain_main, mmr_group), spread_row);
+        spread.entry((campain_main, mmr_group)).or_default().push(user_row.mmr);
     }
     spread
 }
@@ -72,42 +79,35 @@ pub fn battle_spread<T>(
     users_rows: &std::collections::HashMap<u64, LeaderboardRow>,
     battle_dist: u32,
     filter_battle: u32,
-    filter_time: u64
+    filter_time: u64,
+    factions: &FactionRegistry
 ) -> std::collections::HashMap<(String, u32), (u64, u64)>
 where 
     T: LeaderboardMark
 {
-    let mut spread: std::collections::HashMap<(String, u32), (u64, u64)> = std::collections::HashMap::new();
+    spread_counts(battle_spread_samples(leaderboard, users_rows, battle_dist, filter_battle, filter_time, factions))
+}
 
-    // Filter users by minimum battles and recent activity.
-    for (&user_id, user_row) in users_rows.iter().filter(|obj| if obj.1.battles >= filter_battle && obj.1.last_session >= filter_time {true} else {false}) {
-        let faction_1_battles = match leaderboard.get_battle_faction_hash().get(&(user_id, "faction_1".to_string())) {
-            Some(&battles) => battles,
-            None => 0
-        };
-        let faction_2_battles = match leaderboard.get_battle_faction_hash().get(&(user_id, "faction_2".to_string())) {
-            Some(&battles) => battles,
-            None => 0
-        };
+/// Buckets of [`battle_spread`] with the MMR of every player instead of the count and sum.
+pub fn battle_spread_samples<T>(
+    leaderboard: &T,
+    users_rows: &std::collections::HashMap<u64, LeaderboardRow>,
+    battle_dist: u32,
+    filter_battle: u32,
+    filter_time: u64,
+    factions: &FactionRegistry
+) -> std::collections::HashMap<(String, u32), Vec<u32>>
+where 
+    T: LeaderboardMark
+{
+    let mut spread: std::collections::HashMap<(String, u32), Vec<u32>> = std::collections::HashMap::new();
 
-        // Determine dominant faction for the user (>=65% of battles), otherwise mark as mixed.
-        let campain_main = if ((faction_1_battles) as f64) / (user_row.battles as f64) >= 0.65 {
-            "faction_1".to_string()
-        } else if ((faction_2_battles) as f64) / (user_row.battles as f64) >= 0.65 {
-            "faction_2".to_string()
-        } else {
-            "mixed".to_string()
-        };
+    // Filter users by minimum battles and recent activity.
+    for (&user_id, user_row) in users_rows.iter().filter(|obj| obj.1.battles >= filter_battle && obj.1.last_session >= filter_time) {
+        let campain_main = dominant_faction(leaderboard, user_id, user_row.battles, factions);
         // Snap to the lower bound of the fixed-width battles bucket.
         let battle_group = (user_row.battles / battle_dist) * battle_dist;
-        let mut spread_row = match spread.get(&(campain_main.clone(), battle_group)) {
-            Some(dd) => *dd,
-            None => (0, 0 as u64)
-        };
-        // Accumulate player count and total MMR for this bucket.
-        spread_row.0 += 1;
-        spread_row.1 += user_row.mmr as u64;
-        spread.insert((campain_main, battle_group), spread_row);
+        spread.entry((campain_main, battle_group)).or_default().push(user_row.mmr);
     }
     spread
 }
@@ -127,47 +127,49 @@ pub fn country_spread<T>(
     user_country: &std::collections::HashMap<u64, String>,
     mmr_dist: u32,
     filter_battle: u32,
-    filter_time: u64
+    filter_time: u64,
+    factions: &FactionRegistry
 ) -> std::collections::HashMap<(String, String, u32), (u64, u64)>
 where 
     T: LeaderboardMark
 {
-    let mut spread: std::collections::HashMap<(String, String, u32), (u64, u64)> = std::collections::HashMap::new();
+    spread_counts(country_spread_samples(leaderboard, users_rows, user_country, mmr_dist, filter_battle, filter_time, factions))
+}
 
-    // Filter users by minimum battles and recent activity.
-    for (&user_id, user_row) in users_rows.iter().filter(|obj| if obj.1.battles >= filter_battle && obj.1.last_session >= filter_time {true} else {false}) {
-        let faction_1_battles = match leaderboard.get_battle_faction_hash().get(&(user_id, "faction_1".to_string())) {
-            Some(&battles) => battles,
-            None => 0
-        };
-        let faction_2_battles = match leaderboard.get_battle_faction_hash().get(&(user_id, "faction_2".to_string())) {
-            Some(&battles) => battles,
-            None => 0
-        };
+/// Buckets of [`country_spread`] with the MMR of every player instead of the count and sum.
+pub fn country_spread_samples<T>(
+    leaderboard: &T,
+    users_rows: &std::collections::HashMap<u64, LeaderboardRow>,
+    user_country: &std::collections::HashMap<u64, String>,
+    mmr_dist: u32,
+    filter_battle: u32,
+    filter_time: u64,
+    factions: &FactionRegistry
+) -> std::collections::HashMap<(String, String, u32), Vec<u32>>
+where 
+    T: LeaderboardMark
+{
+    let mut spread: std::collections::HashMap<(String, String, u32), Vec<u32>> = std::collections::HashMap::new();
 
-        // Determine dominant faction for the user (>=65% of battles), otherwise mark as mixed.
-        let campain_main = if ((faction_1_battles) as f64) / (user_row.battles as f64) >= 0.65 {
-            "faction_1".to_string()
-        } else if ((faction_2_battles) as f64) / (user_row.battles as f64) >= 0.65 {
-            "faction_2".to_string()
-        } else {
-            "mixed".to_string()
-        };
-        // Snap to the lower bound of the fixed-width MMR bucket and resolve country.
-        let mmr_group = (user_row.mmr / mmr_dist) * mmr_dist;
-        let country = user_country.get(&user_id);
+    // Filter users by minimum battles and recent activity.
+    for (&user_id, user_row) in users_rows.iter().filter(|obj| obj.1.battles >= filter_battle && obj.1.last_session >= filter_time) {
         // Skip users with no known country.
-        if country == None {
-            continue;
-        }
-        let mut spread_row = match spread.get(&(campain_main.clone(), country.unwrap().to_string(), mmr_group)) {
-            Some(dd) => *dd,
-            None => (0, 0 as u64)
+        let country = match user_country.get(&user_id) {
+            Some(country) => country,
+            None => continue
         };
-        // Accumulate player count and total MMR for this bucket.
-        spread_row.0 += 1;
-        spread_row.1 += user_row.mmr as u64;
-        spread.insert((campain_main, country.unwrap().to_string(), mmr_group), spread_row);
+        let campain_main = dominant_faction(leaderboard, user_id, user_row.battles, factions);
+        // Snap to the lower bound of the fixed-width MMR bucket.
+        let mmr_group = (user_row.mmr / mmr_dist) * mmr_dist;
+        spread.entry((campain_main, country.to_string(), mmr_group)).or_default().push(user_row.mmr);
     }
     spread
-}
\ No newline at end of file
+}
+
+/// (player_count, total_mmr_sum) of every bucket of a `*_samples` map.
+pub fn spread_counts<K: Eq + std::hash::Hash>(samples: std::collections::HashMap<K, Vec<u32>>) -> std::collections::HashMap<K, (u64, u64)> {
+    samples.into_iter().map(|(key, mmrs)| {
+        let mmr_sum = mmrs.iter().map(|mmr| *mmr as u64).sum();
+        (key, (mmrs.len() as u64, mmr_sum))
+    }).collect()
+}
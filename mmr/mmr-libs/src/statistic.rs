@@ -1,6 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+use crate::estimate::BucketEstimate;
+use crate::params::MMRParams;
 use crate::{datasets, types::{self, MMRAgg, MMRType}};
 
-#[derive(Clone,Debug)]
+/// Layout version of [`StatisticReport`]; bumped when fields change.
+pub const STATISTIC_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
 pub struct Statistic {
     // key: (team1_top3_avg, team2_top3_avg), value: (team1_wins, total_sessions)
     pub top_3_disbalance_spread: std::collections::BTreeMap<(u32, u32), (u64, u64)>,
@@ -34,45 +41,6 @@ pub struct Statistic {
 
 
 impl Statistic {
-    pub fn to_string(&self, statboard: &String) -> String {
-        // Serialize statistic into legacy line format consumed by downstream scripts.
-        println!("{}", statboard.clone());
-        let mut str = String::new();
-        str = str + statboard.as_str() + "__top_3_disbalance_spread:{";
-        let mut spread_btree: std::collections::BTreeMap<i32, (u64, u64)> = std::collections::BTreeMap::new();
-
-        // Re-bucket top3 delta into 200-MMR ranges for compact output.
-        for item in self.top_3_disbalance_spread.iter() {
-            match spread_btree.get_mut(&(((((item.0.0 as i32) - (item.0.1 as i32)) / 200) * 200))) {
-                Some(btree) => {
-                    btree.0 += item.1.0;
-                    btree.1 += item.1.1; 
-                },
-                None => {
-                    spread_btree.insert((((item.0.0 as i32) - (item.0.1 as i32)) / 200) * 200, (item.1.0, item.1.1));
-                }
-            }
-        }
-
-        for spread in spread_btree.iter() {
-            str = str + "\"" + spread.0.to_string().as_str() + "\":[" + ((spread.1.0 as f64) / (spread.1.1 as f64)).to_string().as_str() + "," + spread.1.0.to_string().as_str() + "," + spread.1.1.to_string().as_str() + "],"
-        }
-        str = str + "};\n";
-        str = str + statboard.as_str() + "__battles:" + self.battles.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__disbalance_team_1:" + self.disbalance_team_1.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__disbalance_team_1_victory:" + self.disbalance_team_1_victory.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__disbalance_team_2:" + self.disbalance_team_2.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__disbalance_team_2_victory:" + self.disbalance_team_2_victory.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__new_user_disbalance_battles:" + self.new_user_disbalance_battles.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__new_user_disbalance_allie_battles:" + self.new_user_disbalance_allie_battles.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__new_user_disbalance_battles_victory:" + self.new_user_disbalance_battles_victory.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__new_user_battles:" + self.new_user_battles.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__new_users_session:" + self.new_users_session.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__newbie_count_disbalance_team_1:" + self.newbie_count_disbalance_team_1.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__newbie_count_disbalance_team_2:" + self.newbie_count_disbalance_team_2.to_string().as_str() + ";\n";
-        str = str + statboard.as_str() + "__new_user_disbalance_session:" + self.new_user_disbalance_session.to_string().as_str() + ";\n";
-        str
-    }
     pub fn add_statistic(&mut self, other: &Statistic){
         // Merge counters from another partial statistic payload.
         self.battles += other.battles;
@@ -101,6 +69,246 @@ impl Statistic {
     }
 }
 
+#[derive(Debug)]
+pub enum StatisticError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+    // Reports of different runs, with the name of the first differing field.
+    RunMismatch(&'static str)
+}
+
+impl std::fmt::Display for StatisticError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatisticError::Io(error) => write!(f, "io error: {}", error),
+            StatisticError::Json(error) => write!(f, "invalid statistics report: {}", error),
+            StatisticError::UnsupportedVersion(version) => write!(f, "unsupported statistics schema version {} (supported {})", version, STATISTIC_SCHEMA_VERSION),
+            StatisticError::RunMismatch(field) => write!(f, "reports of different runs: {} differs", field)
+        }
+    }
+}
+
+impl std::error::Error for StatisticError {}
+
+impl From<std::io::Error> for StatisticError {
+    fn from(error: std::io::Error) -> Self {
+        StatisticError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for StatisticError {
+    fn from(error: serde_json::Error) -> Self {
+        StatisticError::Json(error)
+    }
+}
+
+/// One entry of [`Statistic::top_3_disbalance_spread`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpreadCell {
+    pub team_1_top_3: u32,
+    pub team_2_top_3: u32,
+    pub team_1_wins: u64,
+    pub sessions: u64
+}
+
+/// [`Statistic`] of one board with the spread as a list, as stored in a [`StatisticReport`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BoardStatistic {
+    pub board: String,
+    pub battles: u64,
+    pub disbalance_team_1: u64,
+    pub disbalance_team_1_victory: u64,
+    pub disbalance_team_2: u64,
+    pub disbalance_team_2_victory: u64,
+    pub new_user_disbalance_battles: u64,
+    pub new_user_disbalance_allie_battles: u64,
+    pub new_user_disbalance_battles_victory: u64,
+    pub new_user_battles: u64,
+    pub new_users_session: u64,
+    pub newbie_count_disbalance_team_1: u64,
+    pub newbie_count_disbalance_team_2: u64,
+    pub new_user_disbalance_session: u64,
+    // Ordered by (team_1_top_3, team_2_top_3).
+    pub top_3_disbalance_spread: Vec<SpreadCell>
+}
+
+// Counter columns of the board CSV, in `BoardStatistic` field order.
+const BOARD_COLUMNS: [&str; 13] = [
+    "battles", "disbalance_team_1", "disbalance_team_1_victory", "disbalance_team_2", "disbalance_team_2_victory",
+    "new_user_disbalance_battles", "new_user_disbalance_allie_battles", "new_user_disbalance_battles_victory",
+    "new_user_battles", "new_users_session", "newbie_count_disbalance_team_1", "newbie_count_disbalance_team_2",
+    "new_user_disbalance_session"
+];
+
+impl BoardStatistic {
+    pub fn new(board: &str, statistic: &Statistic) -> Self {
+        Self {
+            board: board.to_string(),
+            battles: statistic.battles,
+            disbalance_team_1: statistic.disbalance_team_1,
+            disbalance_team_1_victory: statistic.disbalance_team_1_victory,
+            disbalance_team_2: statistic.disbalance_team_2,
+            disbalance_team_2_victory: statistic.disbalance_team_2_victory,
+            new_user_disbalance_battles: statistic.new_user_disbalance_battles,
+            new_user_disbalance_allie_battles: statistic.new_user_disbalance_allie_battles,
+            new_user_disbalance_battles_victory: statistic.new_user_disbalance_battles_victory,
+            new_user_battles: statistic.new_user_battles,
+            new_users_session: statistic.new_users_session,
+            newbie_count_disbalance_team_1: statistic.newbie_count_disbalance_team_1,
+            newbie_count_disbalance_team_2: statistic.newbie_count_disbalance_team_2,
+            new_user_disbalance_session: statistic.new_user_disbalance_session,
+            top_3_disbalance_spread: statistic.top_3_disbalance_spread.iter()
+                .map(|((team_1_top_3, team_2_top_3), (team_1_wins, sessions))| SpreadCell { team_1_top_3: *team_1_top_3, team_2_top_3: *team_2_top_3, team_1_wins: *team_1_wins, sessions: *sessions })
+                .collect()
+        }
+    }
+
+    /// The board as the `Statistic` accumulator it was built from.
+    pub fn statistic(&self) -> Statistic {
+        Statistic {
+            top_3_disbalance_spread: self.top_3_disbalance_spread.iter().map(|cell| ((cell.team_1_top_3, cell.team_2_top_3), (cell.team_1_wins, cell.sessions))).collect(),
+            battles: self.battles,
+            disbalance_team_1: self.disbalance_team_1,
+            disbalance_team_1_victory: self.disbalance_team_1_victory,
+            disbalance_team_2: self.disbalance_team_2,
+            disbalance_team_2_victory: self.disbalance_team_2_victory,
+            new_user_disbalance_battles: self.new_user_disbalance_battles,
+            new_user_disbalance_allie_battles: self.new_user_disbalance_allie_battles,
+            new_user_disbalance_battles_victory: self.new_user_disbalance_battles_victory,
+            new_user_battles: self.new_user_battles,
+            new_users_session: self.new_users_session,
+            newbie_count_disbalance_team_1: self.newbie_count_disbalance_team_1,
+            newbie_count_disbalance_team_2: self.newbie_count_disbalance_team_2,
+            new_user_disbalance_session: self.new_user_disbalance_session
+        }
+    }
+
+    fn counters(&self) -> [u64; 13] {
+        [
+            self.battles, self.disbalance_team_1, self.disbalance_team_1_victory, self.disbalance_team_2, self.disbalance_team_2_victory,
+            self.new_user_disbalance_battles, self.new_user_disbalance_allie_battles, self.new_user_disbalance_battles_victory,
+            self.new_user_battles, self.new_users_session, self.newbie_count_disbalance_team_1, self.newbie_count_disbalance_team_2,
+            self.new_user_disbalance_session
+        ]
+    }
+}
+
+/// Self-describing statistics of a run: the schema version, the algorithm and library version
+/// that produced it, the run parameters and one [`BoardStatistic`] per board key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StatisticReport {
+    pub schema_version: u32,
+    // `RatingSystem::NAME` of the rating algorithm.
+    pub algorithm: String,
+    // `mmr_libs` version that computed the statistics.
+    pub algorithm_version: String,
+    pub params: MMRParams,
+    // Userstat files folded into the report, several after a merge.
+    pub sources: Vec<String>,
+    // Ordered by board key.
+    pub boards: Vec<BoardStatistic>
+}
+
+impl StatisticReport {
+    pub fn new(algorithm: &str, params: &MMRParams, sources: Vec<String>, statistics: &std::collections::BTreeMap<String, Statistic>) -> Self {
+        Self {
+            schema_version: STATISTIC_SCHEMA_VERSION,
+            algorithm: algorithm.to_string(),
+            algorithm_version: env!("CARGO_PKG_VERSION").to_string(),
+            params: params.clone(),
+            sources,
+            boards: statistics.iter().map(|(board, statistic)| BoardStatistic::new(board, statistic)).collect()
+        }
+    }
+
+    /// Reads a JSON report written by [`StatisticReport::write`].
+    pub fn read(path: &str) -> Result<Self, StatisticError> {
+        let report: Self = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        if report.schema_version != STATISTIC_SCHEMA_VERSION {
+            return Err(StatisticError::UnsupportedVersion(report.schema_version));
+        }
+        Ok(report)
+    }
+
+    /// The boards as `Statistic` accumulators by board key.
+    pub fn statistics(&self) -> std::collections::BTreeMap<String, Statistic> {
+        self.boards.iter().map(|board| (board.board.clone(), board.statistic())).collect()
+    }
+
+    /// Adds the boards of `other` with [`Statistic::add_statistic`] and appends its sources.
+    ///
+    /// Both reports must come from the same algorithm, library version and parameters.
+    pub fn merge(&mut self, other: &StatisticReport) -> Result<(), StatisticError> {
+        if self.algorithm != other.algorithm {
+            return Err(StatisticError::RunMismatch("algorithm"));
+        }
+        if self.algorithm_version != other.algorithm_version {
+            return Err(StatisticError::RunMismatch("algorithm_version"));
+        }
+        if self.params != other.params {
+            return Err(StatisticError::RunMismatch("params"));
+        }
+        let mut statistics = self.statistics();
+        for (board, statistic) in other.statistics() {
+            match statistics.get_mut(&board) {
+                Some(base) => base.add_statistic(&statistic),
+                None => {statistics.insert(board, statistic);}
+            };
+        }
+        self.boards = statistics.iter().map(|(board, statistic)| BoardStatistic::new(board, statistic)).collect();
+        self.sources.extend(other.sources.iter().cloned());
+        Ok(())
+    }
+
+    /// Board counters as `;`-separated CSV, one row per board; every row repeats the schema
+    /// version, algorithm and algorithm version.
+    pub fn boards_csv(&self) -> String {
+        let mut str = "schema_version;algorithm;algorithm_version;board;".to_string() + BOARD_COLUMNS.join(";").as_str() + "\n";
+        for board in self.boards.iter() {
+            str += (self.csv_prefix() + board.board.as_str()).as_str();
+            for counter in board.counters() {
+                str += (";".to_string() + counter.to_string().as_str()).as_str();
+            }
+            str += "\n";
+        }
+        str
+    }
+
+    /// `top_3_disbalance_spread` of every board as `;`-separated CSV, one row per cell, with the
+    /// team 1 win rate, its standard error and bootstrap interval (`BucketEstimate::of_proportion`
+    /// with the `estimate` section of the report's params). The estimates are computed on output,
+    /// so a merged report gets them from the merged counts.
+    pub fn spread_csv(&self) -> String {
+        let mut str = "schema_version;algorithm;algorithm_version;board;team_1_top_3;team_2_top_3;team_1_wins;sessions;team_1_win_rate;std_error;ci_low;ci_high;suppressed\n".to_string();
+        for board in self.boards.iter() {
+            for cell in board.top_3_disbalance_spread.iter() {
+                let estimate = BucketEstimate::of_proportion(cell.team_1_wins, cell.sessions, &self.params.estimate);
+                str += (self.csv_prefix() + board.board.as_str()
+                    + ";" + cell.team_1_top_3.to_string().as_str()
+                    + ";" + cell.team_2_top_3.to_string().as_str()
+                    + ";" + cell.team_1_wins.to_string().as_str()
+                    + ";" + cell.sessions.to_string().as_str()
+                    + ";" + estimate.csv_columns(4).as_str()
+                    + "\n").as_str();
+            }
+        }
+        str
+    }
+
+    fn csv_prefix(&self) -> String {
+        self.schema_version.to_string() + ";" + self.algorithm.as_str() + ";" + self.algorithm_version.as_str() + ";"
+    }
+
+    /// Writes `<prefix>.json` (the full report), `<prefix>.csv` ([`StatisticReport::boards_csv`])
+    /// and `<prefix>_spread.csv` ([`StatisticReport::spread_csv`]).
+    pub fn write(&self, prefix: &str) {
+        std::fs::write(prefix.to_string() + ".json", serde_json::to_string_pretty(self).unwrap() + "\n").unwrap();
+        std::fs::write(prefix.to_string() + ".csv", self.boards_csv()).unwrap();
+        std::fs::write(prefix.to_string() + "_spread.csv", self.spread_csv()).unwrap();
+    }
+}
+
 pub async fn proc_statistic(
     statboard: String,
     team_1_mmr: &types::TeamMMR,
@@ -410,4 +618,40 @@ pub async fn proc_statistic(
 
     //println!("here! {:?}", write_session_time);
     (statboard, statistic)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statistic(battles: u64, cell: (u32, u32), team_1_wins: u64) -> Statistic {
+        Statistic {
+            top_3_disbalance_spread: [(cell, (team_1_wins, battles))].into_iter().collect(),
+            battles,
+            disbalance_team_1: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reports_merge_with_add_statistic() {
+        let params = MMRParams::default();
+        let slice_1: std::collections::BTreeMap<String, Statistic> = [("common".to_string(), statistic(3, (1500, 900), 2))].into_iter().collect();
+        let slice_2: std::collections::BTreeMap<String, Statistic> = [("common".to_string(), statistic(2, (1500, 900), 1)), ("ranked".to_string(), statistic(2, (1200, 1100), 0))].into_iter().collect();
+        let mut report = StatisticReport::new("v1", &params, vec!["slice_1".to_string()], &slice_1);
+        let other: StatisticReport = serde_json::from_str(&serde_json::to_string(&StatisticReport::new("v1", &params, vec!["slice_2".to_string()], &slice_2)).unwrap()).unwrap();
+
+        report.merge(&other).unwrap();
+        let mut expected = slice_1["common"].clone();
+        expected.add_statistic(&slice_2["common"]);
+        assert_eq!(report.statistics()["common"], expected);
+        assert_eq!(report.boards[0].top_3_disbalance_spread, vec![SpreadCell { team_1_top_3: 1500, team_2_top_3: 900, team_1_wins: 3, sessions: 5 }]);
+        assert_eq!(report.sources, vec!["slice_1".to_string(), "slice_2".to_string()]);
+        assert_eq!(report.boards_csv().lines().nth(2).unwrap(), "1;v1;".to_string() + env!("CARGO_PKG_VERSION") + ";ranked;2;1;0;0;0;0;0;0;0;0;0;0;0");
+        // 5 sessions are below the default `min_sample`: counts only.
+        assert_eq!(report.spread_csv().lines().nth(1).unwrap(), "1;v1;".to_string() + env!("CARGO_PKG_VERSION") + ";common;1500;900;3;5;;;;;true");
+
+        let glicko = StatisticReport::new("glicko", &params, Vec::new(), &slice_2);
+        assert!(matches!(report.merge(&glicko), Err(StatisticError::RunMismatch("algorithm"))));
+    }
+}
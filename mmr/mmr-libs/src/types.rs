@@ -1,3 +1,6 @@
+use crate::decay::DecayState;
+use crate::params::{CalibrationParams, DiffMMRParams, PoolParams};
+
 #[derive(Clone, Debug)]
 pub struct MMRPair(pub u64, pub MMRType, pub UserBattleRow);
 
@@ -6,20 +9,47 @@ pub struct MMRAgg(pub i64, pub i64);
 
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct TeamMMR(pub Vec<MMRPair>);
 
 #[derive(Clone, Debug)]
 pub struct MMRChangeDebug(pub i32, pub i32, pub i32, pub i32, pub f64, pub f64);
 
+// (rating_before, deviation_before, volatility_before, rating_after, deviation_after, volatility_after)
+#[derive(Clone, Debug)]
+pub struct MMRChangeDebugGlicko(pub f64, pub f64, pub f64, pub f64, pub f64, pub f64);
+
 #[derive(Clone, Debug)]
 pub struct MMRChangeDebugV2(pub f64, pub f64, pub f64, pub f64, pub f64, pub f64, pub f64, pub f64, pub f64, pub f64, pub f64);
 
 
 
+/// Prepared v2 update of one player: the pool delta of a session with its change payload.
+#[derive(Clone, Debug)]
+pub struct PoolChange {
+    pub user_id: u64,
+    // Session row of the player.
+    pub user: UserBattleRow,
+    // Pool delta `inc_mmr - dec_mmr`, applied to the pool MMR of `change`.
+    pub diff_mmr: i32,
+    pub change: LeaderboardChangeV2,
+    pub debug: MMRChangeDebugV2
+}
+
 #[derive(Clone, Debug)]
 pub struct TeamMMRV2(pub Vec<(u64, MMRType, UserBattleRow, f64)>);
 
+// (change, applied mmr delta, user_row_before, debug, cl_id) payloads streamed to the change writers.
+pub type ChangeRecordV1 = (LeaderboardChangeV1, i32, LeaderboardRow, MMRChangeDebug, u16);
+pub type ChangeRecordV2 = (LeaderboardChangeV2, i32, LeaderboardRow, MMRChangeDebugV2, u16);
+pub type ChangeRecordGlicko = (LeaderboardChangeV1, i32, LeaderboardRow, MMRChangeDebugGlicko, u16);
+
+// Prepared per-player updates of one session (`RatingSystem::Prepared`).
+// Glicko-2: (session row, change, before, after).
+pub type GlickoUpdate = (UserBattleRow, LeaderboardChangeV1, GlickoRating, GlickoRating);
+// TrueSkill: (session row, change, before, after, weight, credit).
+pub type TrueSkillUpdate = (UserBattleRow, LeaderboardChangeV1, TrueSkillRating, TrueSkillRating, f64, f64);
+
 
 #[derive(Clone, Debug)]
 pub enum MMRType{
@@ -44,13 +74,19 @@ pub struct Leaderboard{
     pub users: std::collections::HashMap<u64, LeaderboardRow>,
     pub sets: Vec<LeaderboardChangeV1>,
     pub battle_score_hash: std::collections::BTreeMap<(u32, u64), u32>,
-    pub battle_faction_hash: std::collections::HashMap<(u64, String), u64>
+    pub battle_faction_hash: std::collections::HashMap<(u64, String), u64>,
+    pub params: DiffMMRParams,
+    pub decay: DecayState,
+    pub calibration: CalibrationParams,
+    // Players skipped by the calibration estimates of new players (flagged by `abuse`).
+    pub bootstrap_excluded: std::collections::HashSet<u64>
 }
 
 
 #[derive(Clone, Debug)]
 pub struct LeaderboardChangeV1 {
     pub user_id: u64,
+    pub session_id: u64,
     pub mmr: MMRType,
     pub top_3: Vec<MMRType>,
     pub victory: bool,
@@ -93,13 +129,19 @@ pub struct LeaderboardV2{
     pub users: std::collections::HashMap<u64, LeaderboardRow>,
     pub sets: Vec<LeaderboardChangeV2>,
     pub battle_score_hash: std::collections::BTreeMap<(u32, u64), u32>,
-    pub battle_faction_hash: std::collections::HashMap<(u64, String), u64>
+    pub battle_faction_hash: std::collections::HashMap<(u64, String), u64>,
+    pub params: PoolParams,
+    pub decay: DecayState,
+    pub calibration: CalibrationParams,
+    // Players skipped by the calibration estimates of new players (flagged by `abuse`).
+    pub bootstrap_excluded: std::collections::HashSet<u64>
 }
 
 
 #[derive(Clone, Debug)]
 pub struct LeaderboardChangeV2 {
     pub user_id: u64,
+    pub session_id: u64,
     pub mmr: MMRType,
     pub top_3: Vec<(MMRType, f64)>,
     pub victory: bool,
@@ -111,6 +153,33 @@ pub struct LeaderboardChangeV2 {
     pub last_session: u64
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GlickoRating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64
+}
+
+#[derive(Clone, Debug)]
+pub struct LeaderboardGlicko{
+    pub users: std::collections::HashMap<u64, LeaderboardRow>,
+    pub glicko: std::collections::HashMap<u64, GlickoRating>,
+    pub battle_faction_hash: std::collections::HashMap<(u64, String), u64>
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TrueSkillRating {
+    pub mu: f64,
+    pub sigma: f64
+}
+
+#[derive(Clone, Debug)]
+pub struct LeaderboardTrueSkill{
+    pub users: std::collections::HashMap<u64, LeaderboardRow>,
+    pub skill: std::collections::HashMap<u64, TrueSkillRating>,
+    pub battle_faction_hash: std::collections::HashMap<(u64, String), u64>
+}
+
 pub trait LeaderboardMark {
     fn get_battle_faction_hash(&self) -> &std::collections::HashMap<(u64, String), u64>;
 }
@@ -124,4 +193,14 @@ impl LeaderboardMark for LeaderboardV2 {
     fn get_battle_faction_hash(&self) -> &std::collections::HashMap<(u64, String), u64> {
         &self.battle_faction_hash
     }
+}
+impl LeaderboardMark for LeaderboardGlicko {
+    fn get_battle_faction_hash(&self) -> &std::collections::HashMap<(u64, String), u64> {
+        &self.battle_faction_hash
+    }
+}
+impl LeaderboardMark for LeaderboardTrueSkill {
+    fn get_battle_faction_hash(&self) -> &std::collections::HashMap<(u64, String), u64> {
+        &self.battle_faction_hash
+    }
 }
\ No newline at end of file
@@ -1,132 +1,131 @@
 
 use crate::datasets::UserFaction;
+use crate::jsonl::{JsonlReader, JsonlReport, SessionRowRecord, UserStatRecord};
+
+/// Streams the userstat dataset as typed `UserBattleRow`s in file order.
+///
+/// Rows that are not valid JSON, miss a required field or have no faction are counted in
+/// the [`JsonlReport`] with their line number.
+pub struct UserStatReader<'a> {
+    rows: JsonlReader<UserStatRecord>,
+    user_team: &'a std::collections::HashMap<(u64,u64), (u8, bool)>,
+    user_faction: &'a UserFaction
+}
+
+impl<'a> UserStatReader<'a> {
+    pub fn open(
+        path: &str,
+        user_team: &'a std::collections::HashMap<(u64,u64), (u8, bool)>,
+        user_faction: &'a UserFaction
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            rows: JsonlReader::open(path)?,
+            user_team,
+            user_faction
+        })
+    }
+
+    /// Opens `path` at a position returned by [`UserStatReader::row_start`].
+    pub fn open_at(
+        path: &str,
+        (offset, line_number): (u64, u64),
+        user_team: &'a std::collections::HashMap<(u64,u64), (u8, bool)>,
+        user_faction: &'a UserFaction
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            rows: JsonlReader::open_at(path, offset, line_number)?,
+            user_team,
+            user_faction
+        })
+    }
+
+    pub fn report(&self) -> &JsonlReport {
+        self.rows.report()
+    }
+
+    /// (byte offset, line number) just before the last yielded row.
+    pub fn row_start(&self) -> (u64, u64) {
+        self.rows.row_start()
+    }
+}
+
+impl Iterator for UserStatReader<'_> {
+    type Item = UserBattleRow;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (line, record) = self.rows.next()?;
+            match UserBattleRow::from_record(record, self.user_team, self.user_faction) {
+                Some(row) => return Some(row),
+                None => self.rows.reject(line, "missing faction".to_string())
+            }
+        }
+    }
+}
 use crate::types::UserBattleRow;
 
 
 impl UserBattleRow {
-    pub fn parsing_str(
-        line: String, 
+    /// Builds a row from a userstat record.
+    ///
+    /// Team and victory come from the preloaded `user_team` map (`(0, false)` when missing;
+    /// `SessionTeams::new` numbers the teams of its factions again), the faction from
+    /// `user_faction` with the record's own `faction` as fallback. Returns `None` when neither
+    /// provides a faction.
+    pub fn from_record(
+        record: UserStatRecord, 
         user_team: &std::collections::HashMap<(u64,u64), (u8, bool)>, 
         user_faction: &UserFaction,
     ) -> Option<Self> {
-        // Parse one JSON-like line into key/value fields.
-        let hash_line: std::collections::BTreeMap<String, String> = line.replace("{", "").replace("}", "").split(",").filter_map(|item: &str| {
-            let splited: Vec<String> = item.split(":").map(|it| it.to_string()).collect();
-            if splited.len() == 2 {
-                Some((splited[0].clone(), splited[1].clone()))
-            } else {
-                None
-            }
-        }).collect();
-    
-        let user_id = hash_line.get("user_id");
-        let session_id = hash_line.get("session_id");
-        let commit_time = hash_line.get("commit_time");
-    
-        // Required fields for valid row construction.
-        if user_id == None || session_id == None || commit_time == None {
-            return None;
-        }
-        let user_id = user_id.unwrap().parse::<u64>().unwrap();
-        // session_id is expected in hexadecimal format in this parser path.
-        let session_id =  u64::from_str_radix(session_id.unwrap().clone().as_str(), 16).unwrap();
-        // Team/victory are sourced from preloaded user_team map.
-        let (_team, victory) = match user_team.get(&(user_id, session_id)) {
+        let (team, victory) = match user_team.get(&(record.user_id, record.session_id)) {
             Some((t, v)) => (*t, *v),
             None => (0, false)
         };
         // Prefer external faction dataset, fallback to row field when missing.
-        let faction = match user_faction.0.get(&(user_id, session_id)) {
+        let faction = match user_faction.0.get(&(record.user_id, record.session_id)) {
             Some(mode) => mode.clone(),
-            None => hash_line.get("faction").unwrap().to_string()
+            None => record.faction?
         };
-        let row = UserBattleRow { 
-            user_id: user_id, 
-            session_id: session_id, 
-            commit_time: commit_time.unwrap().parse::<u64>().unwrap(), 
-            battle_score: match hash_line.get("battle_score") {
-                Some(data) => data.parse::<u32>().unwrap(),
-                None => 0
-            }, 
+        Some(UserBattleRow { 
+            user_id: record.user_id, 
+            session_id: record.session_id, 
+            commit_time: record.commit_time, 
+            battle_score: record.battle_score, 
             victories: victory, 
-            early_quit: match hash_line.get("early_quit") {
-                Some(data) => if data.parse::<u16>().unwrap() == 1 {true} else {false},
-                None => false
-            }, 
-            team_score_top_20_percent: match hash_line.get("team_score_top_20_percent") {
-                Some(data) => if data.parse::<u16>().unwrap() == 1 {true} else {false},
-                None => false
-            },
-            team: if faction == "faction_2".to_string() {2} else {1},
-            faction: faction
-        };
-        Some(row)
-        //None
+            early_quit: record.early_quit, 
+            team_score_top_20_percent: record.team_score_top_20_percent,
+            team,
+            faction
+        })
     }
-    pub fn parsing_row(line: String) -> Option<Self> {
-        // Parse already-normalized stored row (session_id is decimal here).
-        let hash_line: std::collections::BTreeMap<String, String> = line.replace("{", "").replace("}", "").split(",").filter_map(|item: &str| {
-            let splited: Vec<String> = item.split(":").map(|it| it.to_string()).collect();
-            if splited.len() == 2 {
-                Some((splited[0].clone(), splited[1].clone()))
-            } else {
-                None
-            }
-        }).collect();
-    
-        let user_id = hash_line.get("user_id");
-        let session_id = hash_line.get("session_id");
-        let commit_time = hash_line.get("commit_time");
-    
-        // Required fields for valid row construction.
-        if user_id == None || session_id == None || commit_time == None {
-            return None;
+
+    /// Builds a row from a `data/memory` record.
+    pub fn from_memory(record: SessionRowRecord) -> Self {
+        UserBattleRow {
+            user_id: record.user_id,
+            session_id: record.session_id,
+            commit_time: record.commit_time,
+            team: record.team,
+            battle_score: record.battle_score,
+            victories: record.victories,
+            early_quit: record.early_quit,
+            team_score_top_20_percent: record.team_score_top_20_percent,
+            faction: record.faction
         }
-        let row = UserBattleRow { 
-            user_id: user_id.unwrap().parse::<u64>().unwrap(), 
-            session_id: session_id.unwrap().parse::<u64>().unwrap(), 
-            commit_time: commit_time.unwrap().parse::<u64>().unwrap(), 
-            team: match hash_line.get("team") {
-                Some(data) => data.parse::<u8>().unwrap(),
-                None => 0
-            },
-            battle_score: match hash_line.get("battle_score") {
-                Some(data) => data.parse::<u32>().unwrap(),
-                None => 0
-            }, 
-            victories: match hash_line.get("victories") {
-                Some(data) => if data.parse::<u16>().unwrap() == 1 {true} else {false},
-                None => false
-            }, 
-            early_quit: match hash_line.get("early_quit") {
-                Some(data) => if data.parse::<u16>().unwrap() == 1 {true} else {false},
-                None => false
-            }, 
-            team_score_top_20_percent: match hash_line.get("team_score_top_20_percent") {
-                Some(data) => if data.parse::<u16>().unwrap() == 1 {true} else {false},
-                None => false
-            } , 
-            faction: match hash_line.get("faction") {
-                Some(data) => data.clone(),
-                None => panic!()
-            } 
-        };
-        Some(row)
-        //None
     }
-    pub fn to_string(&self) -> String {
-        // Serialize row back to legacy comma-separated key:value format.
-        let mut str: String = String::new();
-        str = str + "user_id:" + self.user_id.to_string().as_str();
-        str = str + ",session_id:" + self.session_id.to_string().as_str();
-        str = str + ",commit_time:" + self.commit_time.to_string().as_str();
-        str = str + ",battle_score:" + self.battle_score.to_string().as_str();
-        str = str + ",team:" + self.team.to_string().as_str();
-        str = str + ",faction:" + self.faction.to_string().as_str();
-        // Optional flags are included only when true.
-        str = str  + if self.victories {",victories:1"} else {""};
-        str = str  + if self.early_quit {",early_quit:1"} else {""};
-        str = str  + if self.team_score_top_20_percent {",team_score_top_20_percent:1"} else {""};
-        str
+
+    /// The `data/memory` record of this row.
+    pub fn to_record(&self) -> SessionRowRecord {
+        SessionRowRecord {
+            user_id: self.user_id,
+            session_id: self.session_id,
+            commit_time: self.commit_time,
+            team: self.team,
+            battle_score: self.battle_score,
+            victories: self.victories,
+            early_quit: self.early_quit,
+            team_score_top_20_percent: self.team_score_top_20_percent,
+            faction: self.faction.clone()
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,348 @@
+use serde::{Deserialize, Serialize};
+
+use crate::classification::{Aggregate, SessionRules};
+use crate::datasets::{Registrations, SessionMode};
+use crate::memory::SessionMemory;
+use crate::rating::{self, RatingSystem, SessionTeams};
+use crate::types::TeamMMR;
+
+/// Names of the [`WinModel`] features, in coefficient order. Each is the team 1 value minus
+/// the team 2 value of a [`TeamFeatures`] field.
+pub const WIN_MODEL_FEATURES: [&str; 5] = ["top_3_avg", "mmr_mean", "mmr_variance", "calibrated_share", "newbies"];
+
+// Newton iterations of `WinModel::fit` and the step size that ends them early.
+const FIT_ITERATIONS: usize = 50;
+const FIT_TOLERANCE: f64 = 1e-9;
+
+/// Team aggregates the win model compares, computed with `classification::Aggregate`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TeamFeatures {
+    // `None` when the team has no calibrated player.
+    pub top_3_avg: Option<f64>,
+    pub mmr_mean: Option<f64>,
+    pub mmr_variance: Option<f64>,
+    // `None` for an empty team.
+    pub calibrated_share: Option<f64>,
+    // Players within `classification::NEWBIE_WINDOW` of their registration.
+    pub newbies: f64
+}
+
+impl TeamFeatures {
+    pub fn new(team_mmr: &TeamMMR, registrations: &Registrations) -> Self {
+        let players = team_mmr.0.len() as f64;
+        Self {
+            top_3_avg: Aggregate::TopAvg(3).value(team_mmr, registrations),
+            mmr_mean: Aggregate::TopAvg(team_mmr.0.len()).value(team_mmr, registrations),
+            mmr_variance: Aggregate::MmrVariance.value(team_mmr, registrations),
+            calibrated_share: Aggregate::CalibratedShare.value(team_mmr, registrations),
+            newbies: Aggregate::NewbieShare.value(team_mmr, registrations).unwrap_or(0.0) * players
+        }
+    }
+
+    /// Team 1 minus team 2 per feature, in [`WIN_MODEL_FEATURES`] order; a feature undefined
+    /// for either team is `0`.
+    pub fn gap(&self, other: &Self) -> [f64; 5] {
+        let gap = |a: Option<f64>, b: Option<f64>| match (a, b) {
+            (Some(a), Some(b)) => a - b,
+            _ => 0.0
+        };
+        [
+            gap(self.top_3_avg, other.top_3_avg),
+            gap(self.mmr_mean, other.mmr_mean),
+            gap(self.mmr_variance, other.mmr_variance),
+            gap(self.calibrated_share, other.calibrated_share),
+            self.newbies - other.newbies
+        ]
+    }
+}
+
+/// Decided session of a replay: feature gaps, the rating's own prediction and the outcome.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WinSample {
+    pub features: [f64; 5],
+    // `RatingSystem::win_probability` before the session.
+    pub rating_probability: f64,
+    pub team_1_won: bool
+}
+
+impl WinSample {
+    /// Sample of a session; `None` for sessions skipped by `rules` or without a single winner,
+    /// the sessions [`crate::evaluation::evaluate_session`] does not score either.
+    pub fn new<T: RatingSystem>(rating: &T, session_memory: &SessionMemory, session_mode: &SessionMode, registrations: &Registrations, rules: &SessionRules) -> Option<Self> {
+        let teams = SessionTeams::new(rating, session_memory, &rules.factions);
+        if !teams.is_rated(rules, session_mode, registrations) {
+            return None;
+        }
+        let team_1_won = teams.team_1_won()?;
+        Some(Self {
+            features: TeamFeatures::new(&teams.team_1_mmr, registrations).gap(&TeamFeatures::new(&teams.team_2_mmr, registrations)),
+            rating_probability: rating.win_probability(&teams),
+            team_1_won
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum WinModelError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    // Feature names of the file differ from `WIN_MODEL_FEATURES`.
+    Features(Vec<String>),
+    // Coefficients in the file instead of one per feature.
+    Coefficients(usize)
+}
+
+impl std::fmt::Display for WinModelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WinModelError::Io(error) => write!(f, "io error: {}", error),
+            WinModelError::Parse(error) => write!(f, "invalid win model: {}", error),
+            WinModelError::Features(features) => write!(f, "features [{}] differ from [{}]", features.join(", "), WIN_MODEL_FEATURES.join(", ")),
+            WinModelError::Coefficients(count) => write!(f, "{} coefficients for {} features", count, WIN_MODEL_FEATURES.len())
+        }
+    }
+}
+
+impl std::error::Error for WinModelError {}
+
+impl From<std::io::Error> for WinModelError {
+    fn from(error: std::io::Error) -> Self {
+        WinModelError::Io(error)
+    }
+}
+
+/// Logistic model of a team 1 victory, stored as RON:
+/// `P(team 1 wins) = 1 / (1 + e^-(intercept + Σ coefficient · gap))`
+/// over the [`TeamFeatures::gap`] of the two teams. The intercept is the team 1 side advantage.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WinModel {
+    // Feature names, `WIN_MODEL_FEATURES` when written by this version.
+    pub features: Vec<String>,
+    pub intercept: f64,
+    // Log-odds per unit of each feature gap.
+    pub coefficients: Vec<f64>,
+    // Decided sessions the model was fitted on.
+    pub sessions: u64
+}
+
+impl WinModel {
+    /// Fits the model by Newton's method on the log-likelihood with an L2 penalty of `ridge`
+    /// on the coefficients of the standardized features (the intercept is not penalized).
+    ///
+    /// 1. Every feature is divided by its standard deviation over `samples` (constant features
+    ///    keep a zero coefficient).
+    /// 2. Newton steps run until the largest parameter change is below `FIT_TOLERANCE`.
+    /// 3. The coefficients are scaled back to feature units.
+    pub fn fit(samples: &[WinSample], ridge: f64) -> Self {
+        Self::fit_features(samples, ridge, &WIN_MODEL_FEATURES)
+    }
+
+    /// [`WinModel::fit`] on the named features only; the others keep a zero coefficient.
+    ///
+    /// The features are correlated (a strong top 3 usually means a strong team), so the full
+    /// model splits their effect; a model of `top_3_avg` alone gives the gap that
+    /// [`WinModel::top_3_gap`] turns into a disbalance threshold.
+    pub fn fit_features(samples: &[WinSample], ridge: f64, features: &[&str]) -> Self {
+        let n = samples.len() as f64;
+        let scales: Vec<f64> = (0..WIN_MODEL_FEATURES.len()).map(|feature| {
+            if samples.is_empty() || !features.contains(&WIN_MODEL_FEATURES[feature]) {
+                return 0.0;
+            }
+            let mean = samples.iter().map(|sample| sample.features[feature]).sum::<f64>() / n;
+            (samples.iter().map(|sample| (sample.features[feature] - mean).powi(2)).sum::<f64>() / n).sqrt()
+        }).collect();
+        // Design row: 1 for the intercept, then the standardized features.
+        let row = |sample: &WinSample| -> Vec<f64> {
+            std::iter::once(1.0).chain(sample.features.iter().zip(scales.iter()).map(|(value, scale)| if *scale > 0.0 {value / scale} else {0.0})).collect()
+        };
+        let size = WIN_MODEL_FEATURES.len() + 1;
+        let mut beta = vec![0.0; size];
+        for _ in 0..FIT_ITERATIONS {
+            let mut gradient = vec![0.0; size];
+            let mut hessian = vec![vec![0.0; size]; size];
+            for sample in samples.iter() {
+                let x = row(sample);
+                let p = sigmoid(x.iter().zip(beta.iter()).map(|(x, b)| x * b).sum());
+                let y = if sample.team_1_won {1.0} else {0.0};
+                for i in 0..size {
+                    gradient[i] += (y - p) * x[i];
+                    for j in 0..size {
+                        hessian[i][j] += p * (1.0 - p) * x[i] * x[j];
+                    }
+                }
+            }
+            for i in 1..size {
+                gradient[i] -= ridge * beta[i];
+                // Constant features have an all-zero column; the penalty keeps the system solvable.
+                hessian[i][i] += ridge.max(1e-9);
+            }
+            let step = match solve(hessian, gradient) {
+                Some(step) => step,
+                None => break
+            };
+            for (b, delta) in beta.iter_mut().zip(step.iter()) {
+                *b += delta;
+            }
+            if step.iter().all(|delta| delta.abs() < FIT_TOLERANCE) {
+                break;
+            }
+        }
+        Self {
+            features: WIN_MODEL_FEATURES.iter().map(|name| name.to_string()).collect(),
+            intercept: beta[0],
+            coefficients: beta[1..].iter().zip(scales.iter()).map(|(b, scale)| if *scale > 0.0 {b / scale} else {0.0}).collect(),
+            sessions: samples.len() as u64
+        }
+    }
+
+    /// Team 1 win probability for the feature gaps of a session.
+    pub fn probability(&self, features: &[f64; 5]) -> f64 {
+        sigmoid(self.intercept + self.coefficients.iter().zip(features.iter()).map(|(b, x)| b * x).sum::<f64>())
+    }
+
+    /// Team 1 win probability of two ranked teams (`SessionTeams::team_1_mmr` / `team_2_mmr`);
+    /// `registrations` tell the newbies apart.
+    pub fn predict(&self, team_1: &TeamMMR, team_2: &TeamMMR, registrations: &Registrations) -> f64 {
+        self.probability(&TeamFeatures::new(team_1, registrations).gap(&TeamFeatures::new(team_2, registrations)))
+    }
+
+    /// Top-3 average gap at which team 1 wins with `probability` when every other gap is 0:
+    /// a data-derived replacement for the fixed disbalance threshold of the session rules
+    /// (`Gap(1, TopAvg(3), Above(..))`). `None` when the model has no top-3 effect.
+    pub fn top_3_gap(&self, probability: f64) -> Option<f64> {
+        let coefficient = self.coefficients.first().copied().unwrap_or(0.0);
+        if coefficient == 0.0 || probability <= 0.0 || probability >= 1.0 {
+            return None;
+        }
+        Some(((probability / (1.0 - probability)).ln() - self.intercept) / coefficient)
+    }
+
+    /// Reads a RON model file; the features must be `WIN_MODEL_FEATURES` with one coefficient
+    /// each.
+    pub fn read(path: &str) -> Result<Self, WinModelError> {
+        let data = std::fs::read_to_string(path)?;
+        let model: Self = ron::from_str(&data).map_err(WinModelError::Parse)?;
+        if model.features != WIN_MODEL_FEATURES {
+            return Err(WinModelError::Features(model.features));
+        }
+        if model.coefficients.len() != WIN_MODEL_FEATURES.len() {
+            return Err(WinModelError::Coefficients(model.coefficients.len()));
+        }
+        Ok(model)
+    }
+
+    /// Writes the model as pretty-printed RON.
+    pub fn write(&self, path: &str) {
+        let data = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).unwrap();
+        std::fs::write(path, data + "\n").unwrap();
+    }
+}
+
+/// Replays `sessions` in order with `rating`, taking the [`WinSample`] of every session before
+/// applying it through [`rating::proc_session`] with classifier id `0`.
+pub async fn replay<T: RatingSystem>(
+    rating: &mut T,
+    sessions: &[SessionMemory],
+    session_mode: &SessionMode,
+    registrations: &Registrations,
+    rules: &SessionRules
+) -> Vec<WinSample> {
+    let mut samples: Vec<WinSample> = Vec::new();
+    let (sender, _) = flume::unbounded();
+    let (sender_tasks, _) = flume::unbounded::<T::Change>();
+    let (sender_check, _) = flume::unbounded();
+    let (sender_session_class, _) = flume::unbounded();
+    for session in sessions.iter() {
+        samples.extend(WinSample::new(rating, session, session_mode, registrations, rules));
+        rating::proc_session(rating, session.clone(), 0, sender.clone(), session_mode, registrations, rules, sender_tasks.clone(), sender_check.clone(), sender_session_class.clone()).await;
+    }
+    samples
+}
+
+fn sigmoid(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Solves `matrix · x = vector` by Gaussian elimination with partial pivoting; `None` for a
+/// singular matrix.
+fn solve(mut matrix: Vec<Vec<f64>>, mut vector: Vec<f64>) -> Option<Vec<f64>> {
+    let size = vector.len();
+    for column in 0..size {
+        let pivot = (column..size).max_by(|a, b| matrix[*a][column].abs().total_cmp(&matrix[*b][column].abs()))?;
+        if matrix[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        vector.swap(column, pivot);
+        for row in column + 1..size {
+            let factor = matrix[row][column] / matrix[column][column];
+            let (upper, lower) = matrix.split_at_mut(row);
+            for (value, pivot) in lower[0][column..].iter_mut().zip(upper[column][column..].iter()) {
+                *value -= factor * pivot;
+            }
+            vector[row] -= factor * vector[column];
+        }
+    }
+    let mut solution = vec![0.0; size];
+    for row in (0..size).rev() {
+        let sum: f64 = (row + 1..size).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (vector[row] - sum) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_recovers_the_top_3_effect() {
+        // Outcomes drawn from P = sigmoid(0.2 + 0.004 · top-3 gap) with a fixed LCG.
+        let mut state: u64 = 7;
+        let mut uniform = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 11) as f64 / (1_u64 << 53) as f64
+        };
+        let samples: Vec<WinSample> = (0..4000).map(|index| {
+            let gap = (index % 81) as f64 * 20.0 - 800.0;
+            let mean_gap = ((index * 37) % 61) as f64 * 10.0 - 300.0;
+            let features = [gap, mean_gap, 0.0, 0.0, (index % 3) as f64 - 1.0];
+            WinSample { features, rating_probability: 0.5, team_1_won: uniform() < sigmoid(0.2 + 0.004 * gap) }
+        }).collect();
+
+        let model = WinModel::fit(&samples, 0.0001);
+        assert_eq!(model.sessions, 4000);
+        assert!((model.coefficients[0] - 0.004).abs() < 0.0008, "{:?}", model);
+        assert!(model.coefficients[1].abs() < 0.0008, "{:?}", model);
+        assert!((model.intercept - 0.2).abs() < 0.15, "{:?}", model);
+        assert_eq!(model.coefficients[2], 0.0);
+        assert!((model.probability(&[0.0; 5]) - sigmoid(model.intercept)).abs() < 1e-12);
+
+        let top_3 = WinModel::fit_features(&samples, 0.0001, &["top_3_avg"]);
+        assert_eq!(top_3.coefficients[1..], [0.0; 4]);
+        assert!((top_3.coefficients[0] - 0.004).abs() < 0.0008, "{:?}", top_3);
+
+        let model = WinModel { features: WIN_MODEL_FEATURES.iter().map(|name| name.to_string()).collect(), intercept: 0.0, coefficients: vec![0.004, 0.0, 0.0, 0.0, 0.0], sessions: 0 };
+        assert!((model.top_3_gap(0.5).unwrap()).abs() < 1e-9);
+        assert!((model.top_3_gap(sigmoid(3.2)).unwrap() - 800.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn read_rejects_models_of_other_features() {
+        let path = std::env::temp_dir().join("mmr_win_model_test_".to_string() + std::process::id().to_string().as_str());
+        let path = path.to_str().unwrap().to_string();
+        let mut model = WinModel { features: WIN_MODEL_FEATURES.iter().map(|name| name.to_string()).collect(), intercept: 0.1, coefficients: vec![0.004, 0.0, 0.0, 0.3, 0.2], sessions: 10 };
+        model.write(&path);
+        assert_eq!(WinModel::read(&path).unwrap(), model);
+
+        model.coefficients.pop();
+        model.write(&path);
+        assert!(matches!(WinModel::read(&path), Err(WinModelError::Coefficients(4))));
+
+        model.features.swap(0, 1);
+        model.write(&path);
+        assert!(matches!(WinModel::read(&path), Err(WinModelError::Features(_))));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(WinModel::read(&path), Err(WinModelError::Io(_))));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{types::{self, LeaderboardChangeV1, LeaderboardRow, MMRType, MMRChangeDebug, MMRChangeDebugV2, LeaderboardChangeV2}, memory};
+use crate::{types::{self, LeaderboardChangeV1, LeaderboardRow, MMRType, MMRChangeDebug, MMRChangeDebugGlicko, MMRChangeDebugV2, LeaderboardChangeV2}, memory};
 
 use tokio::{fs::File, io::BufWriter};
 use std::sync::Arc;
@@ -120,6 +120,35 @@ pub async fn write_change(
     change_file.write_all(session_str.as_bytes()).await.unwrap();
 }
 
+pub async fn write_change_glicko(
+    change: LeaderboardChangeV1,
+    diff_mmr: i32,
+    user_row: LeaderboardRow,
+    change_file: &mut BufWriter<File>,
+    change_debug: MMRChangeDebugGlicko
+){
+    
+    let session_str: String = "{".to_string()
+    + "u:" + change.user_id.to_string().as_str()
+    + ",v:" + change.victory.to_string().as_str()
+    + ",dm:" + diff_mmr.to_string().as_str()
+    + ",m:" + user_row.mmr.to_string().as_str()
+    + ",o:" + change.top_3.iter().map(|o| o.to_string()).collect::<Vec<String>>().join(",").to_string().as_str()
+    + ",t:" + user_row.top_20.to_string().as_str()
+    + ",e:" + user_row.early_quites.to_string().as_str()
+    + ",bs:" + change.battle_score.to_string().as_str()
+    + ",bsm:" + change.battle_score_muld.to_string().as_str()
+    + ",de:[r:" + (((change_debug.0 * 10.0) as i64) as f64 / 10.0).to_string().as_str() + ",rd:"
+    + (((change_debug.1 * 10.0) as i64) as f64 / 10.0).to_string().as_str() + ",vo:"
+    + (((change_debug.2 * 100000.0) as i64) as f64 / 100000.0).to_string().as_str() + ",nr:"
+    + (((change_debug.3 * 10.0) as i64) as f64 / 10.0).to_string().as_str() + ",nrd:"
+    + (((change_debug.4 * 10.0) as i64) as f64 / 10.0).to_string().as_str() + ",nvo:"
+    + (((change_debug.5 * 100000.0) as i64) as f64 / 100000.0).to_string().as_str() + "]"
+    + "}\n";
+
+    change_file.write_all(session_str.as_bytes()).await.unwrap();
+}
+
 pub async fn write_change_v2(
     change: LeaderboardChangeV2,
     diff_mmr: i32,
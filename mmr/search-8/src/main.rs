@@ -0,0 +1,215 @@
+use std::time::Instant;
+use mmr_libs::algorithm::{Algorithm, BoxedTask, RatingTask};
+use mmr_libs::classification::SessionRules;
+use mmr_libs::datasets::{Registrations, SessionMode, UserFaction, UserTeam};
+use mmr_libs::evaluation::{load_sessions, replay, EvaluationMetrics};
+use mmr_libs::memory::SessionMemory;
+use mmr_libs::params::{MMRParams, SearchSpace};
+use mmr_libs::rating::RatingSystem;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use clap::{Parser, ValueEnum};
+
+/// How candidate parameter sets are generated from the search space.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Strategy {
+    /// Every combination of the evenly spaced grid points.
+    Grid,
+    /// `--trials` uniform draws inside the ranges.
+    Random,
+}
+
+/// Predictive metric used to rank candidates.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Metric {
+    /// Mean negative log-likelihood (lower is better).
+    LogLoss,
+    /// Mean squared probability error (lower is better).
+    Brier,
+    /// ROC area (higher is better).
+    Auc,
+}
+
+/// Command-line arguments for the parameter search.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Algorithm whose constants are searched; only v1 and v2 have tunable constants.
+    #[arg(long, value_enum)]
+    pub algorithm: Algorithm,
+    /// Path to the file containing (user_id, session_id) -> (team, victory) mappings.
+    #[arg(long)]
+    pub user_team: String,
+    /// Path to the file mapping session_id to mode name.
+    #[arg(long)]
+    pub session_mode: String,
+    /// Path to the file mapping user_id to faction.
+    #[arg(long)]
+    pub user_faction: String,
+    /// Path to the training slice of the userstat dataset.
+    #[arg(long)]
+    pub data: String,
+    /// RON parameter file used as the starting point (defaults when omitted).
+    #[arg(long)]
+    pub params: Option<String>,
+    /// RON file with the session skip rules (built-in rules when omitted).
+    #[arg(long)]
+    pub rules: Option<String>,
+    /// RON search space file (built-in space around the defaults when omitted).
+    #[arg(long)]
+    pub space: Option<String>,
+    /// Candidate generation strategy.
+    #[arg(long, value_enum, default_value_t = Strategy::Grid)]
+    pub strategy: Strategy,
+    /// Number of random candidates.
+    #[arg(long, default_value_t = 50)]
+    pub trials: usize,
+    /// Seed of the random strategy.
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Metric that ranks the candidates.
+    #[arg(long, value_enum, default_value_t = Metric::LogLoss)]
+    pub metric: Metric,
+    /// Evaluation group scored by the metric (`common` or a session mode name).
+    #[arg(long, default_value = "common")]
+    pub group: String,
+    /// Path of the best configuration (RON).
+    #[arg(long, default_value = "data/params_best.ron")]
+    pub output: String,
+    /// Path of the per-trial log.
+    #[arg(long, default_value = "data/params_search_8")]
+    pub log: String,
+}
+
+/// Entry point for the parameter search.
+///
+/// 1. Loads the auxiliary datasets and the training slice into memory once.
+/// 2. Builds the candidate list: the base parameters first, then the grid or random draws.
+/// 3. Replays the slice from an empty leaderboard for every candidate and scores the
+///    pre-session win predictions of `--group` with `--metric`.
+/// 4. Logs every trial and writes the best candidate as RON to `--output`.
+#[tokio::main]
+async fn main() {
+  let args: Args = Args::parse();
+  if !args.algorithm.tunable() {
+    panic!("--algorithm {}: only v1 and v2 have tunable constants", args.algorithm);
+  }
+  let start = Instant::now();
+
+  let user_team = UserTeam::new(&args.user_team).0;
+  let session_mode = SessionMode::new(&args.session_mode);
+  let registrations = Registrations::new();
+  let user_faction = UserFaction::new(&args.user_faction);
+  let sessions = load_sessions(&args.data, &user_team, &user_faction);
+  let rules = args.rules.as_ref().map(|path| SessionRules::read(path)).unwrap_or_default();
+
+  println!("Load datasets: {:?}, sessions: {}", start.elapsed(), sessions.len());
+
+  let base = match &args.params {
+    Some(path) => MMRParams::read(path),
+    None => MMRParams::default()
+  };
+  let space = match &args.space {
+    Some(path) => SearchSpace::read(path),
+    None => SearchSpace::default_for(args.algorithm.name())
+  };
+  let unknown = space.unknown_names();
+  if !unknown.is_empty() {
+    panic!("unknown parameters in search space: {:?}", unknown);
+  }
+
+  let mut candidates: Vec<MMRParams> = vec![base.clone()];
+  match args.strategy {
+    Strategy::Grid => candidates.extend(space.grid(&base)),
+    Strategy::Random => {
+      let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+      for _ in 0..args.trials {
+        candidates.push(space.sample(&base, || rng.gen::<f64>()));
+      }
+    }
+  }
+  println!("Candidates: {}", candidates.len());
+
+  let log_file = tokio::fs::File::create(&args.log).await.unwrap();
+  let mut log_file = BufWriter::new(log_file);
+
+  // (trial, score, params); score is oriented so that lower is better.
+  let mut best: Option<(usize, f64, MMRParams)> = None;
+  for (trial, params) in candidates.into_iter().enumerate() {
+    let metrics = args.algorithm.dispatch(TrialMetrics {
+      params: &params,
+      sessions: &sessions,
+      session_mode: &session_mode,
+      registrations: &registrations,
+      rules: &rules,
+      group: &args.group,
+    }).await;
+    let score = match &metrics {
+      Some(metrics) => match args.metric {
+        Metric::LogLoss => metrics.log_loss,
+        Metric::Brier => metrics.brier,
+        Metric::Auc => -metrics.auc.unwrap_or(0.5)
+      },
+      None => f64::INFINITY
+    };
+
+    let mut str = "trial:".to_string() + trial.to_string().as_str()
+      + ",score:" + score.to_string().as_str();
+    if let Some(metrics) = &metrics {
+      str = str + ",sessions:" + metrics.sessions.to_string().as_str()
+        + ",log_loss:" + metrics.log_loss.to_string().as_str()
+        + ",brier:" + metrics.brier.to_string().as_str()
+        + ",auc:" + match metrics.auc {
+          Some(auc) => auc.to_string(),
+          None => "none".to_string()
+        }.as_str();
+    }
+    str = str + "," + params.to_string(&space).as_str();
+    println!("{}", str);
+    log_file.write_all((str + "\n").as_bytes()).await.unwrap();
+
+    if best.as_ref().map(|best| score < best.1).unwrap_or(true) {
+      best = Some((trial, score, params));
+    }
+  }
+  log_file.flush().await.unwrap();
+
+  if let Some((trial, score, params)) = best {
+    params.write(&args.output);
+    println!("Best trial: {}, score: {}, written to {} ({:?})", trial, score, args.output, start.elapsed());
+  }
+}
+
+/// [`trial_metrics`] with the leaderboard of `--algorithm`.
+struct TrialMetrics<'a> {
+  params: &'a MMRParams,
+  sessions: &'a [SessionMemory],
+  session_mode: &'a SessionMode,
+  registrations: &'a Registrations,
+  rules: &'a SessionRules,
+  group: &'a str,
+}
+
+impl<'a> RatingTask for TrialMetrics<'a> {
+  type Output = BoxedTask<'a, Option<EvaluationMetrics>>;
+
+  fn call<T: RatingSystem>(self) -> Self::Output {
+    Box::pin(trial_metrics::<T>(self.params, self.sessions, self.session_mode, self.registrations, self.rules, self.group))
+  }
+}
+
+/// Replays the training slice with `params` from an empty leaderboard and returns the
+/// metrics of `group`, or `None` when the group received no decided sessions.
+async fn trial_metrics<T: RatingSystem>(
+  params: &MMRParams,
+  sessions: &[SessionMemory],
+  session_mode: &SessionMode,
+  registrations: &Registrations,
+  rules: &SessionRules,
+  group: &str
+) -> Option<EvaluationMetrics> {
+  let mut leaderboard = T::empty();
+  leaderboard.set_params(params);
+  let evaluation = replay(&mut leaderboard, sessions, session_mode, registrations, rules).await;
+  evaluation.metrics(10).remove(group)
+}
@@ -0,0 +1,172 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Largest accepted request body.
+pub const MAX_BODY: usize = 1 << 20;
+/// Longest accepted request line or header line, line break included.
+pub const MAX_LINE: usize = 8 << 10;
+/// Most header lines accepted in one request.
+pub const MAX_HEADERS: usize = 100;
+
+/// Parsed HTTP/1.1 request; only what the service routes on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    // Path without the query string, e.g. `/player/42`.
+    pub path: String,
+    // Query string parameters, e.g. `n=100` of `/top?n=100`.
+    pub query: std::collections::BTreeMap<String, String>,
+    pub body: Vec<u8>
+}
+
+/// Response status and JSON body.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String
+}
+
+impl Response {
+    pub fn json(status: u16, body: String) -> Self {
+        Self { status, body }
+    }
+
+    /// Error response with a `{"error": message}` body.
+    pub fn error(status: u16, message: &str) -> Self {
+        Self { status, body: serde_json::json!({ "error": message }).to_string() }
+    }
+}
+
+/// Why [`read_request`] rejected a request.
+#[derive(Debug)]
+pub enum RequestError {
+    // Malformed request or failed read; answered with 400.
+    Io(std::io::Error),
+    // Request line or a header line over `MAX_LINE`, or more than `MAX_HEADERS` headers;
+    // answered with 431.
+    HeadersTooLarge
+}
+
+impl RequestError {
+    /// Error response of the rejected request.
+    pub fn response(&self) -> Response {
+        match self {
+            RequestError::Io(error) => Response::error(400, error.to_string().as_str()),
+            RequestError::HeadersTooLarge => Response::error(431, "request line or headers too large")
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::Io(error) => write!(f, "{}", error),
+            RequestError::HeadersTooLarge => write!(f, "request line or headers too large")
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<std::io::Error> for RequestError {
+    fn from(error: std::io::Error) -> Self {
+        RequestError::Io(error)
+    }
+}
+
+/// Reads one line into `line`, at most [`MAX_LINE`] bytes; returns the bytes read.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<usize, RequestError> {
+    line.clear();
+    let read = reader.take(MAX_LINE as u64).read_line(line)?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(RequestError::HeadersTooLarge);
+    }
+    Ok(read)
+}
+
+/// Reads one request from `stream`: the request line, the headers and a body of
+/// `Content-Length` bytes. Chunked bodies are not supported.
+///
+/// Only [`MAX_LINE`] bytes of a line and [`MAX_HEADERS`] headers are buffered, so an oversized
+/// head is rejected before it is read whole (`RequestError::HeadersTooLarge`).
+pub fn read_request<S: Read>(stream: S) -> Result<Request, RequestError> {
+    let invalid = |message: &str| RequestError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string()));
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(invalid("malformed request line"))
+    };
+
+    let mut content_length = 0;
+    let mut headers = 0;
+    loop {
+        if read_line(&mut reader, &mut line)? == 0 {
+            return Err(invalid("headers end early"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(RequestError::HeadersTooLarge);
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().map_err(|_| invalid("bad content-length"))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(invalid("body too large"));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.split('&').filter_map(|pair| pair.split_once('=')).map(|(key, value)| (key.to_string(), value.to_string())).collect()),
+        None => (target, std::collections::BTreeMap::new())
+    };
+    Ok(Request { method, path, query, body })
+}
+
+/// Writes `response` as a JSON response and closes the exchange (`Connection: close`).
+pub fn write_response<S: Write>(mut stream: S, response: &Response) -> std::io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error"
+    };
+    let head = "HTTP/1.1 ".to_string() + response.status.to_string().as_str() + " " + reason + "\r\n"
+        + "Content-Type: application/json\r\n"
+        + "Content-Length: " + response.body.len().to_string().as_str() + "\r\n"
+        + "Connection: close\r\n\r\n";
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_request_caps_the_request_line_and_headers() {
+        let request = read_request("POST /session?dry=1 HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}".as_bytes()).unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.query["dry"].as_str(), request.body.as_slice()), ("POST", "/session", "1", b"{}".as_slice()));
+
+        let long_line = "GET /".to_string() + "a".repeat(MAX_LINE).as_str() + " HTTP/1.1\r\n\r\n";
+        let long_header = "GET / HTTP/1.1\r\nX-Pad: ".to_string() + "a".repeat(MAX_LINE).as_str() + "\r\n\r\n";
+        let many_headers = "GET / HTTP/1.1\r\n".to_string() + "X-Pad: a\r\n".repeat(MAX_HEADERS + 1).as_str() + "\r\n";
+        for head in [long_line, long_header, many_headers] {
+            assert_eq!(read_request(head.as_bytes()).unwrap_err().response().status, 431);
+        }
+        assert_eq!(read_request("GET\r\n\r\n".as_bytes()).unwrap_err().response().status, 400);
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+use mmr_libs::algorithm::{Algorithm, RatingTask};
+use mmr_libs::history::HistoryStore;
+use mmr_libs::params::MMRParams;
+use mmr_libs::rating::{leaderboard_dir, RatingSystem};
+use server::http::{read_request, write_response};
+use server::service::Service;
+use clap::Parser;
+
+/// Command-line arguments for the rating service.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Rating algorithm whose leaderboard is loaded and updated.
+    #[arg(long, value_enum)]
+    pub algorithm: Algorithm,
+    /// Leaderboard directory, overrides `data/leaderboard_<algorithm>`.
+    #[arg(long)]
+    pub dir: Option<String>,
+    /// Port on 127.0.0.1; the service never listens on other interfaces.
+    #[arg(long, default_value_t = 8088)]
+    pub port: u16,
+    /// RON file with v1/v2 formula constants (built-in defaults when omitted).
+    #[arg(long)]
+    pub params: Option<String>,
+    /// Appends the changes of applied sessions to the history store in `<dir>/history`.
+    #[arg(long)]
+    pub history: bool,
+    /// Applied sessions kept in the session log before the leaderboard is persisted (0: never).
+    #[arg(long, default_value_t = 100)]
+    pub compact_every: u64,
+}
+
+/// Entry point for the rating service.
+///
+/// 1. Restores the leaderboard of `--algorithm` from `--dir` (`RatingSystem::restore_from`)
+///    and replays the session log (`Service::open`).
+/// 2. Listens on `127.0.0.1:<port>` and answers one request at a time (`Service::handle`),
+///    each on its own connection (`Connection: close`).
+fn main() {
+  let args: Args = Args::parse();
+  args.algorithm.dispatch(Serve(&args));
+}
+
+/// [`serve`] with the leaderboard of `--algorithm`.
+struct Serve<'a>(&'a Args);
+
+impl RatingTask for Serve<'_> {
+  type Output = ();
+
+  fn call<T: RatingSystem>(self) {
+    serve::<T>(self.0)
+  }
+}
+
+/// Serves the leaderboard of `T` until the process is stopped.
+fn serve<T: RatingSystem>(args: &Args) {
+  let dir = args.dir.clone().unwrap_or_else(leaderboard_dir::<T>);
+  std::fs::create_dir_all(&dir).unwrap();
+  let mut leaderboard = match T::restore_from(&dir) {
+    Ok(leaderboard) => leaderboard,
+    Err(error) => {
+      eprintln!("{}: {}", dir, error);
+      std::process::exit(1);
+    }
+  };
+  if let Some(path) = &args.params {
+    leaderboard.set_params(&MMRParams::read(path));
+  }
+  let history = if args.history {Some(HistoryStore::open(&(dir.clone() + "/history")).unwrap())} else {None};
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut service = runtime.block_on(Service::open(leaderboard, &dir, history, args.compact_every)).unwrap();
+  let listener = std::net::TcpListener::bind(("127.0.0.1", args.port)).unwrap();
+  println!("Listening on {}, players: {}, logged sessions: {}", listener.local_addr().unwrap(), service.leaderboard.get_users().len(), service.log.sessions);
+
+  for stream in listener.incoming() {
+    let mut stream = match stream {
+      Ok(stream) => stream,
+      Err(error) => {
+        println!("accept: {}", error);
+        continue;
+      }
+    };
+    // A stalled client must not block the service.
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let response = match read_request(&mut stream) {
+      Ok(request) => runtime.block_on(service.handle(&request)),
+      Err(error) => error.response()
+    };
+    if let Err(error) = write_response(&mut stream, &response) {
+      println!("write: {}", error);
+    }
+  }
+}
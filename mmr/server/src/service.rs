@@ -0,0 +1,496 @@
+use std::io::Write;
+use serde::{Deserialize, Serialize};
+
+use mmr_libs::classification::SessionRules;
+use mmr_libs::datasets::{Registrations, SessionMode};
+use mmr_libs::history::{HistoryEntry, HistoryStore};
+use mmr_libs::memory::SessionMemory;
+use mmr_libs::ranking::{RankFilter, Ranking};
+use mmr_libs::rating::{self, RatingSystem};
+use mmr_libs::snapshot;
+use mmr_libs::types::{MMRType, UserBattleRow};
+
+use crate::http::{Request, Response};
+
+/// One player row of a session posted to `/session` or `/session/preview`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionRow {
+    pub user_id: u64,
+    #[serde(default)]
+    pub commit_time: u64,
+    #[serde(default)]
+    pub battle_score: u32,
+    #[serde(default)]
+    pub victories: bool,
+    #[serde(default)]
+    pub early_quit: bool,
+    #[serde(default)]
+    pub team_score_top_20_percent: bool,
+    // A faction of the built-in registry (`faction_1` or `faction_2`); it also selects the team.
+    pub faction: String
+}
+
+/// Body of `/session` and `/session/preview`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionRequest {
+    pub session_id: u64,
+    // Raw mode name, normalized with `SessionMode::normalize`; unknown counts as newbie.
+    #[serde(default)]
+    pub mode: Option<String>,
+    pub rows: Vec<SessionRow>
+}
+
+/// Per-player change in a session response.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerChange {
+    pub user_id: u64,
+    pub mmr_before: u32,
+    pub mmr_after: u32,
+    pub delta: i64,
+    pub components: Vec<f64>
+}
+
+/// Response of `/session` and `/session/preview`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionResponse {
+    pub session_id: u64,
+    // `false` for sessions the pipeline skips: fewer than 5 players per side or `newbie_common`.
+    pub rated: bool,
+    pub applied: bool,
+    pub changes: Vec<PlayerChange>
+}
+
+/// Response of `/player/{id}` and the rows of `/top`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerResponse {
+    pub user_id: u64,
+    pub mmr: u32,
+    // `mmr`, `not_enought` or `new`, as in the change and CSV writers.
+    pub mmr_type: String,
+    pub battles: u32,
+    pub victories: u32,
+    pub last_session: u64,
+    // Among calibrated players; `None` before calibration.
+    pub rank: Option<usize>,
+    pub percentile: Option<f64>
+}
+
+/// First line of the session log: checksum of the snapshot the logged sessions apply to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct LogHeader {
+    // `snapshot::checksum` of `<dir>/snapshot`, `None` without a snapshot.
+    snapshot: Option<u32>
+}
+
+/// Sessions applied since the last snapshot of the leaderboard, in `<dir>/sessions`: a
+/// [`LogHeader`] line, then one JSON [`SessionRequest`] per line in arrival order.
+///
+/// The header ties the log to its snapshot. A compaction persists a new snapshot before it
+/// resets the log, so a log whose header names another snapshot is already part of the
+/// leaderboard and is dropped on open.
+pub struct SessionLog {
+    dir: String,
+    file: std::fs::File,
+    // Sessions appended since the last reset.
+    pub sessions: u64
+}
+
+impl SessionLog {
+    /// Opens the log of `dir` and returns the sessions to replay on the restored leaderboard.
+    ///
+    /// A torn last line (crash mid-write) is cut off, and a log of another snapshot is
+    /// dropped; both are rewritten before the log is reopened for appends.
+    pub fn open(dir: &str) -> std::io::Result<(Self, Vec<SessionRequest>)> {
+        let path = dir.to_string() + "/sessions";
+        let header = LogHeader { snapshot: snapshot::checksum(&(dir.to_string() + "/snapshot"))? };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error)
+        };
+
+        let mut lines = text.split_inclusive('\n');
+        let mut kept = serde_json::to_string(&header).unwrap() + "\n";
+        let mut sessions: Vec<SessionRequest> = Vec::new();
+        if lines.next().and_then(|line| serde_json::from_str::<LogHeader>(line).ok()) == Some(header) {
+            for line in lines.take_while(|line| line.ends_with('\n')) {
+                match serde_json::from_str::<SessionRequest>(line) {
+                    Ok(session) => {
+                        kept += line;
+                        sessions.push(session);
+                    },
+                    Err(_) => break
+                }
+            }
+        }
+        if kept != text {
+            std::fs::write(&path, &kept)?;
+        }
+        let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        Ok((Self { dir: dir.to_string(), file, sessions: sessions.len() as u64 }, sessions))
+    }
+
+    /// Appends `session` to the log.
+    pub fn append(&mut self, session: &SessionRequest) -> std::io::Result<()> {
+        self.file.write_all((serde_json::to_string(session).unwrap() + "\n").as_bytes())?;
+        self.sessions += 1;
+        Ok(())
+    }
+
+    /// Starts an empty log on the current snapshot of the directory.
+    pub fn reset(&mut self) -> std::io::Result<()> {
+        let header = LogHeader { snapshot: snapshot::checksum(&(self.dir.clone() + "/snapshot"))? };
+        self.file.set_len(0)?;
+        self.file.write_all((serde_json::to_string(&header).unwrap() + "\n").as_bytes())?;
+        self.sessions = 0;
+        Ok(())
+    }
+}
+
+/// Rating service over one leaderboard held in memory.
+///
+/// Requests are handled one at a time, so `/session` updates apply in arrival order and a
+/// preview always sees the state after the previous update.
+pub struct Service<T: RatingSystem> {
+    pub leaderboard: T,
+    // Directory of the leaderboard snapshot and the session log.
+    pub dir: String,
+    // Rating history the applied sessions are appended to, when enabled.
+    pub history: Option<HistoryStore>,
+    pub log: SessionLog,
+    // Logged sessions after which the leaderboard is persisted and the log reset; 0 never
+    // compacts.
+    pub compact_every: u64,
+    // Calibrated players of `leaderboard`; dropped by an applied session and rebuilt by the
+    // next `GET` ([`Service::ranking`]), so a write does not re-sort the board.
+    ranking: Option<Ranking>
+}
+
+impl<T: RatingSystem> Service<T> {
+    /// Opens the service over `leaderboard`, restored from the snapshot in `dir`:
+    ///
+    /// 1. Replays the sessions of the session log ([`SessionLog::open`]).
+    /// 2. Leaves the ranking to the first `GET` that needs it.
+    pub async fn open(mut leaderboard: T, dir: &str, history: Option<HistoryStore>, compact_every: u64) -> std::io::Result<Self> {
+        let (log, sessions) = SessionLog::open(dir)?;
+        for session in sessions.iter() {
+            rate(&mut leaderboard, session).await;
+        }
+        Ok(Self { leaderboard, dir: dir.to_string(), history, log, compact_every, ranking: None })
+    }
+
+    /// Ranking of the calibrated players, rebuilt when a session was applied since the last one.
+    pub fn ranking(&mut self) -> &Ranking {
+        self.ranking.get_or_insert_with(|| Ranking::new(&self.leaderboard, &calibrated()))
+    }
+
+    /// Routes `request`:
+    ///
+    /// - `GET /player/{id}`       — rating, battles, rank and percentile of a player;
+    /// - `GET /top?n=N`           — the first N calibrated players (default 100);
+    /// - `POST /session/preview`  — per-player changes of a session, leaving the leaderboard as is;
+    /// - `POST /session`          — the same changes applied, logged and added to the history.
+    pub async fn handle(&mut self, request: &Request) -> Response {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["player", user_id]) => match user_id.parse::<u64>() {
+                Ok(user_id) => self.player(user_id),
+                Err(_) => Response::error(400, "player id must be a number")
+            },
+            ("GET", ["top"]) => match request.query.get("n").map(|n| n.parse::<usize>()).unwrap_or(Ok(100)) {
+                Ok(n) => self.top(n),
+                Err(_) => Response::error(400, "n must be a number")
+            },
+            ("POST", ["session", "preview"]) => match serde_json::from_slice::<SessionRequest>(&request.body) {
+                Ok(session) => Response::json(200, serde_json::to_string(&preview(&mut self.leaderboard, &session).await).unwrap()),
+                Err(error) => Response::error(400, error.to_string().as_str())
+            },
+            ("POST", ["session"]) => match serde_json::from_slice::<SessionRequest>(&request.body) {
+                Ok(session) => self.apply(&session).await,
+                Err(error) => Response::error(400, error.to_string().as_str())
+            },
+            (_, ["player", _]) | (_, ["top"]) | (_, ["session"]) | (_, ["session", "preview"]) => Response::error(405, "method not allowed"),
+            _ => Response::error(404, "not found")
+        }
+    }
+
+    fn player(&mut self, user_id: u64) -> Response {
+        let ranked = self.ranking().get(user_id).cloned();
+        match self.leaderboard.get_users().get(&user_id) {
+            Some(row) => {
+                let mmr_type = match self.leaderboard.get_mmr(user_id) {
+                    MMRType::MMR(_) => "mmr",
+                    MMRType::NotEnought(_) => "not_enought",
+                    MMRType::None => "new"
+                };
+                Response::json(200, serde_json::to_string(&PlayerResponse {
+                    user_id,
+                    mmr: row.mmr,
+                    mmr_type: mmr_type.to_string(),
+                    battles: row.battles,
+                    victories: row.victories,
+                    last_session: row.last_session,
+                    rank: ranked.as_ref().map(|ranked| ranked.rank),
+                    percentile: ranked.as_ref().map(|ranked| ranked.percentile)
+                }).unwrap())
+            },
+            None => Response::error(404, "unknown player")
+        }
+    }
+
+    fn top(&mut self, n: usize) -> Response {
+        let players: Vec<PlayerResponse> = self.ranking().top(n).iter().map(|ranked| PlayerResponse {
+            user_id: ranked.row.user_id,
+            mmr: ranked.row.mmr,
+            mmr_type: "mmr".to_string(),
+            battles: ranked.row.battles,
+            victories: ranked.row.victories,
+            last_session: ranked.row.last_session,
+            rank: Some(ranked.rank),
+            percentile: Some(ranked.percentile)
+        }).collect();
+        Response::json(200, serde_json::to_string(&players).unwrap())
+    }
+
+    /// Applies `session` to the live leaderboard:
+    ///
+    /// 1. Appends it to the session log, so a restart replays it; a failed append applies
+    ///    nothing (status 500).
+    /// 2. Rates it and drops the ranking, rebuilt by the next `GET`.
+    /// 3. Appends the changes to the history store; a failed write answers 500, the session
+    ///    stays applied and logged.
+    /// 4. Compacts every `compact_every` logged sessions ([`Service::compact`]).
+    async fn apply(&mut self, session: &SessionRequest) -> Response {
+        if let Err(error) = self.log.append(session) {
+            return Response::error(500, ("session log: ".to_string() + error.to_string().as_str()).as_str());
+        }
+        let response = rate(&mut self.leaderboard, session).await;
+        if response.rated {
+            self.ranking = None;
+            if let Err(error) = self.append_history(session, &response) {
+                return Response::error(500, ("session applied, history: ".to_string() + error.to_string().as_str()).as_str());
+            }
+        }
+        if self.compact_every > 0 && self.log.sessions >= self.compact_every {
+            if let Err(error) = self.compact().await {
+                eprintln!("compact: {}", error);
+            }
+        }
+        Response::json(200, serde_json::to_string(&SessionResponse { applied: response.rated, ..response }).unwrap())
+    }
+
+    /// Appends the changes of the applied `session` to the history store, when enabled.
+    fn append_history(&mut self, session: &SessionRequest, response: &SessionResponse) -> std::io::Result<()> {
+        let Some(history) = self.history.as_mut() else {
+            return Ok(());
+        };
+        let mode = session.mode.clone().unwrap_or_default();
+        for change in response.changes.iter() {
+            let commit_time = session.rows.iter().find(|row| row.user_id == change.user_id).map(|row| row.commit_time).unwrap_or(0);
+            history.append(&HistoryEntry {
+                user_id: change.user_id,
+                session_id: session.session_id,
+                commit_time,
+                mmr_before: change.mmr_before,
+                mmr_after: change.mmr_after,
+                mode: mode.clone(),
+                components: change.components.clone()
+            })?;
+        }
+        history.flush()
+    }
+
+    /// Persists the leaderboard to `dir` (`RatingSystem::persist_to`) and resets the session
+    /// log on the new snapshot.
+    pub async fn compact(&mut self) -> std::io::Result<()> {
+        self.leaderboard.persist_to(&self.dir).await;
+        self.log.reset()
+    }
+}
+
+/// Ranking population of the endpoints: calibrated players (6+ battles).
+fn calibrated() -> RankFilter {
+    RankFilter { min_battles: 6, ..RankFilter::default() }
+}
+
+/// Pipeline inputs of `session`: its rows as the session memory, its mode, no registrations
+/// and the built-in session rules.
+fn session_input(session: &SessionRequest) -> (SessionMemory, SessionMode, Registrations, SessionRules) {
+    let rules = SessionRules::default();
+    let rows: Vec<UserBattleRow> = session.rows.iter().map(|row| UserBattleRow {
+        user_id: row.user_id,
+        session_id: session.session_id,
+        commit_time: row.commit_time,
+        team: rules.factions.id(&row.faction).map(|id| id.0).unwrap_or(0),
+        battle_score: row.battle_score,
+        victories: row.victories,
+        early_quit: row.early_quit,
+        team_score_top_20_percent: row.team_score_top_20_percent,
+        faction: row.faction.clone()
+    }).collect();
+    let mut session_mode = SessionMode(std::collections::HashMap::new());
+    if let Some(mode) = &session.mode {
+        session_mode.0.insert(session.session_id, SessionMode::normalize(mode));
+    }
+    (SessionMemory { now_session_id: session.session_id, rows }, session_mode, Registrations(std::collections::HashMap::new()), rules)
+}
+
+/// Per-player change of a change payload (`RatingSystem::history_entry`).
+fn player_change<T: RatingSystem>(change: &T::Change) -> PlayerChange {
+    let entry = T::history_entry(change);
+    PlayerChange { user_id: entry.user_id, mmr_before: entry.mmr_before, mmr_after: entry.mmr_after, delta: entry.delta(), components: entry.components }
+}
+
+/// Rates `session` on `leaderboard` through `rating::proc_session` (classifier id 0: no
+/// statistics) and collects the change records of its players.
+pub async fn rate<T: RatingSystem>(leaderboard: &mut T, session: &SessionRequest) -> SessionResponse {
+    let (memory, session_mode, registrations, rules) = session_input(session);
+    let (sender, _) = flume::unbounded();
+    let (sender_tasks, changes) = flume::unbounded();
+    let (sender_check, _) = flume::unbounded();
+    let (sender_session_class, _) = flume::unbounded();
+    let rated = rating::proc_session(leaderboard, memory, 0, sender, &session_mode, &registrations, &rules, sender_tasks, sender_check, sender_session_class).await.is_some();
+
+    let changes = changes.drain().map(|change| player_change::<T>(&change)).collect();
+    SessionResponse { session_id: session.session_id, rated, applied: false, changes }
+}
+
+/// Changes [`rate`] would make, including the decay of returning players, computed on
+/// `leaderboard` without keeping any change (`rating::preview`).
+pub async fn preview<T: RatingSystem>(leaderboard: &mut T, session: &SessionRequest) -> SessionResponse {
+    let (memory, session_mode, registrations, rules) = session_input(session);
+    let changes = rating::preview(leaderboard, &memory, &session_mode, &registrations, &rules).await;
+    SessionResponse {
+        session_id: session.session_id,
+        rated: changes.is_some(),
+        applied: false,
+        changes: changes.unwrap_or_default().iter().map(|change| player_change::<T>(change)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{read_request, write_response};
+    use mmr_libs::params::{DecayParams, MMRParams};
+    use mmr_libs::types::{Leaderboard, LeaderboardRow};
+    use std::io::{Read, Write};
+
+    // Sends one request to `address` and returns the status and body.
+    fn call(address: std::net::SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let request = method.to_string() + " " + path + " HTTP/1.1\r\nHost: localhost\r\nContent-Length: " + body.len().to_string().as_str() + "\r\n\r\n" + body;
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse::<u16>().unwrap();
+        (status, response.split_once("\r\n\r\n").unwrap().1.to_string())
+    }
+
+    #[tokio::test]
+    async fn serves_players_previews_and_applies_sessions_on_localhost() {
+        let dir = std::env::temp_dir().join("mmr_server_test_".to_string() + std::process::id().to_string().as_str());
+        let dir = dir.to_str().unwrap().to_string();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut leaderboard = Leaderboard::empty();
+        for user_id in 1..=10u64 {
+            leaderboard.users.insert(user_id, LeaderboardRow { user_id, mmr: 1400 + 20 * user_id as u32, battles: 10, victories: 5, early_quites: 0, top_20: 0, battle_score: 16000, last_session: 1 });
+        }
+        leaderboard.write_to(&dir).await;
+        let service = Service::open(leaderboard, &dir, Some(HistoryStore::open(&(dir.clone() + "/history")).unwrap()), 2).await.unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = 6;
+        let server = std::thread::spawn(move || {
+            let mut service = service;
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let request = read_request(&mut stream).unwrap();
+                let response = runtime.block_on(service.handle(&request));
+                write_response(&mut stream, &response).unwrap();
+            }
+            service
+        });
+
+        let (status, body) = call(address, "GET", "/player/10", "");
+        assert_eq!(status, 200);
+        let player: PlayerResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!((player.mmr, player.rank), (1600, Some(1)));
+        assert_eq!(call(address, "GET", "/player/77", "").0, 404);
+
+        let rows: Vec<String> = (1..=10).map(|user_id| format!(
+            "{{\"user_id\":{},\"commit_time\":5,\"battle_score\":1600,\"victories\":{},\"faction\":\"faction_{}\"}}",
+            user_id, user_id % 2 == 0, 1 + user_id % 2
+        )).collect();
+        let session = "{\"session_id\":9,\"mode\":\"ranked_high_teir\",\"rows\":[".to_string() + rows.join(",").as_str() + "]}";
+        let (status, body) = call(address, "POST", "/session/preview", &session);
+        assert_eq!(status, 200);
+        let preview: SessionResponse = serde_json::from_str(&body).unwrap();
+        assert!(preview.rated && !preview.applied);
+        assert_eq!(preview.changes.len(), 10);
+
+        // The preview left the leaderboard untouched; applying gives the same changes.
+        let (_status, body) = call(address, "GET", "/player/10", "");
+        assert_eq!(serde_json::from_str::<PlayerResponse>(&body).unwrap().battles, 10);
+        let (_status, body) = call(address, "POST", "/session", &session);
+        let applied: SessionResponse = serde_json::from_str(&body).unwrap();
+        assert!(applied.applied);
+        assert_eq!(applied.changes, preview.changes);
+        assert_eq!(call(address, "GET", "/top?n=3", "").1.matches("user_id").count(), 3);
+
+        let mut service = server.join().unwrap();
+        assert_eq!(service.leaderboard.get_battles(10), 11);
+        assert_eq!(service.history.as_mut().unwrap().trajectory(10).unwrap().len(), 1);
+
+        // One session is below `compact_every`: it lives in the log and is replayed on open.
+        assert_eq!((service.log.sessions, Leaderboard::restore_from(&dir).unwrap().get_battles(10)), (1, 10));
+        let mut reopened = Service::open(Leaderboard::restore_from(&dir).unwrap(), &dir, None, 2).await.unwrap();
+        assert_eq!((reopened.log.sessions, reopened.leaderboard.get_battles(10)), (1, 11));
+        assert_eq!(reopened.ranking().get(10).unwrap().row.battles, 11);
+
+        // A torn last line is cut off; a log of a replaced snapshot is dropped.
+        std::fs::OpenOptions::new().append(true).open(dir.clone() + "/sessions").unwrap().write_all(b"{\"session_id\":10,").unwrap();
+        assert_eq!(Service::open(Leaderboard::restore_from(&dir).unwrap(), &dir, None, 2).await.unwrap().log.sessions, 1);
+        service.leaderboard.persist_to(&dir).await;
+        let compacted = Service::open(Leaderboard::restore_from(&dir).unwrap(), &dir, None, 2).await.unwrap();
+        assert_eq!((compacted.log.sessions, compacted.leaderboard.get_battles(10)), (0, 11));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn preview_decays_returning_players_like_the_applied_session() {
+        let mut leaderboard = Leaderboard::empty();
+        leaderboard.set_params(&MMRParams { decay: DecayParams { period_days: 1.0, mean_pull: 0.5, recalibration: 0, confidence_battles: 0.0 }, ..MMRParams::default() });
+        for user_id in 1..=10u64 {
+            leaderboard.users.insert(user_id, LeaderboardRow { user_id, mmr: 1000 + 100 * user_id as u32, battles: 10, victories: 5, early_quites: 0, top_20: 0, battle_score: 16000, last_session: 1 });
+        }
+        let before = leaderboard.users.clone();
+        // Two full idle periods before the session.
+        let session = SessionRequest {
+            session_id: 9,
+            mode: Some("ranked_high_teir".to_string()),
+            rows: (1..=10u64).map(|user_id| SessionRow {
+                user_id,
+                commit_time: 3 * 86_400_000,
+                battle_score: 1600,
+                victories: user_id % 2 == 0,
+                early_quit: false,
+                team_score_top_20_percent: false,
+                faction: "faction_".to_string() + (1 + user_id % 2).to_string().as_str()
+            }).collect()
+        };
+
+        let preview = preview(&mut leaderboard, &session).await;
+        assert_eq!(leaderboard.users, before);
+        assert_eq!((leaderboard.decay.marks.len(), leaderboard.decay.mean_at), (0, 0));
+        // Player 10 keeps a quarter of its gap of 450 to the mean of 1550.
+        assert!(preview.rated);
+        assert_eq!(preview.changes.iter().find(|change| change.user_id == 10).unwrap().mmr_before, 1663);
+
+        let applied = rate(&mut leaderboard, &session).await;
+        assert_eq!(applied.changes, preview.changes);
+    }
+}
@@ -0,0 +1,38 @@
+use mmr_libs::statistic::StatisticReport;
+use clap::Parser;
+
+/// Command-line arguments for the statistics merge tool.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Statistics reports (`data/statistic_<algorithm>_8.json`) of the dataset slices, merged in order.
+    #[arg(required = true)]
+    pub reports: Vec<String>,
+    /// Output prefix: writes `<output>.json`, `<output>.csv` and `<output>_spread.csv`.
+    #[arg(long)]
+    pub output: String,
+}
+
+/// Entry point for the statistics merge tool.
+///
+/// 1. Reads every report written by `leaderboard-8` (`StatisticReport::read`); reports of
+///    another schema version are rejected.
+/// 2. Folds them into the first one with `StatisticReport::merge`: boards with the same key are
+///    combined with `Statistic::add_statistic`, the sources are concatenated. All reports must
+///    come from the same algorithm, library version and parameters.
+/// 3. Writes the merged report and prints one key:value line per board.
+fn main() {
+  let args: Args = Args::parse();
+  let read = |path: &String| StatisticReport::read(path).unwrap_or_else(|error| panic!("{}: {}", path, error));
+
+  let mut report = read(&args.reports[0]);
+  for path in args.reports.iter().skip(1) {
+    report.merge(&read(path)).unwrap_or_else(|error| panic!("{}: {}", path, error));
+  }
+  report.write(&args.output);
+
+  for board in report.boards.iter() {
+    println!("board:{},battles:{},disbalance_team_1:{},disbalance_team_2:{},new_user_battles:{}", board.board, board.battles, board.disbalance_team_1, board.disbalance_team_2, board.new_user_battles);
+  }
+  println!("algorithm:{},sources:{},boards:{}", report.algorithm, report.sources.len(), report.boards.len());
+}
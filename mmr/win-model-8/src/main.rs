@@ -0,0 +1,143 @@
+use std::time::Instant;
+use mmr_libs::algorithm::{Algorithm, BoxedTask, RatingTask};
+use mmr_libs::classification::SessionRules;
+use mmr_libs::datasets::{Registrations, SessionMode, UserFaction, UserTeam};
+use mmr_libs::evaluation::{auc, brier_score, load_sessions, log_loss};
+use mmr_libs::memory::SessionMemory;
+use mmr_libs::params::MMRParams;
+use mmr_libs::rating::RatingSystem;
+use mmr_libs::win_model::{self, WinModel, WinSample};
+use clap::Parser;
+
+/// Command-line arguments for the win-probability model fit.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Algorithm replayed to rank the teams.
+    #[arg(long, value_enum)]
+    pub algorithm: Algorithm,
+    /// Path to the file containing (user_id, session_id) -> (team, victory) mappings.
+    #[arg(long)]
+    pub user_team: String,
+    /// Path to the file mapping session_id to mode name.
+    #[arg(long)]
+    pub session_mode: String,
+    /// Path to the file mapping user_id to faction.
+    #[arg(long)]
+    pub user_faction: String,
+    /// Path to the main userstat dataset file.
+    #[arg(long)]
+    pub data: String,
+    /// RON file with v1/v2 formula constants (built-in defaults when omitted).
+    #[arg(long)]
+    pub params: Option<String>,
+    /// RON file with the session skip rules (built-in rules when omitted).
+    #[arg(long)]
+    pub rules: Option<String>,
+    /// Share of the last decided sessions held out of the fit and scored instead.
+    #[arg(long, default_value_t = 0.2)]
+    pub holdout: f64,
+    /// L2 penalty on the standardized coefficients.
+    #[arg(long, default_value_t = 1.0)]
+    pub ridge: f64,
+    /// Team 1 win probabilities to translate into top-3 average gaps (comma-separated).
+    #[arg(long, value_delimiter = ',', default_value = "0.6,0.7,0.8")]
+    pub threshold: Vec<f64>,
+    /// Model path (default `data/win_model_<algorithm>_8.ron`).
+    #[arg(long)]
+    pub output: Option<String>,
+}
+
+/// Entry point for the win-probability model fit.
+///
+/// 1. Loads the auxiliary datasets and the userstat slice into memory.
+/// 2. Replays the slice with `--algorithm` from an empty leaderboard and takes the feature
+///    gaps of every decided, rated session before it is applied (`win_model::replay`).
+/// 3. Fits `WinModel` on the first sessions and scores it and the algorithm's own
+///    `win_probability` on the last `--holdout` share.
+/// 4. Writes the model as RON and prints its coefficients and the scores.
+/// 5. Fits a model of the top-3 average gap alone and prints the gap of every `--threshold`
+///    probability, a data-derived disbalance threshold for the session rules.
+#[tokio::main]
+async fn main() {
+  let args: Args = Args::parse();
+  let start = Instant::now();
+
+  let user_team = UserTeam::new(&args.user_team).0;
+  let session_mode = SessionMode::new(&args.session_mode);
+  let registrations = Registrations::new();
+  let user_faction = UserFaction::new(&args.user_faction);
+  let sessions = load_sessions(&args.data, &user_team, &user_faction);
+
+  println!("Load datasets: {:?}, sessions: {}", start.elapsed(), sessions.len());
+
+  let name = args.algorithm.name();
+  let samples = args.algorithm.dispatch(Replay(&args, &sessions, &session_mode, &registrations)).await;
+
+  let fitted = ((samples.len() as f64) * (1.0 - args.holdout.clamp(0.0, 1.0))).round() as usize;
+  let model = WinModel::fit(&samples[..fitted], args.ridge);
+  let output = args.output.clone().unwrap_or("data/win_model_".to_string() + name + "_8.ron");
+  model.write(&output);
+
+  for (feature, coefficient) in model.features.iter().zip(model.coefficients.iter()) {
+    println!("feature:{},coefficient:{}", feature, coefficient);
+  }
+  println!("feature:intercept,coefficient:{}", model.intercept);
+  for (split, split_samples) in [("fit", &samples[..fitted]), ("holdout", &samples[fitted..])] {
+    if split_samples.is_empty() {
+      continue;
+    }
+    let predictions: Vec<(f64, bool)> = split_samples.iter().map(|sample| (model.probability(&sample.features), sample.team_1_won)).collect();
+    let rating: Vec<(f64, bool)> = split_samples.iter().map(|sample| (sample.rating_probability, sample.team_1_won)).collect();
+    println!("{}", score_line(split, "model", &predictions));
+    println!("{}", score_line(split, name, &rating));
+  }
+  // Thresholds come from the top-3 gap alone: the full model shares its effect with the mean.
+  let top_3 = WinModel::fit_features(&samples[..fitted], args.ridge, &["top_3_avg"]);
+  for probability in args.threshold.iter() {
+    println!("probability:{},top_3_gap:{}", probability, match top_3.top_3_gap(*probability) {
+      Some(gap) => format!("{:.0}", gap),
+      None => "none".to_string()
+    });
+  }
+  println!("algorithm:{},sessions:{},fitted:{},model:{}", name, samples.len(), fitted, output);
+}
+
+/// [`replay`] with the leaderboard of `--algorithm`.
+struct Replay<'a>(&'a Args, &'a [SessionMemory], &'a SessionMode, &'a Registrations);
+
+impl<'a> RatingTask for Replay<'a> {
+  type Output = BoxedTask<'a, Vec<WinSample>>;
+
+  fn call<T: RatingSystem>(self) -> Self::Output {
+    Box::pin(replay::<T>(self.0, self.1, self.2, self.3))
+  }
+}
+
+/// Replays the slice with the rating algorithm `T` and returns the decided sessions.
+async fn replay<T: RatingSystem>(
+  args: &Args,
+  sessions: &[SessionMemory],
+  session_mode: &SessionMode,
+  registrations: &Registrations
+) -> Vec<WinSample> {
+  let mut leaderboard = T::empty();
+  if let Some(path) = &args.params {
+    leaderboard.set_params(&MMRParams::read(path));
+  }
+  let rules = args.rules.as_ref().map(|path| SessionRules::read(path)).unwrap_or_default();
+  win_model::replay(&mut leaderboard, sessions, session_mode, registrations, &rules).await
+}
+
+/// `split:..,predictor:..,sessions:..,log_loss:..,brier:..,auc:..` line of one predictor.
+fn score_line(split: &str, predictor: &str, predictions: &[(f64, bool)]) -> String {
+  "split:".to_string() + split
+    + ",predictor:" + predictor
+    + ",sessions:" + predictions.len().to_string().as_str()
+    + ",log_loss:" + log_loss(predictions).to_string().as_str()
+    + ",brier:" + brier_score(predictions).to_string().as_str()
+    + ",auc:" + match auc(predictions) {
+      Some(auc) => auc.to_string(),
+      None => "none".to_string()
+    }.as_str()
+}